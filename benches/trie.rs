@@ -0,0 +1,116 @@
+//! Trie and timestamp hot paths
+//!
+//! Run with `cargo bench`; pass a filter to run one group, e.g.
+//! `cargo bench -- diff`. The timestamps are ten writes a second from two
+//! nodes starting at 1_700_000_000_000, the same shape as `markle bench`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use markle::arena::TrieArena;
+use markle::timestamp::Timestamp;
+use markle::trie::Trie;
+
+const START: i64 = 1_700_000_000_000;
+const NODES: [&str; 2] = ["0000000000000001", "0000000000000002"];
+
+fn timestamps(count: usize) -> Vec<Timestamp> {
+    (0..count)
+        .map(|i| {
+            Timestamp::new(
+                START + i as i64 * 100,
+                (i % 4) as u16,
+                NODES[i % NODES.len()].to_string(),
+            )
+        })
+        .collect()
+}
+
+fn insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    for count in [1_000, 10_000] {
+        let log = timestamps(count);
+        group.throughput(Throughput::Elements(count as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &log, |b, log| {
+            b.iter_batched(
+                Trie::new,
+                |mut trie| {
+                    for ts in log {
+                        trie.insert(ts.clone());
+                    }
+                    trie
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn build(c: &mut Criterion) {
+    let mut group = c.benchmark_group("build");
+    // A million timestamps takes long enough per iteration that criterion's
+    // default of 100 samples would run for minutes
+    group.sample_size(10);
+    let count = 1_000_000;
+    let log = timestamps(count);
+    group.throughput(Throughput::Elements(count as u64));
+    group.bench_function(BenchmarkId::new("trie", count), |b| {
+        b.iter_batched(|| log.clone(), Trie::build, BatchSize::LargeInput)
+    });
+    // Building flat and converting, which boxes every node a second time;
+    // slower than `trie`, so `Trie::build` doesn't do it
+    group.bench_function(BenchmarkId::new("arena_to_trie", count), |b| {
+        b.iter_batched(
+            || log.clone(),
+            |log| Trie::from(&TrieArena::build(log)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function(BenchmarkId::new("arena", count), |b| {
+        b.iter_batched(|| log.clone(), TrieArena::build, BatchSize::LargeInput)
+    });
+    group.finish();
+}
+
+fn diff(c: &mut Criterion) {
+    let mut group = c.benchmark_group("diff");
+    let ours = Trie::build(timestamps(100_000));
+    // Identical tries walk only the root; one late write forces a descent
+    // to a single differing leaf; an early one diverges near the start
+    let same = ours.clone();
+    let mut late = ours.clone();
+    late.insert(Timestamp::new(
+        START + 9_999_950,
+        0,
+        "0000000000000003".into(),
+    ));
+    let mut early = ours.clone();
+    early.insert(Timestamp::new(START + 50, 0, "0000000000000003".into()));
+    for (name, theirs) in [("same", &same), ("late", &late), ("early", &early)] {
+        group.bench_with_input(BenchmarkId::new("trie", name), theirs, |b, theirs| {
+            b.iter(|| black_box(&ours).diff(black_box(theirs)))
+        });
+    }
+
+    let arena = TrieArena::build(timestamps(100_000));
+    let mut arena_early = arena.clone();
+    arena_early.insert(Timestamp::new(START + 50, 0, "0000000000000003".into()));
+    group.bench_function(BenchmarkId::new("arena", "early"), |b| {
+        b.iter(|| black_box(&arena).diff(black_box(&arena_early)))
+    });
+    group.finish();
+}
+
+fn hash(c: &mut Criterion) {
+    let mut group = c.benchmark_group("timestamp_hash");
+    let ts = Timestamp::new(START, 7, NODES[0].to_string());
+    group.bench_function("hash", |b| b.iter(|| black_box(&ts).hash()));
+    // What `hash` used to cost before murmur3 ran: formatting the string
+    // through chrono into a fresh allocation
+    group.bench_function("to_string", |b| b.iter(|| black_box(&ts).to_string()));
+    group.finish();
+}
+
+criterion_group!(benches, insert, build, diff, hash);
+criterion_main!(benches);
@@ -0,0 +1,18 @@
+//! Trie bytes as they arrive in sync frames and saved artifacts. Whatever
+//! decodes must re-encode to the same bytes and survive a diff.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use markle::trie::Trie;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(trie) = Trie::decode(data) {
+        assert_eq!(Trie::decode(&trie.encode()).as_ref(), Ok(&trie));
+        trie.diff(&Trie::new());
+        trie.iter_minutes().count();
+    }
+    if let Ok(trie) = Trie::load(data) {
+        trie.diff(&Trie::new());
+        trie.save();
+    }
+});
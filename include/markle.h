@@ -0,0 +1,154 @@
+#ifndef MARKLE_H
+#define MARKLE_H
+
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Result of a call that can fail
+ */
+typedef enum MarkleStatus {
+  MarkleStatus_Ok = 0,
+  MarkleStatus_NullPointer,
+  MarkleStatus_InvalidUtf8,
+  MarkleStatus_InvalidTimestamp,
+  MarkleStatus_ClockDrift,
+  MarkleStatus_CounterOverflow,
+  MarkleStatus_DuplicateNode,
+  MarkleStatus_OutOfRange,
+  MarkleStatus_Regression,
+  MarkleStatus_Decode,
+  MarkleStatus_TooLarge,
+} MarkleStatus;
+
+/**
+ * A node's hybrid logical clock
+ */
+typedef struct MarkleClock MarkleClock;
+
+/**
+ * A merkle trie of timestamps
+ */
+typedef struct MarkleTrie MarkleTrie;
+
+/**
+ * Create a clock for `node`, or for a fresh random node id if `node` is
+ * null. Returns null if `node` isn't valid UTF-8 or a valid node id.
+ *
+ * # Safety
+ *
+ * `node` must be null or a NUL-terminated string.
+ */
+struct MarkleClock *markle_clock_new(const char *node);
+
+/**
+ * # Safety
+ *
+ * `clock` must be null or returned by `markle_clock_new`, and not used
+ * afterwards.
+ */
+void markle_clock_free(struct MarkleClock *clock);
+
+/**
+ * Mint a timestamp for a local event at physical time `phys`
+ * (milliseconds since the Unix epoch), writing its string form to `out`
+ *
+ * # Safety
+ *
+ * `clock` must come from `markle_clock_new` and `out` must be writable.
+ * The string written must be released with `markle_string_free`.
+ */
+enum MarkleStatus markle_clock_send(struct MarkleClock *clock, int64_t phys, char **out);
+
+/**
+ * Merge a timestamp received from another node, writing the clock's new
+ * timestamp to `out`
+ *
+ * # Safety
+ *
+ * As `markle_clock_send`, and `timestamp` must be a NUL-terminated string.
+ */
+enum MarkleStatus markle_clock_recv(struct MarkleClock *clock,
+                                    const char *timestamp,
+                                    int64_t phys,
+                                    char **out);
+
+/**
+ * # Safety
+ *
+ * `s` must be null or a string written by this library, and not used
+ * afterwards.
+ */
+void markle_string_free(char *s);
+
+/**
+ * Create an empty trie under the default hash scheme
+ */
+struct MarkleTrie *markle_trie_new(void);
+
+/**
+ * # Safety
+ *
+ * `trie` must be null or returned by this library, and not used
+ * afterwards.
+ */
+void markle_trie_free(struct MarkleTrie *trie);
+
+/**
+ * # Safety
+ *
+ * `trie` must come from this library and `timestamp` must be a
+ * NUL-terminated string.
+ */
+enum MarkleStatus markle_trie_insert(struct MarkleTrie *trie, const char *timestamp);
+
+/**
+ * # Safety
+ *
+ * `trie` must come from this library.
+ */
+uint64_t markle_trie_root_hash(const struct MarkleTrie *trie);
+
+/**
+ * Compare two tries, returning true and writing the millisecond time they
+ * diverge from to `out` if they differ
+ *
+ * # Safety
+ *
+ * `a` and `b` must come from this library and `out` must be writable.
+ */
+bool markle_trie_diff(const struct MarkleTrie *a, const struct MarkleTrie *b, int64_t *out);
+
+/**
+ * Serialize a trie, returning a buffer of `*len` bytes to release with
+ * `markle_bytes_free`
+ *
+ * # Safety
+ *
+ * `trie` must come from this library and `len` must be writable.
+ */
+uint8_t *markle_trie_save(const struct MarkleTrie *trie, size_t *len);
+
+/**
+ * # Safety
+ *
+ * `bytes` and `len` must be null or exactly as returned by
+ * `markle_trie_save`, and not used afterwards.
+ */
+void markle_bytes_free(uint8_t *bytes, size_t len);
+
+/**
+ * Deserialize a trie written by `markle_trie_save` into `out`
+ *
+ * # Safety
+ *
+ * `bytes` must point to `len` readable bytes and `out` must be writable.
+ */
+enum MarkleStatus markle_trie_load(const uint8_t *bytes, size_t len, struct MarkleTrie **out);
+
+#endif  /* MARKLE_H */
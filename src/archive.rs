@@ -0,0 +1,291 @@
+// A cold tier for compacted history. Compaction forgets messages so the
+// store and trie stay small; archiving them first means an audit can
+// still read them back later.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::message::Message;
+
+/// Object storage in the shape of S3: whole objects put, fetched and
+/// listed by key prefix
+pub trait ObjectStore {
+    type Error: fmt::Debug;
+
+    fn put(&mut self, key: &str, bytes: Vec<u8>) -> Result<(), Self::Error>;
+
+    /// The object under `key`, or None if there is none
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+
+    /// Keys starting with `prefix`, in lexicographic order
+    fn list(&self, prefix: &str) -> Result<Vec<String>, Self::Error>;
+}
+
+/// Objects kept in memory, for tests
+#[derive(Debug, Clone, Default)]
+pub struct MemoryObjectStore {
+    objects: BTreeMap<String, Vec<u8>>,
+}
+
+impl MemoryObjectStore {
+    pub fn new() -> Self {
+        MemoryObjectStore::default()
+    }
+}
+
+impl ObjectStore for MemoryObjectStore {
+    type Error = core::convert::Infallible;
+
+    fn put(&mut self, key: &str, bytes: Vec<u8>) -> Result<(), Self::Error> {
+        self.objects.insert(key.to_string(), bytes);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.objects.get(key).cloned())
+    }
+
+    fn list(&self, prefix: &str) -> Result<Vec<String>, Self::Error> {
+        Ok(self
+            .objects
+            .range(prefix.to_string()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .cloned()
+            .collect())
+    }
+}
+
+/// One archived batch: the object key and the span of its timestamps
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub key: String,
+    /// Oldest message's millis
+    pub from: i64,
+    /// Newest message's millis
+    pub to: i64,
+}
+
+/// Compacted messages kept in object storage; see `Engine::compact_archived`
+///
+/// Each compaction writes one segment under `prefix`, its key recording
+/// the time range it spans, so listing the prefix is the index and a
+/// rehydration only fetches segments overlapping the range asked for.
+/// Segment keys look like `{prefix}{from}-{to}-{hash}` with the times
+/// zero-padded, so they list oldest first.
+#[derive(Debug)]
+pub struct Archive<O> {
+    objects: O,
+    prefix: String,
+}
+
+impl<O: ObjectStore> Archive<O> {
+    const MAGIC: [u8; 4] = *b"MARC";
+    const VERSION: u8 = 1;
+
+    pub fn new(objects: O, prefix: impl Into<String>) -> Self {
+        Archive {
+            objects,
+            prefix: prefix.into(),
+        }
+    }
+
+    pub fn objects(&self) -> &O {
+        &self.objects
+    }
+
+    /// Write `messages` as one segment, returning it, or None if there was
+    /// nothing to write
+    pub fn store(
+        &mut self,
+        messages: &[Message],
+    ) -> Result<Option<Segment>, ArchiveError<O::Error>> {
+        let (Some(from), Some(to)) = (
+            messages.iter().map(|m| m.timestamp.millis()).min(),
+            messages.iter().map(|m| m.timestamp.millis()).max(),
+        ) else {
+            return Ok(None);
+        };
+
+        let mut w = Writer::new();
+        for byte in Self::MAGIC {
+            w.put_u8(byte);
+        }
+        w.put_u8(Self::VERSION);
+        w.put_u32(messages.len() as u32);
+        for message in messages {
+            message.encode(&mut w);
+        }
+        let bytes = w.into_bytes();
+        // Content addressed, so two compactions over one span don't collide
+        let key = format!(
+            "{}{:020}-{:020}-{:016x}",
+            self.prefix,
+            from,
+            to,
+            xxh3_64(&bytes)
+        );
+        self.objects.put(&key, bytes).map_err(ArchiveError::Store)?;
+        Ok(Some(Segment { key, from, to }))
+    }
+
+    /// Every segment, oldest first
+    pub fn segments(&self) -> Result<Vec<Segment>, ArchiveError<O::Error>> {
+        let keys = self
+            .objects
+            .list(&self.prefix)
+            .map_err(ArchiveError::Store)?;
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| {
+                let mut parts = key[self.prefix.len()..].splitn(3, '-');
+                let from = parts.next()?.parse().ok()?;
+                let to = parts.next()?.parse().ok()?;
+                Some(Segment { key, from, to })
+            })
+            .collect())
+    }
+
+    /// Read back the archived messages timestamped in `from..to`, in
+    /// timestamp order
+    ///
+    /// This is for audits: the engine drops compacted history it is sent,
+    /// so applying these doesn't restore them.
+    pub fn rehydrate(&self, from: i64, to: i64) -> Result<Vec<Message>, ArchiveError<O::Error>> {
+        let mut messages = Vec::new();
+        for segment in self.segments()? {
+            if segment.to < from || segment.from >= to {
+                continue;
+            }
+            let bytes = self
+                .objects
+                .get(&segment.key)
+                .map_err(ArchiveError::Store)?
+                .ok_or_else(|| ArchiveError::Missing(segment.key.clone()))?;
+            let range = from..to;
+            messages.extend(
+                Self::decode(&bytes)?
+                    .into_iter()
+                    .filter(|m| range.contains(&m.timestamp.millis())),
+            );
+        }
+        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        messages.dedup_by(|a, b| a.timestamp == b.timestamp);
+        Ok(messages)
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Vec<Message>, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = r.get_u8()?;
+        }
+        if magic != Self::MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = r.get_u8()?;
+        if version != Self::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let count = r.get_u32()?;
+        let messages = (0..count)
+            .map(|_| Message::decode(&mut r))
+            .collect::<Result<_, _>>()?;
+        r.finish()?;
+        Ok(messages)
+    }
+}
+
+// Errors related to archiving and rehydrating messages
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ArchiveError<E> {
+    Store(E),
+    Decode(DecodeError),
+    // A listed segment that couldn't be fetched
+    Missing(String),
+}
+
+impl<E> From<DecodeError> for ArchiveError<E> {
+    fn from(err: DecodeError) -> Self {
+        ArchiveError::Decode(err)
+    }
+}
+
+impl<E: fmt::Debug> fmt::Display for ArchiveError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Store(err) => write!(f, "object store failed: {:?}", err),
+            ArchiveError::Decode(err) => write!(f, "archive segment unreadable: {}", err),
+            ArchiveError::Missing(key) => write!(f, "archive segment {} is missing", key),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for ArchiveError<E> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::engine::Engine;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+
+    // Refuses every write
+    #[derive(Debug, Default)]
+    struct Offline;
+
+    impl ObjectStore for Offline {
+        type Error = ();
+
+        fn put(&mut self, _key: &str, _bytes: Vec<u8>) -> Result<(), ()> {
+            Err(())
+        }
+
+        fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, ()> {
+            Err(())
+        }
+
+        fn list(&self, _prefix: &str) -> Result<Vec<String>, ()> {
+            Err(())
+        }
+    }
+
+    #[test]
+    fn test_compact_archived() {
+        let mut engine =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let title = engine
+            .write("todos", "1", "title", "buy milk", 1000)
+            .unwrap();
+        let done = engine.write("todos", "1", "done", "true", 61_000).unwrap();
+        engine
+            .write("todos", "2", "title", "buy eggs", 61_000)
+            .unwrap();
+        engine.delete("todos", "1", 121_000).unwrap();
+
+        let mut offline = Archive::new(Offline, "todos/");
+        let got = engine.compact_archived(200_000, &mut offline);
+        assert!(matches!(got, Err(ArchiveError::Store(()))));
+        assert_eq!(engine.store().len(), 4);
+
+        let mut archive = Archive::new(MemoryObjectStore::new(), "todos/");
+        assert_eq!(engine.compact_archived(200_000, &mut archive), Ok(2));
+        assert_eq!(engine.store().len(), 2);
+        // Nothing left to compact writes no segment
+        assert_eq!(engine.compact_archived(200_000, &mut archive), Ok(0));
+
+        let segments = archive.segments().unwrap();
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].from, segments[0].to), (1000, 61_000));
+        assert_eq!(
+            archive.rehydrate(0, 200_000).unwrap(),
+            vec![title, done.clone()]
+        );
+        assert_eq!(archive.rehydrate(2000, 200_000).unwrap(), vec![done]);
+        assert_eq!(archive.rehydrate(62_000, 200_000).unwrap(), vec![]);
+    }
+}
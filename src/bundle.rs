@@ -0,0 +1,301 @@
+// Sync through files, for deployments that share a folder (Dropbox,
+// Syncthing, a USB stick) rather than a network path between nodes. A
+// node exports what it has written since some point into a bundle, and
+// every node that finds the file imports it; importing is idempotent, so
+// a folder of bundles can be imported over and over.
+
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use chrono::Utc;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::engine::Engine;
+use crate::error::SyncError;
+use crate::message::Message;
+use crate::store::{MessageStore, StoreError};
+use crate::trie::{Trie, TrieError};
+
+/// What `SyncBundle::file_name` ends with
+pub const BUNDLE_EXTENSION: &str = "markle-bundle";
+
+/// Messages from one node plus its whole trie, as a file
+///
+/// On disk: magic, version, the exporting node's id, when it was
+/// exported, the millis the messages start from, the trie, the messages,
+/// then xxh3_64 of every byte before it, so a file a folder sync hasn't
+/// finished copying is refused rather than half applied.
+///
+/// The trie lets an importer skip minutes it already holds, and tells the
+/// importer what to export back: `since_for` diffs it against the
+/// importer's own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncBundle {
+    pub node: String,
+    pub created: i64,
+    pub since: i64,
+    pub trie: Trie,
+    pub messages: Vec<Message>,
+}
+
+impl SyncBundle {
+    pub const MAGIC: [u8; 4] = *b"MBDL";
+    pub const VERSION: u8 = 1;
+    const CHECKSUM_LEN: usize = 8;
+
+    /// Every message in `engine` from `since` on, with its trie; pass
+    /// `i64::MIN` for everything
+    pub fn export<S: MessageStore>(engine: &Engine<S>, since: i64) -> Result<Self, StoreError> {
+        Ok(SyncBundle {
+            node: engine.clock().node().to_string(),
+            created: Utc::now().timestamp_millis(),
+            since,
+            trie: engine.trie()?.clone(),
+            messages: engine.messages_since(since),
+        })
+    }
+
+    /// Where `engine` should export from for the author of this bundle to
+    /// catch up, or None if the author has everything it has
+    pub fn since_for<S: MessageStore>(
+        &self,
+        engine: &Engine<S>,
+    ) -> Result<Option<i64>, StoreError> {
+        let since = engine.trie()?.diff(&self.trie);
+        Ok(since.map(|since| since.timestamp_millis()))
+    }
+
+    /// A name unique to the exporting node and time, so bundles from
+    /// several nodes can share a folder
+    pub fn file_name(&self) -> String {
+        format!("{}-{:020}.{}", self.node, self.created, BUNDLE_EXTENSION)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        for byte in Self::MAGIC {
+            w.put_u8(byte);
+        }
+        w.put_u8(Self::VERSION);
+        w.put_str(&self.node);
+        w.put_i64(self.created);
+        w.put_i64(self.since);
+        self.trie.encode_into(&mut w);
+        w.put_u32(self.messages.len() as u32);
+        for message in &self.messages {
+            message.encode(&mut w);
+        }
+        let mut bytes = w.into_bytes();
+        let checksum = xxh3_64(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = r.get_u8()?;
+        }
+        if magic != Self::MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = r.get_u8()?;
+        if version != Self::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let Some(split) = bytes.len().checked_sub(Self::CHECKSUM_LEN) else {
+            return Err(DecodeError::UnexpectedEof);
+        };
+        let (checked, checksum) = bytes.split_at(split);
+        if Reader::new(checksum).get_u64()? != xxh3_64(checked) {
+            return Err(DecodeError::ChecksumMismatch);
+        }
+
+        let mut r = Reader::new(&checked[Self::MAGIC.len() + 1..]);
+        let node = r.get_str()?.to_string();
+        let created = r.get_i64()?;
+        let since = r.get_i64()?;
+        let trie = Trie::decode_from(&mut r)?;
+        let count = r.get_u32()?;
+        let messages = (0..count)
+            .map(|_| Message::decode(&mut r))
+            .collect::<Result<_, _>>()?;
+        r.finish()?;
+        Ok(SyncBundle {
+            node,
+            created,
+            since,
+            trie,
+            messages,
+        })
+    }
+
+    /// Write the bundle to `path`, through a sibling temporary file so
+    /// the file never exists half written
+    pub fn write(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        fs::write(&tmp, self.encode())?;
+        fs::rename(&tmp, path)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, BundleError> {
+        let bytes = fs::read(path).map_err(BundleError::Io)?;
+        Ok(SyncBundle::decode(&bytes)?)
+    }
+
+    /// Read the bundle at `path` and apply it to `engine`, returning the
+    /// messages that were new to it
+    pub fn import<S: MessageStore>(
+        engine: &mut Engine<S>,
+        path: impl AsRef<Path>,
+    ) -> Result<Vec<Message>, BundleError> {
+        let bundle = SyncBundle::read(path)?;
+        bundle.apply(engine, Utc::now().timestamp_millis())
+    }
+
+    /// Apply the bundle's messages to `engine`, as a sync frame carrying
+    /// them would be, returning those new to it
+    ///
+    /// Applying a bundle again, or one overlapping another, changes
+    /// nothing the first time didn't.
+    pub fn apply<S: MessageStore>(
+        self,
+        engine: &mut Engine<S>,
+        phys: i64,
+    ) -> Result<Vec<Message>, BundleError> {
+        engine
+            .trie()
+            .map_err(SyncError::from)?
+            .check_layout(&self.trie)?;
+        let applied = engine.apply_batch(self.messages, &self.trie, phys)?;
+        debug!(node = %self.node, applied = applied.len(), "imported bundle");
+        Ok(applied)
+    }
+}
+
+// Errors related to reading and importing sync bundles
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BundleError {
+    Io(io::Error),
+    Decode(DecodeError),
+    Trie(TrieError),
+    Sync(SyncError),
+}
+
+impl From<DecodeError> for BundleError {
+    fn from(err: DecodeError) -> Self {
+        BundleError::Decode(err)
+    }
+}
+
+impl From<TrieError> for BundleError {
+    fn from(err: TrieError) -> Self {
+        BundleError::Trie(err)
+    }
+}
+
+impl From<SyncError> for BundleError {
+    fn from(err: SyncError) -> Self {
+        BundleError::Sync(err)
+    }
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::Io(err) => write!(f, "bundle unreadable: {}", err),
+            BundleError::Decode(err) => write!(f, "bundle corrupt: {}", err),
+            BundleError::Trie(err) => write!(f, "bundle {}", err),
+            BundleError::Sync(err) => write!(f, "bundle not applied: {}", err),
+        }
+    }
+}
+
+impl core::error::Error for BundleError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+    use crate::trie::{HashScheme, Resolution};
+
+    fn engine() -> Engine<MemoryStore> {
+        Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let now = Utc::now().timestamp_millis();
+        let mut a = engine();
+        let mut b = engine();
+        // A minute apart, so the diff can tell them apart
+        let earlier = now - 60_000;
+        a.write("todos", "1", "title", "buy milk", earlier).unwrap();
+        a.write("todos", "2", "title", "buy eggs", earlier).unwrap();
+        b.write("todos", "3", "title", "buy jam", now).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("markle-bundle-{}", make_client_id()));
+        fs::create_dir_all(&dir).unwrap();
+        let bundle = SyncBundle::export(&a, i64::MIN).unwrap();
+        let path = dir.join(bundle.file_name());
+        bundle.write(&path).unwrap();
+        assert!(path.to_string_lossy().ends_with(".markle-bundle"));
+        assert_eq!(SyncBundle::read(&path).unwrap(), bundle);
+
+        // Importing twice applies once
+        assert_eq!(SyncBundle::import(&mut b, &path).unwrap().len(), 2);
+        assert!(SyncBundle::import(&mut b, &path).unwrap().is_empty());
+        assert_eq!(b.store().len(), 3);
+
+        // The answer carries only what `a` lacks
+        let since = bundle.since_for(&b).unwrap().unwrap();
+        let reply = SyncBundle::export(&b, since).unwrap();
+        assert_eq!(reply.messages.len(), 1);
+        let reply_path = dir.join(reply.file_name());
+        reply.write(&reply_path).unwrap();
+        SyncBundle::import(&mut a, &reply_path).unwrap();
+        assert_eq!(a.trie().unwrap().diff(b.trie().unwrap()), None);
+        assert_eq!(
+            SyncBundle::export(&a, i64::MIN).unwrap().since_for(&b),
+            Ok(None)
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt() {
+        let mut a = engine();
+        a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        let bytes = SyncBundle::export(&a, i64::MIN).unwrap().encode();
+        assert_eq!(&bytes[..4], b"MBDL");
+
+        // A file cut short, as an unfinished folder sync leaves it
+        let cut = &bytes[..bytes.len() - 20];
+        assert_eq!(SyncBundle::decode(cut), Err(DecodeError::ChecksumMismatch));
+        let mut flipped = bytes.clone();
+        flipped[30] ^= 1;
+        assert_eq!(
+            SyncBundle::decode(&flipped),
+            Err(DecodeError::ChecksumMismatch)
+        );
+        assert_eq!(SyncBundle::decode(b"MRKC"), Err(DecodeError::BadMagic));
+
+        let mut hourly = SyncBundle::decode(&bytes).unwrap();
+        hourly.trie = Trie::with_resolution(HashScheme::default(), Resolution::Hour);
+        assert!(matches!(
+            hourly.apply(&mut engine(), 1000),
+            Err(BundleError::Trie(TrieError::ResolutionMismatch(
+                Resolution::Minute,
+                Resolution::Hour
+            )))
+        ));
+    }
+}
@@ -0,0 +1,622 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chrono::Utc;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::timestamp::{ClockError, Timestamp};
+
+/// A node's clock as persisted between runs: the last timestamp it issued
+/// or received
+///
+/// A hybrid logical clock must never go backwards, so applications save
+/// this after writes and restore it on startup through
+/// `Timestamp::observe_persisted`, which also catches OS clock rollbacks.
+/// `Engine::new` raises the clock past anything the node wrote to its
+/// store too, which covers writes made after the last save.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClockState {
+    pub last: Timestamp,
+}
+
+impl ClockState {
+    const MAGIC: [u8; 4] = *b"MRKC";
+    const VERSION: u8 = 1;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        for byte in Self::MAGIC {
+            w.put_u8(byte);
+        }
+        w.put_u8(Self::VERSION);
+        self.last.encode(&mut w);
+        w.into_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = r.get_u8()?;
+        }
+        if magic != Self::MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = r.get_u8()?;
+        if version != Self::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let last = Timestamp::decode(&mut r)?;
+        r.finish()?;
+        Ok(ClockState { last })
+    }
+}
+
+impl Timestamp {
+    /// Snapshot this clock for persisting
+    pub fn to_state(&self) -> ClockState {
+        ClockState { last: self.clone() }
+    }
+
+    pub fn from_state(state: ClockState) -> Self {
+        state.last
+    }
+}
+
+/// Somewhere to persist a node's clock
+pub trait ClockStore {
+    type Error;
+
+    /// The saved state, or None if nothing was saved yet
+    fn load(&mut self) -> Result<Option<ClockState>, Self::Error>;
+
+    fn save(&mut self, state: &ClockState) -> Result<(), Self::Error>;
+}
+
+/// Keeps the clock in a single file
+///
+/// Saves write a sibling temporary file, sync it to disk and rename it over
+/// the original, then sync the directory so the rename is durable too. A
+/// crash or power loss mid-save leaves either the previous state or the new
+/// one, never a torn file.
+#[derive(Debug, Clone)]
+pub struct FileClockStore {
+    path: PathBuf,
+}
+
+impl FileClockStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        FileClockStore { path: path.into() }
+    }
+}
+
+impl ClockStore for FileClockStore {
+    type Error = io::Error;
+
+    fn load(&mut self) -> Result<Option<ClockState>, io::Error> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        ClockState::from_bytes(&bytes)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn save(&mut self, state: &ClockState) -> Result<(), io::Error> {
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&state.to_bytes())?;
+        file.sync_all()?;
+        drop(file);
+        fs::rename(&tmp, &self.path)?;
+        // The rename lives in the directory, which Windows can't open
+        #[cfg(unix)]
+        {
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+/// A wall clock, in milliseconds since the epoch
+///
+/// The engine takes physical time as an argument rather than reading a
+/// clock, so this is for the code driving it; tests swap in
+/// `testing::MockClock`.
+pub trait TimeSource {
+    fn now(&self) -> i64;
+}
+
+/// The operating system's wall clock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SystemClock;
+
+impl TimeSource for SystemClock {
+    fn now(&self) -> i64 {
+        Utc::now().timestamp_millis()
+    }
+}
+
+/// Where physical time comes from: the local clock, or the server's
+///
+/// A device whose clock is far off can't write, since its timestamps trip
+/// the drift check on every peer. Once a drift error or a server time
+/// shows the offset, `trust_server` has `now` add it to the local clock
+/// for a while, so the user can keep syncing while the app asks them to
+/// fix their clock. Pass `now()` wherever the engine takes `phys`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PhysicalTime {
+    // Server minus local, and the local time the trust lapses
+    trusted: Option<(i64, i64)>,
+}
+
+impl PhysicalTime {
+    /// How long `trust_server` lasts unless renewed
+    pub const TRUST_WINDOW: i64 = 60 * 60 * 1000;
+
+    /// Trusting the local clock
+    pub fn new() -> Self {
+        PhysicalTime::default()
+    }
+
+    /// Take time from the server, `server` being its time when the local
+    /// clock read `local`; renews the window if already trusting it
+    pub fn trust_server(&mut self, server: i64, local: i64) {
+        self.trusted = Some((server - local, local + Self::TRUST_WINDOW));
+    }
+
+    /// Trust the server by the offset a drift error measured, returning
+    /// false if `err` wasn't one
+    pub fn remediate(&mut self, err: &ClockError, local: i64) -> bool {
+        match err.drift_offset() {
+            Some(offset) => {
+                self.trust_server(local + offset, local);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Trust the server by an estimated offset, returning false if there
+    /// is no estimate yet
+    pub fn trust_estimate(&mut self, estimator: &OffsetEstimator, local: i64) -> bool {
+        match estimator.server_now(local) {
+            Some(server) => {
+                self.trust_server(server, local);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Go back to the local clock, e.g. once the user has fixed it
+    pub fn trust_local(&mut self) {
+        self.trusted = None;
+    }
+
+    /// The offset being applied, if trusting the server at local time
+    /// `local`; show the user something while this is set
+    pub fn offset_at(&self, local: i64) -> Option<i64> {
+        match self.trusted {
+            Some((offset, until)) if local < until => Some(offset),
+            _ => None,
+        }
+    }
+
+    /// Physical time for the local clock reading `local`
+    pub fn at(&self, local: i64) -> i64 {
+        local + self.offset_at(local).unwrap_or(0)
+    }
+
+    pub fn now(&self) -> i64 {
+        self.now_from(&SystemClock)
+    }
+
+    /// `now`, reading the local clock from `source`
+    pub fn now_from(&self, source: &impl TimeSource) -> i64 {
+        self.at(source.now())
+    }
+
+    /// `now`, reading the local clock through a smoother
+    pub fn now_smoothed<M: Smear>(&self, clock: &mut SmoothedClock<M>) -> i64 {
+        self.at(clock.now())
+    }
+}
+
+/// Smooths wall clock readings before they reach the hybrid clock
+///
+/// Each reading pairs the wall clock with a monotonic one, so a smoother
+/// can tell the wall clock stepping, as NTP does when it corrects a large
+/// error or a leap second is applied, from time passing.
+pub trait Smear {
+    /// Physical time for the wall clock reading `wall`, taken when the
+    /// monotonic clock read `mono`; both in milliseconds
+    fn smooth(&mut self, wall: i64, mono: i64) -> i64;
+}
+
+/// Passes the wall clock through untouched
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NoSmear;
+
+impl Smear for NoSmear {
+    fn smooth(&mut self, wall: i64, _mono: i64) -> i64 {
+        wall
+    }
+}
+
+/// Spreads each wall clock step evenly over a window
+///
+/// A step forward reaches peers as timestamps from the future, and one
+/// backward piles writes onto the logical counter until the wall clock
+/// catches up; both surface as bursts of drift errors. Smearing instead
+/// runs the clock slightly fast or slow for `window` milliseconds until
+/// it has absorbed the step. A second step during a smear starts a new
+/// one from wherever the first had got to.
+///
+/// Steps within the tolerance, which covers the jitter between the two
+/// clocks, apply at once. The output never goes backwards, so a backward
+/// step larger than the window holds the clock still for part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LinearSmear {
+    window: i64,
+    tolerance: i64,
+    // Wall minus monotonic, as (target, smear start value, smear start)
+    offset: Option<(i64, i64, i64)>,
+    last: i64,
+}
+
+impl LinearSmear {
+    /// Default for `tolerance`
+    pub const TOLERANCE: i64 = 100;
+
+    pub fn new(window: i64) -> Self {
+        LinearSmear {
+            window: window.max(1),
+            tolerance: Self::TOLERANCE,
+            offset: None,
+            last: i64::MIN,
+        }
+    }
+
+    /// Apply steps of up to `tolerance` milliseconds immediately
+    pub fn tolerance(mut self, tolerance: i64) -> Self {
+        self.tolerance = tolerance.max(0);
+        self
+    }
+
+    /// The offset applied when the monotonic clock reads `mono`
+    fn current(&self, mono: i64) -> Option<i64> {
+        let (target, from, start) = self.offset?;
+        let elapsed = mono - start;
+        if elapsed >= self.window {
+            return Some(target);
+        }
+        let step = (target - from) as i128 * elapsed.max(0) as i128 / self.window as i128;
+        Some(from + step as i64)
+    }
+
+    /// How much of a step is still to be absorbed at `mono`, positive when
+    /// the output is behind the wall clock
+    pub fn pending(&self, mono: i64) -> i64 {
+        match (self.offset, self.current(mono)) {
+            (Some((target, _, _)), Some(current)) => target - current,
+            _ => 0,
+        }
+    }
+}
+
+impl Smear for LinearSmear {
+    fn smooth(&mut self, wall: i64, mono: i64) -> i64 {
+        let offset = wall - mono;
+        self.offset = match (self.offset, self.current(mono)) {
+            (Some((target, from, start)), _) if (offset - target).abs() <= self.tolerance => {
+                Some((offset, from + offset - target, start))
+            }
+            (Some(_), Some(current)) => Some((offset, current, mono)),
+            _ => Some((offset, offset, mono)),
+        };
+        let current = self.current(mono).unwrap_or(offset);
+        self.last = self.last.max(mono + current);
+        self.last
+    }
+}
+
+/// A wall clock read through a `Smear`
+///
+/// Keep one per process; pass `now()` wherever the engine takes `phys`,
+/// or hand it to `PhysicalTime::now_smoothed`.
+#[derive(Debug, Clone)]
+pub struct SmoothedClock<M = LinearSmear> {
+    smear: M,
+    origin: Instant,
+}
+
+impl<M: Smear> SmoothedClock<M> {
+    pub fn new(smear: M) -> Self {
+        SmoothedClock {
+            smear,
+            origin: Instant::now(),
+        }
+    }
+
+    pub fn smear(&self) -> &M {
+        &self.smear
+    }
+
+    /// The smoothed wall time in milliseconds
+    pub fn now(&mut self) -> i64 {
+        let mono = self.origin.elapsed().as_millis() as i64;
+        self.smear.smooth(Utc::now().timestamp_millis(), mono)
+    }
+}
+
+/// Estimates how far our wall clock is from a server's, from the times
+/// the server stamps on its sync responses
+///
+/// Each sample is a request sent at local `sent`, answered at server time
+/// `server`, and received at local `received`. Assuming the reply took
+/// half the round trip, the server's clock read `server + rtt / 2` when
+/// ours read `received`. Of the recent samples the one with the shortest
+/// round trip is used, as it bounds the error tightest.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OffsetEstimator {
+    // (rtt, offset), newest last
+    samples: VecDeque<(i64, i64)>,
+    // The server's hybrid clock and wall time from its last response
+    server_clock: Option<(Timestamp, i64)>,
+}
+
+impl OffsetEstimator {
+    const SAMPLES: usize = 8;
+
+    pub fn new() -> Self {
+        OffsetEstimator::default()
+    }
+
+    pub fn observe(&mut self, sent: i64, server: i64, received: i64) {
+        let rtt = (received - sent).max(0);
+        if self.samples.len() == Self::SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((rtt, server + rtt / 2 - received));
+    }
+
+    /// Record the server's hybrid clock alongside its wall time
+    pub fn observe_clock(&mut self, clock: Timestamp, server: i64) {
+        self.server_clock = Some((clock, server));
+    }
+
+    fn best(&self) -> Option<(i64, i64)> {
+        self.samples.iter().min_by_key(|(rtt, _)| *rtt).copied()
+    }
+
+    /// Server time minus ours, in milliseconds: positive when our clock is
+    /// behind
+    pub fn offset(&self) -> Option<i64> {
+        self.best().map(|(_, offset)| offset)
+    }
+
+    /// The round trip of the sample `offset` comes from, which bounds its
+    /// error to half of it
+    pub fn rtt(&self) -> Option<i64> {
+        self.best().map(|(rtt, _)| rtt)
+    }
+
+    /// The server's time when ours reads `local`
+    pub fn server_now(&self, local: i64) -> Option<i64> {
+        self.offset().map(|offset| local + offset)
+    }
+
+    /// How far the server's hybrid clock ran ahead of its own wall clock
+    ///
+    /// A large lead with a small `offset` means some other peer's clock is
+    /// ahead and dragged the group's timestamps with it; a large `offset`
+    /// means our own clock is off.
+    pub fn server_lead(&self) -> Option<i64> {
+        self.server_clock
+            .as_ref()
+            .map(|(clock, wall)| clock.millis() - wall)
+    }
+}
+
+/// How often remote clocks have run ahead of ours; see `Engine::drift_stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DriftStats {
+    /// Remote timestamps received ahead of our physical time, whether or
+    /// not they were within the drift limit
+    pub ahead: u64,
+    /// Timestamps refused for exceeding the drift limit, sent or received
+    pub rejected: u64,
+    /// The furthest ahead any remote timestamp has been
+    pub max_ahead: Duration,
+}
+
+type DriftCallback = Box<dyn FnMut(Duration) + Send>;
+
+// Watches the timestamps an engine's clock receives
+#[derive(Default)]
+pub(crate) struct DriftMonitor {
+    stats: DriftStats,
+    on_drift: Option<DriftCallback>,
+}
+
+impl fmt::Debug for DriftMonitor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DriftMonitor")
+            .field("stats", &self.stats)
+            .field("on_drift", &self.on_drift.is_some())
+            .finish()
+    }
+}
+
+impl DriftMonitor {
+    pub(crate) fn stats(&self) -> DriftStats {
+        self.stats
+    }
+
+    pub(crate) fn set_callback(&mut self, callback: Option<DriftCallback>) {
+        self.on_drift = callback;
+    }
+
+    // Called with every remote timestamp before the clock takes it
+    pub(crate) fn observe(&mut self, remote: &Timestamp, phys: i64) {
+        if remote.millis() <= phys {
+            return;
+        }
+        let ahead = Duration::from_millis((remote.millis() - phys) as u64);
+        self.stats.ahead += 1;
+        self.stats.max_ahead = self.stats.max_ahead.max(ahead);
+        histogram!("markle_clock_ahead_seconds", ahead.as_secs_f64());
+        if let Some(on_drift) = &mut self.on_drift {
+            on_drift(ahead);
+        }
+    }
+
+    // Count and log a clock error if it was drift
+    pub(crate) fn report(&mut self, err: Option<&ClockError>) {
+        if let Some(err @ ClockError::Drift { .. }) = err {
+            self.stats.rejected += 1;
+            counter!("markle_clock_drift_rejections_total", 1);
+            log_warn!("{}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timestamp::make_client_id;
+
+    #[test]
+    fn test_state_roundtrip() {
+        let clock = Timestamp::new(1711231855000, 7, make_client_id());
+        let state = clock.to_state();
+        let got = ClockState::from_bytes(&state.to_bytes()).unwrap();
+        assert_eq!(Timestamp::from_state(got), clock);
+
+        let got = ClockState::from_bytes(b"MRKC\x02").err();
+        assert_eq!(got, Some(DecodeError::UnsupportedVersion(2)));
+    }
+
+    #[test]
+    fn test_file_store() {
+        let path = std::env::temp_dir().join(format!("markle-clock-{}", make_client_id()));
+        let mut store = FileClockStore::new(&path);
+        assert_eq!(store.load().unwrap(), None);
+
+        let state = Timestamp::new(1711231855000, 7, make_client_id()).to_state();
+        store.save(&state).unwrap();
+        assert_eq!(FileClockStore::new(&path).load().unwrap(), Some(state));
+
+        fs::write(&path, b"garbage").unwrap();
+        let err = store.load().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_state_serde() {
+        // Fields rather than the string form, which pads or cuts the node
+        let state = Timestamp::new(1711231855000, 7, "abc".to_string()).to_state();
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(
+            json,
+            r#"{"last":{"millis":1711231855000,"counter":7,"node":"abc"}}"#
+        );
+        assert_eq!(serde_json::from_str::<ClockState>(&json).unwrap(), state);
+    }
+
+    #[test]
+    fn test_trust_server_time() {
+        let hour = 60 * 60 * 1000;
+        let server = 1711231855000;
+        let local = server - 2 * hour;
+        let remote = Timestamp::new(server, 0, make_client_id());
+        let mut clock = Timestamp::new(0, 0, make_client_id());
+        let mut time = PhysicalTime::new();
+
+        let err = clock.recv(&remote, time.at(local)).unwrap_err();
+        let err = err.clock().unwrap();
+        assert_eq!(err.drift_offset(), Some(2 * hour));
+        assert!(time.remediate(err, local));
+        assert_eq!(time.offset_at(local), Some(2 * hour));
+        clock.recv(&remote, time.at(local)).unwrap();
+        clock.send(time.at(local + 1000)).unwrap();
+
+        // Trust lapses unless renewed
+        let later = local + PhysicalTime::TRUST_WINDOW;
+        assert_eq!(time.at(later), later);
+        time.trust_server(server, local);
+        time.trust_local();
+        assert_eq!(time.offset_at(local), None);
+        assert!(!time.remediate(&ClockError::Overflow, local));
+    }
+
+    #[test]
+    fn test_offset_estimator() {
+        let mut estimator = OffsetEstimator::new();
+        assert_eq!(estimator.offset(), None);
+
+        // Our clock is 5s behind; the slow round trip is the worse estimate
+        estimator.observe(1000, 6500, 2000);
+        estimator.observe(3000, 8040, 3100);
+        assert_eq!(estimator.rtt(), Some(100));
+        assert_eq!(estimator.offset(), Some(4990));
+        assert_eq!(estimator.server_now(10_000), Some(14_990));
+
+        let clock = Timestamp::new(9040, 0, make_client_id());
+        estimator.observe_clock(clock, 8040);
+        assert_eq!(estimator.server_lead(), Some(1000));
+
+        let mut time = PhysicalTime::new();
+        assert!(time.trust_estimate(&estimator, 3100));
+        assert_eq!(time.at(3100), 8090);
+        assert!(!PhysicalTime::new().trust_estimate(&OffsetEstimator::new(), 0));
+    }
+
+    #[test]
+    fn test_linear_smear() {
+        let wall = 1711231855000;
+        let mut smear = LinearSmear::new(10_000);
+        assert_eq!(smear.smooth(wall, 0), wall);
+        // Jitter passes straight through
+        assert_eq!(smear.smooth(wall + 1050, 1000), wall + 1050);
+
+        // NTP steps the clock 2s forward; it takes the window to catch up
+        let mono = 2000;
+        let stepped = wall + 2050 + 2000;
+        assert_eq!(smear.smooth(stepped, mono), wall + 2050);
+        assert_eq!(smear.pending(mono), 2000);
+        assert_eq!(smear.smooth(stepped + 5000, mono + 5000), stepped + 4000);
+        assert_eq!(
+            smear.smooth(stepped + 10_000, mono + 10_000),
+            stepped + 10_000
+        );
+        assert_eq!(smear.pending(mono + 10_000), 0);
+
+        // A step back larger than the window holds the clock still rather
+        // than going backwards
+        let mut smear = LinearSmear::new(1000).tolerance(0);
+        assert_eq!(smear.smooth(wall, 0), wall);
+        assert_eq!(smear.smooth(wall - 5000, 100), wall + 100);
+        assert_eq!(smear.smooth(wall - 4000, 1100), wall + 100);
+        assert_eq!(smear.smooth(wall + 2000, 7100), wall + 2000);
+
+        let mut clock = SmoothedClock::new(NoSmear);
+        let before = Utc::now().timestamp_millis();
+        assert!(PhysicalTime::new().now_smoothed(&mut clock) >= before);
+    }
+}
@@ -0,0 +1,218 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::header::Header;
+
+/// Byte writer for the sync wire format
+///
+/// Integers are written big-endian, strings and byte slices are prefixed
+/// with their length as a u32.
+#[derive(Debug, Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn new() -> Self {
+        Writer { buf: Vec::new() }
+    }
+
+    pub fn put_u8(&mut self, v: u8) {
+        self.buf.push(v);
+    }
+
+    pub fn put_u16(&mut self, v: u16) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn put_u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn put_u64(&mut self, v: u64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn put_i64(&mut self, v: i64) {
+        self.buf.extend_from_slice(&v.to_be_bytes());
+    }
+
+    pub fn put_bytes(&mut self, v: &[u8]) {
+        self.put_u32(v.len() as u32);
+        self.buf.extend_from_slice(v);
+    }
+
+    pub fn put_str(&mut self, v: &str) {
+        self.put_bytes(v.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Byte reader for the sync wire format
+#[derive(Debug)]
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Reader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.buf.len())
+            .ok_or(DecodeError::UnexpectedEof)?;
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn get_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_u16(&mut self) -> Result<u16, DecodeError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn get_u32(&mut self) -> Result<u32, DecodeError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_be_bytes(bytes))
+    }
+
+    pub fn get_u64(&mut self) -> Result<u64, DecodeError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    pub fn get_i64(&mut self) -> Result<i64, DecodeError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(i64::from_be_bytes(bytes))
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn get_str(&mut self) -> Result<&'a str, DecodeError> {
+        core::str::from_utf8(self.get_bytes()?).map_err(|_| DecodeError::InvalidUtf8)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+
+    /// Error unless every byte has been consumed
+    pub fn finish(&self) -> Result<(), DecodeError> {
+        if self.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes(self.buf.len() - self.pos))
+        }
+    }
+}
+
+// Errors related to decoding wire data
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum DecodeError {
+    UnexpectedEof,
+    TrailingBytes(usize),
+    InvalidUtf8,
+    InvalidTag(u8),
+    InvalidKey(u8),
+    TooDeep(usize),
+    UnsupportedVersion(u8),
+    UnknownHashScheme(u8),
+    UnknownResolution(u8),
+    BadMagic,
+    InvalidDepth(u8),
+    // Found, expected
+    HeaderMismatch(Header, Header),
+    InvalidJson(String),
+    ChecksumMismatch,
+    InvalidProto(String),
+    UnknownCompression(u8),
+    InvalidCompressed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DecodeError::UnexpectedEof => write!(f, "unexpected end of input"),
+            DecodeError::TrailingBytes(n) => write!(f, "{} trailing bytes after message", n),
+            DecodeError::InvalidUtf8 => write!(f, "invalid utf-8 in string"),
+            DecodeError::InvalidTag(tag) => write!(f, "invalid tag {}", tag),
+            DecodeError::InvalidKey(key) => write!(f, "invalid trie key {}", key),
+            DecodeError::TooDeep(depth) => write!(f, "trie nesting exceeds depth {}", depth),
+            DecodeError::UnsupportedVersion(v) => write!(f, "unsupported format version {}", v),
+            DecodeError::UnknownHashScheme(id) => write!(f, "unknown hash scheme {}", id),
+            DecodeError::UnknownResolution(id) => write!(f, "unknown trie resolution {}", id),
+            DecodeError::BadMagic => write!(f, "not a markle artifact"),
+            DecodeError::InvalidDepth(depth) => write!(f, "invalid trie depth {}", depth),
+            DecodeError::HeaderMismatch(found, expected) => write!(
+                f,
+                "artifact was written as {}, expected {}",
+                found, expected
+            ),
+            DecodeError::InvalidJson(ref reason) => write!(f, "invalid json: {}", reason),
+            DecodeError::ChecksumMismatch => write!(f, "checksum mismatch, data corrupted"),
+            DecodeError::InvalidProto(ref reason) => write!(f, "invalid protobuf: {}", reason),
+            DecodeError::UnknownCompression(id) => write!(f, "unknown compression {}", id),
+            DecodeError::InvalidCompressed => write!(f, "compressed data is corrupt or too large"),
+        }
+    }
+}
+
+impl core::error::Error for DecodeError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let mut w = Writer::new();
+        w.put_u8(7);
+        w.put_u16(0xBEEF);
+        w.put_u32(0xDEADBEEF);
+        w.put_i64(-42);
+        w.put_str("markle");
+        let bytes = w.into_bytes();
+
+        let mut r = Reader::new(&bytes);
+        assert_eq!(r.get_u8(), Ok(7));
+        assert_eq!(r.get_u16(), Ok(0xBEEF));
+        assert_eq!(r.get_u32(), Ok(0xDEADBEEF));
+        assert_eq!(r.get_i64(), Ok(-42));
+        assert_eq!(r.get_str(), Ok("markle"));
+        assert_eq!(r.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut w = Writer::new();
+        w.put_str("markle");
+        let bytes = w.into_bytes();
+
+        let mut r = Reader::new(&bytes[..6]);
+        assert_eq!(r.get_str(), Err(DecodeError::UnexpectedEof));
+
+        // A length prefix larger than the input must not panic
+        let mut r = Reader::new(&[0xFF, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(r.get_bytes(), Err(DecodeError::UnexpectedEof));
+    }
+}
@@ -0,0 +1,154 @@
+use alloc::vec::Vec;
+use chrono::{DateTime, Utc};
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::trie::{HashScheme, Resolution, Trie};
+
+/// v1 compatibility codec
+///
+/// The wire layout of the original tries: 32-bit murmur3 hashes combined
+/// with XOR, minute buckets keyed by 16 base-3 digits. Deployed peers speak
+/// this format, so it is frozen; any change to `Trie` must keep this codec
+/// producing and accepting exactly the bytes pinned by the tests below.
+pub struct V1Codec;
+
+impl V1Codec {
+    pub const VERSION: u8 = 1;
+
+    /// Encode a trie, or None if it doesn't use the v1 hash scheme
+    pub fn encode(trie: &Trie) -> Option<Vec<u8>> {
+        if trie.scheme() != HashScheme::Murmur32Xor {
+            return None;
+        }
+
+        let mut w = Writer::new();
+        w.put_u8(Self::VERSION);
+        trie.encode_nodes(&mut w);
+        Some(w.into_bytes())
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Trie, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let version = r.get_u8()?;
+        if version != Self::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let trie = Trie::decode_nodes(&mut r, HashScheme::Murmur32Xor, Resolution::Minute)?;
+        r.finish()?;
+        Ok(trie)
+    }
+
+    /// Diff a local trie against a peer's v1-encoded trie
+    pub fn diff(local: &Trie, remote: &[u8]) -> Result<Option<DateTime<Utc>>, DecodeError> {
+        Ok(local.diff(&Self::decode(remote)?))
+    }
+}
+
+/// JSON merkle codec of James Long's crdt example and Actual Budget
+///
+/// Their tries are nested objects, `{"0": {...}, "1": {...}, "hash": n}`,
+/// where `n` is the murmur3 XOR as JavaScript leaves it: a signed 32-bit
+/// integer. Keys and hash inputs already match the v1 scheme, with one
+/// caveat: the JavaScript keys aren't padded, so tries only agree for
+/// timestamps from 2004 on, when minute keys reach 16 digits.
+#[cfg(feature = "js")]
+pub struct CrdtJson;
+
+#[cfg(feature = "js")]
+impl CrdtJson {
+    /// Encode a trie as `JSON.stringify` would, or None if it doesn't use
+    /// the v1 hash scheme
+    pub fn encode(trie: &Trie) -> Option<String> {
+        if trie.scheme() != HashScheme::Murmur32Xor {
+            return None;
+        }
+        Some(trie.to_json())
+    }
+
+    pub fn decode(json: &str) -> Result<Trie, DecodeError> {
+        Trie::from_json(json, HashScheme::Murmur32Xor)
+    }
+
+    /// Diff a local trie against a peer's JSON trie
+    pub fn diff(local: &Trie, remote: &str) -> Result<Option<DateTime<Utc>>, DecodeError> {
+        Ok(local.diff(&Self::decode(remote)?))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timestamp::Timestamp;
+
+    fn v1_trie() -> Trie {
+        let node = "1234123412341234".to_string();
+        Trie::build(vec![Timestamp::new(1711231855000, 0, node)])
+    }
+
+    #[test]
+    fn test_encode_pinned() {
+        let bytes = V1Codec::encode(&v1_trie()).unwrap();
+
+        // version, then 17 nested nodes of (hash, child count, child key),
+        // the leaf having no children
+        assert_eq!(bytes.len(), 1 + 16 * 6 + 5);
+        assert_eq!(bytes[0], 1);
+        let hash = &bytes[1..5];
+        assert_eq!(hash, &[0xDA, 0xB0, 0xC7, 0x95]);
+        assert_eq!(&bytes[5..7], &[1, b'1']);
+        assert_eq!(&bytes[7..11], hash);
+        assert_eq!(&bytes[bytes.len() - 5..bytes.len() - 1], hash);
+        assert_eq!(bytes[bytes.len() - 1], 0);
+    }
+
+    #[test]
+    fn test_diff_remote() {
+        let local = v1_trie();
+        let remote = V1Codec::encode(&local).unwrap();
+        assert_eq!(V1Codec::diff(&local, &remote), Ok(None));
+
+        let got = V1Codec::diff(&Trie::new(), &remote).unwrap();
+        let want = DateTime::from_timestamp_millis(1711231800000);
+        assert_eq!(got, want);
+    }
+
+    #[test]
+    fn test_encode_rejects_other_schemes() {
+        let trie = Trie::with_scheme(HashScheme::Xxh64Add);
+        assert_eq!(V1Codec::encode(&trie), None);
+    }
+
+    #[cfg(feature = "js")]
+    #[test]
+    fn test_crdt_json_pinned() {
+        let want = include_str!("../tests/reference/single.json").trim();
+        assert_eq!(CrdtJson::encode(&v1_trie()).unwrap(), want);
+
+        let decoded = CrdtJson::decode(want).unwrap();
+        assert_eq!(decoded, v1_trie());
+        assert_eq!(decoded.diff(&v1_trie()), None);
+        assert_eq!(CrdtJson::encode(&decoded).unwrap(), want);
+    }
+
+    #[cfg(feature = "js")]
+    #[test]
+    fn test_crdt_json_diff() {
+        assert_eq!(CrdtJson::encode(&Trie::new()).unwrap(), "{}");
+
+        let got = CrdtJson::diff(&Trie::new(), &CrdtJson::encode(&v1_trie()).unwrap()).unwrap();
+        let want = DateTime::from_timestamp_millis(1711231800000);
+        assert_eq!(got, want);
+
+        let got = CrdtJson::decode(r#"{"3":{"hash":1},"hash":1}"#).err();
+        let want = DecodeError::InvalidJson("unexpected key \"3\"".to_string());
+        assert_eq!(got, Some(want));
+    }
+
+    #[test]
+    fn test_decode_unknown_version() {
+        let mut bytes = V1Codec::encode(&v1_trie()).unwrap();
+        bytes[0] = 2;
+        let got = V1Codec::decode(&bytes).err();
+        assert_eq!(got, Some(DecodeError::UnsupportedVersion(2)));
+    }
+}
@@ -0,0 +1,103 @@
+// Optional compression for encoded tries and message batches. A compressed
+// payload starts with one byte naming its compression, so readers needn't
+// be told out of band and bare payloads can still be marked as such.
+
+use alloc::vec::Vec;
+
+use crate::codec::DecodeError;
+
+/// Largest payload `decompress` will inflate, so a small hostile input
+/// can't claim gigabytes
+pub const MAX_DECOMPRESSED_LEN: usize = 256 << 20;
+
+/// How a payload's body is compressed
+///
+/// Deflate needs the `compression` feature; without it such payloads fail
+/// to decode with `DecodeError::UnknownCompression`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    #[default]
+    None,
+    /// Raw deflate at a level that favours speed, as phones pay for CPU too
+    #[cfg(feature = "compression")]
+    Deflate,
+}
+
+impl Compression {
+    pub fn id(self) -> u8 {
+        match self {
+            Compression::None => 0,
+            #[cfg(feature = "compression")]
+            Compression::Deflate => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Compression::None),
+            #[cfg(feature = "compression")]
+            1 => Some(Compression::Deflate),
+            _ => None,
+        }
+    }
+}
+
+/// `bytes` behind a byte naming `compression`, compressed with it
+pub fn compress(bytes: &[u8], compression: Compression) -> Vec<u8> {
+    let mut out = Vec::with_capacity(bytes.len() + 1);
+    out.push(compression.id());
+    match compression {
+        Compression::None => out.extend_from_slice(bytes),
+        #[cfg(feature = "compression")]
+        Compression::Deflate => {
+            out.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(bytes, 3))
+        }
+    }
+    out
+}
+
+/// Undo `compress`, whichever compression it used
+pub fn decompress(bytes: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let (&id, body) = bytes.split_first().ok_or(DecodeError::UnexpectedEof)?;
+    match Compression::from_id(id).ok_or(DecodeError::UnknownCompression(id))? {
+        Compression::None => Ok(body.to_vec()),
+        #[cfg(feature = "compression")]
+        Compression::Deflate => {
+            miniz_oxide::inflate::decompress_to_vec_with_limit(body, MAX_DECOMPRESSED_LEN)
+                .map_err(|_| DecodeError::InvalidCompressed)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_compress() {
+        let bytes: Vec<u8> = (0..4096u32).map(|i| (i % 7) as u8).collect();
+        let bare = compress(&bytes, Compression::None);
+        assert_eq!(bare.len(), bytes.len() + 1);
+        assert_eq!(decompress(&bare), Ok(bytes.clone()));
+
+        assert_eq!(decompress(&[]), Err(DecodeError::UnexpectedEof));
+        assert_eq!(decompress(&[9, 1]), Err(DecodeError::UnknownCompression(9)));
+
+        #[cfg(feature = "compression")]
+        {
+            let deflated = compress(&bytes, Compression::Deflate);
+            assert!(deflated.len() < bytes.len() / 10);
+            assert_eq!(decompress(&deflated), Ok(bytes));
+            assert_eq!(
+                decompress(&[1, 0xff, 0xff]),
+                Err(DecodeError::InvalidCompressed)
+            );
+        }
+        #[cfg(not(feature = "compression"))]
+        assert_eq!(
+            decompress(&[1, 1, 1, 1]),
+            Err(DecodeError::UnknownCompression(1))
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+use chrono::{DateTime, Utc};
+
+use crate::error::SyncError;
+use crate::timestamp::{Timestamp, TimestampError};
+use crate::trie::Trie;
+
+/// A value and the timestamp of the write that set it
+///
+/// A value of None is a tombstone: the write was a delete. Tombstones win
+/// and lose like any other write, so a delete can't be undone by an older
+/// write arriving late.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry<V> {
+    pub value: Option<V>,
+    pub timestamp: Timestamp,
+}
+
+impl<V> Entry<V> {
+    pub fn is_tombstone(&self) -> bool {
+        self.value.is_none()
+    }
+}
+
+/// Last-writer-wins register holding a single value
+///
+/// Writes carry timestamps minted by the caller's clock, and the greatest
+/// one wins whatever order they arrive in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LwwRegister<T> {
+    entry: Option<Entry<T>>,
+}
+
+impl<T> Default for LwwRegister<T> {
+    fn default() -> Self {
+        LwwRegister { entry: None }
+    }
+}
+
+impl<T: Clone> LwwRegister<T> {
+    pub fn new() -> Self {
+        LwwRegister::default()
+    }
+
+    /// The current value, or None if unset or deleted
+    pub fn get(&self) -> Option<&T> {
+        self.entry.as_ref()?.value.as_ref()
+    }
+
+    /// The timestamp of the winning write, delete or not
+    pub fn timestamp(&self) -> Option<&Timestamp> {
+        self.entry.as_ref().map(|entry| &entry.timestamp)
+    }
+
+    pub fn entry(&self) -> Option<&Entry<T>> {
+        self.entry.as_ref()
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.entry.as_ref().is_some_and(Entry::is_tombstone)
+    }
+
+    /// Write `value` at `timestamp`, returning whether it won
+    pub fn set(&mut self, value: T, timestamp: Timestamp) -> bool {
+        self.write(Entry {
+            value: Some(value),
+            timestamp,
+        })
+    }
+
+    /// Delete at `timestamp`, returning whether it won
+    pub fn delete(&mut self, timestamp: Timestamp) -> bool {
+        self.write(Entry {
+            value: None,
+            timestamp,
+        })
+    }
+
+    /// Take a write, returning whether it won
+    pub fn write(&mut self, entry: Entry<T>) -> bool {
+        let wins = match &self.entry {
+            Some(current) => entry.timestamp > current.timestamp,
+            None => true,
+        };
+        if wins {
+            self.entry = Some(entry);
+        }
+        wins
+    }
+
+    /// Take the other replica's winning write, returning whether it won here
+    pub fn merge(&mut self, other: &LwwRegister<T>) -> bool {
+        match &other.entry {
+            Some(entry) => self.write(entry.clone()),
+            None => false,
+        }
+    }
+
+    /// Forget a tombstone written before `horizon`, leaving the register
+    /// unset; returns whether there was one
+    pub fn prune(&mut self, horizon: i64) -> bool {
+        let stale = self
+            .entry
+            .as_ref()
+            .is_some_and(|entry| entry.is_tombstone() && entry.timestamp.millis() < horizon);
+        if stale {
+            self.entry = None;
+        }
+        stale
+    }
+}
+
+/// Last-writer-wins map
+///
+/// Every entry keeps the timestamp of its last write, and of two writes to
+/// a key the one with the greater timestamp wins. Timestamps order by
+/// time, then counter, then node, so any two maps holding the same writes
+/// agree whatever order they merged them in.
+///
+/// The map keeps a trie over the timestamps of its current entries,
+/// tombstones included. Two maps whose tries match hold the same winners;
+/// otherwise `diff` gives the time to exchange `entries_since`.
+#[derive(Debug, Clone)]
+pub struct LwwMap<K, V> {
+    clock: Timestamp,
+    entries: BTreeMap<K, Entry<V>>,
+    trie: Trie,
+    // Tombstones written before this have been pruned
+    horizon: i64,
+}
+
+impl<K: Ord + Clone, V: Clone> LwwMap<K, V> {
+    pub fn new(clock: Timestamp) -> Self {
+        LwwMap::with_trie(clock, Trie::new())
+    }
+
+    /// A map indexing its entries in `trie`, which must be empty; use it to
+    /// pick the hash scheme or depth
+    pub fn with_trie(clock: Timestamp, trie: Trie) -> Self {
+        LwwMap {
+            clock,
+            entries: BTreeMap::new(),
+            trie,
+            horizon: i64::MIN,
+        }
+    }
+
+    pub fn clock(&self) -> &Timestamp {
+        &self.clock
+    }
+
+    pub fn trie(&self) -> &Trie {
+        &self.trie
+    }
+
+    /// The current value, or None if unset or deleted
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key)?.value.as_ref()
+    }
+
+    /// The winning write for `key`, which may be a tombstone
+    pub fn entry(&self, key: &K) -> Option<&Entry<V>> {
+        self.entries.get(key)
+    }
+
+    /// Keys with a live value
+    pub fn len(&self) -> usize {
+        self.entries.values().filter(|e| !e.is_tombstone()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Live entries in key order
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries
+            .iter()
+            .filter_map(|(key, entry)| Some((key, entry.value.as_ref()?)))
+    }
+
+    /// Write `value` under a fresh timestamp, returning it
+    pub fn set(&mut self, key: K, value: V, phys: i64) -> Result<Timestamp, TimestampError> {
+        self.write(key, Some(value), phys)
+    }
+
+    /// Delete `key` by writing its tombstone, returning the timestamp
+    pub fn delete(&mut self, key: K, phys: i64) -> Result<Timestamp, TimestampError> {
+        self.write(key, None, phys)
+    }
+
+    fn write(&mut self, key: K, value: Option<V>, phys: i64) -> Result<Timestamp, TimestampError> {
+        let mut clock = self.clock.clone();
+        let timestamp = clock.send(phys)?;
+        self.check(&timestamp)?;
+        self.clock = clock;
+
+        let entry = Entry {
+            value,
+            timestamp: timestamp.clone(),
+        };
+        self.put(key, entry);
+        Ok(timestamp)
+    }
+
+    /// Forget tombstones written before `horizon`, returning how many
+    ///
+    /// They leave the trie too, so peers agree again once they prune with
+    /// the same horizon; derive it from a shared policy, as for
+    /// `Engine::compact`. Afterwards writes older than the horizon to keys
+    /// this map doesn't hold are dropped, since they may be for a pruned
+    /// key, so nothing that old may still be in flight.
+    pub fn prune(&mut self, horizon: i64) -> usize {
+        let stale: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_tombstone() && entry.timestamp.millis() < horizon)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            if let Some(entry) = self.entries.remove(key) {
+                self.trie.remove(&entry.timestamp);
+            }
+        }
+        self.horizon = self.horizon.max(horizon);
+        stale.len()
+    }
+
+    /// Merge another replica's entries, returning how many won
+    pub fn merge(&mut self, other: &LwwMap<K, V>, phys: i64) -> Result<usize, SyncError> {
+        self.merge_entries(
+            other
+                .entries
+                .iter()
+                .map(|(key, entry)| (key.clone(), entry.clone())),
+            phys,
+        )
+    }
+
+    /// Merge entries received from another replica, e.g. the result of its
+    /// `entries_since`, returning how many won
+    ///
+    /// The clock observes every winning timestamp. On error the entries
+    /// before the failing one stay merged.
+    pub fn merge_entries(
+        &mut self,
+        entries: impl IntoIterator<Item = (K, Entry<V>)>,
+        phys: i64,
+    ) -> Result<usize, SyncError> {
+        let mut won = 0;
+        for (key, entry) in entries {
+            if !self.wins(&key, &entry.timestamp) {
+                continue;
+            }
+            self.check(&entry.timestamp)?;
+            self.clock.recv(&entry.timestamp, phys)?;
+            self.put(key, entry);
+            won += 1;
+        }
+        Ok(won)
+    }
+
+    /// The time from which this map and `other` hold different winners,
+    /// or None if they agree
+    pub fn diff(&self, other: &LwwMap<K, V>) -> Option<DateTime<Utc>> {
+        self.trie.diff(&other.trie)
+    }
+
+    /// Entries written at or after `millis`, to send a replica whose trie
+    /// diverges from ours there
+    pub fn entries_since(&self, millis: i64) -> Vec<(K, Entry<V>)> {
+        self.entries
+            .iter()
+            .filter(|(_, entry)| entry.timestamp.millis() >= millis)
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect()
+    }
+
+    // Whether a write at `timestamp` beats the current one for `key`
+    fn wins(&self, key: &K, timestamp: &Timestamp) -> bool {
+        match self.entries.get(key) {
+            Some(current) => *timestamp > current.timestamp,
+            None => timestamp.millis() >= self.horizon,
+        }
+    }
+
+    fn check(&self, timestamp: &Timestamp) -> Result<(), TimestampError> {
+        self.trie
+            .check(timestamp)
+            .map_err(|_| TimestampError::OutOfRangeError(timestamp.millis()))
+    }
+
+    // Replace the entry for `key`, moving its trie entry with it
+    fn put(&mut self, key: K, entry: Entry<V>) {
+        self.trie.insert(entry.timestamp.clone());
+        if let Some(old) = self.entries.insert(key, entry) {
+            self.trie.remove(&old.timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::{String, ToString};
+
+    const MINUTE: i64 = 60_000;
+
+    fn map(node: &str) -> LwwMap<String, String> {
+        LwwMap::new(Timestamp::new(0, 0, node.to_string()))
+    }
+
+    #[test]
+    fn test_set_get() {
+        let mut a = map("1111111111111111");
+        let first = a
+            .set("title".to_string(), "buy milk".to_string(), MINUTE)
+            .unwrap();
+        let second = a
+            .set("title".to_string(), "buy eggs".to_string(), MINUTE)
+            .unwrap();
+        assert!(second > first);
+        assert_eq!(a.get(&"title".to_string()).unwrap(), "buy eggs");
+        assert_eq!(a.len(), 1);
+        assert_eq!(a.trie().root_hash(), second.hash() as u64);
+    }
+
+    #[test]
+    fn test_merge_converges() {
+        let mut a = map("1111111111111111");
+        let mut b = map("2222222222222222");
+        a.set("x".to_string(), "a".to_string(), MINUTE).unwrap();
+        b.set("x".to_string(), "b".to_string(), MINUTE).unwrap();
+        a.set("y".to_string(), "a".to_string(), 5 * MINUTE).unwrap();
+        b.set("z".to_string(), "b".to_string(), 9 * MINUTE).unwrap();
+        assert!(a.diff(&b).is_some());
+
+        let (mut ab, mut ba) = (a.clone(), b.clone());
+        ab.merge(&b, 9 * MINUTE).unwrap();
+        ba.merge(&a, 9 * MINUTE).unwrap();
+        assert_eq!(ab.diff(&ba), None);
+        assert_eq!(ab.trie(), ba.trie());
+        // Same time and counter, so the greater node wins
+        assert_eq!(ab.get(&"x".to_string()).unwrap(), "b");
+        assert_eq!(ba.get(&"x".to_string()).unwrap(), "b");
+
+        // Merging again changes nothing
+        assert_eq!(ab.merge(&ba, 9 * MINUTE), Ok(0));
+        assert!(ab.clock() >= ba.entry(&"z".to_string()).map(|e| &e.timestamp).unwrap());
+    }
+
+    #[test]
+    fn test_exchange_by_diff() {
+        let mut a = map("1111111111111111");
+        let mut b = map("2222222222222222");
+        for minute in 0..5 {
+            a.set(minute.to_string(), "a".to_string(), minute * MINUTE)
+                .unwrap();
+        }
+        b.merge(&a, 5 * MINUTE).unwrap();
+        a.set("3".to_string(), "late".to_string(), 30 * MINUTE)
+            .unwrap();
+        b.set("9".to_string(), "b".to_string(), 40 * MINUTE)
+            .unwrap();
+
+        let since = a.diff(&b).unwrap().timestamp_millis();
+        let to_b = a.entries_since(since);
+        let to_a = b.entries_since(since);
+        b.merge_entries(to_b, 40 * MINUTE).unwrap();
+        a.merge_entries(to_a, 40 * MINUTE).unwrap();
+        assert_eq!(a.diff(&b), None);
+        assert_eq!(b.get(&"3".to_string()).unwrap(), "late");
+    }
+
+    #[test]
+    fn test_register() {
+        let ts = |minute: i64, node: &str| Timestamp::new(minute * MINUTE, 0, node.to_string());
+        let mut a = LwwRegister::new();
+        let mut b = LwwRegister::new();
+        assert!(a.set("milk", ts(1, "1111111111111111")));
+        assert!(b.set("eggs", ts(1, "2222222222222222")));
+        assert!(!a.set("jam", ts(0, "1111111111111111")));
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        b.merge(&a);
+        assert_eq!(ab, b);
+        assert_eq!(b.get(), Some(&"eggs"));
+
+        assert!(b.delete(ts(2, "1111111111111111")));
+        assert!(b.is_deleted());
+        assert_eq!(b.get(), None);
+        assert!(!b.set("late", ts(1, "3333333333333333")));
+        assert!(!b.prune(2 * MINUTE));
+        assert!(b.prune(3 * MINUTE));
+        assert_eq!(b.timestamp(), None);
+    }
+
+    #[test]
+    fn test_delete_and_prune() {
+        let mut a = map("1111111111111111");
+        let mut b = map("2222222222222222");
+        a.set("x".to_string(), "a".to_string(), MINUTE).unwrap();
+        a.set("y".to_string(), "a".to_string(), MINUTE).unwrap();
+        b.merge(&a, MINUTE).unwrap();
+        let old = a.entry(&"x".to_string()).unwrap().clone();
+
+        a.delete("x".to_string(), 2 * MINUTE).unwrap();
+        assert_eq!(a.get(&"x".to_string()), None);
+        assert_eq!(a.len(), 1);
+        assert_eq!(a.iter().count(), 1);
+        b.merge(&a, 2 * MINUTE).unwrap();
+        assert!(b.entry(&"x".to_string()).unwrap().is_tombstone());
+        assert_eq!(a.diff(&b), None);
+
+        // Tombstones inside the window are kept
+        assert_eq!(a.prune(2 * MINUTE), 0);
+        assert_eq!(a.prune(3 * MINUTE), 1);
+        assert!(a.diff(&b).is_some());
+        assert_eq!(b.prune(3 * MINUTE), 1);
+        assert_eq!(a.diff(&b), None);
+
+        // A late copy of the deleted write stays deleted
+        assert_eq!(a.merge_entries([("x".to_string(), old)], 3 * MINUTE), Ok(0));
+        assert_eq!(a.get(&"x".to_string()), None);
+    }
+}
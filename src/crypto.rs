@@ -0,0 +1,345 @@
+// Client-side encryption of message values. A relay or server that only
+// syncs never needs to read what it stores, so values are sealed with the
+// application's key as they're written and stay sealed in every store and
+// frame. Timestamps stay plaintext for the trie, and datasets, rows and
+// columns so last-writer-wins and compaction work on peers without the key.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+
+use crate::codec::Writer;
+use crate::message::Message;
+use crate::table::{Cell, TOMBSTONE};
+use crate::timestamp::Timestamp;
+
+/// Prefix of every sealed value: the key id and the hex of nonce,
+/// ciphertext and tag follow, `markle-sealed:{id:08x}:{hex}`
+pub const SEALED_PREFIX: &str = "markle-sealed:";
+
+/// A 256-bit XChaCha20-Poly1305 key
+pub type Key = [u8; 32];
+
+const NONCE_LEN: usize = 24;
+
+/// The keys values are sealed and opened with
+///
+/// New values are sealed under the active key, and each names the key it
+/// was sealed under, so rotating only changes what's written from then
+/// on; the history keeps its old keys and needs them to be read. Values
+/// are bound to their timestamp and cell, so a sealed value copied into
+/// another message doesn't open.
+///
+/// Sealed values take twice their plaintext plus about a hundred bytes;
+/// leave room for that in a `SizeLimit`, which is checked before sealing.
+pub struct Keyring {
+    keys: BTreeMap<u32, Key>,
+    active: u32,
+    on_rotate: Option<Box<dyn FnMut(u32, u32) + Send>>,
+    lookup: Option<Box<dyn Fn(u32) -> Option<Key> + Send>>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Never the keys themselves
+        f.debug_struct("Keyring")
+            .field("ids", &self.keys.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// A keyring sealing under `key`, known as `id`
+    pub fn new(id: u32, key: Key) -> Self {
+        Keyring {
+            keys: BTreeMap::from([(id, key)]),
+            active: id,
+            on_rotate: None,
+            lookup: None,
+        }
+    }
+
+    /// Also open values sealed under `key`, without sealing with it
+    pub fn with_key(mut self, id: u32, key: Key) -> Self {
+        self.keys.insert(id, key);
+        self
+    }
+
+    /// The id new values are sealed under
+    pub fn active(&self) -> u32 {
+        self.active
+    }
+
+    /// Ids of every key held, oldest id first
+    pub fn ids(&self) -> impl Iterator<Item = u32> + '_ {
+        self.keys.keys().copied()
+    }
+
+    /// Seal new values under `key` from now on, keeping the old keys for
+    /// reading, and tell the `on_rotate` callback
+    ///
+    /// Every device should rotate to the same id and key; the application
+    /// distributes them.
+    pub fn rotate(&mut self, id: u32, key: Key) {
+        let old = self.active;
+        self.keys.insert(id, key);
+        self.active = id;
+        if let Some(callback) = &mut self.on_rotate {
+            callback(old, id);
+        }
+    }
+
+    /// Forget a key no longer needed for reading, returning false if it
+    /// is the active one or wasn't held
+    ///
+    /// Values sealed under it fail to open afterwards, unless the lookup
+    /// hook can still find it.
+    pub fn retire(&mut self, id: u32) -> bool {
+        id != self.active && self.keys.remove(&id).is_some()
+    }
+
+    /// Call `callback` with the old and new active ids after each
+    /// `rotate`, e.g. to persist the new key or tell other devices
+    pub fn on_rotate(&mut self, callback: impl FnMut(u32, u32) + Send + 'static) {
+        self.on_rotate = Some(Box::new(callback));
+    }
+
+    /// Ask `lookup` for keys the keyring doesn't hold, such as one a peer
+    /// rotated to and a key server has; it isn't remembered
+    pub fn on_missing_key(&mut self, lookup: impl Fn(u32) -> Option<Key> + Send + 'static) {
+        self.lookup = Some(Box::new(lookup));
+    }
+
+    fn key(&self, id: u32) -> Option<Key> {
+        match self.keys.get(&id) {
+            Some(key) => Some(*key),
+            None => self.lookup.as_ref()?(id),
+        }
+    }
+
+    /// Seal `message.value` under the active key, in place
+    ///
+    /// Tombstones are left alone, as peers without the key must still see
+    /// rows deleted.
+    ///
+    /// # Panics
+    ///
+    /// If the operating system's random source is unavailable.
+    pub fn seal(&self, message: &mut Message) {
+        if message.column == TOMBSTONE {
+            return;
+        }
+        let key = self.keys[&self.active];
+        let mut nonce = [0; NONCE_LEN];
+        getrandom::getrandom(&mut nonce).expect("os random source");
+        let aad = associated(
+            &message.timestamp,
+            &message.dataset,
+            &message.row,
+            &message.column,
+        );
+        let sealed = XChaCha20Poly1305::new(&key.into())
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: message.value.as_bytes(),
+                    aad: &aad,
+                },
+            )
+            .expect("sealing fits in memory");
+        let mut hex = String::with_capacity(2 * (NONCE_LEN + sealed.len()));
+        for byte in nonce.iter().chain(&sealed) {
+            hex.push_str(&format!("{:02x}", byte));
+        }
+        message.value = format!("{}{:08x}:{}", SEALED_PREFIX, self.active, hex);
+    }
+
+    /// The plaintext of a sealed message's value
+    pub fn open(&self, message: &Message) -> Result<String, CryptoError> {
+        self.open_value(
+            &message.timestamp,
+            &message.dataset,
+            &message.row,
+            &message.column,
+            &message.value,
+        )
+    }
+
+    /// The plaintext of a table cell's sealed value
+    pub fn open_cell(
+        &self,
+        dataset: &str,
+        row: &str,
+        column: &str,
+        cell: &Cell,
+    ) -> Result<String, CryptoError> {
+        self.open_value(&cell.timestamp, dataset, row, column, &cell.value)
+    }
+
+    fn open_value(
+        &self,
+        timestamp: &Timestamp,
+        dataset: &str,
+        row: &str,
+        column: &str,
+        value: &str,
+    ) -> Result<String, CryptoError> {
+        let rest = value
+            .strip_prefix(SEALED_PREFIX)
+            .ok_or(CryptoError::NotSealed)?;
+        let (id, hex) = rest.split_once(':').ok_or(CryptoError::Malformed)?;
+        let id = u32::from_str_radix(id, 16).map_err(|_| CryptoError::Malformed)?;
+        let bytes = from_hex(hex).ok_or(CryptoError::Malformed)?;
+        if bytes.len() < NONCE_LEN {
+            return Err(CryptoError::Malformed);
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let key = self.key(id).ok_or(CryptoError::UnknownKey(id))?;
+        let aad = associated(timestamp, dataset, row, column);
+        let plain = XChaCha20Poly1305::new(&key.into())
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| CryptoError::Decrypt)?;
+        String::from_utf8(plain).map_err(|_| CryptoError::Malformed)
+    }
+}
+
+/// Whether a value is sealed, as opposed to written in the clear
+pub fn is_sealed(value: &str) -> bool {
+    value.starts_with(SEALED_PREFIX)
+}
+
+// What a value is bound to: its timestamp and cell
+fn associated(timestamp: &Timestamp, dataset: &str, row: &str, column: &str) -> Vec<u8> {
+    let mut w = Writer::new();
+    timestamp.encode(&mut w);
+    w.put_str(dataset);
+    w.put_str(row);
+    w.put_str(column);
+    w.into_bytes()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Errors related to opening sealed values
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CryptoError {
+    // The value was written in the clear
+    NotSealed,
+    Malformed,
+    UnknownKey(u32),
+    // The wrong key, a value moved from another message, or tampering
+    Decrypt,
+    // Read through an engine with no keyring set
+    NoKeyring,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CryptoError::NotSealed => write!(f, "value is not sealed"),
+            CryptoError::Malformed => write!(f, "sealed value is malformed"),
+            CryptoError::UnknownKey(id) => write!(f, "no key {:08x} to open value", id),
+            CryptoError::Decrypt => write!(f, "sealed value failed authentication"),
+            CryptoError::NoKeyring => write!(f, "no keyring to open value"),
+        }
+    }
+}
+
+impl core::error::Error for CryptoError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    fn message(value: &str) -> Message {
+        Message {
+            timestamp: Timestamp::new(1000, 0, "1234123412341234".to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_seal() {
+        let keyring = Keyring::new(1, [7; 32]);
+        let mut sealed = message("buy milk");
+        keyring.seal(&mut sealed);
+        assert!(is_sealed(&sealed.value));
+        assert!(!sealed.value.contains("milk"));
+        assert_eq!(keyring.open(&sealed), Ok("buy milk".to_string()));
+
+        // Nonces are random, so equal values seal differently
+        let mut again = message("buy milk");
+        keyring.seal(&mut again);
+        assert_ne!(again.value, sealed.value);
+
+        // Bound to the cell and timestamp
+        let mut moved = sealed.clone();
+        moved.row = "2".to_string();
+        assert_eq!(keyring.open(&moved), Err(CryptoError::Decrypt));
+        let mut tampered = sealed.clone();
+        tampered.value.pop();
+        tampered.value.push('0');
+        assert_eq!(keyring.open(&tampered), Err(CryptoError::Decrypt));
+
+        assert_eq!(
+            Keyring::new(1, [8; 32]).open(&sealed),
+            Err(CryptoError::Decrypt)
+        );
+        assert_eq!(keyring.open(&message("plain")), Err(CryptoError::NotSealed));
+
+        let mut tombstone = message("1");
+        tombstone.column = TOMBSTONE.to_string();
+        keyring.seal(&mut tombstone);
+        assert_eq!(tombstone.value, "1");
+    }
+
+    #[test]
+    fn test_rotate() {
+        let rotations = Arc::new(Mutex::new(Vec::new()));
+        let mut keyring = Keyring::new(1, [1; 32]);
+        let seen = rotations.clone();
+        keyring.on_rotate(move |old, new| seen.lock().unwrap().push((old, new)));
+
+        let mut old = message("before");
+        keyring.seal(&mut old);
+        keyring.rotate(2, [2; 32]);
+        let mut new = message("after");
+        keyring.seal(&mut new);
+        assert!(new.value.starts_with("markle-sealed:00000002:"));
+        assert_eq!(*rotations.lock().unwrap(), vec![(1, 2)]);
+
+        // Old values still open until their key is retired
+        assert_eq!(keyring.open(&old), Ok("before".to_string()));
+        assert!(!keyring.retire(2));
+        assert!(keyring.retire(1));
+        assert_eq!(keyring.open(&old), Err(CryptoError::UnknownKey(1)));
+
+        // A key not held can come from the lookup hook
+        keyring.on_missing_key(|id| (id == 1).then_some([1; 32]));
+        assert_eq!(keyring.open(&old), Ok("before".to_string()));
+        assert_eq!(keyring.ids().collect::<Vec<_>>(), vec![2]);
+        assert_eq!(keyring.open(&new), Ok("after".to_string()));
+    }
+}
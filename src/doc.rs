@@ -0,0 +1,188 @@
+use chrono::Utc;
+use serde_json::{Map, Value};
+
+use crate::engine::{Engine, WriteError};
+use crate::store::MessageStore;
+use crate::table::Cell;
+
+/// JSON documents merged field by field
+///
+/// Each document is a row in `dataset`. Every leaf of the document is
+/// written as its own column, named by its JSON pointer (`/address/city`)
+/// and holding the JSON-encoded value, so concurrent edits to different
+/// fields both survive. Arrays and scalars are leaves and merge
+/// last-writer-wins as a whole.
+#[derive(Debug)]
+pub struct Doc<S> {
+    engine: Engine<S>,
+    dataset: String,
+}
+
+impl<S: MessageStore> Doc<S> {
+    pub fn new(engine: Engine<S>, dataset: &str) -> Self {
+        Doc {
+            engine,
+            dataset: dataset.to_string(),
+        }
+    }
+
+    /// Write every field of `value` into document `id`
+    pub fn put(&mut self, id: &str, value: &Value) -> Result<(), WriteError> {
+        self.set(id, "", value)
+    }
+
+    /// Write `value` at `pointer` inside document `id`
+    pub fn set(&mut self, id: &str, pointer: &str, value: &Value) -> Result<(), WriteError> {
+        self.set_at(id, pointer, value, Utc::now().timestamp_millis())
+    }
+
+    pub fn set_at(
+        &mut self,
+        id: &str,
+        pointer: &str,
+        value: &Value,
+        phys: i64,
+    ) -> Result<(), WriteError> {
+        let mut leaves = Vec::new();
+        flatten(pointer.to_string(), value, &mut leaves);
+        for (pointer, leaf) in leaves {
+            self.engine
+                .write(&self.dataset, id, &pointer, &leaf.to_string(), phys)?;
+        }
+        Ok(())
+    }
+
+    /// Reassemble document `id` from its fields
+    pub fn get(&self, id: &str) -> Option<Value> {
+        let row = self.engine.table().row(&self.dataset, id)?;
+
+        // Replaying fields oldest first lets a newer write at `/a` replace an
+        // older one at `/a/b`, and vice versa
+        let mut cells: Vec<(&String, &Cell)> = row.iter().collect();
+        cells.sort_by(|a, b| a.1.timestamp.cmp(&b.1.timestamp));
+
+        let mut doc = Value::Object(Map::new());
+        for (pointer, cell) in cells {
+            let leaf = serde_json::from_str(&cell.value).unwrap_or(Value::Null);
+            assign(&mut doc, pointer, leaf);
+        }
+        Some(doc)
+    }
+
+    pub fn engine(&self) -> &Engine<S> {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut Engine<S> {
+        &mut self.engine
+    }
+}
+
+// Collect (pointer, leaf) pairs; non-empty objects are walked, anything
+// else is a leaf
+fn flatten(pointer: String, value: &Value, leaves: &mut Vec<(String, Value)>) {
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, child) in map {
+                flatten(format!("{}/{}", pointer, escape(key)), child, leaves);
+            }
+        }
+        _ => leaves.push((pointer, value.clone())),
+    }
+}
+
+// Set `pointer` to `leaf`, replacing anything in the way with objects
+fn assign(doc: &mut Value, pointer: &str, leaf: Value) {
+    if pointer.is_empty() {
+        *doc = leaf;
+        return;
+    }
+
+    let mut node = doc;
+    let mut tokens = pointer[1..].split('/').map(unescape).peekable();
+    while let Some(token) = tokens.next() {
+        if !node.is_object() {
+            *node = Value::Object(Map::new());
+        }
+        let map = node.as_object_mut().unwrap();
+        if tokens.peek().is_none() {
+            map.insert(token, leaf);
+            return;
+        }
+        node = map.entry(token).or_insert(Value::Null);
+    }
+}
+
+// RFC 6901 escaping of a single reference token
+fn escape(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+fn unescape(token: &str) -> String {
+    token.replace("~1", "/").replace("~0", "~")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+    use serde_json::json;
+
+    fn doc() -> Doc<MemoryStore> {
+        let engine =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        Doc::new(engine, "notes")
+    }
+
+    #[test]
+    fn test_put_get() {
+        let mut doc = doc();
+        let value = json!({
+            "title": "groceries",
+            "meta": { "pinned": true, "a/b": 1 },
+            "tags": ["food", "weekly"],
+            "empty": {},
+        });
+        doc.put("n1", &value).unwrap();
+
+        assert_eq!(doc.get("n1"), Some(value));
+        assert_eq!(doc.get("n2"), None);
+    }
+
+    #[test]
+    fn test_field_level_merge() {
+        let mut a = doc();
+        let value = json!({ "title": "groceries", "body": "milk" });
+        a.set_at("n1", "", &value, 1).unwrap();
+        let mut b = Doc::new(
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap(),
+            "notes",
+        );
+        for message in a.engine().messages_since(i64::MIN) {
+            b.engine_mut().apply(message, 1).unwrap();
+        }
+
+        // Concurrent edits to different fields
+        a.set_at("n1", "/title", &json!("shopping"), 10).unwrap();
+        b.set_at("n1", "/body", &json!("milk, eggs"), 10).unwrap();
+        for message in b.engine().messages_since(10) {
+            a.engine_mut().apply(message, 10).unwrap();
+        }
+
+        let want = json!({ "title": "shopping", "body": "milk, eggs" });
+        assert_eq!(a.get("n1"), Some(want));
+    }
+
+    #[test]
+    fn test_replace_structure() {
+        let mut doc = doc();
+        doc.set_at("n1", "/meta", &json!({ "pinned": true }), 1)
+            .unwrap();
+        doc.set_at("n1", "/meta", &json!("none"), 2).unwrap();
+        assert_eq!(doc.get("n1"), Some(json!({ "meta": "none" })));
+
+        doc.set_at("n1", "/meta/color", &json!("red"), 3).unwrap();
+        assert_eq!(doc.get("n1"), Some(json!({ "meta": { "color": "red" } })));
+    }
+}
@@ -0,0 +1,1597 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::archive::{Archive, ArchiveError, ObjectStore};
+use crate::clock::{DriftMonitor, DriftStats};
+#[cfg(feature = "crypto")]
+use crate::crypto::{CryptoError, Keyring};
+use crate::error::SyncError;
+use crate::limit::{LimitError, SizeLimit};
+use crate::message::Message;
+use crate::snapshot::StateSnapshot;
+use crate::store::{
+    BudgetError, BudgetStatus, MessageStore, MessageStoreExt, SizeBudget, StoreError,
+};
+use crate::subscribe::{BucketChange, BucketFilter, SubscriptionId, Subscriptions};
+use crate::table::{Cell, ConflictResolver, Table, TOMBSTONE};
+use crate::timestamp::{ClockError, CounterOverflow, Epoch, Timestamp, TimestampError};
+use crate::trie::{HashScheme, KeyError, Trie};
+use crate::version::NodeVersionMap;
+
+/// What a message contributes to the trie
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Identity {
+    /// Its timestamp alone, as every deployed peer expects
+    #[default]
+    Timestamp,
+    /// Its timestamp mixed with `Message::content_hash`, so a payload
+    /// altered in transit or storage changes the root hash. Every peer in a
+    /// group must agree on this; it isn't negotiated.
+    Content,
+}
+
+/// A local replica: the node's clock, its message log, the merkle trie
+/// indexing that log, and the last-writer-wins table materialized from it
+#[derive(Debug)]
+pub struct Engine<S> {
+    clock: Timestamp,
+    // Unset until a deferred build finishes
+    trie: OnceLock<Trie>,
+    building: Mutex<Building>,
+    // Shared with snapshots, and copied on the first write after one is taken
+    table: Arc<Table>,
+    store: S,
+    versions: NodeVersionMap,
+    identity: Identity,
+    // Deleted rows with tombstones older than this have been compacted
+    horizon: i64,
+    subscriptions: Subscriptions,
+    drift: DriftMonitor,
+    limit: Option<SizeLimit>,
+    overflow: CounterOverflow,
+    resolver: Resolver,
+    #[cfg(feature = "crypto")]
+    keyring: Option<Keyring>,
+    // Indexed by the trie but never stored, from a bootstrap snapshot
+    superseded: BTreeSet<Timestamp>,
+}
+
+// The application's conflict resolver, if it set one
+struct Resolver(Option<Box<dyn ConflictResolver + Send>>);
+
+impl Resolver {
+    fn apply(&mut self, table: &mut Table, message: &Message) -> bool {
+        match &mut self.0 {
+            Some(resolver) => table.apply_with(message, resolver.as_mut()),
+            None => table.apply(message),
+        }
+    }
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Resolver").field(&self.0.is_some()).finish()
+    }
+}
+
+/// A frozen view of an engine's table; see `Engine::snapshot`
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    table: Arc<Table>,
+    as_of: Timestamp,
+}
+
+impl Snapshot {
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    /// The value a cell had when the snapshot was taken
+    pub fn get(&self, dataset: &str, row: &str, column: &str) -> Option<&Cell> {
+        self.table.get(dataset, row, column)
+    }
+
+    /// The engine's clock when the snapshot was taken
+    pub fn as_of(&self) -> &Timestamp {
+        &self.as_of
+    }
+}
+
+// Where a deferred trie build stands
+#[derive(Debug)]
+enum Building {
+    Done,
+    Running(JoinHandle<Result<Trie, KeyError>>),
+    Failed(StoreError),
+}
+
+impl<S: MessageStore> Engine<S> {
+    /// Open a replica; see `with_scheme`
+    pub fn new(clock: Timestamp, store: S) -> Result<Self, StoreError> {
+        Engine::with_scheme(clock, store, HashScheme::default())
+    }
+
+    /// Open a replica with `open_verified`
+    ///
+    /// Errors if the store holds a timestamp outside the trie's range,
+    /// which can't happen to a store only ever written through an engine.
+    pub fn with_scheme(clock: Timestamp, store: S, scheme: HashScheme) -> Result<Self, StoreError> {
+        Engine::open_verified(clock, store, scheme)
+    }
+
+    /// Open a replica, building the trie and checking every stored
+    /// timestamp against it before returning
+    pub fn open_verified(
+        clock: Timestamp,
+        store: S,
+        scheme: HashScheme,
+    ) -> Result<Self, StoreError> {
+        let (engine, timestamps) = Engine::load(clock, store);
+        let mut trie = Trie::with_scheme(scheme);
+        trie.try_insert_batch(&timestamps)?;
+        engine.trie.set(trie).expect("trie is unset after load");
+        Ok(engine)
+    }
+
+    /// Open a replica without waiting for the trie
+    ///
+    /// The table is materialized up front so reads are served at once,
+    /// while the trie is built and checked on a background thread. Anything
+    /// that needs the trie (writes, applies, sync) waits for it; call
+    /// `wait` to surface a failed check up front.
+    pub fn open_fast(clock: Timestamp, store: S, scheme: HashScheme) -> Self {
+        let (mut engine, timestamps) = Engine::load(clock, store);
+        let handle = thread::spawn(move || {
+            let mut trie = Trie::with_scheme(scheme);
+            trie.try_insert_batch(&timestamps)?;
+            Ok(trie)
+        });
+        engine.building = Mutex::new(Building::Running(handle));
+        engine
+    }
+
+    // Everything but the trie, plus the timestamps to build it from
+    fn load(mut clock: Timestamp, store: S) -> (Self, Vec<Timestamp>) {
+        let mut table = Table::new();
+        let mut versions = NodeVersionMap::new();
+        let mut timestamps = Vec::new();
+        for message in store.all_messages() {
+            table.apply(&message);
+            versions.observe(&message.timestamp);
+            timestamps.push(message.timestamp);
+        }
+        // Never issue below what this node already wrote, even if the
+        // clock was restored from a stale save
+        if let Some(newest) = versions.get(clock.node()) {
+            if *newest > clock {
+                clock = newest.clone();
+            }
+        }
+
+        let engine = Engine {
+            clock,
+            trie: OnceLock::new(),
+            building: Mutex::new(Building::Done),
+            table: Arc::new(table),
+            store,
+            versions,
+            identity: Identity::default(),
+            horizon: i64::MIN,
+            subscriptions: Subscriptions::default(),
+            drift: DriftMonitor::default(),
+            limit: None,
+            overflow: CounterOverflow::default(),
+            resolver: Resolver(None),
+            #[cfg(feature = "crypto")]
+            keyring: None,
+            superseded: BTreeSet::new(),
+        };
+        (engine, timestamps)
+    }
+
+    /// Whether the trie is built, so nothing will block on it
+    pub fn is_ready(&self) -> bool {
+        self.trie.get().is_some()
+    }
+
+    /// Block until the trie is built
+    ///
+    /// Errors if the store failed its integrity check or the build
+    /// panicked; such an engine can still be read but not written or
+    /// synced.
+    pub fn wait(&self) -> Result<&Trie, StoreError> {
+        if let Some(trie) = self.trie.get() {
+            return Ok(trie);
+        }
+        // Nothing below panics with the lock held, but a poisoned lock
+        // still guards a whole `Building`
+        let mut building = self.building.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(trie) = self.trie.get() {
+            return Ok(trie);
+        }
+        let err = match std::mem::replace(&mut *building, Building::Done) {
+            Building::Running(handle) => match handle.join() {
+                Ok(Ok(trie)) => return Ok(self.trie.get_or_init(|| trie)),
+                Ok(Err(err)) => StoreError::Integrity(err),
+                Err(_) => StoreError::Poisoned,
+            },
+            Building::Failed(err) => err,
+            // Only an engine left half-opened gets here; treat it like a
+            // build that died
+            Building::Done => StoreError::Poisoned,
+        };
+        *building = Building::Failed(err.clone());
+        Err(err)
+    }
+
+    // The trie, once `wait` has succeeded
+    fn built(&self) -> &Trie {
+        self.trie.get().expect("trie is built once waited for")
+    }
+
+    fn built_mut(&mut self) -> &mut Trie {
+        self.trie.get_mut().expect("trie is built once waited for")
+    }
+
+    // Replace the trie outright, once `wait` has succeeded, so there is no
+    // deferred build or failure left to abandon
+    fn set_trie(&mut self, trie: Trie) {
+        debug_assert!(self.is_ready(), "trie replaced before it was built");
+        self.trie = OnceLock::from(trie);
+        self.building = Mutex::new(Building::Done);
+    }
+
+    // Build the trie over every stored message
+    fn index(store: &S, scheme: HashScheme, identity: Identity) -> Result<Trie, KeyError> {
+        let messages = store.all_messages();
+        let mut trie = Trie::with_scheme(scheme);
+        match identity {
+            Identity::Timestamp => {
+                let timestamps: Vec<Timestamp> =
+                    messages.into_iter().map(|m| m.timestamp).collect();
+                trie.try_insert_batch(&timestamps)?;
+            }
+            Identity::Content => {
+                for message in messages {
+                    let content = message.content_hash();
+                    trie.try_insert_with(message.timestamp, content)?;
+                }
+            }
+        }
+        Ok(trie)
+    }
+
+    // The content mixed into a message's trie entry
+    fn content(&self, message: &Message) -> u64 {
+        match self.identity {
+            Identity::Timestamp => 0,
+            Identity::Content => message.content_hash(),
+        }
+    }
+
+    /// Rebuild the trie from the message log under another hash scheme
+    ///
+    /// Errors, changing nothing, if the trie can't be built; see `wait`.
+    pub fn rehash(&mut self, scheme: HashScheme) -> Result<(), StoreError> {
+        self.wait()?;
+        self.reindex(scheme, self.identity)
+    }
+
+    pub fn identity(&self) -> Identity {
+        self.identity
+    }
+
+    /// Rebuild the trie from the message log under another identity
+    pub fn set_identity(&mut self, identity: Identity) -> Result<(), StoreError> {
+        let scheme = self.wait()?.scheme();
+        self.reindex(scheme, identity)?;
+        self.identity = identity;
+        Ok(())
+    }
+
+    // Rebuild the trie, keeping superseded history if it can be hashed
+    // without its content; if not, sync fetches it again
+    fn reindex(&mut self, scheme: HashScheme, identity: Identity) -> Result<(), StoreError> {
+        let mut trie = Engine::index(&self.store, scheme, identity)?;
+        match identity {
+            Identity::Timestamp => {
+                for timestamp in &self.superseded {
+                    trie.try_insert(timestamp.clone())?;
+                }
+            }
+            Identity::Content => self.superseded.clear(),
+        }
+        self.set_trie(trie);
+        Ok(())
+    }
+
+    pub fn size_limit(&self) -> Option<&SizeLimit> {
+        self.limit.as_ref()
+    }
+
+    /// Cap the size of local writes, or lift the cap with None
+    ///
+    /// Only writes are checked; messages from peers are taken as they come.
+    pub fn set_size_limit(&mut self, limit: Option<SizeLimit>) {
+        self.limit = limit;
+    }
+
+    pub fn counter_overflow(&self) -> CounterOverflow {
+        self.overflow
+    }
+
+    /// Choose what the clock does when a batch import fills a
+    /// millisecond's counter; it errors unless told to spill
+    pub fn set_counter_overflow(&mut self, overflow: CounterOverflow) {
+        self.overflow = overflow;
+    }
+
+    /// Merge writes to the same cell with `resolver` rather than keeping
+    /// the later one, or go back to last-writer-wins with None
+    ///
+    /// The table is rebuilt from the stored log under the new resolver, as
+    /// reopening the engine and setting it again would. Every replica must
+    /// use the same resolver; see `ConflictResolver`.
+    pub fn set_conflict_resolver(&mut self, resolver: Option<Box<dyn ConflictResolver + Send>>) {
+        self.resolver = Resolver(resolver);
+        let mut table = Table::new();
+        for message in self.store.all_messages() {
+            self.resolver.apply(&mut table, &message);
+        }
+        self.table = Arc::new(table);
+    }
+
+    // Bring a local write under the size limit, if there is one, then
+    // seal it if there are keys
+    fn fit(&mut self, message: &mut Message) -> Result<(), LimitError> {
+        if let Some(limit) = &mut self.limit {
+            limit.fit(message)?;
+        }
+        #[cfg(feature = "crypto")]
+        if let Some(keyring) = &self.keyring {
+            keyring.seal(message);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "crypto")]
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.keyring.as_ref()
+    }
+
+    /// The keyring, to rotate or retire keys
+    #[cfg(feature = "crypto")]
+    pub fn keyring_mut(&mut self) -> Option<&mut Keyring> {
+        self.keyring.as_mut()
+    }
+
+    /// Seal the values of local writes with `keyring` from now on, or
+    /// write them in the clear with None
+    ///
+    /// What is already stored stays as it was written, sealed or not;
+    /// messages from peers are stored as they come. Read sealed values
+    /// with `get_opened`.
+    #[cfg(feature = "crypto")]
+    pub fn set_keyring(&mut self, keyring: Option<Keyring>) {
+        self.keyring = keyring;
+    }
+
+    /// The current plaintext of a cell sealed with the engine's keyring
+    #[cfg(feature = "crypto")]
+    pub fn get_opened(
+        &self,
+        dataset: &str,
+        row: &str,
+        column: &str,
+    ) -> Result<Option<String>, CryptoError> {
+        let Some(cell) = self.table.get(dataset, row, column) else {
+            return Ok(None);
+        };
+        let keyring = self.keyring.as_ref().ok_or(CryptoError::NoKeyring)?;
+        keyring.open_cell(dataset, row, column, cell).map(Some)
+    }
+
+    /// Call `callback` whenever a minute bucket matching `filter` changes
+    ///
+    /// It fires once per bucket per write, apply, batch or compaction, after
+    /// the trie, table and store are all updated, so derived caches can
+    /// invalidate just the minutes and datasets that moved. Rebuilding the
+    /// trie under another scheme or identity fires nothing.
+    pub fn subscribe(
+        &mut self,
+        filter: BucketFilter,
+        callback: impl FnMut(&BucketChange) + Send + 'static,
+    ) -> SubscriptionId {
+        self.subscriptions.add(filter, Box::new(callback))
+    }
+
+    /// Remove a subscription, returning false if it was already gone
+    pub fn unsubscribe(&mut self, id: SubscriptionId) -> bool {
+        self.subscriptions.remove(id)
+    }
+
+    /// Call `callback` with how far ahead of `phys` each remote timestamp
+    /// is, whenever one is ahead at all
+    ///
+    /// It fires before the drift limit is checked, so it also sees the
+    /// timestamps that are then rejected; applications can warn that the
+    /// device clock looks wrong well before syncs start failing. Replaces
+    /// any callback set before.
+    pub fn on_drift(&mut self, callback: impl FnMut(Duration) + Send + 'static) {
+        self.drift.set_callback(Some(Box::new(callback)));
+    }
+
+    /// Counts of remote timestamps ahead of our clock and of drift
+    /// rejections, since the engine was opened
+    pub fn drift_stats(&self) -> DriftStats {
+        self.drift.stats()
+    }
+
+    // Fire subscriptions for the buckets `messages` changed
+    fn notify<'a>(&mut self, messages: impl IntoIterator<Item = &'a Message>) {
+        let trie = self.trie.get().expect("trie is built after a change");
+        self.subscriptions.notify(trie, messages);
+    }
+
+    pub fn clock(&self) -> &Timestamp {
+        &self.clock
+    }
+
+    /// Resume the clock from a timestamp persisted before a restart; see
+    /// `Timestamp::observe_persisted`
+    pub fn observe_persisted(&mut self, last: &Timestamp, phys: i64) -> Result<(), ClockError> {
+        self.clock.observe_persisted(last, phys)
+    }
+
+    /// The trie, waiting for a deferred build; errors as `wait` does
+    pub fn trie(&self) -> Result<&Trie, StoreError> {
+        self.wait()
+    }
+
+    pub fn table(&self) -> &Table {
+        &self.table
+    }
+
+    fn table_mut(&mut self) -> &mut Table {
+        Arc::make_mut(&mut self.table)
+    }
+
+    /// A read-only view of the table as it stands now
+    ///
+    /// Later writes, applies and compactions don't show through, so a long
+    /// report can read from it, or hand it to another thread, without
+    /// seeing a batch half applied. Taking one is cheap; the first change
+    /// afterwards copies the table, so drop snapshots once done with them.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            table: self.table.clone(),
+            as_of: self.clock.clone(),
+        }
+    }
+
+    /// The current value of a cell
+    pub fn get(&self, dataset: &str, row: &str, column: &str) -> Option<&Cell> {
+        self.table.get(dataset, row, column)
+    }
+
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    pub fn into_store(self) -> S {
+        self.store
+    }
+
+    // Error unless the trie can index the timestamp
+    fn check(&self, timestamp: &Timestamp) -> Result<(), TimestampError> {
+        self.built()
+            .check(timestamp)
+            .map_err(|_| TimestampError::OutOfRangeError(timestamp.millis()))
+    }
+
+    /// The replica's state, for another to bootstrap from
+    ///
+    /// Cheaper than `MessageStoreExt::snapshot`, since the table and trie
+    /// are already built.
+    pub fn state_snapshot(&self) -> Result<StateSnapshot, StoreError> {
+        let mut all: BTreeSet<Timestamp> = self
+            .store
+            .all_messages()
+            .into_iter()
+            .map(|message| message.timestamp)
+            .collect();
+        all.extend(self.superseded.iter().cloned());
+        Ok(StateSnapshot::from_parts(
+            self.clock.clone(),
+            self.wait()?.clone(),
+            &self.table,
+            all,
+        ))
+    }
+
+    /// Take on a peer's state instead of replaying its history, returning
+    /// the snapshot's messages that were new to this replica
+    ///
+    /// The snapshot's trie replaces ours and local messages it doesn't
+    /// cover are indexed on top, so nothing written here is lost; sync
+    /// then carries on by diff. The superseded history is never stored,
+    /// which has costs:
+    ///
+    /// - peers can't fetch it from this replica, only from one that has it
+    /// - compaction can't remove it from the trie
+    /// - it's remembered in memory only; after a restart the trie is built
+    ///   from the store, and the next sync fetches the history after all
+    ///
+    /// The snapshot must have been taken under our scheme and identity.
+    pub fn apply_snapshot(
+        &mut self,
+        snapshot: StateSnapshot,
+        phys: i64,
+    ) -> Result<Vec<Message>, SyncError> {
+        self.wait()?;
+        let mut clock = self.clock.clone();
+        self.drift.observe(&snapshot.as_of, phys);
+        clock
+            .recv_with(&snapshot.as_of, phys, self.overflow)
+            .inspect_err(|err| self.drift.report(err.clock()))?;
+        let local = self.store.all_messages();
+        let covered: BTreeSet<&Timestamp> = snapshot
+            .messages
+            .iter()
+            .map(|message| &message.timestamp)
+            .chain(&snapshot.superseded)
+            .collect();
+        let mut trie = snapshot.trie.clone();
+        let mut table = snapshot.table();
+        for message in &local {
+            if !covered.contains(&message.timestamp) {
+                trie.try_insert_with(message.timestamp.clone(), self.content(message))
+                    .map_err(|_| TimestampError::OutOfRangeError(message.timestamp.millis()))?;
+                self.resolver.apply(&mut table, message);
+            }
+        }
+        self.clock = clock;
+
+        for timestamp in snapshot
+            .messages
+            .iter()
+            .map(|m| &m.timestamp)
+            .chain(&snapshot.superseded)
+        {
+            self.versions.observe(timestamp);
+        }
+        self.superseded = snapshot
+            .superseded
+            .into_iter()
+            .filter(|timestamp| !self.store.contains(timestamp))
+            .collect();
+        self.set_trie(trie);
+        self.table = Arc::new(table);
+        let fresh: Vec<Message> = snapshot
+            .messages
+            .into_iter()
+            .filter(|message| !self.store.contains(&message.timestamp))
+            .collect();
+        self.store.insert_batch(fresh.clone());
+        self.notify(&fresh);
+        Ok(fresh)
+    }
+
+    /// Record a local write, minting a new timestamp for it
+    pub fn write(
+        &mut self,
+        dataset: &str,
+        row: &str,
+        column: &str,
+        value: &str,
+        phys: i64,
+    ) -> Result<Message, WriteError> {
+        self.wait()?;
+        let mut clock = self.clock.clone();
+        let mut message = Message {
+            timestamp: clock
+                .send_with(phys, self.overflow)
+                .inspect_err(|err| self.drift.report(Some(err)))?,
+            dataset: dataset.to_string(),
+            row: row.to_string(),
+            column: column.to_string(),
+            value: value.to_string(),
+        };
+        self.check(&message.timestamp)?;
+        self.fit(&mut message)?;
+        self.clock = clock;
+
+        let content = self.content(&message);
+        self.built_mut()
+            .insert_with(message.timestamp.clone(), content);
+        self.resolver
+            .apply(Arc::make_mut(&mut self.table), &message);
+        self.versions.observe(&message.timestamp);
+        self.store.insert(message.clone());
+        self.notify([&message]);
+        Ok(message)
+    }
+
+    /// Record many local writes at once
+    ///
+    /// Each op is `(dataset, row, column, value)`. Timestamps are minted in
+    /// order, then the trie, table and store each take the whole batch in a
+    /// single pass. If minting fails, a timestamp falls outside the trie's
+    /// range, or an op is refused by the size limit, nothing is written
+    /// and the clock is left untouched. Offloaded values stay in the blob
+    /// store regardless.
+    pub fn write_batch(
+        &mut self,
+        ops: &[(&str, &str, &str, &str)],
+        phys: i64,
+    ) -> Result<Vec<Message>, WriteError> {
+        self.wait()?;
+        let mut clock = self.clock.clone();
+        let mut messages = Vec::with_capacity(ops.len());
+        for (dataset, row, column, value) in ops {
+            let timestamp = clock
+                .send_with(phys, self.overflow)
+                .inspect_err(|err| self.drift.report(Some(err)))?;
+            self.check(&timestamp)?;
+            let mut message = Message {
+                timestamp,
+                dataset: dataset.to_string(),
+                row: row.to_string(),
+                column: column.to_string(),
+                value: value.to_string(),
+            };
+            self.fit(&mut message)?;
+            messages.push(message);
+        }
+        self.clock = clock;
+
+        match self.identity {
+            Identity::Timestamp => {
+                let timestamps: Vec<Timestamp> =
+                    messages.iter().map(|m| m.timestamp.clone()).collect();
+                self.built_mut().insert_batch(&timestamps);
+            }
+            Identity::Content => {
+                for message in &messages {
+                    self.built_mut()
+                        .insert_with(message.timestamp.clone(), message.content_hash());
+                }
+            }
+        }
+        let table = Arc::make_mut(&mut self.table);
+        for message in &messages {
+            self.resolver.apply(table, message);
+        }
+        if let Some(last) = messages.last() {
+            self.versions.observe(&last.timestamp);
+        }
+        self.store.insert_batch(messages.clone());
+        self.notify(&messages);
+        Ok(messages)
+    }
+
+    /// Delete a row by writing its tombstone
+    pub fn delete(&mut self, dataset: &str, row: &str, phys: i64) -> Result<Message, WriteError> {
+        self.write(dataset, row, TOMBSTONE, "1", phys)
+    }
+
+    /// Physically remove the history of rows deleted before `horizon`
+    ///
+    /// Every message of such a row older than its tombstone leaves the
+    /// store, trie and table; the tombstone itself is kept so the deletion
+    /// still syncs. Peers' tries only agree again once they compact with
+    /// the same horizon, so it should be derived from a shared policy (for
+    /// example "a week before now, in whole days"). Returns the number of
+    /// messages removed, none if the store failed its integrity check.
+    pub fn compact(&mut self, horizon: i64) -> usize {
+        // Nothing can be taken out of a trie that failed to build
+        if self.wait().is_err() {
+            return 0;
+        }
+        let (deleted, removed) = self.compactable(horizon);
+        self.remove_compacted(horizon, &deleted, removed)
+    }
+
+    /// `compact`, first writing the messages it removes to `archive` so
+    /// they can be rehydrated later
+    ///
+    /// If the archive write fails, or the store failed its integrity check,
+    /// nothing is removed.
+    pub fn compact_archived<O: ObjectStore>(
+        &mut self,
+        horizon: i64,
+        archive: &mut Archive<O>,
+    ) -> Result<usize, ArchiveError<O::Error>> {
+        if self.wait().is_err() {
+            return Ok(0);
+        }
+        let (deleted, removed) = self.compactable(horizon);
+        archive.store(&removed)?;
+        Ok(self.remove_compacted(horizon, &deleted, removed))
+    }
+
+    // Rows deleted before `horizon`, and their messages older than the
+    // tombstone
+    fn compactable(&self, horizon: i64) -> (Vec<(String, String, Timestamp)>, Vec<Message>) {
+        let deleted: Vec<(String, String, Timestamp)> = self
+            .table
+            .deleted_rows()
+            .into_iter()
+            .filter(|(_, _, cell)| cell.timestamp.millis() < horizon)
+            .map(|(dataset, row, cell)| {
+                (dataset.to_string(), row.to_string(), cell.timestamp.clone())
+            })
+            .collect();
+
+        let messages = self
+            .store
+            .all_messages()
+            .into_iter()
+            .filter(|message| {
+                deleted.iter().any(|(dataset, row, tombstone)| {
+                    message.dataset == *dataset
+                        && message.row == *row
+                        && message.timestamp < *tombstone
+                })
+            })
+            .collect();
+        (deleted, messages)
+    }
+
+    fn remove_compacted(
+        &mut self,
+        horizon: i64,
+        deleted: &[(String, String, Timestamp)],
+        messages: Vec<Message>,
+    ) -> usize {
+        let mut removed = Vec::new();
+        for message in messages {
+            if self.store.remove(&message.timestamp) {
+                let content = self.content(&message);
+                self.built_mut().remove_with(&message.timestamp, content);
+                removed.push(message);
+            }
+        }
+        for (dataset, row, _) in deleted {
+            self.table_mut().compact_row(dataset, row);
+        }
+
+        self.horizon = self.horizon.max(horizon);
+        self.notify(&removed);
+        removed.len()
+    }
+
+    /// Check the store against a size budget, escalating as it grows
+    ///
+    /// Compaction uses `horizon` exactly as `compact` does, and is followed
+    /// by a vacuum. Meant to be called periodically, e.g. after each sync.
+    ///
+    /// Sizes are the store's `size_bytes`. What that leaves on disk is up
+    /// to the store's `vacuum`: a `LogStore` only shrinks its file when
+    /// opened with a scratch file.
+    pub fn enforce_budget(
+        &mut self,
+        budget: &SizeBudget,
+        horizon: i64,
+    ) -> Result<BudgetStatus, BudgetError> {
+        let size = self.store.size_bytes();
+        if size < budget.warn {
+            return Ok(BudgetStatus::Within(size));
+        }
+        if size < budget.compact {
+            warn!(size, warn = budget.warn, "store past its warning size");
+            log_warn!(
+                "store is {} bytes, past its warning size of {}",
+                size,
+                budget.warn
+            );
+            return Ok(BudgetStatus::Warned(size));
+        }
+
+        self.compact(horizon);
+        self.store.vacuum();
+        let size = self.store.size_bytes();
+        if size >= budget.limit {
+            return Err(BudgetError::Exceeded(size, budget.limit));
+        }
+        Ok(BudgetStatus::Compacted(size))
+    }
+
+    // A message for a row whose history was already compacted away
+    fn is_compacted(&self, message: &Message) -> bool {
+        match self.table.tombstone(&message.dataset, &message.row) {
+            Some(cell) => {
+                cell.timestamp.millis() < self.horizon && message.timestamp < cell.timestamp
+            }
+            None => false,
+        }
+    }
+
+    /// Apply a message received from a peer
+    ///
+    /// Returns false if the message was already known. The trie is only
+    /// touched for new messages, since inserting a timestamp twice would
+    /// cancel it out of the hash.
+    ///
+    /// Timestamps identify messages, so a different message under a known
+    /// timestamp can only come from a client reusing counters. It is
+    /// rejected rather than dropped, since either copy may be the one the
+    /// rest of the group kept.
+    pub fn apply(&mut self, message: Message, phys: i64) -> Result<bool, SyncError> {
+        let applied = self.apply_one(&message, phys)?;
+        if applied {
+            self.notify([&message]);
+        }
+        Ok(applied)
+    }
+
+    // Apply without notifying subscribers
+    fn apply_one(&mut self, message: &Message, phys: i64) -> Result<bool, SyncError> {
+        self.wait()?;
+        if let Some(known) = self.store.get(&message.timestamp) {
+            if known != *message {
+                return Err(SyncError::ReusedTimestamp(message.timestamp.to_string()));
+            }
+            return Ok(false);
+        }
+        if self.is_compacted(message) || self.superseded.contains(&message.timestamp) {
+            return Ok(false);
+        }
+        self.apply_new(message.clone(), phys)?;
+        Ok(true)
+    }
+
+    // Apply a message known not to be in the store
+    fn apply_new(&mut self, message: Message, phys: i64) -> Result<(), SyncError> {
+        self.check(&message.timestamp)?;
+        self.drift.observe(&message.timestamp, phys);
+        self.clock
+            .recv_with(&message.timestamp, phys, self.overflow)
+            .inspect_err(|err| self.drift.report(err.clock()))?;
+        let content = self.content(&message);
+        self.built_mut()
+            .insert_with(message.timestamp.clone(), content);
+        self.resolver
+            .apply(Arc::make_mut(&mut self.table), &message);
+        self.versions.observe(&message.timestamp);
+        self.store.insert(message);
+        Ok(())
+    }
+
+    /// Apply a batch of messages sent along with the sender's trie,
+    /// returning the ones that were new
+    ///
+    /// Peers that reset their cursor re-send history we already hold, so
+    /// most of the store lookups `apply` would do are wasted. Two summaries
+    /// let the batch skip them:
+    ///
+    /// - a minute whose hash in `remote` matches ours holds nothing we
+    ///   lack, so its messages are dropped unseen
+    /// - a message newer than anything seen from its node can't be stored
+    ///   yet, so it is applied without a lookup
+    ///
+    /// Only the remaining messages go through `apply`. Dropped minutes
+    /// aren't checked for reused timestamps.
+    pub fn apply_batch(
+        &mut self,
+        messages: Vec<Message>,
+        remote: &Trie,
+        phys: i64,
+    ) -> Result<Vec<Message>, SyncError> {
+        let span = debug_span!(
+            "store_apply_batch",
+            messages = messages.len(),
+            applied = tracing::field::Empty,
+        );
+        self.wait()?;
+        let mut replayed: HashMap<Epoch, bool> = HashMap::new();
+        let mut applied = Vec::new();
+        for message in messages {
+            let epoch = message.timestamp.epoch();
+            let replay = *replayed.entry(epoch).or_insert_with(|| {
+                let ours = self.built().minute_hash(epoch);
+                ours.is_some() && ours == remote.minute_hash(epoch)
+            });
+            if replay {
+                continue;
+            }
+
+            let fresh = !self.versions.covers(&message.timestamp);
+            let result = if fresh {
+                self.apply_new(message.clone(), phys).map(|()| true)
+            } else {
+                self.apply_one(&message, phys)
+            };
+            match result {
+                Ok(true) => applied.push(message),
+                Ok(false) => {}
+                Err(err) => {
+                    // What was applied before the failure stays applied
+                    self.notify(&applied);
+                    return Err(err);
+                }
+            }
+        }
+        self.notify(&applied);
+        record!(span, applied = applied.len());
+        Ok(applied)
+    }
+
+    /// The newest timestamp seen from `node`
+    pub fn high_water(&self, node: &str) -> Option<&Timestamp> {
+        self.versions.get(node)
+    }
+
+    /// The newest timestamp seen from every node, written or applied
+    pub fn versions(&self) -> &NodeVersionMap {
+        &self.versions
+    }
+
+    pub fn messages_since(&self, millis: i64) -> Vec<Message> {
+        let span = debug_span!(
+            "store_messages_since",
+            since = millis,
+            found = tracing::field::Empty
+        );
+        let messages = self.store.messages_since(millis);
+        record!(span, found = messages.len());
+        messages
+    }
+
+    /// A page of `messages_since`; see `MessageStore::messages_since_page`
+    pub fn messages_since_page(
+        &self,
+        millis: i64,
+        limit: usize,
+        cursor: Option<&Timestamp>,
+    ) -> Vec<Message> {
+        let span = debug_span!(
+            "store_messages_since_page",
+            since = millis,
+            limit,
+            found = tracing::field::Empty,
+        );
+        let messages = self.store.messages_since_page(millis, limit, cursor);
+        record!(span, found = messages.len());
+        messages
+    }
+}
+
+// Errors related to recording a local write
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum WriteError {
+    Timestamp(TimestampError),
+    Limit(LimitError),
+    Store(StoreError),
+}
+
+impl From<TimestampError> for WriteError {
+    fn from(err: TimestampError) -> Self {
+        WriteError::Timestamp(err)
+    }
+}
+
+impl From<ClockError> for WriteError {
+    fn from(err: ClockError) -> Self {
+        WriteError::Timestamp(err.into())
+    }
+}
+
+impl From<StoreError> for WriteError {
+    fn from(err: StoreError) -> Self {
+        WriteError::Store(err)
+    }
+}
+
+impl From<LimitError> for WriteError {
+    fn from(err: LimitError) -> Self {
+        WriteError::Limit(err)
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WriteError::Timestamp(ref err) => err.fmt(f),
+            WriteError::Limit(ref err) => err.fmt(f),
+            WriteError::Store(ref err) => err.fmt(f),
+        }
+    }
+}
+
+impl core::error::Error for WriteError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::timestamp::make_client_id;
+    use crate::trie::DEFAULT_DEPTH;
+
+    #[test]
+    fn test_apply_duplicate() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+
+        let msg = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+
+        assert_eq!(b.apply(msg.clone(), 1000), Ok(true));
+        assert_eq!(b.apply(msg, 1000), Ok(false));
+        assert_eq!(a.trie().unwrap().diff(b.trie().unwrap()), None);
+        assert_eq!(b.store().len(), 1);
+    }
+
+    #[test]
+    fn test_counter_overflow() {
+        let clock = Timestamp::new(1000, 0xFFFF, make_client_id());
+        let mut a = Engine::new(clock, MemoryStore::new()).unwrap();
+        assert_eq!(
+            a.write("todos", "1", "title", "buy milk", 1000),
+            Err(ClockError::Overflow.into())
+        );
+        a.set_counter_overflow(CounterOverflow::Spill);
+        let msg = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        assert_eq!((msg.timestamp.millis(), msg.timestamp.counter()), (1001, 0));
+    }
+
+    #[test]
+    fn test_conflict_resolver() {
+        use crate::table::Conflict;
+
+        // Values are deltas, and the cell holds their sum
+        struct Sum;
+
+        impl ConflictResolver for Sum {
+            fn resolve(&mut self, conflict: &Conflict<'_>) -> Option<String> {
+                let current: i64 = conflict.current.value.parse().ok()?;
+                let incoming: i64 = conflict.incoming.value.parse().ok()?;
+                Some((current + incoming).to_string())
+            }
+        }
+
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let ours = a.write("likes", "1", "count", "2", 1000).unwrap();
+        let theirs = b.write("likes", "1", "count", "3", 2000).unwrap();
+
+        // Set after writing, so the table is rebuilt from the log
+        a.set_conflict_resolver(Some(Box::new(Sum)));
+        b.set_conflict_resolver(Some(Box::new(Sum)));
+        assert_eq!(a.apply(theirs.clone(), 2000), Ok(true));
+        assert_eq!(b.apply(ours.clone(), 2000), Ok(true));
+        assert_eq!(b.apply(ours, 2000), Ok(false));
+        for engine in [&a, &b] {
+            let cell = engine.get("likes", "1", "count").unwrap();
+            assert_eq!(cell.value, "5");
+            assert_eq!(cell.timestamp, theirs.timestamp);
+        }
+
+        a.set_conflict_resolver(None);
+        assert_eq!(a.get("likes", "1", "count").unwrap().value, "3");
+    }
+
+    #[cfg(feature = "crypto")]
+    #[test]
+    fn test_keyring() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut relay =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.set_keyring(Some(Keyring::new(1, [9; 32])));
+        b.set_keyring(Some(Keyring::new(1, [9; 32])));
+
+        let written = a
+            .write_batch(&[("todos", "1", "title", "buy milk")], 1000)
+            .unwrap();
+        let tombstone = a.delete("todos", "2", 1000).unwrap();
+        assert!(crate::crypto::is_sealed(&written[0].value));
+        assert_eq!(tombstone.value, "1");
+        assert_eq!(
+            a.get_opened("todos", "1", "title"),
+            Ok(Some("buy milk".to_string()))
+        );
+
+        // A relay without the key stores and indexes the sealed messages
+        for message in a.messages_since(i64::MIN) {
+            relay.apply(message, 1000).unwrap();
+        }
+        assert_eq!(relay.trie().unwrap().diff(a.trie().unwrap()), None);
+        assert!(!relay
+            .get("todos", "1", "title")
+            .unwrap()
+            .value
+            .contains("milk"));
+        assert!(relay.table().is_deleted("todos", "2"));
+        assert_eq!(
+            relay.get_opened("todos", "1", "title"),
+            Err(CryptoError::NoKeyring)
+        );
+
+        for message in relay.messages_since(i64::MIN) {
+            b.apply(message, 1000).unwrap();
+        }
+        assert_eq!(
+            b.get_opened("todos", "1", "title"),
+            Ok(Some("buy milk".to_string()))
+        );
+
+        // Rotating keeps history readable
+        a.keyring_mut().unwrap().rotate(2, [10; 32]);
+        a.write("todos", "1", "title", "buy eggs", 2000).unwrap();
+        assert_eq!(
+            a.get_opened("todos", "1", "title"),
+            Ok(Some("buy eggs".to_string()))
+        );
+        let history: Vec<String> = a
+            .messages_since(i64::MIN)
+            .iter()
+            .filter(|m| m.column == "title")
+            .map(|m| a.keyring().unwrap().open(m).unwrap())
+            .collect();
+        assert_eq!(history, vec!["buy milk", "buy eggs"]);
+    }
+
+    #[test]
+    fn test_apply_reused_timestamp() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let msg = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        b.apply(msg.clone(), 1000).unwrap();
+        let trie = b.trie().unwrap().clone();
+
+        let mut reused = msg.clone();
+        reused.value = "buy eggs".to_string();
+        let got = b.apply(reused, 1000);
+        let want = SyncError::ReusedTimestamp(msg.timestamp.to_string());
+        assert_eq!(got, Err(want));
+        assert_eq!(b.trie().unwrap().root_hash(), trie.root_hash());
+        assert_eq!(b.get("todos", "1", "title").unwrap().value, "buy milk");
+    }
+
+    #[test]
+    fn test_get_latest() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let old = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        let new = a
+            .write("todos", "1", "title", "buy oat milk", 2000)
+            .unwrap();
+
+        // Arrival order doesn't matter
+        b.apply(new, 2000).unwrap();
+        b.apply(old, 2000).unwrap();
+        assert_eq!(a.get("todos", "1", "title").unwrap().value, "buy oat milk");
+        assert_eq!(b.get("todos", "1", "title"), a.get("todos", "1", "title"));
+    }
+
+    #[test]
+    fn test_new_rebuilds_trie() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        a.write("todos", "2", "title", "buy eggs", 120_000).unwrap();
+        let trie = a.trie().unwrap().clone();
+
+        let reopened = Engine::new(a.clock().clone(), a.into_store()).unwrap();
+        assert_eq!(reopened.trie().unwrap().diff(&trie), None);
+        assert_eq!(
+            reopened.get("todos", "2", "title").unwrap().value,
+            "buy eggs"
+        );
+    }
+
+    #[test]
+    fn test_rehash() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        a.write("todos", "2", "title", "buy eggs", 120_000).unwrap();
+
+        a.rehash(HashScheme::Xxh64Add).unwrap();
+        assert_eq!(a.trie().unwrap().scheme(), HashScheme::Xxh64Add);
+
+        let b = Engine::with_scheme(a.clock().clone(), a.store().clone(), HashScheme::Xxh64Add)
+            .unwrap();
+        assert_eq!(a.trie().unwrap(), b.trie().unwrap());
+    }
+
+    #[test]
+    fn test_delete_and_compact() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let title = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        a.write("todos", "1", "done", "false", 1000).unwrap();
+        a.write("todos", "2", "title", "buy eggs", 1000).unwrap();
+        a.delete("todos", "1", 2000).unwrap();
+        for message in a.messages_since(i64::MIN) {
+            b.apply(message, 2000).unwrap();
+        }
+        assert_eq!(b.get("todos", "1", "title"), None);
+        assert!(b.table().is_deleted("todos", "1"));
+
+        // Tombstones newer than the horizon are left alone
+        assert_eq!(a.compact(2000), 0);
+
+        assert_eq!(a.compact(3000), 2);
+        assert_eq!(b.compact(3000), 2);
+        assert_eq!(a.store().len(), 2);
+        assert_eq!(a.trie().unwrap(), b.trie().unwrap());
+        assert_eq!(a.get("todos", "2", "title").unwrap().value, "buy eggs");
+
+        // Re-delivered history of a compacted row is dropped
+        assert_eq!(a.apply(title, 3000), Ok(false));
+        assert_eq!(a.trie().unwrap(), b.trie().unwrap());
+    }
+
+    #[test]
+    fn test_subscribe() {
+        use std::sync::{Arc, Mutex};
+
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let seen = changes.clone();
+        let filter = BucketFilter {
+            minutes: Some(Epoch(0)..Epoch(2)),
+            dataset: Some("todos".to_string()),
+        };
+        let id = b.subscribe(filter, move |change| {
+            seen.lock().unwrap().push(change.clone())
+        });
+
+        a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        a.write("todos", "1", "done", "false", 2000).unwrap();
+        a.write("notes", "1", "body", "hi", 3000).unwrap();
+        a.write("todos", "2", "title", "buy eggs", 60_000).unwrap();
+        a.write("todos", "3", "title", "buy jam", 180_000).unwrap();
+        let messages = a.messages_since(i64::MIN);
+        b.apply_batch(messages.clone(), a.trie().unwrap(), 180_000)
+            .unwrap();
+
+        // One change per bucket, skipping the minute out of range
+        let got = changes.lock().unwrap().clone();
+        assert_eq!(got.len(), 2);
+        assert_eq!(got[0].epoch, Epoch(0));
+        assert_eq!(got[0].hash, b.trie().unwrap().minute_hash(Epoch(0)));
+        assert_eq!(got[0].datasets, vec!["notes", "todos"]);
+        assert_eq!(got[1].epoch, Epoch(1));
+
+        // Replays change nothing and fire nothing
+        b.apply_batch(messages, a.trie().unwrap(), 180_000).unwrap();
+        assert_eq!(changes.lock().unwrap().len(), 2);
+
+        assert!(b.unsubscribe(id));
+        assert!(!b.unsubscribe(id));
+        b.write("todos", "4", "title", "buy tea", 180_000).unwrap();
+        assert_eq!(changes.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_on_drift() {
+        use std::sync::{Arc, Mutex};
+
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let drifts = Arc::new(Mutex::new(Vec::new()));
+        let seen = drifts.clone();
+        b.on_drift(move |ahead| seen.lock().unwrap().push(ahead));
+
+        let behind = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        let near = a.write("todos", "2", "title", "buy eggs", 31_000).unwrap();
+        let far = a.write("todos", "3", "title", "buy jam", 200_000).unwrap();
+        assert_eq!(b.apply(behind, 1000), Ok(true));
+        assert_eq!(b.apply(near, 1000), Ok(true));
+        let err = b.apply(far, 1000).unwrap_err();
+        assert!(matches!(err.clock(), Some(ClockError::Drift { .. })));
+
+        let want = vec![Duration::from_secs(30), Duration::from_secs(199)];
+        assert_eq!(*drifts.lock().unwrap(), want);
+        let stats = b.drift_stats();
+        assert_eq!((stats.ahead, stats.rejected), (2, 1));
+        assert_eq!(stats.max_ahead, Duration::from_secs(199));
+        assert_eq!(a.drift_stats(), DriftStats::default());
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut engine =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        engine
+            .write("todos", "1", "title", "buy milk", 1000)
+            .unwrap();
+        let snapshot = engine.snapshot();
+        assert_eq!(snapshot.as_of(), engine.clock());
+
+        engine
+            .write_batch(
+                &[
+                    ("todos", "1", "title", "buy eggs"),
+                    ("todos", "2", "title", "buy jam"),
+                ],
+                2000,
+            )
+            .unwrap();
+        let reader = thread::spawn(move || {
+            let title = snapshot.get("todos", "1", "title").unwrap().value.clone();
+            (title, snapshot.get("todos", "2", "title").is_none())
+        });
+        assert_eq!(reader.join().unwrap(), ("buy milk".to_string(), true));
+        assert_eq!(
+            engine.snapshot().get("todos", "1", "title").unwrap().value,
+            "buy eggs"
+        );
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let node = make_client_id();
+        let mut a = Engine::new(Timestamp::new(0, 0, node.clone()), MemoryStore::new()).unwrap();
+        let mut b = Engine::new(Timestamp::new(0, 0, node), MemoryStore::new()).unwrap();
+        let ops = [
+            ("todos", "1", "title", "buy milk"),
+            ("todos", "1", "title", "buy oat milk"),
+            ("todos", "2", "title", "buy eggs"),
+        ];
+
+        let batch = a.write_batch(&ops, 1000).unwrap();
+        for (dataset, row, column, value) in ops {
+            b.write(dataset, row, column, value, 1000).unwrap();
+        }
+        assert_eq!(batch, b.messages_since(i64::MIN));
+        assert_eq!(a.trie().unwrap(), b.trie().unwrap());
+        assert_eq!(a.clock(), b.clock());
+        assert_eq!(a.get("todos", "1", "title").unwrap().value, "buy oat milk");
+    }
+
+    #[test]
+    fn test_size_limit() {
+        use crate::limit::{MemoryBlobStore, OversizePolicy};
+
+        let mut engine =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let small = engine.write("todos", "1", "title", "buy milk", 0).unwrap();
+        let max = small.size() + 4;
+        let pasted = "é".repeat(100);
+
+        engine.set_size_limit(Some(SizeLimit::new(max, OversizePolicy::Reject)));
+        let size = small.size() - "buy milk".len() + pasted.len();
+        assert_eq!(
+            engine.write("todos", "1", "title", &pasted, 0),
+            Err(LimitError::TooLarge(size, max).into())
+        );
+        // A refused batch writes nothing, the small op included
+        let ops = [
+            ("todos", "2", "title", "ok"),
+            ("todos", "1", "title", &pasted),
+        ];
+        assert!(engine.write_batch(&ops, 0).is_err());
+        assert_eq!(engine.store().len(), 1);
+        assert_eq!(engine.clock(), &small.timestamp);
+
+        // 12 bytes of room is six two-byte characters
+        engine.set_size_limit(Some(SizeLimit::new(max, OversizePolicy::Truncate)));
+        let cut = engine.write("todos", "1", "title", &pasted, 0).unwrap();
+        assert_eq!(cut.value, "é".repeat(6));
+        assert_eq!(engine.get("todos", "1", "title").unwrap().value, cut.value);
+
+        let offload = OversizePolicy::Offload(Box::new(MemoryBlobStore::new()));
+        engine.set_size_limit(Some(SizeLimit::new(max + 40, offload)));
+        let moved = engine.write("todos", "1", "title", &pasted, 0).unwrap();
+        let Some(OversizePolicy::Offload(blobs)) = engine.size_limit().map(|l| &l.policy) else {
+            unreachable!()
+        };
+        assert_eq!(blobs.get(&moved.value), Some(pasted));
+    }
+
+    #[test]
+    fn test_write_batch_drift() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.write("todos", "1", "title", "buy milk", 1_000_000)
+            .unwrap();
+        let clock = a.clock().clone();
+
+        let ops = [("todos", "2", "title", "buy eggs")];
+        assert!(a.write_batch(&ops, 0).is_err());
+        assert_eq!(a.clock(), &clock);
+        assert_eq!(a.store().len(), 1);
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        let end = Trie::new().end_millis();
+        let mut a =
+            Engine::new(Timestamp::new(end, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let got = a.write("todos", "1", "title", "buy milk", end);
+        assert_eq!(got, Err(TimestampError::OutOfRangeError(end).into()));
+        assert_eq!(a.clock().millis(), end);
+        assert!(a.store().is_empty());
+
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let message = Message {
+            timestamp: Timestamp::new(-1, 0, make_client_id()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: "buy milk".to_string(),
+        };
+        assert_eq!(
+            b.apply(message, 0),
+            Err(TimestampError::OutOfRangeError(-1).into())
+        );
+    }
+
+    #[test]
+    fn test_content_identity() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.set_identity(Identity::Content).unwrap();
+        b.set_identity(Identity::Content).unwrap();
+
+        let msg = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        let mut tampered = msg.clone();
+        tampered.value = "buy eggs".to_string();
+        b.apply(tampered, 1000).unwrap();
+        assert_ne!(a.trie().unwrap(), b.trie().unwrap());
+
+        // Timestamp identity can't tell the two apart
+        a.set_identity(Identity::Timestamp).unwrap();
+        b.set_identity(Identity::Timestamp).unwrap();
+        assert_eq!(a.trie().unwrap(), b.trie().unwrap());
+
+        a.set_identity(Identity::Content).unwrap();
+        a.write_batch(&[("todos", "2", "title", "buy eggs")], 1000)
+            .unwrap();
+        let trie = a.trie().unwrap().clone();
+        a.set_identity(Identity::Content).unwrap();
+        assert_eq!(a.trie().unwrap(), &trie);
+    }
+
+    #[test]
+    fn test_apply_batch_skips_replays() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let mut b =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        a.write("todos", "2", "title", "buy eggs", 120_000).unwrap();
+
+        let history = a.messages_since(i64::MIN);
+        let applied = b
+            .apply_batch(history.clone(), a.trie().unwrap(), 120_000)
+            .unwrap();
+        assert_eq!(applied, history);
+        assert_eq!(b.high_water(a.clock().node()), Some(&history[1].timestamp));
+        assert_eq!(b.versions(), a.versions());
+
+        // The whole batch again, plus one new write in an old minute
+        let new = a
+            .write("todos", "3", "title", "buy bread", 120_001)
+            .unwrap();
+        let mut replay = history;
+        replay.push(new.clone());
+        let applied = b.apply_batch(replay, a.trie().unwrap(), 120_001).unwrap();
+        assert_eq!(applied, vec![new]);
+        assert_eq!(a.trie().unwrap(), b.trie().unwrap());
+    }
+
+    #[test]
+    fn test_enforce_budget() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        let first = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        a.write("todos", "1", "done", "true", 1000).unwrap();
+        let tombstone = a.delete("todos", "1", 1000).unwrap();
+        let full = a.store().size_bytes();
+
+        let budget = |warn, compact, limit| SizeBudget {
+            warn,
+            compact,
+            limit,
+        };
+        let got = a.enforce_budget(&budget(full + 1, full + 1, full + 1), 2000);
+        assert_eq!(got, Ok(BudgetStatus::Within(full)));
+        let got = a.enforce_budget(&budget(full, full + 1, full + 1), 2000);
+        assert_eq!(got, Ok(BudgetStatus::Warned(full)));
+
+        // Nothing old enough to compact yet
+        let got = a.enforce_budget(&budget(0, 0, full), 1000);
+        assert_eq!(got, Err(BudgetError::Exceeded(full, full)));
+
+        let got = a.enforce_budget(&budget(0, 0, full), 2000);
+        assert_eq!(got, Ok(BudgetStatus::Compacted(tombstone.size())));
+        assert!(!a.store().contains(&first.timestamp));
+    }
+
+    #[test]
+    fn test_new_raises_stale_clock() {
+        let node = make_client_id();
+        let mut a = Engine::new(Timestamp::new(0, 0, node.clone()), MemoryStore::new()).unwrap();
+        let saved = a.clock().to_state();
+        let last = a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+
+        // Restored from a save taken before the last write
+        let mut reopened = Engine::new(Timestamp::from_state(saved), a.into_store()).unwrap();
+        assert_eq!(reopened.clock(), &last.timestamp);
+        let next = reopened
+            .write("todos", "1", "title", "buy eggs", 1000)
+            .unwrap();
+        assert!(next.timestamp > last.timestamp);
+    }
+
+    #[test]
+    fn test_open_modes() {
+        let mut a =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        a.write("todos", "1", "title", "buy milk", 1000).unwrap();
+        let hash = a.trie().unwrap().root_hash();
+
+        let fast = Engine::open_fast(a.clock().clone(), a.into_store(), HashScheme::default());
+        assert_eq!(fast.get("todos", "1", "title").unwrap().value, "buy milk");
+        assert_eq!(fast.wait().map(Trie::root_hash), Ok(hash));
+        assert!(fast.is_ready());
+
+        let mut store = MemoryStore::new();
+        let ts = Timestamp::new(i64::MAX / 2, 0, make_client_id());
+        store.insert(Message {
+            timestamp: ts.clone(),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: "buy milk".to_string(),
+        });
+        let clock = Timestamp::new(0, 0, make_client_id());
+        let fast = Engine::open_fast(clock.clone(), store.clone(), HashScheme::default());
+        let err = StoreError::Integrity(KeyError::OutOfRange(ts.millis(), DEFAULT_DEPTH));
+        assert_eq!(fast.wait().err(), Some(err.clone()));
+        assert_eq!(fast.trie().err(), Some(err.clone()));
+        assert!(fast.get("todos", "1", "title").is_some());
+
+        // Writes, applies and compaction refuse rather than panic
+        let mut fast = fast;
+        let got = fast.write("todos", "2", "title", "buy eggs", 1000);
+        assert_eq!(got, Err(WriteError::Store(err.clone())));
+        let message = Message {
+            timestamp: Timestamp::new(1000, 0, make_client_id()),
+            dataset: "todos".to_string(),
+            row: "2".to_string(),
+            column: "title".to_string(),
+            value: "buy eggs".to_string(),
+        };
+        assert_eq!(
+            fast.apply(message, 1000),
+            Err(SyncError::Store(err.clone()))
+        );
+        assert_eq!(fast.compact(i64::MAX), 0);
+        assert_eq!(fast.rehash(HashScheme::Xxh64Add), Err(err.clone()));
+        assert_eq!(fast.set_identity(Identity::Content), Err(err.clone()));
+        assert_eq!(fast.identity(), Identity::Timestamp);
+
+        let got = Engine::open_verified(clock, store, HashScheme::default()).err();
+        assert_eq!(got, Some(err));
+    }
+}
@@ -0,0 +1,151 @@
+// Framing for transports that don't frame or check data themselves, such
+// as raw TCP, BLE characteristics or files. HTTP and WebSockets already
+// delimit and protect each frame, so they send payloads bare.
+
+use alloc::vec::Vec;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::timestamp::Timestamp;
+
+/// A payload, usually a sync frame, with the sender's clock and a checksum
+///
+/// On the wire: magic, version, the length of the rest as a u32, the
+/// timestamp, the length-prefixed payload, then xxh3_64 of every byte
+/// before it. The length comes early so a stream reader knows how much to
+/// buffer from the first nine bytes; see `frame_len`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyncEnvelope {
+    pub timestamp: Timestamp,
+    pub payload: Vec<u8>,
+}
+
+impl SyncEnvelope {
+    pub const MAGIC: [u8; 4] = *b"MENV";
+    pub const VERSION: u8 = 1;
+    // Magic, version and length
+    const PREFIX_LEN: usize = 9;
+    const CHECKSUM_LEN: usize = 8;
+
+    pub fn new(timestamp: Timestamp, payload: Vec<u8>) -> Self {
+        SyncEnvelope { timestamp, payload }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Writer::new();
+        self.timestamp.encode(&mut body);
+        body.put_bytes(&self.payload);
+        let body = body.into_bytes();
+
+        let mut w = Writer::new();
+        for byte in Self::MAGIC {
+            w.put_u8(byte);
+        }
+        w.put_u8(Self::VERSION);
+        w.put_u32((body.len() + Self::CHECKSUM_LEN) as u32);
+        let mut bytes = w.into_bytes();
+        bytes.extend_from_slice(&body);
+        let checksum = xxh3_64(&bytes);
+        bytes.extend_from_slice(&checksum.to_be_bytes());
+        bytes
+    }
+
+    /// Decode exactly one envelope, checking it arrived intact
+    pub fn decode(bytes: &[u8]) -> Result<SyncEnvelope, DecodeError> {
+        let len = SyncEnvelope::frame_len(bytes)?.ok_or(DecodeError::UnexpectedEof)?;
+        if bytes.len() < len {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        if bytes.len() > len {
+            return Err(DecodeError::TrailingBytes(bytes.len() - len));
+        }
+
+        let (checked, checksum) = bytes.split_at(len - Self::CHECKSUM_LEN);
+        let mut r = Reader::new(checksum);
+        if r.get_u64()? != xxh3_64(checked) {
+            return Err(DecodeError::ChecksumMismatch);
+        }
+
+        let mut r = Reader::new(&checked[Self::PREFIX_LEN..]);
+        let timestamp = Timestamp::decode(&mut r)?;
+        let payload = r.get_bytes()?.to_vec();
+        r.finish()?;
+        Ok(SyncEnvelope { timestamp, payload })
+    }
+
+    /// The whole envelope's length in bytes, once its first nine have
+    /// arrived, or None before then
+    ///
+    /// Errors as soon as the prefix shows the bytes aren't an envelope
+    /// this version can read, so a stream can be dropped early.
+    pub fn frame_len(prefix: &[u8]) -> Result<Option<usize>, DecodeError> {
+        if prefix.len() < Self::PREFIX_LEN {
+            return Ok(None);
+        }
+        let mut r = Reader::new(&prefix[..Self::PREFIX_LEN]);
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = r.get_u8()?;
+        }
+        if magic != Self::MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+        let version = r.get_u8()?;
+        if version != Self::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let rest = r.get_u32()? as usize;
+        if rest < Self::CHECKSUM_LEN {
+            return Err(DecodeError::UnexpectedEof);
+        }
+        Ok(Some(Self::PREFIX_LEN + rest))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec;
+
+    #[test]
+    fn test_envelope() {
+        let ts = Timestamp::new(1711231855000, 3, "1234123412341234".to_string());
+        let envelope = SyncEnvelope::new(ts, vec![1, 2, 3, 4]);
+        let bytes = envelope.encode();
+        assert_eq!(&bytes[..4], b"MENV");
+        assert_eq!(SyncEnvelope::decode(&bytes), Ok(envelope.clone()));
+
+        // A stream knows the length from the prefix alone
+        assert_eq!(SyncEnvelope::frame_len(&bytes[..8]), Ok(None));
+        assert_eq!(SyncEnvelope::frame_len(&bytes[..9]), Ok(Some(bytes.len())));
+
+        // Any flipped bit past the prefix fails the checksum
+        for i in SyncEnvelope::PREFIX_LEN..bytes.len() {
+            let mut corrupt = bytes.clone();
+            corrupt[i] ^= 0x10;
+            assert_eq!(
+                SyncEnvelope::decode(&corrupt),
+                Err(DecodeError::ChecksumMismatch)
+            );
+        }
+
+        let mut future = bytes.clone();
+        future[4] = 2;
+        assert_eq!(
+            SyncEnvelope::decode(&future),
+            Err(DecodeError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            SyncEnvelope::decode(&bytes[..bytes.len() - 1]),
+            Err(DecodeError::UnexpectedEof)
+        );
+        let mut longer = bytes.clone();
+        longer.push(0);
+        assert_eq!(
+            SyncEnvelope::decode(&longer),
+            Err(DecodeError::TrailingBytes(1))
+        );
+    }
+}
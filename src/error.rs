@@ -0,0 +1,121 @@
+// The crate's errors, one type per concern: reading timestamps, running
+// the clock, indexing and comparing tries, decoding wire data and taking in
+// a peer's messages. Each lives with the code raising it, bar `SyncError`,
+// which the clock, the engine and the sync protocol all raise and so lives
+// here; this module gathers them, and `Error` wraps any of them for
+// callers that only pass failures up.
+
+use alloc::string::String;
+
+pub use crate::codec::DecodeError;
+pub use crate::store::StoreError;
+pub use crate::timestamp::{ClockError, ParseError, TimestampError};
+pub use crate::trie::{KeyError, TrieError};
+
+// Errors related to taking in a peer's messages and tries
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum SyncError {
+    #[error("malformed sync frame: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("rejected message: {0}")]
+    Timestamp(#[from] TimestampError),
+    #[error("peer {0}")]
+    Trie(#[from] TrieError),
+    // A message under our own node id: another device cloned from this
+    // one, or a restore from backup that kept the original's id
+    #[error("duplicate node identifier {0}")]
+    DuplicateNode(String),
+    // A different message under a timestamp we hold: a client reusing
+    // counters
+    #[error("timestamp {0} reused by a different message")]
+    ReusedTimestamp(String),
+    // Our own trie is unusable
+    #[error("local {0}")]
+    Store(#[from] StoreError),
+}
+
+impl SyncError {
+    /// The clock's error, if the clock refused
+    pub fn clock(&self) -> Option<&ClockError> {
+        match self {
+            SyncError::Timestamp(err) => err.clock(),
+            _ => None,
+        }
+    }
+}
+
+impl From<ClockError> for SyncError {
+    fn from(err: ClockError) -> Self {
+        SyncError::Timestamp(err.into())
+    }
+}
+
+// Errors related to anything the crate does; the message says only which
+// layer failed, and `source` has the rest
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    #[error("could not parse timestamp")]
+    Parse(#[from] ParseError),
+    #[error("clock refused timestamp")]
+    Clock(#[from] ClockError),
+    #[error("timestamp rejected")]
+    Timestamp(#[from] TimestampError),
+    #[error("trie operation failed")]
+    Trie(#[from] TrieError),
+    #[error("could not decode")]
+    Decode(#[from] DecodeError),
+    #[error("sync failed")]
+    Sync(#[from] SyncError),
+}
+
+impl From<KeyError> for Error {
+    fn from(err: KeyError) -> Self {
+        Error::Trie(err.into())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timestamp::Timestamp;
+    use crate::trie::Trie;
+    use alloc::string::ToString;
+
+    // Each layer's failure passes up through `?` into the one type
+    fn insert(trie: &mut Trie, s: &str) -> Result<(), Error> {
+        let timestamp: Timestamp = s.parse()?;
+        trie.try_insert(timestamp)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut trie = Trie::new();
+        assert_eq!(
+            insert(&mut trie, "2024-03-23T22:10:55.000Z-0000-1234123412341234"),
+            Ok(())
+        );
+        assert_eq!(
+            insert(&mut trie, "1234"),
+            Err(Error::Parse(ParseError::Malformed))
+        );
+
+        let late = "2100-01-01T00:00:00.000Z-0000-1234123412341234";
+        let err = insert(&mut trie, late).unwrap_err();
+        assert!(matches!(
+            err,
+            Error::Trie(TrieError::Key(KeyError::OutOfRange(..)))
+        ));
+        // Display names the layer and `source` the cause, without repeating
+        let source = core::error::Error::source(&err).unwrap();
+        assert_eq!(err.to_string(), "trie operation failed");
+        assert!(source.to_string().contains("outside the range"));
+
+        let mut clock = Timestamp::new(0, 0, "1234123412341234".to_string());
+        let ours = clock.clone();
+        let err = Error::from(clock.recv(&ours, 0).unwrap_err());
+        assert!(matches!(err, Error::Sync(SyncError::DuplicateNode(_))));
+    }
+}
@@ -0,0 +1,384 @@
+// C bindings, so mobile apps can embed the clock and trie rather than
+// reimplement them. Timestamps cross the boundary in their string form.
+// Every object and string handed out must be released with the matching
+// `_free` function. The header is generated with
+// `cbindgen --config cbindgen.toml --output include/markle.h`.
+
+use std::ffi::{c_char, CStr, CString};
+use std::ptr;
+
+use crate::error::SyncError;
+use crate::limit::LimitError;
+use crate::timestamp::{make_client_id, ClockError, Timestamp, TimestampError};
+use crate::trie::{Trie, TrieError};
+
+/// Result of a call that can fail
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkleStatus {
+    Ok = 0,
+    NullPointer,
+    InvalidUtf8,
+    InvalidTimestamp,
+    ClockDrift,
+    CounterOverflow,
+    DuplicateNode,
+    OutOfRange,
+    Regression,
+    Decode,
+    TooLarge,
+}
+
+impl From<ClockError> for MarkleStatus {
+    fn from(err: ClockError) -> Self {
+        match err {
+            ClockError::Drift { .. } => MarkleStatus::ClockDrift,
+            ClockError::Overflow => MarkleStatus::CounterOverflow,
+            ClockError::Regression { .. } => MarkleStatus::Regression,
+        }
+    }
+}
+
+impl From<TimestampError> for MarkleStatus {
+    fn from(err: TimestampError) -> Self {
+        match err {
+            TimestampError::Clock(err) => err.into(),
+            TimestampError::OutOfRangeError(_) => MarkleStatus::OutOfRange,
+            TimestampError::UnrepresentableError(_) => MarkleStatus::InvalidTimestamp,
+            TimestampError::InvalidNodeError(_) => MarkleStatus::InvalidTimestamp,
+        }
+    }
+}
+
+impl From<LimitError> for MarkleStatus {
+    fn from(err: LimitError) -> Self {
+        match err {
+            LimitError::TooLarge(..) => MarkleStatus::TooLarge,
+        }
+    }
+}
+
+impl From<SyncError> for MarkleStatus {
+    fn from(err: SyncError) -> Self {
+        match err {
+            SyncError::Timestamp(err) => err.into(),
+            SyncError::Decode(_) => MarkleStatus::Decode,
+            SyncError::Trie(TrieError::Key(_)) => MarkleStatus::OutOfRange,
+            SyncError::DuplicateNode(_) => MarkleStatus::DuplicateNode,
+            _ => MarkleStatus::InvalidTimestamp,
+        }
+    }
+}
+
+/// A node's hybrid logical clock
+pub struct MarkleClock {
+    clock: Timestamp,
+}
+
+/// A merkle trie of timestamps
+pub struct MarkleTrie {
+    trie: Trie,
+}
+
+// Borrow a C string as UTF-8
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, MarkleStatus> {
+    if s.is_null() {
+        return Err(MarkleStatus::NullPointer);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| MarkleStatus::InvalidUtf8)
+}
+
+unsafe fn to_timestamp(s: *const c_char) -> Result<Timestamp, MarkleStatus> {
+    Timestamp::parse(to_str(s)?).ok_or(MarkleStatus::InvalidTimestamp)
+}
+
+// Hand a timestamp's string form to the caller
+unsafe fn put_timestamp(timestamp: &Timestamp, out: *mut *mut c_char) -> MarkleStatus {
+    // The string form never contains a NUL
+    let s = CString::new(timestamp.to_string()).expect("timestamp has no NUL");
+    *out = s.into_raw();
+    MarkleStatus::Ok
+}
+
+fn status(result: Result<MarkleStatus, MarkleStatus>) -> MarkleStatus {
+    result.unwrap_or_else(|status| status)
+}
+
+/// Create a clock for `node`, or for a fresh random node id if `node` is
+/// null. Returns null if `node` isn't valid UTF-8 or a valid node id.
+///
+/// # Safety
+///
+/// `node` must be null or a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn markle_clock_new(node: *const c_char) -> *mut MarkleClock {
+    let node = if node.is_null() {
+        make_client_id()
+    } else {
+        match to_str(node) {
+            Ok(node) => node.to_string(),
+            Err(_) => return ptr::null_mut(),
+        }
+    };
+    let Ok(clock) = Timestamp::try_new(0, 0, node) else {
+        return ptr::null_mut();
+    };
+    let clock = MarkleClock { clock };
+    Box::into_raw(Box::new(clock))
+}
+
+/// # Safety
+///
+/// `clock` must be null or returned by `markle_clock_new`, and not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn markle_clock_free(clock: *mut MarkleClock) {
+    if !clock.is_null() {
+        drop(Box::from_raw(clock));
+    }
+}
+
+/// Mint a timestamp for a local event at physical time `phys`
+/// (milliseconds since the Unix epoch), writing its string form to `out`
+///
+/// # Safety
+///
+/// `clock` must come from `markle_clock_new` and `out` must be writable.
+/// The string written must be released with `markle_string_free`.
+#[no_mangle]
+pub unsafe extern "C" fn markle_clock_send(
+    clock: *mut MarkleClock,
+    phys: i64,
+    out: *mut *mut c_char,
+) -> MarkleStatus {
+    if clock.is_null() || out.is_null() {
+        return MarkleStatus::NullPointer;
+    }
+    status((|| {
+        let timestamp = (*clock).clock.send(phys)?;
+        Ok(put_timestamp(&timestamp, out))
+    })())
+}
+
+/// Merge a timestamp received from another node, writing the clock's new
+/// timestamp to `out`
+///
+/// # Safety
+///
+/// As `markle_clock_send`, and `timestamp` must be a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn markle_clock_recv(
+    clock: *mut MarkleClock,
+    timestamp: *const c_char,
+    phys: i64,
+    out: *mut *mut c_char,
+) -> MarkleStatus {
+    if clock.is_null() || out.is_null() {
+        return MarkleStatus::NullPointer;
+    }
+    status((|| {
+        let remote = to_timestamp(timestamp)?;
+        let timestamp = (*clock).clock.recv(&remote, phys)?;
+        Ok(put_timestamp(&timestamp, out))
+    })())
+}
+
+/// # Safety
+///
+/// `s` must be null or a string written by this library, and not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn markle_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Create an empty trie under the default hash scheme
+#[no_mangle]
+pub extern "C" fn markle_trie_new() -> *mut MarkleTrie {
+    Box::into_raw(Box::new(MarkleTrie { trie: Trie::new() }))
+}
+
+/// # Safety
+///
+/// `trie` must be null or returned by this library, and not used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn markle_trie_free(trie: *mut MarkleTrie) {
+    if !trie.is_null() {
+        drop(Box::from_raw(trie));
+    }
+}
+
+/// # Safety
+///
+/// `trie` must come from this library and `timestamp` must be a
+/// NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn markle_trie_insert(
+    trie: *mut MarkleTrie,
+    timestamp: *const c_char,
+) -> MarkleStatus {
+    if trie.is_null() {
+        return MarkleStatus::NullPointer;
+    }
+    status((|| {
+        let timestamp = to_timestamp(timestamp)?;
+        (*trie)
+            .trie
+            .try_insert(timestamp)
+            .map_err(|_| MarkleStatus::OutOfRange)?;
+        Ok(MarkleStatus::Ok)
+    })())
+}
+
+/// # Safety
+///
+/// `trie` must come from this library.
+#[no_mangle]
+pub unsafe extern "C" fn markle_trie_root_hash(trie: *const MarkleTrie) -> u64 {
+    if trie.is_null() {
+        return 0;
+    }
+    (*trie).trie.root_hash()
+}
+
+/// Compare two tries, returning true and writing the millisecond time they
+/// diverge from to `out` if they differ
+///
+/// # Safety
+///
+/// `a` and `b` must come from this library and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn markle_trie_diff(
+    a: *const MarkleTrie,
+    b: *const MarkleTrie,
+    out: *mut i64,
+) -> bool {
+    if a.is_null() || b.is_null() || out.is_null() {
+        return false;
+    }
+    match (*a).trie.diff(&(*b).trie) {
+        Some(time) => {
+            *out = time.timestamp_millis();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Serialize a trie, returning a buffer of `*len` bytes to release with
+/// `markle_bytes_free`
+///
+/// # Safety
+///
+/// `trie` must come from this library and `len` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn markle_trie_save(trie: *const MarkleTrie, len: *mut usize) -> *mut u8 {
+    if trie.is_null() || len.is_null() {
+        return ptr::null_mut();
+    }
+    let bytes = (*trie).trie.save().into_boxed_slice();
+    *len = bytes.len();
+    Box::into_raw(bytes) as *mut u8
+}
+
+/// # Safety
+///
+/// `bytes` and `len` must be null or exactly as returned by
+/// `markle_trie_save`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn markle_bytes_free(bytes: *mut u8, len: usize) {
+    if !bytes.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(bytes, len)));
+    }
+}
+
+/// Deserialize a trie written by `markle_trie_save` into `out`
+///
+/// # Safety
+///
+/// `bytes` must point to `len` readable bytes and `out` must be writable.
+#[no_mangle]
+pub unsafe extern "C" fn markle_trie_load(
+    bytes: *const u8,
+    len: usize,
+    out: *mut *mut MarkleTrie,
+) -> MarkleStatus {
+    if bytes.is_null() || out.is_null() {
+        return MarkleStatus::NullPointer;
+    }
+    match Trie::load(std::slice::from_raw_parts(bytes, len)) {
+        Ok(trie) => {
+            *out = Box::into_raw(Box::new(MarkleTrie { trie }));
+            MarkleStatus::Ok
+        }
+        Err(_) => MarkleStatus::Decode,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    unsafe fn take(s: *mut c_char) -> String {
+        let owned = CStr::from_ptr(s).to_str().unwrap().to_string();
+        markle_string_free(s);
+        owned
+    }
+
+    #[test]
+    fn test_clock() {
+        unsafe {
+            let node = CString::new("1234123412341234").unwrap();
+            let clock = markle_clock_new(node.as_ptr());
+            let mut out = ptr::null_mut();
+
+            assert_eq!(markle_clock_send(clock, 1000, &mut out), MarkleStatus::Ok);
+            assert_eq!(take(out), "1970-01-01T00:00:01.000Z-0000-1234123412341234");
+
+            let remote = CString::new("1970-01-01T00:00:02.000Z-0005-5678567856785678").unwrap();
+            let got = markle_clock_recv(clock, remote.as_ptr(), 1000, &mut out);
+            assert_eq!(got, MarkleStatus::Ok);
+            assert_eq!(take(out), "1970-01-01T00:00:02.000Z-0006-1234123412341234");
+
+            let got = markle_clock_send(clock, 2000 - 120_000, &mut out);
+            assert_eq!(got, MarkleStatus::ClockDrift);
+
+            let garbage = CString::new("yesterday").unwrap();
+            let got = markle_clock_recv(clock, garbage.as_ptr(), 1000, &mut out);
+            assert_eq!(got, MarkleStatus::InvalidTimestamp);
+            markle_clock_free(clock);
+        }
+    }
+
+    #[test]
+    fn test_trie() {
+        unsafe {
+            let a = markle_trie_new();
+            let b = markle_trie_new();
+            let mut since = 0;
+            assert!(!markle_trie_diff(a, b, &mut since));
+
+            let ts = CString::new("2024-03-23T22:10:55.000Z-0000-1234123412341234").unwrap();
+            assert_eq!(markle_trie_insert(a, ts.as_ptr()), MarkleStatus::Ok);
+            assert!(markle_trie_diff(a, b, &mut since));
+            assert_eq!(since, 1711231800000);
+
+            let mut len = 0;
+            let bytes = markle_trie_save(a, &mut len);
+            let mut c = ptr::null_mut();
+            assert_eq!(markle_trie_load(bytes, len, &mut c), MarkleStatus::Ok);
+            assert_eq!(markle_trie_root_hash(c), markle_trie_root_hash(a));
+            assert_eq!(markle_trie_load(bytes, 1, &mut c), MarkleStatus::Decode);
+            markle_bytes_free(bytes, len);
+
+            markle_trie_free(a);
+            markle_trie_free(b);
+            markle_trie_free(c);
+        }
+    }
+}
@@ -0,0 +1,298 @@
+// Peer-to-peer sync for meshes with no server. Every interval a node picks
+// a few peers at random and sends each its root hash; peers that agree
+// answer at once, and only those that don't go on to a full diff. Changes
+// spread from node to node, so every node needn't reach every other.
+
+use std::time::{Duration, Instant};
+
+use crate::engine::Engine;
+use crate::store::MessageStore;
+use crate::sync::{SyncError, SyncSession, Transport};
+use crate::timestamp::SeededClientIds;
+
+/// Peers contacted per round unless set otherwise
+pub const DEFAULT_FANOUT: usize = 3;
+
+/// How long between rounds unless set otherwise
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What gossiping with one peer came to
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum GossipRound {
+    /// The root hashes matched; nothing more was sent
+    Agreed,
+    /// The hashes differed and a full sync ran; `applied` messages from the
+    /// peer were new here
+    Synced { applied: usize, converged: bool },
+}
+
+/// Gossips one engine's root hash with a random few of its peers
+///
+/// Call `gossip_due` whenever `next_due` comes around. Peers must offer
+/// `Capabilities::GOSSIP`, as every peer from this version on does.
+pub struct Gossip<T> {
+    peers: Vec<(String, T)>,
+    fanout: usize,
+    interval: Duration,
+    due: Instant,
+    rng: SeededClientIds,
+}
+
+impl<T> Default for Gossip<T> {
+    fn default() -> Self {
+        Gossip::new()
+    }
+}
+
+impl<T> Gossip<T> {
+    /// # Panics
+    ///
+    /// If the operating system's random source is unavailable.
+    pub fn new() -> Self {
+        let mut seed = [0; 8];
+        getrandom::getrandom(&mut seed).expect("os random source");
+        Gossip::with_seed(u64::from_le_bytes(seed))
+    }
+
+    /// Draw peers from a fixed seed, so a mesh replays the same rounds
+    pub fn with_seed(seed: u64) -> Self {
+        Gossip {
+            peers: Vec::new(),
+            fanout: DEFAULT_FANOUT,
+            interval: DEFAULT_INTERVAL,
+            due: Instant::now(),
+            rng: SeededClientIds::new(seed),
+        }
+    }
+
+    /// Contact at most `fanout` peers per round
+    ///
+    /// # Panics
+    ///
+    /// If `fanout` is zero.
+    pub fn with_fanout(mut self, fanout: usize) -> Self {
+        assert!(fanout > 0, "fanout must be at least one");
+        self.fanout = fanout;
+        self
+    }
+
+    /// Run a round every `interval`
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn fanout(&self) -> usize {
+        self.fanout
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Gossip with a peer too; replaces any peer of the same name
+    pub fn add_peer(&mut self, name: impl Into<String>, transport: T) {
+        let name = name.into();
+        self.peers.retain(|(known, _)| *known != name);
+        self.peers.push((name, transport));
+    }
+
+    /// Stop gossiping with a peer, handing back its transport
+    pub fn remove_peer(&mut self, name: &str) -> Option<T> {
+        let index = self.peers.iter().position(|(known, _)| known == name)?;
+        Some(self.peers.remove(index).1)
+    }
+
+    /// The peers' names, in the order they were added
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.peers.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// When the next round is due
+    pub fn next_due(&self) -> Instant {
+        self.due
+    }
+
+    // Move `fanout` peers picked at random to the front, returning how many
+    fn shuffle(&mut self) -> usize {
+        let picked = self.fanout.min(self.peers.len());
+        for i in 0..picked {
+            let left = (self.peers.len() - i) as u64;
+            let j = i + (self.rng.next_u64() % left) as usize;
+            self.peers.swap(i, j);
+        }
+        picked
+    }
+}
+
+impl<T> Gossip<T>
+where
+    T: Transport,
+    T::Error: From<SyncError>,
+{
+    /// Run a round if one is due at `now`, scheduling the next an interval
+    /// on; see `round`
+    pub fn gossip_due<S: MessageStore>(
+        &mut self,
+        engine: &mut Engine<S>,
+        now: Instant,
+    ) -> Vec<(String, Result<GossipRound, T::Error>)> {
+        if self.due > now {
+            return Vec::new();
+        }
+        self.due = now + self.interval;
+        self.round(engine)
+    }
+
+    /// Gossip with up to `fanout` peers picked at random, one at a time
+    ///
+    /// A peer that fails is reported and the round carries on with the rest.
+    pub fn round<S: MessageStore>(
+        &mut self,
+        engine: &mut Engine<S>,
+    ) -> Vec<(String, Result<GossipRound, T::Error>)> {
+        let picked = self.shuffle();
+        let mut rounds = Vec::new();
+        for (name, transport) in &mut self.peers[..picked] {
+            let mut session = SyncSession::new(engine).with_peer(name.clone());
+            let result = session.run_gossip(transport).map(|()| {
+                // A peer that agreed answered the opening frame with `Done`
+                if session.rounds() == 1 && session.applied().is_empty() {
+                    GossipRound::Agreed
+                } else {
+                    GossipRound::Synced {
+                        applied: session.applied().len(),
+                        converged: session.is_converged(),
+                    }
+                }
+            });
+            match &result {
+                Ok(GossipRound::Agreed) => {
+                    counter!("markle_gossip_rounds_total", 1, "outcome" => "agreed");
+                }
+                Ok(_) => {
+                    debug!(peer = %name, "gossip escalated to sync");
+                    counter!("markle_gossip_rounds_total", 1, "outcome" => "synced");
+                }
+                Err(_) => {
+                    counter!("markle_gossip_rounds_total", 1, "outcome" => "failed");
+                }
+            }
+            rounds.push((name.clone(), result));
+        }
+        rounds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+    use chrono::Utc;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    type Node = Rc<RefCell<Engine<MemoryStore>>>;
+
+    fn node() -> Node {
+        let engine =
+            Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap();
+        Rc::new(RefCell::new(engine))
+    }
+
+    // Another node of the mesh, answering each frame as a server would
+    struct Link(Node);
+
+    impl Transport for Link {
+        type Error = SyncError;
+
+        fn exchange(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+            SyncSession::new(&mut self.0.borrow_mut()).handle(frame)
+        }
+    }
+
+    #[test]
+    fn test_agreed() {
+        let a = node();
+        let b = node();
+        let mut gossip = Gossip::with_seed(1);
+        gossip.add_peer("b", Link(b.clone()));
+        let rounds = gossip.round(&mut a.borrow_mut());
+        assert_eq!(rounds.len(), 1);
+        assert_eq!(rounds[0].1, Ok(GossipRound::Agreed));
+
+        let now = Utc::now().timestamp_millis();
+        b.borrow_mut()
+            .write("todos", "1", "title", "buy milk", now)
+            .unwrap();
+        let rounds = gossip.round(&mut a.borrow_mut());
+        assert_eq!(
+            rounds[0].1,
+            Ok(GossipRound::Synced {
+                applied: 1,
+                converged: true
+            })
+        );
+        assert_eq!(
+            a.borrow().trie().unwrap().diff(b.borrow().trie().unwrap()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mesh_converges() {
+        let now = Utc::now().timestamp_millis();
+        let nodes: Vec<Node> = (0..6).map(|_| node()).collect();
+        let mut meshes: Vec<Gossip<Link>> = Vec::new();
+        for (i, node) in nodes.iter().enumerate() {
+            let row = i.to_string();
+            node.borrow_mut()
+                .write("todos", &row, "title", "from a node", now)
+                .unwrap();
+            let mut gossip = Gossip::with_seed(i as u64).with_fanout(1);
+            for (j, peer) in nodes.iter().enumerate() {
+                if i != j {
+                    gossip.add_peer(j.to_string(), Link(peer.clone()));
+                }
+            }
+            assert_eq!(gossip.peers().count(), 5);
+            meshes.push(gossip);
+        }
+
+        let root = |node: &Node| node.borrow().trie().unwrap().root_hash();
+        let mut rounds = 0;
+        while nodes.iter().any(|node| root(node) != root(&nodes[0])) {
+            for (gossip, node) in meshes.iter_mut().zip(&nodes) {
+                for (_, result) in gossip.round(&mut node.borrow_mut()) {
+                    result.unwrap();
+                }
+            }
+            rounds += 1;
+            assert!(rounds < 20, "mesh did not converge");
+        }
+        assert_eq!(nodes[0].borrow().store().len(), 6);
+
+        // Once converged, every exchange stops at the hashes
+        let results = meshes[0].round(&mut nodes[0].borrow_mut());
+        assert_eq!(results[0].1, Ok(GossipRound::Agreed));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let a = node();
+        let mut gossip = Gossip::with_seed(1)
+            .with_fanout(2)
+            .with_interval(Duration::from_secs(1));
+        for name in ["b", "c", "d"] {
+            gossip.add_peer(name, Link(node()));
+        }
+        let now = Instant::now();
+        assert_eq!(gossip.gossip_due(&mut a.borrow_mut(), now).len(), 2);
+        assert!(gossip.gossip_due(&mut a.borrow_mut(), now).is_empty());
+        assert_eq!(gossip.next_due(), now + Duration::from_secs(1));
+        assert!(gossip.remove_peer("c").is_some());
+        assert_eq!(gossip.peers().count(), 2);
+    }
+}
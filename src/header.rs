@@ -0,0 +1,149 @@
+use core::fmt;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::trie::{from_layout_id, layout_id, HashScheme, Resolution, Trie};
+
+/// Leading bytes of every persisted artifact
+///
+/// Records how the artifact was produced so that loading it under a
+/// different configuration fails with a typed error instead of quietly
+/// producing tries that never converge. Wire frames don't carry it; peers
+/// already exchange their scheme in every frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub scheme: HashScheme,
+    /// Key digits per timestamp, the trie's time range
+    pub depth: usize,
+    /// Written alongside the scheme, so minute headers read as before
+    pub resolution: Resolution,
+}
+
+impl Header {
+    pub const MAGIC: [u8; 4] = *b"MRKL";
+    pub const VERSION: u8 = 1;
+
+    pub fn new(scheme: HashScheme, depth: usize) -> Self {
+        Header {
+            version: Self::VERSION,
+            scheme,
+            depth,
+            resolution: Resolution::default(),
+        }
+    }
+
+    pub fn with_resolution(mut self, resolution: Resolution) -> Self {
+        self.resolution = resolution;
+        self
+    }
+
+    /// The header describing artifacts built from `trie`
+    pub fn of(trie: &Trie) -> Self {
+        Header::new(trie.scheme(), trie.depth()).with_resolution(trie.resolution())
+    }
+
+    pub fn encode(&self, w: &mut Writer) {
+        for byte in Self::MAGIC {
+            w.put_u8(byte);
+        }
+        w.put_u8(self.version);
+        w.put_u8(layout_id(self.scheme, self.resolution));
+        w.put_u8(self.depth as u8);
+    }
+
+    pub fn decode(r: &mut Reader) -> Result<Header, DecodeError> {
+        let mut magic = [0; 4];
+        for byte in &mut magic {
+            *byte = r.get_u8()?;
+        }
+        if magic != Self::MAGIC {
+            return Err(DecodeError::BadMagic);
+        }
+
+        let version = r.get_u8()?;
+        if version != Self::VERSION {
+            return Err(DecodeError::UnsupportedVersion(version));
+        }
+        let (scheme, resolution) = from_layout_id(r.get_u8()?)?;
+        let depth = r.get_u8()?;
+        if depth == 0 || depth as usize > resolution.max_depth() {
+            return Err(DecodeError::InvalidDepth(depth));
+        }
+
+        Ok(Header::new(scheme, depth as usize).with_resolution(resolution))
+    }
+
+    /// Error unless the artifact was produced the way `expected` describes
+    pub fn expect(&self, expected: &Header) -> Result<(), DecodeError> {
+        if self == expected {
+            Ok(())
+        } else {
+            Err(DecodeError::HeaderMismatch(*self, *expected))
+        }
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "v{} {:?} depth {} by {:?}",
+            self.version, self.scheme, self.depth, self.resolution
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let header = Header::new(HashScheme::Xxh64Add, 20);
+        let mut w = Writer::new();
+        header.encode(&mut w);
+        let bytes = w.into_bytes();
+        assert_eq!(&bytes[..4], b"MRKL");
+
+        let mut r = Reader::new(&bytes);
+        assert_eq!(Header::decode(&mut r), Ok(header));
+        assert_eq!(r.finish(), Ok(()));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let decode = |bytes: &[u8]| Header::decode(&mut Reader::new(bytes));
+        assert_eq!(decode(b"MRKX\x01\x01\x10"), Err(DecodeError::BadMagic));
+        assert_eq!(
+            decode(b"MRKL\x09\x01\x10"),
+            Err(DecodeError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            decode(b"MRKL\x01\x07\x10"),
+            Err(DecodeError::UnknownHashScheme(7))
+        );
+        assert_eq!(
+            decode(b"MRKL\x01\x71\x10"),
+            Err(DecodeError::UnknownResolution(7))
+        );
+        assert_eq!(
+            decode(b"MRKL\x01\x31\x11"),
+            Err(DecodeError::InvalidDepth(17))
+        );
+        assert_eq!(
+            decode(b"MRKL\x01\x01\x00"),
+            Err(DecodeError::InvalidDepth(0))
+        );
+    }
+
+    #[test]
+    fn test_expect() {
+        let ours = Header::new(HashScheme::Murmur32Xor, 16);
+        let theirs = Header::new(HashScheme::Xxh64Add, 16);
+        assert_eq!(ours.expect(&ours), Ok(()));
+        assert_eq!(
+            theirs.expect(&ours),
+            Err(DecodeError::HeaderMismatch(theirs, ours))
+        );
+    }
+}
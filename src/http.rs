@@ -0,0 +1,423 @@
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::Utc;
+use tokio_util::sync::CancellationToken;
+
+use crate::clock::OffsetEstimator;
+
+use crate::engine::Engine;
+use crate::message::Message;
+use crate::store::MessageStore;
+use crate::sync::{
+    Capabilities, SyncError, SyncFailure, SyncSession, CAPABILITIES_HEADER, CLOCK_HEADER,
+    TIME_HEADER,
+};
+use crate::timestamp::Timestamp;
+
+/// Async client for a sync server
+///
+/// Speaks the same protocol as the `markle` binary: each frame is POSTed
+/// to `{url}/sync?group=...` and the response body is the server's reply,
+/// or 204 when it has none. A full round trip sends our trie, applies
+/// the messages we were missing, and uploads the ones the server lacks.
+///
+/// The server's time on each response feeds an `OffsetEstimator` shared by
+/// the client's clones; see `time_estimate`.
+///
+/// Each request offers the client's capabilities, and the server answers
+/// with those it agrees to; a server from before capabilities answers
+/// nothing, which is taken as none.
+#[derive(Debug, Clone)]
+pub struct HttpSyncClient {
+    client: reqwest::Client,
+    endpoint: String,
+    group: String,
+    estimator: Arc<Mutex<OffsetEstimator>>,
+    capabilities: Capabilities,
+    agreed: Arc<Mutex<Capabilities>>,
+}
+
+impl HttpSyncClient {
+    pub fn new(url: &str, group: &str) -> Self {
+        HttpSyncClient::with_client(reqwest::Client::new(), url, group)
+    }
+
+    /// Use a preconfigured client, e.g. one with timeouts or default
+    /// headers for authentication
+    pub fn with_client(client: reqwest::Client, url: &str, group: &str) -> Self {
+        HttpSyncClient {
+            client,
+            endpoint: format!("{}/sync", url.trim_end_matches('/')),
+            group: group.to_string(),
+            estimator: Arc::default(),
+            capabilities: Capabilities::SUPPORTED,
+            agreed: Arc::default(),
+        }
+    }
+
+    /// Offer `capabilities` rather than everything this build supports;
+    /// any it doesn't support are dropped
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities & Capabilities::SUPPORTED;
+        self
+    }
+
+    /// What the server agreed to on the last response
+    pub fn agreed_capabilities(&self) -> Capabilities {
+        *self.agreed.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Our clock's offset from the server's and the round trip, as of the
+    /// responses so far
+    pub fn time_estimate(&self) -> OffsetEstimator {
+        self.estimator().clone()
+    }
+
+    fn estimator(&self) -> MutexGuard<'_, OffsetEstimator> {
+        self.estimator.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    // Feed the server's time headers to the estimator
+    fn observe(&self, headers: &reqwest::header::HeaderMap, sent: i64) {
+        let received = Utc::now().timestamp_millis();
+        let header = |name| headers.get(name)?.to_str().ok();
+        let Some(server) = header(TIME_HEADER).and_then(|v| v.parse().ok()) else {
+            return;
+        };
+        let mut estimator = self.estimator();
+        estimator.observe(sent, server, received);
+        if let Some(clock) = header(CLOCK_HEADER).and_then(Timestamp::parse) {
+            estimator.observe_clock(clock, server);
+        }
+    }
+
+    /// Send one frame and wait for the server's reply, if it has one
+    pub async fn exchange(&self, frame: Vec<u8>) -> Result<Option<Vec<u8>>, HttpSyncError> {
+        let sent = Utc::now().timestamp_millis();
+        let response = self
+            .client
+            .post(&self.endpoint)
+            .query(&[("group", &self.group)])
+            .header("Content-Type", "application/octet-stream")
+            .header(CAPABILITIES_HEADER, self.capabilities.to_header())
+            .body(frame)
+            .send()
+            .await?;
+
+        self.observe(response.headers(), sent);
+        let agreed = response
+            .headers()
+            .get(CAPABILITIES_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Capabilities::empty(), Capabilities::from_header);
+        *self.agreed.lock().unwrap_or_else(|err| err.into_inner()) = agreed;
+        let status = response.status();
+        if status == reqwest::StatusCode::NO_CONTENT {
+            return Ok(None);
+        }
+        if !status.is_success() {
+            return Err(HttpSyncError::Status(status.as_u16()));
+        }
+        Ok(Some(response.bytes().await?.to_vec()))
+    }
+
+    /// Sync `engine` with the server until the two agree, returning the
+    /// messages that were new to it
+    ///
+    /// Cancellation safe: `engine` is only touched between requests, a
+    /// whole reply at a time, so dropping the future leaves its store, trie
+    /// and table agreeing and the next sync picks up from there.
+    pub async fn sync<S: MessageStore>(
+        &self,
+        engine: &mut Engine<S>,
+    ) -> Result<Vec<Message>, HttpSyncError> {
+        self.sync_cancellable(engine, &CancellationToken::new())
+            .await
+    }
+
+    /// `sync`, giving up with `HttpSyncError::Cancelled` once `cancel`
+    /// fires, e.g. when the app is backgrounded
+    ///
+    /// Messages applied before then stay applied; subscribers to `engine`
+    /// have heard about them.
+    pub async fn sync_cancellable<S: MessageStore>(
+        &self,
+        engine: &mut Engine<S>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Message>, HttpSyncError> {
+        let mut session = SyncSession::new(engine).with_capabilities(self.capabilities);
+        let frame = session.start().map_err(HttpSyncError::Sync)?;
+        self.drive(session, frame, cancel).await
+    }
+
+    /// `sync` for a new replica: take the server's state as a snapshot
+    /// instead of replaying its history, then sync by diff as usual
+    ///
+    /// Needs a server that knows snapshots; see
+    /// `SyncSession::start_bootstrap`.
+    pub async fn bootstrap<S: MessageStore>(
+        &self,
+        engine: &mut Engine<S>,
+    ) -> Result<Vec<Message>, HttpSyncError> {
+        let mut session = SyncSession::new(engine).with_capabilities(self.capabilities);
+        let frame = session.start_bootstrap();
+        self.drive(session, frame, &CancellationToken::new()).await
+    }
+
+    // Exchange frames from `frame` on until the session has nothing to say
+    async fn drive<S: MessageStore>(
+        &self,
+        mut session: SyncSession<'_, S>,
+        mut frame: Vec<u8>,
+        cancel: &CancellationToken,
+    ) -> Result<Vec<Message>, HttpSyncError> {
+        loop {
+            let reply = tokio::select! {
+                biased;
+                () = cancel.cancelled() => return Err(HttpSyncError::Cancelled),
+                reply = self.exchange(frame) => reply?,
+            };
+            session.negotiate(self.agreed_capabilities());
+            let Some(reply) = reply else {
+                break;
+            };
+            match session.handle(&reply)? {
+                Some(next) => frame = next,
+                None => break,
+            }
+        }
+        Ok(session.applied().to_vec())
+    }
+}
+
+// Errors related to syncing over HTTP
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum HttpSyncError {
+    Http(reqwest::Error),
+    // Anything but 200 or 204
+    Status(u16),
+    Sync(SyncError),
+    Cancelled,
+}
+
+impl From<reqwest::Error> for HttpSyncError {
+    fn from(err: reqwest::Error) -> Self {
+        HttpSyncError::Http(err)
+    }
+}
+
+impl From<SyncError> for HttpSyncError {
+    fn from(err: SyncError) -> Self {
+        HttpSyncError::Sync(err)
+    }
+}
+
+impl fmt::Display for HttpSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HttpSyncError::Http(ref err) => write!(f, "sync request failed: {}", err),
+            HttpSyncError::Status(status) => write!(f, "sync server returned {}", status),
+            HttpSyncError::Sync(ref err) => err.fmt(f),
+            HttpSyncError::Cancelled => write!(f, "sync cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for HttpSyncError {}
+
+impl HttpSyncError {
+    /// What went wrong, in terms an application can act on
+    pub fn failure(&self) -> SyncFailure {
+        match *self {
+            HttpSyncError::Http(ref err) => SyncFailure::Network {
+                detail: err.to_string(),
+            },
+            HttpSyncError::Status(status) => SyncFailure::from_status(status, self.to_string()),
+            HttpSyncError::Sync(ref err) => err.failure(),
+            // Nothing went wrong; run it again when there's time
+            HttpSyncError::Cancelled => SyncFailure::Network {
+                detail: self.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use crate::store::{MemoryStore, MessageStoreExt};
+    use crate::timestamp::{make_client_id, Timestamp};
+    use crate::trie::Trie;
+
+    fn engine() -> Engine<MemoryStore> {
+        Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap()
+    }
+
+    // Read one POST from `stream` and answer it with `handle`'s reply
+    fn respond(stream: TcpStream, handle: impl FnOnce(&[u8]) -> Option<Vec<u8>>) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut length = 0;
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            let header = line.to_ascii_lowercase();
+            if let Some(value) = header.strip_prefix("content-length:") {
+                length = value.trim().parse().unwrap();
+            }
+            line.clear();
+        }
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).unwrap();
+
+        let mut writer = stream;
+        let reply = handle(&body);
+        // A server whose clock is a minute ahead of ours
+        let now = Utc::now().timestamp_millis() + 60_000;
+        let head = match reply {
+            Some(ref reply) => format!(
+                "HTTP/1.1 200 OK\r\n{}: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+                TIME_HEADER,
+                now,
+                reply.len()
+            ),
+            None => format!(
+                "HTTP/1.1 204 No Content\r\n{}: {}\r\nConnection: close\r\n\r\n",
+                TIME_HEADER, now
+            ),
+        };
+        // The client may have hung up already
+        let _ = writer.write_all(head.as_bytes());
+        let _ = writer.write_all(&reply.unwrap_or_default());
+    }
+
+    // A sync server answering each POST with the reply of a session over
+    // `engine`, one connection per request, until the session converges
+    fn serve(mut engine: Engine<MemoryStore>) -> (String, thread::JoinHandle<Engine<MemoryStore>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let mut session = SyncSession::new(&mut engine);
+            while !session.is_converged() {
+                let (stream, _) = listener.accept().unwrap();
+                respond(stream, |body| session.handle(body).unwrap());
+            }
+            drop(session);
+            engine
+        });
+        (url, handle)
+    }
+
+    #[tokio::test]
+    async fn test_sync_round_trip() {
+        let now = Utc::now().timestamp_millis();
+        let mut server = engine();
+        server
+            .write("todos", "1", "title", "buy milk", now)
+            .unwrap();
+        let mut client = engine();
+        client
+            .write("todos", "2", "title", "buy eggs", now)
+            .unwrap();
+
+        let (url, handle) = serve(server);
+        let http = HttpSyncClient::new(&url, "family");
+        let applied = http.sync(&mut client).await.unwrap();
+        let server = handle.join().unwrap();
+
+        assert_eq!(applied.len(), 1);
+        assert_eq!(applied[0].row, "1");
+        assert_eq!(server.trie().unwrap().diff(client.trie().unwrap()), None);
+        assert_eq!(server.store().len(), 2);
+
+        let estimate = http.time_estimate();
+        let offset = estimate.offset().unwrap();
+        assert!((59_000..=61_000).contains(&offset), "offset {}", offset);
+        assert_eq!(estimate.server_lead(), None);
+        // The test server predates capabilities, so none are agreed
+        assert!(http.agreed_capabilities().is_empty());
+    }
+
+    // A slow server running a fresh session per request, like the real
+    // one, for as long as the test runs
+    fn serve_slowly(engine: Arc<Mutex<Engine<MemoryStore>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    respond(stream.unwrap(), |body| {
+                        thread::sleep(std::time::Duration::from_millis(2));
+                        let mut engine = engine.lock().unwrap();
+                        SyncSession::new(&mut engine).handle(body).ok()?
+                    })
+                });
+            }
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn test_sync_cancelled() {
+        let now = Utc::now().timestamp_millis();
+        let server = Arc::new(Mutex::new(engine()));
+        for row in 0..20 {
+            let row = row.to_string();
+            let mut server = server.lock().unwrap();
+            server.write("todos", &row, "title", &row, now).unwrap();
+        }
+        let http = HttpSyncClient::new(&serve_slowly(server.clone()), "family");
+
+        // Cancel at a different point each round, from before the first
+        // request to after convergence
+        let mut seed = 0x2545_f491_u32;
+        for round in 0..20 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let delay = std::time::Duration::from_micros(u64::from(seed % 15_000));
+
+            let mut client = engine();
+            let row = format!("client {}", round);
+            client.write("todos", &row, "title", &row, now).unwrap();
+            let cancel = CancellationToken::new();
+            let timer = {
+                let cancel = cancel.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    cancel.cancel();
+                })
+            };
+            match http.sync_cancellable(&mut client, &cancel).await {
+                Ok(_) | Err(HttpSyncError::Cancelled) => {}
+                Err(err) => panic!("sync failed: {}", err),
+            }
+            timer.await.unwrap();
+
+            // Whatever was applied is whole: the trie indexes the store
+            let stored = client.store().all_messages();
+            let timestamps = stored.iter().map(|m| m.timestamp.clone()).collect();
+            assert!(client.trie().unwrap().deep_eq(&Trie::build(timestamps)));
+            for message in &stored {
+                assert!(client.get("todos", &message.row, "title").is_some());
+            }
+
+            // and a later sync finishes the job
+            http.sync(&mut client).await.unwrap();
+            assert_eq!(
+                server
+                    .lock()
+                    .unwrap()
+                    .trie()
+                    .unwrap()
+                    .diff(client.trie().unwrap()),
+                None
+            );
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use chrono::Utc;
+
+use crate::engine::{Engine, WriteError};
+use crate::store::MessageStore;
+
+/// The dataset all key-value entries live in
+pub const DATASET: &str = "kv";
+const COLUMN: &str = "value";
+
+/// A synced key-value store
+///
+/// Each key is a row in a single implicit dataset, so concurrent writes to
+/// the same key resolve last-writer-wins. The underlying engine is exposed
+/// for syncing.
+#[derive(Debug)]
+pub struct Kv<S> {
+    engine: Engine<S>,
+}
+
+impl<S: MessageStore> Kv<S> {
+    pub fn new(engine: Engine<S>) -> Self {
+        Kv { engine }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.engine
+            .get(DATASET, key, COLUMN)
+            .map(|cell| cell.value.as_str())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), WriteError> {
+        self.set_at(key, value, Utc::now().timestamp_millis())
+    }
+
+    pub fn set_at(&mut self, key: &str, value: &str, phys: i64) -> Result<(), WriteError> {
+        self.engine.write(DATASET, key, COLUMN, value, phys)?;
+        Ok(())
+    }
+
+    pub fn engine(&self) -> &Engine<S> {
+        &self.engine
+    }
+
+    pub fn engine_mut(&mut self) -> &mut Engine<S> {
+        &mut self.engine
+    }
+
+    pub fn into_engine(self) -> Engine<S> {
+        self.engine
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    #[cfg(feature = "sync")]
+    use crate::sync::SyncSession;
+    use crate::timestamp::{make_client_id, Timestamp};
+
+    fn kv() -> Kv<MemoryStore> {
+        Kv::new(Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap())
+    }
+
+    #[test]
+    fn test_set_get() {
+        let mut kv = kv();
+        assert_eq!(kv.get("settings/theme"), None);
+
+        kv.set_at("settings/theme", "dark", 1000).unwrap();
+        kv.set_at("settings/theme", "light", 1000).unwrap();
+        assert_eq!(kv.get("settings/theme"), Some("light"));
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn test_sync() {
+        let mut a = kv();
+        let mut b = kv();
+        a.set_at("settings/theme", "dark", 1000).unwrap();
+        b.set_at("settings/theme", "light", 2000).unwrap();
+        b.set_at("settings/lang", "en", 2000).unwrap();
+
+        let mut sa = SyncSession::new(a.engine_mut());
+        let mut sb = SyncSession::new(b.engine_mut());
+        let mut frame = Some(sa.start().unwrap());
+        while let Some(bytes) = frame {
+            frame = sb.handle_at(&bytes, 2000).unwrap();
+            std::mem::swap(&mut sa, &mut sb);
+        }
+
+        assert_eq!(a.get("settings/theme"), Some("light"));
+        assert_eq!(a.get("settings/lang"), Some("en"));
+        assert_eq!(b.get("settings/theme"), Some("light"));
+    }
+}
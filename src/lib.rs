@@ -0,0 +1,80 @@
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+extern crate alloc;
+
+#[macro_use]
+mod macros;
+
+// Semver policy: traits downstream crates implement (stores, transports,
+// hashers, loggers, clock stores) only ever gain methods with default
+// bodies; everything else goes on sealed extension traits. Enums that will
+// grow are `#[non_exhaustive]`.
+
+#[cfg(feature = "std")]
+pub mod archive;
+pub mod arena;
+#[cfg(feature = "std")]
+pub mod bundle;
+#[cfg(feature = "std")]
+pub mod clock;
+pub mod codec;
+#[cfg(feature = "compat")]
+pub mod compat;
+pub mod compress;
+pub mod crdt;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+#[cfg(feature = "doc")]
+pub mod doc;
+#[cfg(feature = "std")]
+pub mod engine;
+pub mod envelope;
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "sync")]
+pub mod gossip;
+pub mod header;
+#[cfg(feature = "http-sync")]
+pub mod http;
+#[cfg(feature = "std")]
+pub mod kv;
+#[cfg(feature = "std")]
+pub mod limit;
+#[cfg(feature = "std")]
+pub mod logger;
+#[cfg(feature = "sync")]
+pub mod manager;
+#[cfg(feature = "std")]
+pub mod merge;
+pub mod message;
+#[cfg(feature = "opfs")]
+pub mod opfs;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod prelude;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(feature = "server")]
+pub mod replica;
+pub mod segment;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "test-util")]
+pub mod sim;
+pub mod snapshot;
+pub mod store;
+#[cfg(feature = "std")]
+pub mod subscribe;
+#[cfg(feature = "sync")]
+pub mod sync;
+pub mod table;
+#[cfg(feature = "test-util")]
+pub mod testing;
+pub mod timestamp;
+pub mod trie;
+pub mod version;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "ws-sync")]
+pub mod ws;
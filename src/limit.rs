@@ -0,0 +1,145 @@
+// Caps on what a single local write may weigh. Peers and servers bound the
+// frames they accept, and one message too big for any of them would be
+// resent, and refused, on every sync forever.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::message::Message;
+
+/// How big a message the engine will write, and what it does with bigger
+#[derive(Debug)]
+pub struct SizeLimit {
+    /// Largest `Message::size` allowed
+    pub max: usize,
+    pub policy: OversizePolicy,
+}
+
+/// What to do with a write over the limit
+#[non_exhaustive]
+pub enum OversizePolicy {
+    /// Refuse it with `LimitError::TooLarge`
+    Reject,
+    /// Write as much of the value as fits, cut at a character boundary,
+    /// and log an error; the returned message holds what was kept
+    Truncate,
+    /// Move the value to a blob store and write its reference instead.
+    /// Peers only get the reference, so the blobs must reach them some
+    /// other way, e.g. a store backed by shared object storage.
+    Offload(Box<dyn BlobStore + Send>),
+}
+
+impl fmt::Debug for OversizePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OversizePolicy::Reject => write!(f, "Reject"),
+            OversizePolicy::Truncate => write!(f, "Truncate"),
+            OversizePolicy::Offload(_) => write!(f, "Offload(..)"),
+        }
+    }
+}
+
+/// Somewhere to keep values too big to write inline
+pub trait BlobStore {
+    /// Keep `value`, returning the reference to write in its place
+    fn put(&mut self, value: &str) -> String;
+
+    fn get(&self, reference: &str) -> Option<String>;
+}
+
+/// Prefix of the references `MemoryBlobStore` hands out
+pub const BLOB_PREFIX: &str = "markle-blob:";
+
+/// Blobs kept in memory under their content hash, for tests and for apps
+/// that persist them alongside the store
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBlobStore {
+    blobs: HashMap<String, String>,
+}
+
+impl MemoryBlobStore {
+    pub fn new() -> Self {
+        MemoryBlobStore::default()
+    }
+}
+
+impl BlobStore for MemoryBlobStore {
+    fn put(&mut self, value: &str) -> String {
+        let reference = format!("{}{:016x}", BLOB_PREFIX, xxh3_64(value.as_bytes()));
+        self.blobs.insert(reference.clone(), value.to_string());
+        reference
+    }
+
+    fn get(&self, reference: &str) -> Option<String> {
+        self.blobs.get(reference).cloned()
+    }
+}
+
+impl SizeLimit {
+    pub fn new(max: usize, policy: OversizePolicy) -> Self {
+        SizeLimit { max, policy }
+    }
+
+    // Bring `message` under the limit as the policy says, or refuse it
+    pub(crate) fn fit(&mut self, message: &mut Message) -> Result<(), LimitError> {
+        let size = message.size();
+        if size <= self.max {
+            return Ok(());
+        }
+        let too_large = LimitError::TooLarge(size, self.max);
+        match &mut self.policy {
+            OversizePolicy::Reject => return Err(too_large),
+            OversizePolicy::Truncate => {
+                // What the value may take once everything else is counted
+                let room = self
+                    .max
+                    .checked_sub(size - message.value.len())
+                    .ok_or(too_large)?;
+                let mut end = room;
+                while !message.value.is_char_boundary(end) {
+                    end -= 1;
+                }
+                log_error!(
+                    "truncated value of {}/{}/{} from {} to {} bytes",
+                    message.dataset,
+                    message.row,
+                    message.column,
+                    message.value.len(),
+                    end
+                );
+                message.value.truncate(end);
+            }
+            OversizePolicy::Offload(blobs) => {
+                message.value = blobs.put(&message.value);
+                if message.size() > self.max {
+                    return Err(LimitError::TooLarge(message.size(), self.max));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Errors related to the size limit
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum LimitError {
+    // Size, limit
+    TooLarge(usize, usize),
+}
+
+impl fmt::Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LimitError::TooLarge(size, limit) => write!(
+                f,
+                "message of {} bytes is over the {} byte limit",
+                size, limit
+            ),
+        }
+    }
+}
+
+impl core::error::Error for LimitError {}
@@ -0,0 +1,87 @@
+use std::fmt;
+use std::sync::OnceLock;
+
+/// A minimal sink for the library's notable events
+///
+/// For consumers that can't link `tracing`, such as embedded and FFI
+/// builds. Only drift warnings, sync failures and similar events that an
+/// operator would act on are reported here; per-message detail stays in
+/// `tracing`. Every method defaults to doing nothing.
+pub trait Logger: Send + Sync {
+    fn error(&self, _message: &str) {}
+
+    fn warn(&self, _message: &str) {}
+
+    fn info(&self, _message: &str) {}
+}
+
+/// Discards everything; what the library uses until `set_logger` is called
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopLogger;
+
+impl Logger for NoopLogger {}
+
+static LOGGER: OnceLock<Box<dyn Logger>> = OnceLock::new();
+
+/// Install the process-wide logger
+///
+/// Can only be done once; later calls hand the logger back.
+pub fn set_logger(logger: Box<dyn Logger>) -> Result<(), Box<dyn Logger>> {
+    LOGGER.set(logger)
+}
+
+#[allow(dead_code)]
+pub(crate) enum Level {
+    Error,
+    Warn,
+    Info,
+}
+
+// Messages are only formatted when a logger is installed
+pub(crate) fn emit(level: Level, args: fmt::Arguments) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let message = args.to_string();
+    match level {
+        Level::Error => logger.error(&message),
+        Level::Warn => logger.warn(&message),
+        Level::Info => logger.info(&message),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::engine::Engine;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+
+    #[derive(Clone, Default)]
+    struct Collect(Arc<Mutex<Vec<String>>>);
+
+    impl Logger for Collect {
+        fn warn(&self, message: &str) {
+            self.0.lock().unwrap().push(format!("warn: {}", message));
+        }
+    }
+
+    // The only test that installs a logger, since it is process-wide
+    #[test]
+    fn test_drift_reaches_logger() {
+        let logs = Collect::default();
+        assert!(set_logger(Box::new(logs.clone())).is_ok());
+        assert!(set_logger(Box::new(NoopLogger)).is_err());
+
+        let clock = Timestamp::new(10 * 60_000, 0, make_client_id());
+        let mut engine = Engine::new(clock, MemoryStore::new()).unwrap();
+        assert!(engine.write("todos", "1", "title", "buy milk", 0).is_err());
+
+        let logs = logs.0.lock().unwrap();
+        assert!(logs
+            .iter()
+            .any(|line| line.starts_with("warn: maximum clock drift exceeded")));
+    }
+}
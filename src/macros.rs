@@ -0,0 +1,104 @@
+// Logging shims: forward to `tracing` when the feature is enabled and
+// compile to nothing otherwise, so the library never writes to stdout on
+// its own. Arguments are not evaluated when the feature is off.
+
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)*);
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+// A span entered until the guard it returns is dropped, as in
+// `let span = debug_span!("name", field = value);`. Fields declared
+// `tracing::field::Empty` can be filled in later with `record!`. Without the
+// feature the guard is a unit struct and the fields go unevaluated.
+macro_rules! debug_span {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!($($arg)*).entered();
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::macros::NoSpan;
+        span
+    }};
+}
+
+macro_rules! record {
+    ($span:ident, $($field:ident = $value:expr),+ $(,)?) => {
+        #[cfg(feature = "tracing")]
+        {
+            $($span.record(stringify!($field), $value);)+
+        }
+        #[cfg(not(feature = "tracing"))]
+        let _ = &$span;
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct NoSpan;
+
+// Only std modules warn so far
+#[allow(unused_macros)]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::warn!($($arg)*);
+    };
+}
+
+// Metrics shims in the same vein: forward to the `metrics` crate when the
+// feature is enabled, to whatever recorder the application installed, and
+// compile to nothing otherwise. They take the metric name, the amount, and
+// `"label" => value` pairs.
+
+#[allow(unused_macros)]
+macro_rules! counter {
+    ($name:literal, $value:expr $(, $key:literal => $label:expr)* $(,)?) => {
+        #[cfg(feature = "metrics")]
+        metrics::counter!($name $(, $key => $label)*).increment($value as u64);
+    };
+}
+
+#[allow(unused_macros)]
+macro_rules! histogram {
+    ($name:literal, $value:expr $(, $key:literal => $label:expr)* $(,)?) => {
+        #[cfg(feature = "metrics")]
+        metrics::histogram!($name $(, $key => $label)*).record($value as f64);
+    };
+}
+
+// Events for the installed `logger::Logger`, taking `format!` arguments.
+// These always compile in; they cost a load when no logger is installed.
+// The logger needs std, so these do too, and some are only used by
+// optional modules.
+
+#[cfg(feature = "std")]
+#[allow(unused_macros)]
+macro_rules! log_error {
+    ($($arg:tt)*) => {
+        $crate::logger::emit($crate::logger::Level::Error, format_args!($($arg)*))
+    };
+}
+
+#[cfg(feature = "std")]
+#[allow(unused_macros)]
+macro_rules! log_warn {
+    ($($arg:tt)*) => {
+        $crate::logger::emit($crate::logger::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+#[cfg(feature = "std")]
+#[allow(unused_macros)]
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        $crate::logger::emit($crate::logger::Level::Info, format_args!($($arg)*))
+    };
+}
@@ -1,10 +1,528 @@
-mod timestamp;
-mod trie;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::{
-    timestamp::{make_client_id, Timestamp},
-    trie::Trie,
-};
-use chrono::Utc;
+use clap::{Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
 
-fn main() {}
+use markle::clock::PhysicalTime;
+use markle::engine::Engine;
+use markle::message::Message;
+use markle::store::{MemoryStore, MessageStore};
+use markle::sync::{SyncError, SyncSession, Transport};
+use markle::timestamp::{make_client_id, Timestamp};
+use markle::trie::{HashScheme, Trie, DEFAULT_DEPTH, MAX_DEPTH};
+
+const BACKUP_FORMAT: &str = "markle-backup";
+const BACKUP_VERSION: u64 = 2;
+
+#[derive(Parser)]
+#[command(name = "markle", about = "Inspect and sync markle message logs")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Make and read timestamps
+    Ts {
+        #[command(subcommand)]
+        command: TsCommand,
+    },
+    /// Build and compare merkle tries, in the JSON layout crdt.js keeps
+    Trie {
+        #[command(subcommand)]
+        command: TrieCommand,
+    },
+    /// Sync an empty replica with the server once and summarize it as JSON
+    Sync {
+        /// Base url of the sync server
+        url: String,
+        /// Sync group to pull
+        #[arg(long)]
+        group: String,
+    },
+    /// Stream a group's messages to stdout as NDJSON
+    ///
+    /// Syncs an in-memory replica against the server every interval and
+    /// prints each newly applied message, starting with the full history.
+    Tail {
+        /// Base url of the sync server
+        #[arg(long)]
+        url: String,
+        /// Sync group to follow
+        #[arg(long)]
+        group: String,
+        /// Milliseconds between sync rounds
+        #[arg(long, default_value_t = 1000)]
+        interval: u64,
+    },
+    /// Write a group's full message log to stdout as an NDJSON backup
+    Export {
+        /// Base url of the sync server
+        #[arg(long)]
+        url: String,
+        /// Sync group to export
+        #[arg(long)]
+        group: String,
+    },
+    /// Replay a backup and check it against its recorded count and root hash
+    Verify {
+        /// Backup written by `markle export`
+        path: String,
+    },
+    /// Run local benchmarks on a synthetic message log
+    Bench {
+        /// Size of the synthetic message log
+        #[arg(long, default_value_t = 100_000)]
+        messages: usize,
+        /// Which benchmark to run
+        #[arg(long, value_enum, default_value_t = Profile::All)]
+        profile: Profile,
+    },
+}
+
+#[derive(Subcommand)]
+enum TsCommand {
+    /// A timestamp for the current wall time, as JSON
+    Now {
+        /// Node id; a random one if omitted
+        #[arg(long)]
+        node: Option<String>,
+    },
+    /// Split a timestamp into its parts, as JSON
+    Parse { timestamp: String },
+}
+
+#[derive(Subcommand)]
+enum TrieCommand {
+    /// Build a trie from a file of timestamps, one per line
+    Build {
+        path: String,
+        #[arg(long, value_enum, default_value_t = Scheme::Murmur32Xor)]
+        scheme: Scheme,
+    },
+    /// Print the minute two tries first disagree on, or nothing if they
+    /// agree
+    Diff {
+        a: String,
+        b: String,
+        #[arg(long, value_enum, default_value_t = Scheme::Murmur32Xor)]
+        scheme: Scheme,
+    },
+    /// Print a trie's node count, depth, memory use and time span, as JSON
+    Stats {
+        path: String,
+        #[arg(long, value_enum, default_value_t = Scheme::Murmur32Xor)]
+        scheme: Scheme,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Scheme {
+    Murmur32Xor,
+    Xxh64Add,
+}
+
+impl From<Scheme> for HashScheme {
+    fn from(scheme: Scheme) -> Self {
+        match scheme {
+            Scheme::Murmur32Xor => HashScheme::Murmur32Xor,
+            Scheme::Xxh64Add => HashScheme::Xxh64Add,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum Profile {
+    /// Build a trie from every timestamp
+    Build,
+    /// Diff two full tries that differ in their newest message
+    Diff,
+    /// Apply every message to an empty engine
+    Apply,
+    /// Sync an empty engine with a full one over an in-process transport
+    Sync,
+    All,
+}
+
+/// Sync frames over HTTP: each frame is POSTed to `{url}/sync?group=...`
+/// and the response body is the peer's reply, or 204 when it has none
+struct HttpTransport {
+    agent: ureq::Agent,
+    endpoint: String,
+    group: String,
+}
+
+impl HttpTransport {
+    fn new(url: &str, group: &str) -> Self {
+        HttpTransport {
+            agent: ureq::Agent::new(),
+            endpoint: format!("{}/sync", url.trim_end_matches('/')),
+            group: group.to_string(),
+        }
+    }
+}
+
+impl Transport for HttpTransport {
+    type Error = Box<dyn Error>;
+
+    fn exchange(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, Self::Error> {
+        let response = self
+            .agent
+            .post(&self.endpoint)
+            .query("group", &self.group)
+            .set("Content-Type", "application/octet-stream")
+            .send_bytes(frame)?;
+
+        if response.status() == 204 {
+            return Ok(None);
+        }
+
+        let mut body = Vec::new();
+        response.into_reader().read_to_end(&mut body)?;
+        Ok(Some(body))
+    }
+}
+
+fn message_to_json(message: &Message) -> Value {
+    json!({
+        "dataset": message.dataset,
+        "row": message.row,
+        "column": message.column,
+        "value": message.value,
+        "timestamp": message.timestamp.to_string(),
+        "node": message.timestamp.node(),
+    })
+}
+
+fn message_from_json(value: &Value) -> Option<Message> {
+    let field = |name: &str| value.get(name)?.as_str().map(str::to_string);
+    Some(Message {
+        timestamp: Timestamp::parse(value.get("timestamp")?.as_str()?)?,
+        dataset: field("dataset")?,
+        row: field("row")?,
+        column: field("column")?,
+        value: field("value")?,
+    })
+}
+
+fn timestamp_to_json(timestamp: &Timestamp) -> Value {
+    json!({
+        "timestamp": timestamp.to_string(),
+        "millis": timestamp.millis(),
+        "node": timestamp.node(),
+        "hash": timestamp.hash(),
+    })
+}
+
+fn ts_now(node: Option<String>) -> Result<(), Box<dyn Error>> {
+    let mut clock = Timestamp::new(0, 0, node.unwrap_or_else(make_client_id));
+    let timestamp = clock.send(PhysicalTime::new().now())?;
+    println!("{}", timestamp_to_json(&timestamp));
+    Ok(())
+}
+
+fn ts_parse(s: &str) -> Result<(), Box<dyn Error>> {
+    let timestamp: Timestamp = s.parse()?;
+    println!("{}", timestamp_to_json(&timestamp));
+    Ok(())
+}
+
+fn trie_build(path: &str, scheme: HashScheme) -> Result<(), Box<dyn Error>> {
+    let mut trie = Trie::with_scheme(scheme);
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let timestamp =
+            Timestamp::parse(line.trim()).ok_or(format!("line {}: bad timestamp", n + 1))?;
+        trie.try_insert(timestamp)
+            .map_err(|err| format!("line {}: {}", n + 1, err))?;
+    }
+    println!("{}", trie.to_json());
+    Ok(())
+}
+
+fn trie_diff(a: &str, b: &str, scheme: HashScheme) -> Result<(), Box<dyn Error>> {
+    let load = |path: &str| -> Result<Trie, Box<dyn Error>> {
+        let json = std::fs::read_to_string(path)?;
+        Trie::from_json(&json, scheme).map_err(|err| format!("{}: {}", path, err).into())
+    };
+    if let Some(since) = load(a)?.diff(&load(b)?) {
+        println!(
+            "{}",
+            since.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        );
+    }
+    Ok(())
+}
+
+fn trie_stats(path: &str, scheme: HashScheme) -> Result<(), Box<dyn Error>> {
+    let json = std::fs::read_to_string(path)?;
+    let trie = Trie::from_json(&json, scheme).map_err(|err| format!("{}: {}", path, err))?;
+    let stats = trie.stats();
+    let time = |time: Option<chrono::DateTime<chrono::Utc>>| {
+        time.map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+    };
+    let json = serde_json::json!({
+        "nodes": stats.nodes,
+        "leaves": stats.leaves,
+        "max_depth": stats.max_depth,
+        "memory_bytes": stats.memory_bytes,
+        "oldest": time(stats.oldest),
+        "newest": time(stats.newest),
+    });
+    println!("{}", json);
+    Ok(())
+}
+
+fn sync(url: &str, group: &str) -> Result<(), Box<dyn Error>> {
+    let clock = Timestamp::new(0, 0, make_client_id());
+    let mut engine = Engine::new(clock, MemoryStore::new())?;
+    let mut session = SyncSession::new(&mut engine);
+    session.run(&mut HttpTransport::new(url, group))?;
+    let applied = session.applied().len();
+    let summary = json!({
+        "applied": applied,
+        "root_hash": engine.trie()?.root_hash(),
+    });
+    println!("{}", summary);
+    Ok(())
+}
+
+fn tail(url: &str, group: &str, interval: u64) -> Result<(), Box<dyn Error>> {
+    let clock = Timestamp::new(0, 0, make_client_id());
+    let mut engine = Engine::new(clock, MemoryStore::new())?;
+    let mut transport = HttpTransport::new(url, group);
+    let mut stdout = std::io::stdout().lock();
+
+    loop {
+        let mut session = SyncSession::new(&mut engine);
+        session.run(&mut transport)?;
+
+        for message in session.applied() {
+            writeln!(stdout, "{}", message_to_json(message))?;
+        }
+        stdout.flush()?;
+
+        thread::sleep(Duration::from_millis(interval));
+    }
+}
+
+/// The backup is a header line recording the message count, root hash and
+/// the hash scheme and depth the hash was computed with, followed by one
+/// message per line in timestamp order
+fn export(url: &str, group: &str) -> Result<(), Box<dyn Error>> {
+    let clock = Timestamp::new(0, 0, make_client_id());
+    let mut engine = Engine::new(clock, MemoryStore::new())?;
+    SyncSession::new(&mut engine).run(&mut HttpTransport::new(url, group))?;
+
+    let messages = engine.messages_since(i64::MIN);
+    let mut stdout = std::io::stdout().lock();
+    let header = json!({
+        "format": BACKUP_FORMAT,
+        "version": BACKUP_VERSION,
+        "messages": messages.len(),
+        "root_hash": engine.trie()?.root_hash(),
+        "hash_scheme": engine.trie()?.scheme().id(),
+        "depth": engine.trie()?.depth(),
+    });
+    writeln!(stdout, "{}", header)?;
+    for message in &messages {
+        writeln!(stdout, "{}", message_to_json(message))?;
+    }
+    stdout.flush()?;
+    Ok(())
+}
+
+fn verify(path: &str) -> Result<(), Box<dyn Error>> {
+    let mut lines = BufReader::new(File::open(path)?).lines();
+
+    let header: Value = serde_json::from_str(&lines.next().ok_or("empty backup")??)?;
+    if header["format"] != BACKUP_FORMAT {
+        return Err("not a markle backup".into());
+    }
+    // Version 1 backups predate the scheme and depth fields
+    let (scheme, depth) = match header["version"].as_u64() {
+        Some(1) => (HashScheme::Murmur32Xor, DEFAULT_DEPTH),
+        Some(BACKUP_VERSION) => {
+            let id = header["hash_scheme"]
+                .as_u64()
+                .ok_or("header missing hash_scheme")?;
+            let scheme = u8::try_from(id)
+                .ok()
+                .and_then(HashScheme::from_id)
+                .ok_or(format!("unknown hash scheme {}", id))?;
+            let depth = header["depth"].as_u64().ok_or("header missing depth")? as usize;
+            if !(1..=MAX_DEPTH).contains(&depth) {
+                return Err(format!("invalid trie depth {}", depth).into());
+            }
+            (scheme, depth)
+        }
+        _ => return Err("unsupported backup version".into()),
+    };
+    let want_messages = header["messages"]
+        .as_u64()
+        .ok_or("header missing messages")?;
+    let want_hash = header["root_hash"]
+        .as_u64()
+        .ok_or("header missing root_hash")?;
+
+    let mut store = MemoryStore::new();
+    for (n, line) in lines.enumerate() {
+        let value: Value = serde_json::from_str(&line?)?;
+        // line numbers are 1-based and the header is line 1
+        let message = message_from_json(&value).ok_or(format!("line {}: bad message", n + 2))?;
+        if !store.insert(message) {
+            return Err(format!("line {}: duplicate timestamp", n + 2).into());
+        }
+    }
+
+    let timestamps: Vec<Timestamp> = store
+        .messages_since(i64::MIN)
+        .into_iter()
+        .map(|m| m.timestamp)
+        .collect();
+    let mut trie = Trie::with_depth(scheme, depth);
+    trie.try_insert_batch(&timestamps)?;
+    let got_messages = store.len() as u64;
+    let got_hash = trie.root_hash();
+
+    if got_messages != want_messages {
+        return Err(format!(
+            "expected {} messages, found {}",
+            want_messages, got_messages
+        )
+        .into());
+    }
+    if got_hash != want_hash {
+        return Err(format!("expected root hash {}, rebuilt {}", want_hash, got_hash).into());
+    }
+
+    println!("ok: {} messages, root hash {}", got_messages, got_hash);
+    Ok(())
+}
+
+/// Sync frames handed straight to a peer session in the same process
+struct Loopback<'a> {
+    peer: SyncSession<'a, MemoryStore>,
+}
+
+impl Transport for Loopback<'_> {
+    type Error = SyncError;
+
+    fn exchange(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+        self.peer.handle(frame)
+    }
+}
+
+// A deterministic-shaped log: ten writes a second starting late 2023
+fn bench_engine(messages: usize) -> Result<Engine<MemoryStore>, Box<dyn Error>> {
+    let start = 1_700_000_000_000;
+    let mut engine = Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new())?;
+    for i in 0..messages {
+        let row = (i % 1000).to_string();
+        engine.write(
+            "bench",
+            &row,
+            "value",
+            &i.to_string(),
+            start + i as i64 * 100,
+        )?;
+    }
+    Ok(engine)
+}
+
+fn report(name: &str, ops: usize, elapsed: Duration) {
+    let per_op = elapsed.as_nanos() / ops.max(1) as u128;
+    println!(
+        "{:<8} {:>10} ops {:>12.3?} {:>10} ns/op",
+        name, ops, elapsed, per_op
+    );
+}
+
+fn bench(messages: usize, profile: Profile) -> Result<(), Box<dyn Error>> {
+    let source = bench_engine(messages)?;
+    let log = source.messages_since(i64::MIN);
+    let timestamps: Vec<Timestamp> = log.iter().map(|m| m.timestamp.clone()).collect();
+    let phys = timestamps.last().map_or(0, |ts| ts.millis());
+    let run = |p: Profile| profile == p || profile == Profile::All;
+
+    println!("markle bench: {} messages", messages);
+
+    if run(Profile::Build) {
+        let started = Instant::now();
+        let trie = Trie::build(timestamps.clone());
+        report("build", timestamps.len(), started.elapsed());
+        drop(trie);
+    }
+
+    if run(Profile::Diff) {
+        let full = Trie::build(timestamps.clone());
+        let partial = Trie::build(timestamps[..timestamps.len().saturating_sub(1)].to_vec());
+        let rounds = 1000;
+        let started = Instant::now();
+        for _ in 0..rounds {
+            std::hint::black_box(full.diff(&partial));
+        }
+        report("diff", rounds, started.elapsed());
+        println!("diff visits {} nodes", full.diff_visits(&partial));
+    }
+
+    if run(Profile::Apply) {
+        let mut engine = Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new())?;
+        let started = Instant::now();
+        for message in log.iter().cloned() {
+            engine.apply(message, phys)?;
+        }
+        report("apply", log.len(), started.elapsed());
+    }
+
+    if run(Profile::Sync) {
+        let mut source = bench_engine(messages)?;
+        let mut engine = Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new())?;
+        let started = Instant::now();
+        let mut transport = Loopback {
+            peer: SyncSession::new(&mut source),
+        };
+        SyncSession::new(&mut engine).run(&mut transport)?;
+        report("sync", engine.store().len(), started.elapsed());
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let cli = Cli::parse();
+
+    let result = match cli.command {
+        Command::Ts { command } => match command {
+            TsCommand::Now { node } => ts_now(node),
+            TsCommand::Parse { timestamp } => ts_parse(&timestamp),
+        },
+        Command::Trie { command } => match command {
+            TrieCommand::Build { path, scheme } => trie_build(&path, scheme.into()),
+            TrieCommand::Diff { a, b, scheme } => trie_diff(&a, &b, scheme.into()),
+            TrieCommand::Stats { path, scheme } => trie_stats(&path, scheme.into()),
+        },
+        Command::Sync { url, group } => sync(&url, &group),
+        Command::Tail {
+            url,
+            group,
+            interval,
+        } => tail(&url, &group, interval),
+        Command::Export { url, group } => export(&url, &group),
+        Command::Verify { path } => verify(&path),
+        Command::Bench { messages, profile } => bench(messages, profile),
+    };
+
+    if let Err(err) = result {
+        eprintln!("markle: {}", err);
+        std::process::exit(1);
+    }
+}
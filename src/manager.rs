@@ -0,0 +1,360 @@
+// Periodic sync with several peers over one engine. Peers are synced one
+// at a time, each in a session of its own, so a message two peers both
+// deliver is applied once and the second copy found already known; the
+// trie only ever sees it inserted once.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use crate::engine::Engine;
+use crate::store::MessageStore;
+use crate::sync::{SyncError, SyncSession, Transport};
+
+/// How long between rounds with a peer unless set otherwise
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a peer's root hash is trusted to skip rounds unless set
+/// otherwise
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(300);
+
+/// What a due peer's round came to
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum PeerRound {
+    /// Our root hash matched the peer's last known one, so nothing was sent
+    Skipped,
+    /// The session ran; `applied` messages from the peer were new here
+    Synced { applied: usize, converged: bool },
+}
+
+struct Peer<T> {
+    transport: T,
+    due: Instant,
+    // The peer's root hash and when we learned it
+    root: Option<(u64, Instant)>,
+}
+
+/// Syncs one engine with many peers on a schedule
+///
+/// Call `sync_due` whenever `next_due` comes around. A peer whose last
+/// known root hash matches ours is skipped until that knowledge is older
+/// than the refresh period; `note_root` updates it from outside a round,
+/// e.g. from a header or a gossiped hash, so a skipped peer that moved on
+/// is picked up before then.
+pub struct SyncManager<T> {
+    peers: BTreeMap<String, Peer<T>>,
+    interval: Duration,
+    refresh: Duration,
+}
+
+impl<T> Default for SyncManager<T> {
+    fn default() -> Self {
+        SyncManager::new()
+    }
+}
+
+impl<T> SyncManager<T> {
+    pub fn new() -> Self {
+        SyncManager {
+            peers: BTreeMap::new(),
+            interval: DEFAULT_INTERVAL,
+            refresh: DEFAULT_REFRESH,
+        }
+    }
+
+    /// Sync with each peer every `interval`
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Skip a peer whose known root matches ours only while what we know
+    /// is younger than `refresh`; zero never skips
+    pub fn with_refresh(mut self, refresh: Duration) -> Self {
+        self.refresh = refresh;
+        self
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn refresh(&self) -> Duration {
+        self.refresh
+    }
+
+    /// Track a peer, due at once; replaces any peer of the same name
+    pub fn add_peer(&mut self, name: impl Into<String>, transport: T) {
+        let peer = Peer {
+            transport,
+            due: Instant::now(),
+            root: None,
+        };
+        self.peers.insert(name.into(), peer);
+    }
+
+    /// Stop tracking a peer, handing back its transport
+    pub fn remove_peer(&mut self, name: &str) -> Option<T> {
+        self.peers.remove(name).map(|peer| peer.transport)
+    }
+
+    /// The tracked peers' names, in order
+    pub fn peers(&self) -> impl Iterator<Item = &str> {
+        self.peers.keys().map(String::as_str)
+    }
+
+    pub fn transport(&self, name: &str) -> Option<&T> {
+        self.peers.get(name).map(|peer| &peer.transport)
+    }
+
+    pub fn transport_mut(&mut self, name: &str) -> Option<&mut T> {
+        self.peers.get_mut(name).map(|peer| &mut peer.transport)
+    }
+
+    /// The peer's root hash as of the last converged round or `note_root`
+    pub fn known_root(&self, name: &str) -> Option<u64> {
+        self.peers.get(name)?.root.map(|(hash, _)| hash)
+    }
+
+    /// Record that the peer's trie had root hash `hash` at `now`, returning
+    /// false if no such peer is tracked
+    pub fn note_root(&mut self, name: &str, hash: u64, now: Instant) -> bool {
+        match self.peers.get_mut(name) {
+            Some(peer) => {
+                peer.root = Some((hash, now));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Make a peer due at once, returning false if no such peer is tracked
+    pub fn wake(&mut self, name: &str) -> bool {
+        match self.peers.get_mut(name) {
+            Some(peer) => {
+                peer.due = Instant::now();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When the next peer is due, or None with no peers
+    pub fn next_due(&self) -> Option<Instant> {
+        self.peers.values().map(|peer| peer.due).min()
+    }
+}
+
+impl<T> SyncManager<T>
+where
+    T: Transport,
+    T::Error: From<SyncError>,
+{
+    /// Run a round with every peer due at `now`, in name order
+    ///
+    /// Each peer is scheduled again an interval from `now` whatever its
+    /// round came to. A failed round forgets the peer's root, so the next
+    /// one isn't skipped.
+    pub fn sync_due<S: MessageStore>(
+        &mut self,
+        engine: &mut Engine<S>,
+        now: Instant,
+    ) -> Vec<(String, Result<PeerRound, T::Error>)> {
+        let mut rounds = Vec::new();
+        for (name, peer) in &mut self.peers {
+            if peer.due > now {
+                continue;
+            }
+            peer.due = now + self.interval;
+            let ours = match engine.trie() {
+                Ok(trie) => trie.root_hash(),
+                Err(err) => {
+                    peer.root = None;
+                    rounds.push((name.clone(), Err(SyncError::from(err).into())));
+                    continue;
+                }
+            };
+            let skip = match peer.root {
+                Some((hash, seen)) => hash == ours && now.duration_since(seen) < self.refresh,
+                None => false,
+            };
+            if skip {
+                debug!(peer = %name, "peer root unchanged, skipping");
+                counter!("markle_sync_rounds_skipped_total", 1, "peer" => name.clone());
+                rounds.push((name.clone(), Ok(PeerRound::Skipped)));
+                continue;
+            }
+
+            let mut session = SyncSession::new(engine).with_peer(name.clone());
+            let result = session.run(&mut peer.transport);
+            let applied = session.applied().len();
+            let converged = session.is_converged();
+            let result = result.map(|()| {
+                // Converged tries are equal, so theirs is now ours
+                peer.root = engine
+                    .trie()
+                    .ok()
+                    .filter(|_| converged)
+                    .map(|trie| (trie.root_hash(), now));
+                PeerRound::Synced { applied, converged }
+            });
+            if result.is_err() {
+                peer.root = None;
+            }
+            rounds.push((name.clone(), result));
+        }
+        rounds
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+    use crate::trie::Trie;
+    use chrono::Utc;
+
+    fn engine() -> Engine<MemoryStore> {
+        Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap()
+    }
+
+    // A peer's engine behind a transport, answering each frame in a fresh
+    // session as a server would
+    struct Remote {
+        engine: Engine<MemoryStore>,
+        exchanges: usize,
+    }
+
+    impl Transport for &mut Remote {
+        type Error = SyncError;
+
+        fn exchange(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+            self.exchanges += 1;
+            SyncSession::new(&mut self.engine).handle(frame)
+        }
+    }
+
+    fn remote() -> Remote {
+        Remote {
+            engine: engine(),
+            exchanges: 0,
+        }
+    }
+
+    #[test]
+    fn test_overlapping_peers() {
+        let now = Utc::now().timestamp_millis();
+        let mut local = engine();
+        let mut b = remote();
+        let mut c = remote();
+        b.engine
+            .write("todos", "1", "title", "buy milk", now)
+            .unwrap();
+        // Both peers hold the same message, and one of their own each
+        let shared = b.engine.messages_since(i64::MIN).remove(0);
+        assert!(c.engine.apply(shared, now).unwrap());
+        b.engine
+            .write("todos", "2", "title", "buy eggs", now)
+            .unwrap();
+        c.engine
+            .write("todos", "3", "title", "buy jam", now)
+            .unwrap();
+        local.write("todos", "4", "title", "buy tea", now).unwrap();
+
+        let mut manager = SyncManager::new();
+        manager.add_peer("b", &mut b);
+        manager.add_peer("c", &mut c);
+        let start = Instant::now();
+        let rounds = manager.sync_due(&mut local, start);
+        let applied: Vec<_> = rounds
+            .into_iter()
+            .map(|(name, result)| (name, result.unwrap()))
+            .collect();
+        assert_eq!(
+            applied,
+            vec![
+                (
+                    "b".to_string(),
+                    PeerRound::Synced {
+                        applied: 2,
+                        converged: true
+                    }
+                ),
+                (
+                    "c".to_string(),
+                    PeerRound::Synced {
+                        applied: 1,
+                        converged: true
+                    }
+                ),
+            ]
+        );
+        assert_eq!(local.store().len(), 4);
+
+        // The copy of row 1 from `c` was known, so the trie matches one
+        // built from scratch
+        let mut fresh = Trie::new();
+        for message in local.messages_since(i64::MIN) {
+            fresh.insert(message.timestamp);
+        }
+        assert_eq!(local.trie().unwrap().root_hash(), fresh.root_hash());
+        assert_eq!(
+            manager.known_root("c"),
+            Some(local.trie().unwrap().root_hash())
+        );
+        assert_eq!(manager.next_due(), Some(start + DEFAULT_INTERVAL));
+        drop(manager);
+        assert_eq!(local.trie().unwrap().diff(c.engine.trie().unwrap()), None);
+    }
+
+    #[test]
+    fn test_skip_unchanged() {
+        let now = Utc::now().timestamp_millis();
+        let mut local = engine();
+        let mut b = remote();
+        b.engine
+            .write("todos", "1", "title", "buy milk", now)
+            .unwrap();
+
+        let interval = Duration::from_secs(10);
+        let mut manager = SyncManager::new()
+            .with_interval(interval)
+            .with_refresh(Duration::from_secs(60));
+        manager.add_peer("b", &mut b);
+        let start = Instant::now();
+        assert!(manager.sync_due(&mut local, start)[0].1.is_ok());
+        let exchanges = manager.transport("b").unwrap().exchanges;
+
+        // Not due yet, then due but unchanged
+        assert!(manager
+            .sync_due(&mut local, start + interval / 2)
+            .is_empty());
+        let rounds = manager.sync_due(&mut local, start + interval);
+        assert_eq!(rounds[0].1.as_ref().unwrap(), &PeerRound::Skipped);
+        assert_eq!(manager.transport("b").unwrap().exchanges, exchanges);
+
+        // A local write changes our root, so the peer is synced again
+        local.write("todos", "2", "title", "buy eggs", now).unwrap();
+        let rounds = manager.sync_due(&mut local, start + interval * 2);
+        assert_eq!(
+            rounds[0].1.as_ref().unwrap(),
+            &PeerRound::Synced {
+                applied: 0,
+                converged: true
+            }
+        );
+
+        // What we know of the peer goes stale after the refresh period
+        let later = start + interval * 3;
+        let rounds = manager.sync_due(&mut local, later);
+        assert_eq!(rounds[0].1.as_ref().unwrap(), &PeerRound::Skipped);
+        let rounds = manager.sync_due(&mut local, later + Duration::from_secs(60));
+        assert!(matches!(
+            rounds[0].1.as_ref().unwrap(),
+            PeerRound::Synced { .. }
+        ));
+        drop(manager);
+        assert_eq!(b.engine.store().len(), 2);
+    }
+}
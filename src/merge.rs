@@ -0,0 +1,232 @@
+// Joining two sync groups that should have been one, e.g. two devices that
+// each started a fresh group instead of one joining the other's.
+//
+// Messages both logs hold verbatim are kept once. Where a node id shows up
+// in both with different messages under the same timestamp, as happens to
+// a device restored onto a second phone, their side of that node is moved
+// to a fresh node id so nothing is lost and no timestamp means two things.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::engine::Engine;
+use crate::error::SyncError;
+use crate::message::Message;
+use crate::store::MessageStore;
+use crate::timestamp::{make_client_id, Timestamp};
+
+/// A reviewed-before-applied merge of two groups' message logs
+///
+/// ```
+/// use markle::merge::GroupMerge;
+/// # use markle::{message::Message, timestamp::Timestamp};
+/// # let ours: Vec<Message> = Vec::new();
+/// # let theirs: Vec<Message> = Vec::new();
+///
+/// let merge = GroupMerge::plan(&ours, &theirs);
+/// for (from, to) in merge.renamed() {
+///     println!("node {} becomes {}", from, to);
+/// }
+/// ```
+///
+/// Once applied to an engine holding `ours`, that engine's trie covers
+/// both logs and syncing it pushes the union to the rest of the group.
+/// Devices whose node was renamed should take up their new id before
+/// writing again, or they'll collide afresh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupMerge {
+    messages: Vec<Message>,
+    renamed: Vec<(String, String)>,
+    // ours, theirs
+    collisions: Vec<(Message, Message)>,
+}
+
+impl GroupMerge {
+    /// Merge `theirs` into `ours`, renaming colliding nodes to random ids
+    pub fn plan(ours: &[Message], theirs: &[Message]) -> Self {
+        GroupMerge::plan_with(ours, theirs, |_| make_client_id())
+    }
+
+    /// Like `plan`, with `fresh_node` naming the replacement for a
+    /// colliding node. It's called again until it returns an id neither
+    /// log uses.
+    pub fn plan_with(
+        ours: &[Message],
+        theirs: &[Message],
+        mut fresh_node: impl FnMut(&str) -> String,
+    ) -> Self {
+        let known: BTreeMap<&Timestamp, &Message> = ours
+            .iter()
+            .map(|message| (&message.timestamp, message))
+            .collect();
+
+        let mut collisions = Vec::new();
+        let mut colliding = BTreeSet::new();
+        for message in theirs {
+            if let Some(&mine) = known.get(&message.timestamp) {
+                if mine != message {
+                    collisions.push((mine.clone(), message.clone()));
+                    colliding.insert(message.timestamp.node());
+                }
+            }
+        }
+
+        let used: BTreeSet<&str> = ours
+            .iter()
+            .chain(theirs)
+            .map(|message| message.timestamp.node())
+            .collect();
+        let mut renames: BTreeMap<&str, String> = BTreeMap::new();
+        for node in colliding {
+            let mut fresh = fresh_node(node);
+            while used.contains(fresh.as_str()) || renames.values().any(|taken| *taken == fresh) {
+                fresh = fresh_node(node);
+            }
+            renames.insert(node, fresh);
+        }
+
+        let mut messages = ours.to_vec();
+        for message in theirs {
+            // Held already; a different message held under the same
+            // timestamp is moved to its node's new id below
+            if known.get(&message.timestamp) == Some(&message) {
+                continue;
+            }
+            let mut message = message.clone();
+            if let Some(fresh) = renames.get(message.timestamp.node()) {
+                message.timestamp = Timestamp::new(
+                    message.timestamp.millis(),
+                    message.timestamp.counter(),
+                    fresh.clone(),
+                );
+            }
+            messages.push(message);
+        }
+        messages.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+        messages.dedup();
+
+        GroupMerge {
+            messages,
+            renamed: renames
+                .into_iter()
+                .map(|(node, fresh)| (node.to_string(), fresh))
+                .collect(),
+            collisions,
+        }
+    }
+
+    /// The merged log, oldest first
+    pub fn messages(&self) -> &[Message] {
+        &self.messages
+    }
+
+    pub fn into_messages(self) -> Vec<Message> {
+        self.messages
+    }
+
+    /// Nodes of `theirs` that were moved, and the ids they were moved to
+    pub fn renamed(&self) -> &[(String, String)] {
+        &self.renamed
+    }
+
+    /// Timestamps both logs used for different messages, ours first
+    pub fn collisions(&self) -> &[(Message, Message)] {
+        &self.collisions
+    }
+
+    /// Apply the merged log to `engine`, returning how many messages were
+    /// new to it
+    pub fn apply<S: MessageStore>(
+        &self,
+        engine: &mut Engine<S>,
+        phys: i64,
+    ) -> Result<usize, SyncError> {
+        let mut applied = 0;
+        for message in &self.messages {
+            if engine.apply(message.clone(), phys)? {
+                applied += 1;
+            }
+        }
+        Ok(applied)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::trie::Trie;
+
+    fn message(millis: i64, node: &str, value: &str) -> Message {
+        Message {
+            timestamp: Timestamp::new(millis, 0, node.to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_merge_groups() {
+        let shared = message(1000, "aaaaaaaaaaaaaaaa", "shared");
+        let ours = vec![
+            shared.clone(),
+            message(2000, "aaaaaaaaaaaaaaaa", "ours"),
+            message(3000, "bbbbbbbbbbbbbbbb", "ours only"),
+        ];
+        // The second phone was restored from the first and kept its node id
+        let theirs = vec![
+            shared.clone(),
+            message(2000, "aaaaaaaaaaaaaaaa", "theirs"),
+            message(4000, "aaaaaaaaaaaaaaaa", "theirs later"),
+            message(5000, "cccccccccccccccc", "theirs only"),
+        ];
+
+        let mut next = ["aaaaaaaaaaaaaaaa", "dddddddddddddddd"].into_iter();
+        let merge = GroupMerge::plan_with(&ours, &theirs, |_| next.next().unwrap().to_string());
+        assert_eq!(
+            merge.renamed(),
+            &[(
+                "aaaaaaaaaaaaaaaa".to_string(),
+                "dddddddddddddddd".to_string()
+            )]
+        );
+        assert_eq!(merge.collisions().len(), 1);
+        assert_eq!(merge.collisions()[0].1.value, "theirs");
+
+        let values: Vec<(&str, &str)> = merge
+            .messages()
+            .iter()
+            .map(|m| (m.timestamp.node(), m.value.as_str()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                ("aaaaaaaaaaaaaaaa", "shared"),
+                ("aaaaaaaaaaaaaaaa", "ours"),
+                ("dddddddddddddddd", "theirs"),
+                ("bbbbbbbbbbbbbbbb", "ours only"),
+                ("dddddddddddddddd", "theirs later"),
+                ("cccccccccccccccc", "theirs only"),
+            ]
+        );
+
+        // An engine holding our log takes in only what it lacked
+        let clock = Timestamp::new(0, 0, "eeeeeeeeeeeeeeee".to_string());
+        let mut engine = Engine::new(clock, MemoryStore::new()).unwrap();
+        for message in &ours {
+            engine.apply(message.clone(), 0).unwrap();
+        }
+        assert_eq!(merge.apply(&mut engine, 0).unwrap(), 3);
+        let all: Vec<Timestamp> = merge
+            .messages()
+            .iter()
+            .map(|m| m.timestamp.clone())
+            .collect();
+        assert_eq!(*engine.trie().unwrap(), Trie::build(all));
+        assert_eq!(
+            engine.get("todos", "1", "title").unwrap().value,
+            "theirs only"
+        );
+    }
+}
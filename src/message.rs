@@ -0,0 +1,121 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::compress::{compress, decompress, Compression};
+use crate::timestamp::Timestamp;
+
+/// A single write to one cell: `dataset`/`row`/`column` set to `value`
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    pub timestamp: Timestamp,
+    pub dataset: String,
+    pub row: String,
+    pub column: String,
+    pub value: String,
+}
+
+impl Message {
+    /// xxh3_64 of the payload, everything but the timestamp
+    pub fn content_hash(&self) -> u64 {
+        let mut w = Writer::new();
+        w.put_str(&self.dataset);
+        w.put_str(&self.row);
+        w.put_str(&self.column);
+        w.put_str(&self.value);
+        xxh3_64(&w.into_bytes())
+    }
+
+    /// Bytes the message takes on the wire, the unit of store budgets
+    pub fn size(&self) -> usize {
+        // millis, counter and five length-prefixed strings
+        8 + 2
+            + 5 * 4
+            + self.timestamp.node().len()
+            + self.dataset.len()
+            + self.row.len()
+            + self.column.len()
+            + self.value.len()
+    }
+
+    pub(crate) fn encode(&self, w: &mut Writer) {
+        self.timestamp.encode(w);
+        w.put_str(&self.dataset);
+        w.put_str(&self.row);
+        w.put_str(&self.column);
+        w.put_str(&self.value);
+    }
+
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Message {
+            timestamp: Timestamp::decode(r)?,
+            dataset: r.get_str()?.to_string(),
+            row: r.get_str()?.to_string(),
+            column: r.get_str()?.to_string(),
+            value: r.get_str()?.to_string(),
+        })
+    }
+
+    /// Encode `messages` as one payload, a count then each message,
+    /// compressed behind a byte naming the compression
+    pub fn encode_batch(messages: &[Message], compression: Compression) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_u32(messages.len() as u32);
+        for message in messages {
+            message.encode(&mut w);
+        }
+        compress(&w.into_bytes(), compression)
+    }
+
+    /// Decode what `encode_batch` wrote, with any compression
+    pub fn decode_batch(bytes: &[u8]) -> Result<Vec<Message>, DecodeError> {
+        let bytes = decompress(bytes)?;
+        let mut r = Reader::new(&bytes);
+        let count = r.get_u32()?;
+        let mut messages = Vec::new();
+        for _ in 0..count {
+            messages.push(Message::decode(&mut r)?);
+        }
+        r.finish()?;
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let msg = Message {
+            timestamp: Timestamp::new(1711231855000, 3, "1234123412341234".to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: "buy milk".to_string(),
+        };
+
+        let mut w = Writer::new();
+        msg.encode(&mut w);
+        let bytes = w.into_bytes();
+
+        assert_eq!(msg.size(), bytes.len());
+
+        let mut r = Reader::new(&bytes);
+        let got = Message::decode(&mut r).unwrap();
+        assert_eq!(got, msg);
+        assert!(r.is_empty());
+
+        let batch = alloc::vec![msg.clone(); 100];
+        let bare = Message::encode_batch(&batch, Compression::default());
+        assert_eq!(bare.len(), 1 + 4 + 100 * msg.size());
+        assert_eq!(Message::decode_batch(&bare), Ok(batch.clone()));
+        #[cfg(feature = "compression")]
+        {
+            let deflated = Message::encode_batch(&batch, Compression::Deflate);
+            assert!(deflated.len() * 10 < bare.len());
+            assert_eq!(Message::decode_batch(&deflated), Ok(batch));
+        }
+    }
+}
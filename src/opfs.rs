@@ -0,0 +1,674 @@
+// A message store kept in the browser's origin private file system (OPFS).
+//
+// Dedicated workers get synchronous access handles to OPFS files, which is
+// what lets this sit behind the synchronous `MessageStore` trait where
+// IndexedDB can't. The file is an append-only log of checksummed records,
+// replayed into memory on open:
+//
+//   magic "MLOG", version u8
+//   record*: payload length u32, xxh3_64 of payload u64, payload
+//
+// A payload is a tag and its body: 1 is a u32 count of messages and the
+// messages, 2 a removed timestamp. Every write is one record followed by a
+// flush, so a crash leaves at most a torn last record. Replay stops at the
+// first record that is short or fails its checksum and truncates it away;
+// a batch is one record, so it lands whole or not at all.
+//
+// Vacuuming writes the live messages as one record to a scratch file,
+// copies that over the log and empties the scratch file again. A scratch
+// file found holding a whole image on open is a vacuum cut short, so the
+// copy is redone; a torn one is dropped and the log was never touched.
+
+use core::fmt;
+
+use wasm_bindgen::{JsCast, JsValue};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    FileSystemDirectoryHandle, FileSystemFileHandle, FileSystemGetFileOptions,
+    FileSystemReadWriteOptions, FileSystemSyncAccessHandle, WorkerGlobalScope,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::message::Message;
+use crate::store::{MemoryStore, MessageStore};
+use crate::timestamp::Timestamp;
+
+const MAGIC: [u8; 4] = *b"MLOG";
+const VERSION: u8 = 1;
+const PREAMBLE: u64 = 5;
+// Record length and checksum
+const RECORD_HEADER: usize = 12;
+
+const TAG_INSERT: u8 = 1;
+const TAG_REMOVE: u8 = 2;
+
+/// Random access to the file a `LogStore` lives in
+pub trait LogFile {
+    type Error: fmt::Debug;
+
+    fn size(&mut self) -> Result<u64, Self::Error>;
+
+    /// Fill as much of `buf` as the file holds from `at`, returning how
+    /// many bytes were read
+    fn read_at(&mut self, buf: &mut [u8], at: u64) -> Result<usize, Self::Error>;
+
+    fn write_at(&mut self, buf: &[u8], at: u64) -> Result<(), Self::Error>;
+
+    fn truncate(&mut self, len: u64) -> Result<(), Self::Error>;
+
+    /// Persist everything written so far
+    fn flush(&mut self) -> Result<(), Self::Error>;
+}
+
+/// An OPFS file opened for synchronous access
+///
+/// Only dedicated workers can open one, and only one handle per file may
+/// be open at a time.
+#[derive(Debug)]
+pub struct OpfsFile(FileSystemSyncAccessHandle);
+
+impl OpfsFile {
+    /// Open `name` in the origin's root directory, creating it if needed
+    pub async fn open(name: &str) -> Result<Self, JsValue> {
+        let scope: WorkerGlobalScope = js_sys::global().dyn_into()?;
+        let root: FileSystemDirectoryHandle =
+            JsFuture::from(scope.navigator().storage().get_directory())
+                .await?
+                .dyn_into()?;
+        let options = FileSystemGetFileOptions::new();
+        options.set_create(true);
+        let file: FileSystemFileHandle =
+            JsFuture::from(root.get_file_handle_with_options(name, &options))
+                .await?
+                .dyn_into()?;
+        let handle = JsFuture::from(file.create_sync_access_handle())
+            .await?
+            .dyn_into()?;
+        Ok(OpfsFile(handle))
+    }
+}
+
+impl Drop for OpfsFile {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+impl LogFile for OpfsFile {
+    type Error = JsValue;
+
+    fn size(&mut self) -> Result<u64, JsValue> {
+        Ok(self.0.get_size()? as u64)
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], at: u64) -> Result<usize, JsValue> {
+        let options = FileSystemReadWriteOptions::new();
+        options.set_at(at as f64);
+        Ok(self.0.read_with_u8_array_and_options(buf, &options)? as usize)
+    }
+
+    fn write_at(&mut self, buf: &[u8], at: u64) -> Result<(), JsValue> {
+        let options = FileSystemReadWriteOptions::new();
+        options.set_at(at as f64);
+        let written = self.0.write_with_u8_array_and_options(buf, &options)? as usize;
+        if written != buf.len() {
+            return Err(JsValue::from_str("short write"));
+        }
+        Ok(())
+    }
+
+    fn truncate(&mut self, len: u64) -> Result<(), JsValue> {
+        self.0.truncate_with_f64(len as f64)
+    }
+
+    fn flush(&mut self) -> Result<(), JsValue> {
+        self.0.flush()
+    }
+}
+
+/// A store in an OPFS file
+pub type OpfsStore = LogStore<OpfsFile>;
+
+impl OpfsStore {
+    /// Open or create the store in OPFS file `name`, vacuuming through
+    /// `{name}.vacuum`
+    pub async fn open_opfs(name: &str) -> Result<Self, LogError<JsValue>> {
+        let file = OpfsFile::open(name).await.map_err(LogError::Io)?;
+        let scratch = OpfsFile::open(&format!("{}.vacuum", name))
+            .await
+            .map_err(LogError::Io)?;
+        LogStore::open_with_scratch(file, scratch)
+    }
+}
+
+/// A `MessageStore` over an append-only log file, indexed in memory
+///
+/// # Panics
+///
+/// The store's methods panic if the file can't be written, e.g. when the
+/// origin is out of quota, since `MessageStore` has no way to report it.
+/// What was written before stays readable.
+///
+/// Opened with a scratch file, `vacuum` rewrites the log down to its live
+/// messages, so `Engine::enforce_budget` bounds the file as well as the
+/// messages in it. Without one, `vacuum` does nothing and the file keeps
+/// every removal; use `rewrite` instead.
+#[derive(Debug)]
+pub struct LogStore<F> {
+    file: F,
+    // Where vacuums are staged, if given one
+    scratch: Option<F>,
+    index: MemoryStore,
+    // Where the next record goes
+    end: u64,
+}
+
+impl<F: LogFile> LogStore<F> {
+    /// Replay the log in `file`, dropping a torn last record
+    pub fn open(mut file: F) -> Result<Self, LogError<F::Error>> {
+        let size = file.size().map_err(LogError::Io)?;
+        if size < PREAMBLE {
+            // Empty, or torn before the preamble was flushed
+            file.truncate(0).map_err(LogError::Io)?;
+            file.write_at(&preamble(), 0).map_err(LogError::Io)?;
+            file.flush().map_err(LogError::Io)?;
+            return Ok(LogStore {
+                file,
+                scratch: None,
+                index: MemoryStore::new(),
+                end: PREAMBLE,
+            });
+        }
+
+        let mut bytes = vec![0; size as usize];
+        let read = file.read_at(&mut bytes, 0).map_err(LogError::Io)?;
+        bytes.truncate(read);
+        if bytes.get(..4) != Some(&MAGIC[..]) {
+            return Err(LogError::Decode(DecodeError::BadMagic));
+        }
+        match bytes.get(4) {
+            Some(&VERSION) => {}
+            Some(&version) => {
+                return Err(LogError::Decode(DecodeError::UnsupportedVersion(version)))
+            }
+            None => return Err(LogError::Decode(DecodeError::UnexpectedEof)),
+        }
+
+        let mut store = LogStore {
+            file,
+            scratch: None,
+            index: MemoryStore::new(),
+            end: PREAMBLE,
+        };
+        let mut pos = PREAMBLE as usize;
+        while let Some((payload, next)) = record_at(&bytes, pos) {
+            // A record that checks out but won't decode was written by a
+            // newer version; stop rather than guess at the rest
+            store.replay(payload).map_err(LogError::Decode)?;
+            pos = next;
+        }
+        store.end = pos as u64;
+        if store.end < bytes.len() as u64 {
+            log_warn!(
+                "dropping {} bytes of torn log record",
+                bytes.len() as u64 - store.end
+            );
+            store.file.truncate(store.end).map_err(LogError::Io)?;
+            store.file.flush().map_err(LogError::Io)?;
+        }
+        Ok(store)
+    }
+
+    /// Replay the log in `file` as `open` does, staging vacuums in
+    /// `scratch`
+    ///
+    /// Finishes a vacuum that was cut short first, so the pair must always
+    /// be opened together.
+    pub fn open_with_scratch(mut file: F, mut scratch: F) -> Result<Self, LogError<F::Error>> {
+        let size = scratch.size().map_err(LogError::Io)?;
+        if size > 0 {
+            let mut image = vec![0; size as usize];
+            let read = scratch.read_at(&mut image, 0).map_err(LogError::Io)?;
+            image.truncate(read);
+            if is_image(&image) {
+                log_warn!("finishing an interrupted message log vacuum");
+                file.write_at(&image, 0).map_err(LogError::Io)?;
+                file.truncate(image.len() as u64).map_err(LogError::Io)?;
+                file.flush().map_err(LogError::Io)?;
+            }
+            scratch.truncate(0).map_err(LogError::Io)?;
+            scratch.flush().map_err(LogError::Io)?;
+        }
+        let mut store = LogStore::open(file)?;
+        store.scratch = Some(scratch);
+        Ok(store)
+    }
+
+    fn replay(&mut self, payload: &[u8]) -> Result<(), DecodeError> {
+        let mut r = Reader::new(payload);
+        match r.get_u8()? {
+            TAG_INSERT => {
+                for _ in 0..r.get_u32()? {
+                    self.index.insert(Message::decode(&mut r)?);
+                }
+            }
+            TAG_REMOVE => {
+                self.index.remove(&Timestamp::decode(&mut r)?);
+            }
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        }
+        r.finish()
+    }
+
+    fn append(&mut self, payload: Vec<u8>) {
+        let record = record(&payload);
+        let written = self
+            .file
+            .write_at(&record, self.end)
+            .and_then(|()| self.file.flush());
+        if let Err(err) = written {
+            panic!("message log write failed: {:?}", err);
+        }
+        self.end += record.len() as u64;
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Bytes the log file takes, including removed messages
+    pub fn file_size(&self) -> u64 {
+        self.end
+    }
+
+    /// Write the live messages to a fresh log in `into`, returning the
+    /// store over it
+    ///
+    /// Removals only append to the log, so it grows until rewritten. The
+    /// old file is left alone; swap the two once this returns, so a crash
+    /// midway leaves the old log in place.
+    pub fn rewrite<G: LogFile>(&self, mut into: G) -> Result<LogStore<G>, LogError<G::Error>> {
+        into.truncate(0).map_err(LogError::Io)?;
+        let mut store = LogStore::open(into)?;
+        store.insert_batch(self.index.messages_since(i64::MIN));
+        Ok(store)
+    }
+
+    pub fn into_file(self) -> F {
+        self.file
+    }
+}
+
+fn preamble() -> Vec<u8> {
+    let mut w = Writer::new();
+    for byte in MAGIC {
+        w.put_u8(byte);
+    }
+    w.put_u8(VERSION);
+    w.into_bytes()
+}
+
+fn record(payload: &[u8]) -> Vec<u8> {
+    let mut w = Writer::new();
+    w.put_u32(payload.len() as u32);
+    w.put_u64(xxh3_64(payload));
+    let mut record = w.into_bytes();
+    record.extend_from_slice(payload);
+    record
+}
+
+// Whether `bytes` is a whole vacuumed log: the preamble and exactly one
+// intact record, written even when there are no messages so that a
+// scratch file torn after its preamble isn't mistaken for an empty log
+fn is_image(bytes: &[u8]) -> bool {
+    bytes.get(..PREAMBLE as usize) == Some(&preamble()[..])
+        && record_at(bytes, PREAMBLE as usize).is_some_and(|(_, end)| end == bytes.len())
+}
+
+// The payload of the record at `pos` and where the next one starts, if
+// it's whole and intact
+fn record_at(bytes: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let header = bytes.get(pos..pos + RECORD_HEADER)?;
+    let mut r = Reader::new(header);
+    let len = r.get_u32().ok()? as usize;
+    let checksum = r.get_u64().ok()?;
+    let start = pos + RECORD_HEADER;
+    let payload = bytes.get(start..start.checked_add(len)?)?;
+    (xxh3_64(payload) == checksum).then_some((payload, start + len))
+}
+
+impl<F: LogFile> MessageStore for LogStore<F> {
+    fn insert(&mut self, message: Message) -> bool {
+        self.insert_batch(vec![message]) == 1
+    }
+
+    /// Writes the new messages as one record, so after a crash either all
+    /// of them are there or none are
+    fn insert_batch(&mut self, messages: Vec<Message>) -> usize {
+        let fresh: Vec<Message> = messages
+            .into_iter()
+            .filter(|message| !self.index.contains(&message.timestamp))
+            .collect();
+        if fresh.is_empty() {
+            return 0;
+        }
+
+        let mut w = Writer::new();
+        w.put_u8(TAG_INSERT);
+        w.put_u32(fresh.len() as u32);
+        for message in &fresh {
+            message.encode(&mut w);
+        }
+        self.append(w.into_bytes());
+        self.index.insert_batch(fresh)
+    }
+
+    fn contains(&self, timestamp: &Timestamp) -> bool {
+        self.index.contains(timestamp)
+    }
+
+    fn get(&self, timestamp: &Timestamp) -> Option<Message> {
+        self.index.get(timestamp)
+    }
+
+    fn remove(&mut self, timestamp: &Timestamp) -> bool {
+        if !self.index.contains(timestamp) {
+            return false;
+        }
+        let mut w = Writer::new();
+        w.put_u8(TAG_REMOVE);
+        timestamp.encode(&mut w);
+        self.append(w.into_bytes());
+        self.index.remove(timestamp)
+    }
+
+    fn messages_since(&self, millis: i64) -> Vec<Message> {
+        self.index.messages_since(millis)
+    }
+
+    fn messages_since_page(
+        &self,
+        millis: i64,
+        limit: usize,
+        cursor: Option<&Timestamp>,
+    ) -> Vec<Message> {
+        self.index.messages_since_page(millis, limit, cursor)
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.index.size_bytes()
+    }
+
+    /// Rewrite the log as one record of the live messages, if opened with
+    /// a scratch file; see `open_with_scratch`
+    fn vacuum(&mut self) -> usize {
+        let Some(scratch) = &mut self.scratch else {
+            return 0;
+        };
+        let messages = self.index.messages_since(i64::MIN);
+        let mut w = Writer::new();
+        w.put_u8(TAG_INSERT);
+        w.put_u32(messages.len() as u32);
+        for message in &messages {
+            message.encode(&mut w);
+        }
+        let mut image = preamble();
+        image.extend_from_slice(&record(&w.into_bytes()));
+        if image.len() as u64 >= self.end {
+            return 0;
+        }
+
+        let file = &mut self.file;
+        let written = scratch
+            .truncate(0)
+            .and_then(|()| scratch.write_at(&image, 0))
+            .and_then(|()| scratch.flush())
+            .and_then(|()| file.write_at(&image, 0))
+            .and_then(|()| file.truncate(image.len() as u64))
+            .and_then(|()| file.flush())
+            .and_then(|()| scratch.truncate(0))
+            .and_then(|()| scratch.flush());
+        if let Err(err) = written {
+            panic!("message log vacuum failed: {:?}", err);
+        }
+        let reclaimed = self.end - image.len() as u64;
+        self.end = image.len() as u64;
+        reclaimed as usize
+    }
+}
+
+// Errors related to opening a message log
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum LogError<E> {
+    Io(E),
+    Decode(DecodeError),
+}
+
+impl<E: fmt::Debug> fmt::Display for LogError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::Io(err) => write!(f, "message log i/o failed: {:?}", err),
+            LogError::Decode(err) => write!(f, "message log unreadable: {}", err),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for LogError<E> {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A file that keeps only the first `budget` bytes written, as if the
+    // process died partway through a write
+    #[derive(Debug, Default, Clone)]
+    struct MemFile {
+        bytes: Vec<u8>,
+        budget: Option<usize>,
+    }
+
+    impl LogFile for MemFile {
+        type Error = ();
+
+        fn size(&mut self) -> Result<u64, ()> {
+            Ok(self.bytes.len() as u64)
+        }
+
+        fn read_at(&mut self, buf: &mut [u8], at: u64) -> Result<usize, ()> {
+            let available = self.bytes.get(at as usize..).unwrap_or(&[]);
+            let n = available.len().min(buf.len());
+            buf[..n].copy_from_slice(&available[..n]);
+            Ok(n)
+        }
+
+        fn write_at(&mut self, buf: &[u8], at: u64) -> Result<(), ()> {
+            let keep = match &mut self.budget {
+                Some(budget) => {
+                    let keep = buf.len().min(*budget);
+                    *budget -= keep;
+                    keep
+                }
+                None => buf.len(),
+            };
+            let end = at as usize + keep;
+            if self.bytes.len() < end {
+                self.bytes.resize(end, 0);
+            }
+            self.bytes[at as usize..end].copy_from_slice(&buf[..keep]);
+            Ok(())
+        }
+
+        fn truncate(&mut self, len: u64) -> Result<(), ()> {
+            self.bytes.truncate(len as usize);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<(), ()> {
+            Ok(())
+        }
+    }
+
+    fn message(millis: i64, value: &str) -> Message {
+        Message {
+            timestamp: Timestamp::new(millis, 0, "aaaaaaaaaaaaaaaa".to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_log_store_reopen() {
+        let mut store = LogStore::open(MemFile::default()).unwrap();
+        assert!(store.insert(message(1, "a")));
+        assert!(!store.insert(message(1, "a")));
+        assert_eq!(
+            store.insert_batch(vec![message(2, "b"), message(3, "c")]),
+            2
+        );
+        assert!(store.remove(&message(2, "b").timestamp));
+
+        let reopened = LogStore::open(store.into_file()).unwrap();
+        let values: Vec<String> = reopened
+            .messages_since(i64::MIN)
+            .into_iter()
+            .map(|m| m.value)
+            .collect();
+        assert_eq!(values, vec!["a", "c"]);
+
+        let rewritten = reopened.rewrite(MemFile::default()).unwrap();
+        assert!(rewritten.file_size() < reopened.file_size());
+        assert_eq!(
+            LogStore::open(rewritten.into_file())
+                .unwrap()
+                .messages_since(i64::MIN),
+            reopened.messages_since(i64::MIN)
+        );
+    }
+
+    #[test]
+    fn test_log_store_torn_write() {
+        let mut store = LogStore::open(MemFile::default()).unwrap();
+        store.insert(message(1, "a"));
+        let mut file = store.into_file();
+        let intact = file.bytes.len();
+
+        // Die at every point of writing a batch; it's all or nothing
+        for budget in 0..60 {
+            let mut torn = file.clone();
+            torn.budget = Some(budget);
+            let mut store = LogStore::open(torn).unwrap();
+            store.insert_batch(vec![message(2, "b"), message(3, "c")]);
+            let mut torn = store.into_file();
+            torn.budget = None;
+
+            let store = LogStore::open(torn).unwrap();
+            let count = store.messages_since(i64::MIN).len();
+            assert!(count == 1 || count == 3, "{} messages", count);
+            if count == 1 {
+                assert_eq!(store.file_size(), intact as u64);
+                assert_eq!(store.into_file().bytes.len(), intact);
+            }
+        }
+
+        file.bytes[intact - 1] ^= 1;
+        assert!(LogStore::open(file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_log_store_vacuum() {
+        let open = |file, scratch| LogStore::open_with_scratch(file, scratch).unwrap();
+        let mut store = open(MemFile::default(), MemFile::default());
+        store.insert_batch(vec![message(1, "a"), message(2, "b"), message(3, "c")]);
+        store.remove(&message(2, "b").timestamp);
+        let before = store.file_size();
+        let live = store.messages_since(i64::MIN);
+
+        let mut old = store.file.clone();
+        let reclaimed = store.vacuum();
+        assert!(reclaimed > 0);
+        assert_eq!(store.file_size(), before - reclaimed as u64);
+        assert_eq!(store.vacuum(), 0);
+        let image = store.file.bytes.clone();
+        assert!(store.scratch.as_ref().unwrap().bytes.is_empty());
+
+        let reopened = open(store.into_file(), MemFile::default());
+        assert_eq!(reopened.messages_since(i64::MIN), live);
+
+        // Die while staging: the torn scratch file is dropped
+        for len in 0..image.len() {
+            let scratch = MemFile {
+                bytes: image[..len].to_vec(),
+                budget: None,
+            };
+            let store = open(old.clone(), scratch);
+            assert_eq!(store.messages_since(i64::MIN), live);
+            assert_eq!(store.file_size(), before);
+            assert!(store.scratch.unwrap().bytes.is_empty());
+        }
+
+        // Die while copying over the log: the copy is redone
+        let scratch = MemFile {
+            bytes: image.clone(),
+            budget: None,
+        };
+        for len in 0..=image.len() {
+            let mut file = old.clone();
+            file.bytes[..len].copy_from_slice(&image[..len]);
+            let store = open(file, scratch.clone());
+            assert_eq!(store.messages_since(i64::MIN), live);
+            assert_eq!(store.into_file().bytes, image);
+        }
+
+        // Without a scratch file there's nowhere safe to stage one
+        old.bytes = image;
+        let mut store = LogStore::open(old).unwrap();
+        store.remove(&message(1, "a").timestamp);
+        assert_eq!(store.vacuum(), 0);
+    }
+
+    #[test]
+    fn test_enforce_budget_shrinks_log() {
+        use crate::engine::Engine;
+        use crate::store::{BudgetStatus, SizeBudget};
+
+        let store = LogStore::open_with_scratch(MemFile::default(), MemFile::default()).unwrap();
+        let clock = Timestamp::new(0, 0, "aaaaaaaaaaaaaaaa".to_string());
+        let mut engine = Engine::new(clock, store).unwrap();
+        engine
+            .write("todos", "1", "title", "buy milk", 1000)
+            .unwrap();
+        let tombstone = engine.delete("todos", "1", 1000).unwrap();
+        let before = engine.store().file_size();
+
+        let budget = SizeBudget {
+            warn: 0,
+            compact: 0,
+            limit: usize::MAX,
+        };
+        let got = engine.enforce_budget(&budget, 2000);
+        assert_eq!(got, Ok(BudgetStatus::Compacted(tombstone.size())));
+        // The removal is gone from disk too, not just from the index
+        assert!(engine.store().file_size() < before);
+        assert_eq!(engine.store().len(), 1);
+    }
+
+    #[test]
+    fn test_log_store_bad_magic() {
+        let file = MemFile {
+            bytes: b"SQLite format 3\0".to_vec(),
+            budget: None,
+        };
+        assert!(matches!(
+            LogStore::open(file),
+            Err(LogError::Decode(DecodeError::BadMagic))
+        ));
+    }
+}
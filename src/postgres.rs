@@ -0,0 +1,230 @@
+// The shared log in Postgres. Appends serialize per group on a transaction
+// advisory lock, so the version check and the inserts can't interleave
+// with another replica's, and announce themselves with NOTIFY; every
+// replica LISTENs and forwards what it hears to its router.
+
+use std::fmt;
+
+use futures_util::stream::{self, StreamExt};
+use tokio::sync::{broadcast, Mutex};
+use tokio_postgres::{AsyncMessage, Client, NoTls, Row};
+
+use crate::message::Message;
+use crate::replica::{Change, SharedLog, CHANGES_CAPACITY};
+use crate::timestamp::Timestamp;
+
+/// The channel appends are announced on, as `{version}:{group}`
+pub const CHANNEL: &str = "markle_changes";
+
+/// Creates the log's table if it's missing; run by `PostgresLog::connect`
+pub const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS markle_messages (
+    group_id text NOT NULL,
+    seq bigint NOT NULL,
+    timestamp text NOT NULL,
+    dataset text NOT NULL,
+    row_id text NOT NULL,
+    column_name text NOT NULL,
+    value text NOT NULL,
+    PRIMARY KEY (group_id, seq),
+    UNIQUE (group_id, timestamp)
+)";
+
+/// A `SharedLog` in one Postgres table, `markle_messages`
+///
+/// A group's version is the `seq` of its last message; messages are
+/// numbered from one with no gaps.
+pub struct PostgresLog {
+    client: Mutex<Client>,
+    changes: broadcast::Sender<Change>,
+}
+
+impl fmt::Debug for PostgresLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PostgresLog").finish_non_exhaustive()
+    }
+}
+
+impl PostgresLog {
+    /// Connect with a libpq-style `config`, such as
+    /// `"host=localhost user=markle"`, create the table if needed and
+    /// LISTEN for other replicas' appends
+    ///
+    /// The connection is driven by a task spawned on the current tokio
+    /// runtime; the log stops announcing changes if it drops.
+    pub async fn connect(config: &str) -> Result<Self, PostgresLogError> {
+        let (client, mut connection) = tokio_postgres::connect(config, NoTls).await?;
+        let changes = broadcast::channel(CHANGES_CAPACITY).0;
+        let sender: broadcast::Sender<Change> = changes.clone();
+        tokio::spawn(async move {
+            let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+            while let Some(message) = messages.next().await {
+                match message {
+                    Ok(AsyncMessage::Notification(note)) if note.channel() == CHANNEL => {
+                        match parse_change(note.payload()) {
+                            Some(change) => {
+                                let _ = sender.send(change);
+                            }
+                            None => log_warn!("bad change on {}: {}", CHANNEL, note.payload()),
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log_error!("shared log connection failed: {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+        client.batch_execute(SCHEMA).await?;
+        client.batch_execute(&format!("LISTEN {}", CHANNEL)).await?;
+        Ok(PostgresLog {
+            client: Mutex::new(client),
+            changes,
+        })
+    }
+}
+
+fn parse_change(payload: &str) -> Option<Change> {
+    let (version, group) = payload.split_once(':')?;
+    Some(Change {
+        group: group.to_string(),
+        version: version.parse().ok()?,
+    })
+}
+
+fn message(row: &Row) -> Result<Message, PostgresLogError> {
+    let timestamp: String = row.get("timestamp");
+    Ok(Message {
+        timestamp: Timestamp::parse(&timestamp).ok_or(PostgresLogError::BadTimestamp(timestamp))?,
+        dataset: row.get("dataset"),
+        row: row.get("row_id"),
+        column: row.get("column_name"),
+        value: row.get("value"),
+    })
+}
+
+impl SharedLog for PostgresLog {
+    type Error = PostgresLogError;
+
+    async fn read(&self, group: &str, after: u64) -> Result<(Vec<Message>, u64), Self::Error> {
+        let client = self.client.lock().await;
+        let rows = client
+            .query(
+                "SELECT seq, timestamp, dataset, row_id, column_name, value
+                 FROM markle_messages WHERE group_id = $1 AND seq > $2 ORDER BY seq",
+                &[&group, &(after as i64)],
+            )
+            .await?;
+        let version = rows
+            .last()
+            .map_or(after, |row| row.get::<_, i64>("seq") as u64);
+        let messages = rows.iter().map(message).collect::<Result<_, _>>()?;
+        Ok((messages, version))
+    }
+
+    async fn append(
+        &self,
+        group: &str,
+        expected: u64,
+        messages: &[Message],
+    ) -> Result<Option<u64>, Self::Error> {
+        let mut client = self.client.lock().await;
+        let tx = client.transaction().await?;
+        // Held to commit or rollback, so appends to one group take turns
+        tx.execute("SELECT pg_advisory_xact_lock(hashtext($1))", &[&group])
+            .await?;
+        let version: i64 = tx
+            .query_one(
+                "SELECT COALESCE(MAX(seq), 0) FROM markle_messages WHERE group_id = $1",
+                &[&group],
+            )
+            .await?
+            .get(0);
+        if version as u64 != expected {
+            // Dropping the transaction rolls it back
+            return Ok(None);
+        }
+        let insert = tx
+            .prepare(
+                "INSERT INTO markle_messages
+                 (group_id, seq, timestamp, dataset, row_id, column_name, value)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7)",
+            )
+            .await?;
+        for (seq, message) in (version + 1..).zip(messages) {
+            tx.execute(
+                &insert,
+                &[
+                    &group,
+                    &seq,
+                    &message.timestamp.to_string(),
+                    &message.dataset,
+                    &message.row,
+                    &message.column,
+                    &message.value,
+                ],
+            )
+            .await?;
+        }
+        let version = version as u64 + messages.len() as u64;
+        // Delivered to listeners only once the transaction commits
+        tx.execute(
+            "SELECT pg_notify($1, $2)",
+            &[&CHANNEL, &format!("{}:{}", version, group)],
+        )
+        .await?;
+        tx.commit().await?;
+        Ok(Some(version))
+    }
+
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        Some(self.changes.subscribe())
+    }
+}
+
+// Errors related to the shared log in Postgres
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum PostgresLogError {
+    Postgres(tokio_postgres::Error),
+    // A row whose timestamp doesn't parse
+    BadTimestamp(String),
+}
+
+impl From<tokio_postgres::Error> for PostgresLogError {
+    fn from(err: tokio_postgres::Error) -> Self {
+        PostgresLogError::Postgres(err)
+    }
+}
+
+impl fmt::Display for PostgresLogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PostgresLogError::Postgres(err) => write!(f, "postgres: {}", err),
+            PostgresLogError::BadTimestamp(timestamp) => {
+                write!(f, "bad timestamp in shared log: {}", timestamp)
+            }
+        }
+    }
+}
+
+impl core::error::Error for PostgresLogError {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_change() {
+        assert_eq!(
+            parse_change("12:family:one"),
+            Some(Change {
+                group: "family:one".to_string(),
+                version: 12
+            })
+        );
+        assert_eq!(parse_change("family"), None);
+        assert_eq!(parse_change("x:family"), None);
+    }
+}
@@ -0,0 +1,34 @@
+// The names most applications need, stable across minor releases:
+// `use markle::prelude::*;`
+
+#[cfg(feature = "std")]
+pub use crate::bundle::{BundleError, SyncBundle};
+#[cfg(feature = "std")]
+pub use crate::clock::{
+    ClockState, ClockStore, DriftStats, FileClockStore, LinearSmear, NoSmear, OffsetEstimator,
+    PhysicalTime, Smear, SmoothedClock, SystemClock, TimeSource,
+};
+#[cfg(feature = "crypto")]
+pub use crate::crypto::{CryptoError, Keyring};
+#[cfg(feature = "std")]
+pub use crate::engine::{Engine, Identity, Snapshot, WriteError};
+pub use crate::error::SyncError;
+#[cfg(feature = "sync")]
+pub use crate::gossip::{Gossip, GossipRound};
+#[cfg(feature = "std")]
+pub use crate::limit::{LimitError, OversizePolicy, SizeLimit};
+#[cfg(feature = "sync")]
+pub use crate::manager::{PeerRound, SyncManager};
+pub use crate::message::Message;
+pub use crate::segment::TrieSet;
+pub use crate::store::{MemoryStore, MessageStore, MessageStoreExt, StoreError};
+#[cfg(feature = "sync")]
+pub use crate::sync::{Capabilities, Remedy, SyncFailure, SyncSession, Transport};
+pub use crate::table::{Cell, Conflict, ConflictResolver, Table, TableStore};
+pub use crate::timestamp::{
+    ClockError, CounterOverflow, Epoch, ParseError, Timestamp, TimestampError,
+};
+pub use crate::trie::{
+    Divergence, HashScheme, Resolution, TimestampHasher, Trie, TrieDelta, TrieError, TrieStats,
+};
+pub use crate::version::NodeVersionMap;
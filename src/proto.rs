@@ -0,0 +1,270 @@
+// Protobuf types for the sync protocol, mirroring proto/markle.proto field
+// for field. They're written out rather than generated so building the
+// crate doesn't need protoc; change both together. Peers in other languages
+// generate theirs from the .proto.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::codec::DecodeError;
+use crate::message;
+use crate::timestamp;
+use crate::trie;
+
+/// The schema these types follow
+pub const SCHEMA: &str = include_str!("../proto/markle.proto");
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Timestamp {
+    #[prost(int64, tag = "1")]
+    pub millis: i64,
+    #[prost(uint32, tag = "2")]
+    pub counter: u32,
+    #[prost(string, tag = "3")]
+    pub node: String,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Message {
+    #[prost(message, optional, tag = "1")]
+    pub timestamp: Option<Timestamp>,
+    #[prost(string, tag = "2")]
+    pub dataset: String,
+    #[prost(string, tag = "3")]
+    pub row: String,
+    #[prost(string, tag = "4")]
+    pub column: String,
+    #[prost(string, tag = "5")]
+    pub value: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum HashScheme {
+    Unspecified = 0,
+    Murmur32Xor = 1,
+    Xxh64Add = 2,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum Resolution {
+    Minute = 0,
+    Second = 1,
+    Hour = 2,
+    Day = 3,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TrieNode {
+    #[prost(uint64, tag = "1")]
+    pub hash: u64,
+    #[prost(message, repeated, tag = "2")]
+    pub children: Vec<TrieChild>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct TrieChild {
+    #[prost(uint32, tag = "1")]
+    pub digit: u32,
+    #[prost(message, optional, tag = "2")]
+    pub node: Option<TrieNode>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Trie {
+    #[prost(enumeration = "HashScheme", tag = "1")]
+    pub scheme: i32,
+    #[prost(enumeration = "Resolution", tag = "2")]
+    pub resolution: i32,
+    #[prost(message, optional, tag = "3")]
+    pub root: Option<TrieNode>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SyncRequest {
+    #[prost(message, optional, tag = "1")]
+    pub trie: Option<Trie>,
+    #[prost(message, repeated, tag = "2")]
+    pub messages: Vec<Message>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct SyncResponse {
+    #[prost(oneof = "Reply", tags = "1, 2")]
+    pub reply: Option<Reply>,
+}
+
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Reply {
+    #[prost(message, tag = "1")]
+    Sync(SyncRequest),
+    #[prost(message, tag = "2")]
+    Done(Done),
+}
+
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct Done {}
+
+impl From<&timestamp::Timestamp> for Timestamp {
+    fn from(ts: &timestamp::Timestamp) -> Self {
+        Timestamp {
+            millis: ts.millis(),
+            counter: ts.counter() as u32,
+            node: ts.node().to_string(),
+        }
+    }
+}
+
+impl TryFrom<Timestamp> for timestamp::Timestamp {
+    type Error = DecodeError;
+
+    fn try_from(ts: Timestamp) -> Result<Self, DecodeError> {
+        let counter = u16::try_from(ts.counter)
+            .map_err(|_| DecodeError::InvalidProto(format!("bad counter {}", ts.counter)))?;
+        Ok(timestamp::Timestamp::new(ts.millis, counter, ts.node))
+    }
+}
+
+impl From<&message::Message> for Message {
+    fn from(message: &message::Message) -> Self {
+        Message {
+            timestamp: Some((&message.timestamp).into()),
+            dataset: message.dataset.clone(),
+            row: message.row.clone(),
+            column: message.column.clone(),
+            value: message.value.clone(),
+        }
+    }
+}
+
+impl TryFrom<Message> for message::Message {
+    type Error = DecodeError;
+
+    fn try_from(message: Message) -> Result<Self, DecodeError> {
+        let timestamp = message
+            .timestamp
+            .ok_or_else(|| DecodeError::InvalidProto("message without a timestamp".to_string()))?;
+        Ok(message::Message {
+            timestamp: timestamp.try_into()?,
+            dataset: message.dataset,
+            row: message.row,
+            column: message.column,
+            value: message.value,
+        })
+    }
+}
+
+impl From<&trie::Trie> for Trie {
+    fn from(trie: &trie::Trie) -> Self {
+        trie.to_proto()
+    }
+}
+
+/// Checks what the binary decoder does: a known scheme and resolution,
+/// hashes that fit the scheme, and key digits 0 to 2 at most once each
+impl TryFrom<&Trie> for trie::Trie {
+    type Error = DecodeError;
+
+    fn try_from(trie: &Trie) -> Result<Self, DecodeError> {
+        trie::Trie::from_proto(trie)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::trie::HashScheme as Scheme;
+    use alloc::vec;
+    use prost::Message as _;
+
+    fn ts(millis: i64, counter: u16) -> timestamp::Timestamp {
+        timestamp::Timestamp::new(millis, counter, "1234123412341234".to_string())
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let message = message::Message {
+            timestamp: ts(1711231855000, 3),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: "milk".to_string(),
+        };
+        let bytes = Message::from(&message).encode_to_vec();
+        let decoded = Message::decode(bytes.as_slice()).unwrap();
+        assert_eq!(message::Message::try_from(decoded), Ok(message.clone()));
+
+        for scheme in [Scheme::Murmur32Xor, Scheme::Xxh64Add] {
+            for resolution in [trie::Resolution::Minute, trie::Resolution::Hour] {
+                let mut trie = trie::Trie::with_resolution(scheme, resolution);
+                for i in 0..50 {
+                    trie.insert(ts(1711231855000 + i * 7_919_000, 0));
+                }
+                let request = SyncRequest {
+                    trie: Some((&trie).into()),
+                    messages: vec![(&message).into()],
+                };
+                let decoded = SyncRequest::decode(request.encode_to_vec().as_slice()).unwrap();
+                assert_eq!(decoded, request);
+                let back = trie::Trie::try_from(&decoded.trie.unwrap()).unwrap();
+                assert_eq!(back, trie);
+                assert_eq!(back.encode(), trie.encode());
+                assert_eq!(back.resolution(), resolution);
+            }
+        }
+
+        let empty = trie::Trie::new();
+        assert_eq!(trie::Trie::try_from(&Trie::from(&empty)), Ok(empty));
+
+        let done = SyncResponse {
+            reply: Some(Reply::Done(Done {})),
+        };
+        assert_eq!(
+            SyncResponse::decode(done.encode_to_vec().as_slice()),
+            Ok(done)
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        let wide = Timestamp {
+            millis: 0,
+            counter: 0x10000,
+            node: "1234123412341234".to_string(),
+        };
+        assert_eq!(
+            timestamp::Timestamp::try_from(wide),
+            Err(DecodeError::InvalidProto("bad counter 65536".to_string()))
+        );
+
+        let mut trie = trie::Trie::new();
+        trie.insert(ts(1711231855000, 0));
+        let good = Trie::from(&trie);
+
+        let mut bad = good.clone();
+        bad.scheme = HashScheme::Unspecified as i32;
+        assert_eq!(
+            trie::Trie::try_from(&bad),
+            Err(DecodeError::UnknownHashScheme(0))
+        );
+
+        let mut bad = good.clone();
+        bad.root.as_mut().unwrap().children[0].digit = 3;
+        assert_eq!(
+            trie::Trie::try_from(&bad),
+            Err(DecodeError::InvalidProto("bad digit 3".to_string()))
+        );
+
+        let mut bad = good.clone();
+        let root = bad.root.as_mut().unwrap();
+        root.children.push(root.children[0].clone());
+        assert!(trie::Trie::try_from(&bad).is_err());
+
+        // Murmur hashes are 32 bits
+        let mut bad = good;
+        bad.root.as_mut().unwrap().hash = u64::MAX;
+        assert!(trie::Trie::try_from(&bad).is_err());
+    }
+}
@@ -0,0 +1,168 @@
+// The log several server replicas share. Each replica keeps an engine per
+// group as a cache in front of it: messages are appended to the shared log
+// before a request is answered, and a replica reads what others appended
+// before it serves a group again. Appends name the version they were made
+// against, so two replicas applying the same batch can't both write it.
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+
+use crate::message::Message;
+
+/// How many changes a notification channel buffers for a slow replica
+/// before it misses some and has to reread every group
+pub const CHANGES_CAPACITY: usize = 1024;
+
+/// A group's log reached `version` after another append
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub group: String,
+    pub version: u64,
+}
+
+/// An append-only message log per group, shared by every replica
+///
+/// A group's version is the number of messages appended to it, so a
+/// replica that has read up to some version needs only what follows.
+pub trait SharedLog: Send + Sync + 'static {
+    type Error: fmt::Display + Send;
+
+    /// The messages appended to `group` after version `after`, in order,
+    /// and the version they bring it to
+    fn read(
+        &self,
+        group: &str,
+        after: u64,
+    ) -> impl Future<Output = Result<(Vec<Message>, u64), Self::Error>> + Send;
+
+    /// Append `messages` to `group` if its version is still `expected`,
+    /// returning the new version, or None if another replica appended
+    /// first and nothing was written
+    fn append(
+        &self,
+        group: &str,
+        expected: u64,
+        messages: &[Message],
+    ) -> impl Future<Output = Result<Option<u64>, Self::Error>> + Send;
+
+    /// Announcements of every append, from any replica
+    ///
+    /// With them a replica rereads a group only once told it changed;
+    /// without, the default, it rereads on every request.
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        None
+    }
+}
+
+/// A shared log in memory, for tests and for replicas in one process
+///
+/// Clones share the log.
+#[derive(Clone)]
+pub struct MemoryLog {
+    groups: Arc<Mutex<HashMap<String, Vec<Message>>>>,
+    changes: broadcast::Sender<Change>,
+}
+
+impl Default for MemoryLog {
+    fn default() -> Self {
+        MemoryLog::new()
+    }
+}
+
+impl fmt::Debug for MemoryLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MemoryLog").finish_non_exhaustive()
+    }
+}
+
+impl MemoryLog {
+    pub fn new() -> Self {
+        MemoryLog {
+            groups: Arc::new(Mutex::new(HashMap::new())),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        }
+    }
+
+    /// Every message appended to `group`, in order
+    pub fn messages(&self, group: &str) -> Vec<Message> {
+        let groups = self.groups.lock().unwrap_or_else(|err| err.into_inner());
+        groups.get(group).cloned().unwrap_or_default()
+    }
+}
+
+impl SharedLog for MemoryLog {
+    type Error = Infallible;
+
+    async fn read(&self, group: &str, after: u64) -> Result<(Vec<Message>, u64), Infallible> {
+        let groups = self.groups.lock().unwrap_or_else(|err| err.into_inner());
+        let log = groups.get(group).map_or(&[][..], Vec::as_slice);
+        let after = (after as usize).min(log.len());
+        Ok((log[after..].to_vec(), log.len() as u64))
+    }
+
+    async fn append(
+        &self,
+        group: &str,
+        expected: u64,
+        messages: &[Message],
+    ) -> Result<Option<u64>, Infallible> {
+        let mut groups = self.groups.lock().unwrap_or_else(|err| err.into_inner());
+        let log = groups.entry(group.to_string()).or_default();
+        if log.len() as u64 != expected {
+            return Ok(None);
+        }
+        log.extend_from_slice(messages);
+        let version = log.len() as u64;
+        // Nobody listening is fine
+        let _ = self.changes.send(Change {
+            group: group.to_string(),
+            version,
+        });
+        Ok(Some(version))
+    }
+
+    fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+        Some(self.changes.subscribe())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::timestamp::Timestamp;
+
+    fn message(millis: i64) -> Message {
+        Message {
+            timestamp: Timestamp::new(millis, 0, "1234123412341234".to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: "buy milk".to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_memory_log() {
+        let log = MemoryLog::new();
+        let mut changes = log.changes().unwrap();
+        assert_eq!(log.append("a", 0, &[message(1)]).await, Ok(Some(1)));
+        // Appending against a version that moved on writes nothing
+        assert_eq!(log.append("a", 0, &[message(2)]).await, Ok(None));
+        assert_eq!(log.append("a", 1, &[message(2)]).await, Ok(Some(2)));
+        assert_eq!(log.read("a", 1).await, Ok((vec![message(2)], 2)));
+        assert_eq!(log.read("b", 0).await, Ok((Vec::new(), 0)));
+        assert_eq!(
+            changes.try_recv().unwrap(),
+            Change {
+                group: "a".to_string(),
+                version: 1
+            }
+        );
+        assert_eq!(changes.try_recv().unwrap().version, 2);
+    }
+}
@@ -0,0 +1,329 @@
+// A merkle index split into fixed-length segments of time, each its own
+// shallow trie keyed from the segment's start. One `Trie` needs enough key
+// digits to count every bucket since 1970, and runs out in 2051 at the
+// default depth; segments never run out, and dropping old history is
+// dropping whole segments.
+
+use alloc::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+
+use crate::timestamp::Timestamp;
+use crate::trie::{HashScheme, KeyError, Resolution, Trie, MAX_DEPTH};
+
+/// Key digits per segment unless a set is built with another depth. Twelve
+/// base-3 digits of minutes are 369 days, about a year.
+pub const DEFAULT_SEGMENT_DEPTH: usize = 12;
+
+/// Tries over consecutive spans of time, diffed as one
+///
+/// Segment `n` holds the buckets from `n * 3^depth` up to the next
+/// segment, counted in the set's resolution from the Unix epoch, so peers
+/// with the same scheme, resolution and depth segment alike. Timestamps
+/// before 1970 land in negative segments.
+#[derive(Clone, Debug)]
+pub struct TrieSet {
+    scheme: HashScheme,
+    resolution: Resolution,
+    depth: usize,
+    segments: BTreeMap<i64, Trie>,
+}
+
+impl Default for TrieSet {
+    fn default() -> Self {
+        TrieSet::new()
+    }
+}
+
+impl TrieSet {
+    pub fn new() -> Self {
+        TrieSet::with_depth(HashScheme::default(), DEFAULT_SEGMENT_DEPTH)
+    }
+
+    /// # Panics
+    ///
+    /// If `depth` is zero or greater than `MAX_DEPTH`.
+    pub fn with_depth(scheme: HashScheme, depth: usize) -> Self {
+        assert!(
+            (1..=MAX_DEPTH).contains(&depth),
+            "segment depth must be between 1 and {}",
+            MAX_DEPTH
+        );
+        TrieSet {
+            scheme,
+            resolution: Resolution::default(),
+            depth,
+            segments: BTreeMap::new(),
+        }
+    }
+
+    /// Bucket by `resolution` rather than by minute, keeping the depth
+    ///
+    /// # Panics
+    ///
+    /// If anything was inserted already, or the depth is greater than the
+    /// resolution's `max_depth`.
+    pub fn at_resolution(mut self, resolution: Resolution) -> Self {
+        assert!(self.segments.is_empty(), "trie set is not empty");
+        assert!(
+            self.depth <= resolution.max_depth(),
+            "{:?} segments can be at most {} deep",
+            resolution,
+            resolution.max_depth()
+        );
+        self.resolution = resolution;
+        self
+    }
+
+    pub fn scheme(&self) -> HashScheme {
+        self.scheme
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Milliseconds each segment covers
+    pub fn segment_millis(&self) -> i64 {
+        self.span() * self.resolution.millis()
+    }
+
+    // Buckets per segment
+    fn span(&self) -> i64 {
+        3i64.pow(self.depth as u32)
+    }
+
+    fn segment_start(&self, segment: i64) -> DateTime<Utc> {
+        let millis = segment.saturating_mul(self.segment_millis());
+        DateTime::from_timestamp_millis(millis).unwrap_or(DateTime::<Utc>::MIN_UTC)
+    }
+
+    // The segment holding `millis`, and its bucket within it
+    fn locate(&self, millis: i64) -> (i64, i64) {
+        let bucket = millis.div_euclid(self.resolution.millis());
+        (
+            bucket.div_euclid(self.span()),
+            bucket.rem_euclid(self.span()),
+        )
+    }
+
+    fn empty_segment(&self) -> Trie {
+        Trie::with_depth(self.scheme, self.depth).at_resolution(self.resolution)
+    }
+
+    /// # Panics
+    ///
+    /// If the timestamp's time can't be represented; see `try_insert`.
+    pub fn insert(&mut self, timestamp: Timestamp) {
+        self.insert_with(timestamp, 0)
+    }
+
+    pub fn try_insert(&mut self, timestamp: Timestamp) -> Result<(), KeyError> {
+        self.try_insert_with(timestamp, 0)
+    }
+
+    /// # Panics
+    ///
+    /// If the timestamp's time can't be represented; see
+    /// `try_insert_with`.
+    pub fn insert_with(&mut self, timestamp: Timestamp, content: u64) {
+        if let Err(err) = self.try_insert_with(timestamp, content) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Insert a timestamp with its hash mixed with `content`, as
+    /// `Trie::try_insert_with` does
+    ///
+    /// There is no end of range; this only errors for times before or
+    /// after what a date can hold, which `diff` couldn't report.
+    pub fn try_insert_with(&mut self, timestamp: Timestamp, content: u64) -> Result<(), KeyError> {
+        if DateTime::from_timestamp_millis(timestamp.millis()).is_none() {
+            return Err(KeyError::OutOfRange(timestamp.millis(), self.depth));
+        }
+        let (segment, bucket) = self.locate(timestamp.millis());
+        let fresh = self.empty_segment();
+        let trie = self.segments.entry(segment).or_insert(fresh);
+        let hash = trie.entry_hash(&timestamp, content);
+        trie.insert_bucket(bucket, hash);
+        Ok(())
+    }
+
+    /// Remove a previously inserted timestamp, dropping its segment if
+    /// that leaves it empty
+    pub fn remove(&mut self, timestamp: &Timestamp) {
+        self.remove_with(timestamp, 0)
+    }
+
+    pub fn remove_with(&mut self, timestamp: &Timestamp, content: u64) {
+        let (segment, bucket) = self.locate(timestamp.millis());
+        let Some(trie) = self.segments.get_mut(&segment) else {
+            return;
+        };
+        let hash = trie.entry_hash(timestamp, content);
+        trie.remove_bucket(bucket, hash);
+        if trie.is_empty() {
+            self.segments.remove(&segment);
+        }
+    }
+
+    /// Every inserted timestamp's hash folded together, the same as one
+    /// `Trie` holding them all would have
+    pub fn root_hash(&self) -> u64 {
+        self.segments
+            .values()
+            .fold(0, |acc, trie| self.scheme.combine(acc, trie.root_hash()))
+    }
+
+    /// How many buckets hold anything, across every segment
+    pub fn len(&self) -> usize {
+        self.segments.values().map(Trie::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Each non-empty segment's start and trie, oldest first
+    ///
+    /// A segment's keys count from its own start, so its trie's
+    /// `diff` and `iter_minutes` times are offsets from 1970 to add to it.
+    pub fn segments(&self) -> impl Iterator<Item = (DateTime<Utc>, &Trie)> + '_ {
+        self.segments
+            .iter()
+            .map(|(segment, trie)| (self.segment_start(*segment), trie))
+    }
+
+    /// Drop every segment that ends at or before `time`, returning how
+    /// many went
+    ///
+    /// Peers must prune alike, or they will diff at the oldest segment
+    /// one side still has.
+    pub fn prune_before(&mut self, time: DateTime<Utc>) -> usize {
+        let (keep, _) = self.locate(time.timestamp_millis());
+        let before = self.segments.len();
+        self.segments = self.segments.split_off(&keep);
+        before - self.segments.len()
+    }
+
+    /// The start of the first bucket the two sets disagree on, as
+    /// `Trie::diff` finds it, or None if they hold the same timestamps
+    ///
+    /// Segments are compared by root hash, oldest first, and only the
+    /// first that differs is walked.
+    ///
+    /// # Panics
+    ///
+    /// If the sets' schemes, resolutions or depths differ.
+    pub fn diff(&self, other: &TrieSet) -> Option<DateTime<Utc>> {
+        assert!(
+            self.scheme == other.scheme
+                && self.resolution == other.resolution
+                && self.depth == other.depth,
+            "trie sets are segmented differently"
+        );
+        let empty = self.empty_segment();
+        let mut ours = self.segments.iter().peekable();
+        let mut theirs = other.segments.iter().peekable();
+        loop {
+            let (segment, a, b) = match (ours.peek(), theirs.peek()) {
+                (None, None) => return None,
+                (Some((x, _)), Some((y, _))) if x == y => {
+                    let (segment, a) = ours.next()?;
+                    (*segment, a, theirs.next()?.1)
+                }
+                (Some((x, _)), Some((y, _))) if x > y => {
+                    let (segment, b) = theirs.next()?;
+                    (*segment, &empty, b)
+                }
+                (Some(_), _) => {
+                    let (segment, a) = ours.next()?;
+                    (*segment, a, &empty)
+                }
+                (None, Some(_)) => {
+                    let (segment, b) = theirs.next()?;
+                    (*segment, &empty, b)
+                }
+            };
+            if let Some(bucket) = a.diff_bucket(b) {
+                let millis = (segment * self.span() + bucket) * self.resolution.millis();
+                return DateTime::from_timestamp_millis(millis);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+    use alloc::vec::Vec;
+
+    const MINUTE: i64 = 60_000;
+
+    fn ts(minute: i64) -> Timestamp {
+        Timestamp::new(minute * MINUTE, 0, "1234123412341234".to_string())
+    }
+
+    #[test]
+    fn test_matches_trie() {
+        // Minutes spread over a couple of dozen segments, 2023 to 2046
+        let minutes: Vec<i64> = (0..300).map(|i| 28_000_000 + i * 40_009).collect();
+        let mut set = TrieSet::new();
+        let mut trie = Trie::new();
+        for minute in &minutes {
+            set.insert(ts(*minute));
+            trie.insert(ts(*minute));
+        }
+        assert!(set.segments().count() > 20);
+        assert_eq!(set.len(), trie.len());
+        assert_eq!(set.root_hash(), trie.root_hash());
+        assert_eq!(set.diff(&set.clone()), None);
+
+        for skip in [0, 1, 150, 299] {
+            let mut fewer = set.clone();
+            let mut fewer_trie = trie.clone();
+            fewer.remove(&ts(minutes[skip]));
+            fewer_trie.remove(&ts(minutes[skip]));
+            assert_eq!(set.diff(&fewer), trie.diff(&fewer_trie));
+            assert_eq!(fewer.diff(&set), fewer_trie.diff(&trie));
+            assert_eq!(
+                set.diff(&fewer).unwrap().timestamp_millis(),
+                minutes[skip] * MINUTE
+            );
+        }
+        assert_eq!(set.diff(&TrieSet::new()), trie.diff(&Trie::new()));
+    }
+
+    #[test]
+    fn test_unbounded() {
+        let mut set = TrieSet::new();
+        let span = set.segment_millis() / MINUTE;
+        // Past the end of a default-depth trie, and before 1970
+        let late = 3i64.pow(16) + 5;
+        assert!(Trie::new().try_insert(ts(late)).is_err());
+        set.insert(ts(late));
+        set.insert(ts(-3));
+        set.insert(ts(span));
+        assert_eq!(set.segments().count(), 3);
+        assert_eq!(
+            set.diff(&TrieSet::new()).unwrap().timestamp_millis(),
+            -3 * MINUTE
+        );
+        assert!(set.try_insert(ts(i64::MAX / MINUTE)).is_err());
+
+        let pruned = set.prune_before(DateTime::from_timestamp_millis(span * MINUTE).unwrap());
+        assert_eq!(pruned, 1);
+        let mut other = TrieSet::new();
+        other.insert(ts(span));
+        assert_eq!(set.diff(&other).unwrap().timestamp_millis(), late * MINUTE);
+
+        set.remove(&ts(late));
+        assert_eq!(set.diff(&other), None);
+        assert_eq!(set.segments().count(), 1);
+    }
+}
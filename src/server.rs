@@ -0,0 +1,570 @@
+use std::collections::{BTreeSet, HashMap};
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::extract::{Query, State};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware;
+use axum::response::{IntoResponse, Response};
+use axum::routing::post;
+use axum::Router;
+use chrono::Utc;
+
+use crate::clock::{NoSmear, Smear, SmoothedClock};
+use crate::engine::Engine;
+use crate::message::Message;
+use crate::replica::{Change, SharedLog};
+use crate::store::MessageStore;
+use crate::sync::{Capabilities, SyncSession, CAPABILITIES_HEADER, CLOCK_HEADER, TIME_HEADER};
+use crate::timestamp::Timestamp;
+use crate::trie::Trie;
+
+/// A router serving `POST /sync?group=...`, the endpoint `HttpSyncClient`
+/// and the `markle` binary talk to
+///
+/// Each group gets its own engine, opened with `open` the first time the
+/// group is seen and kept for the life of the router. A request body is
+/// one sync frame: the peer's trie plus any messages it is pushing. They
+/// are applied, and the response is our trie plus the messages the peer
+/// is missing, or 204 once the two agree. Responses carry the server's
+/// wall time and the group's clock in the `TIME_HEADER` and
+/// `CLOCK_HEADER` headers, and the capabilities agreed for the request,
+/// those the client offered in `CAPABILITIES_HEADER` that this build
+/// supports, in that header.
+///
+/// Engines keep their trie, clock and snapshot state in memory and assume
+/// they are the store's only writer; to run several replicas over one
+/// database, use `replicated_router`.
+///
+/// ```no_run
+/// # async fn serve() -> std::io::Result<()> {
+/// use markle::engine::Engine;
+/// use markle::store::MemoryStore;
+/// use markle::timestamp::{make_client_id, Timestamp};
+///
+/// let app = markle::server::router(|_group| {
+///     Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new())
+///         .expect("an empty store indexes")
+/// });
+/// let listener = tokio::net::TcpListener::bind("0.0.0.0:8006").await?;
+/// axum::serve(listener, app).await
+/// # }
+/// ```
+pub fn router<S, F>(open: F) -> Router
+where
+    S: MessageStore + Send + 'static,
+    F: Fn(&str) -> Engine<S> + Send + Sync + 'static,
+{
+    router_with_smear(open, NoSmear)
+}
+
+/// `router`, reading the clock through `smear`
+///
+/// For long-running servers, where an NTP correction stepping the clock
+/// would otherwise reject every client's writes as drifted until the
+/// group's clocks catch up; see `LinearSmear`.
+pub fn router_with_smear<S, F, M>(open: F, smear: M) -> Router
+where
+    S: MessageStore + Send + 'static,
+    F: Fn(&str) -> Engine<S> + Send + Sync + 'static,
+    M: Smear + Send + 'static,
+{
+    let groups = Arc::new(Groups {
+        engines: Mutex::new(HashMap::new()),
+        open,
+        clock: Mutex::new(SmoothedClock::new(smear)),
+    });
+    Router::new()
+        .route("/sync", post(handle_sync::<S, F, M>))
+        .layer(middleware::map_response(stamp_time))
+        .with_state(groups)
+}
+
+struct Groups<S, F, M> {
+    engines: Mutex<HashMap<String, Engine<S>>>,
+    open: F,
+    clock: Mutex<SmoothedClock<M>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct SyncQuery {
+    group: String,
+}
+
+async fn handle_sync<S, F, M>(
+    State(groups): State<Arc<Groups<S, F, M>>>,
+    Query(query): Query<SyncQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response
+where
+    S: MessageStore + Send + 'static,
+    F: Fn(&str) -> Engine<S> + Send + Sync + 'static,
+    M: Smear + Send + 'static,
+{
+    let phys = groups
+        .clock
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .now();
+    let mut engines = groups.engines.lock().unwrap_or_else(|err| err.into_inner());
+    let engine = engines
+        .entry(query.group)
+        .or_insert_with_key(|group| (groups.open)(group));
+
+    let (response, _) = answer(engine, &headers, &body, phys);
+    response
+}
+
+// Handle one frame in a fresh session, returning the response and the
+// messages it applied
+//
+// Frames are self-contained, so a fresh session per request is enough,
+// with the client's offer renegotiated on each.
+fn answer<S: MessageStore>(
+    engine: &mut Engine<S>,
+    headers: &HeaderMap,
+    body: &[u8],
+    phys: i64,
+) -> (Response, Vec<Message>) {
+    let offered = headers
+        .get(CAPABILITIES_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map_or(Capabilities::empty(), Capabilities::from_header);
+    let mut session = SyncSession::new(engine);
+    let agreed = session.negotiate(offered);
+    let mut response = match session.handle_at(body, phys) {
+        Ok(Some(reply)) => {
+            ([(header::CONTENT_TYPE, "application/octet-stream")], reply).into_response()
+        }
+        Ok(None) => StatusCode::NO_CONTENT.into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+    };
+    let applied = session.applied().to_vec();
+
+    if let Ok(clock) = HeaderValue::from_str(&engine.clock().to_string()) {
+        response.headers_mut().insert(CLOCK_HEADER, clock);
+    }
+    if let Ok(agreed) = HeaderValue::from_str(&agreed.to_header()) {
+        response.headers_mut().insert(CAPABILITIES_HEADER, agreed);
+    }
+    (response, applied)
+}
+
+/// `router` for one of several replicas sharing `log`
+///
+/// Each group's engine, opened with `open`, is only a cache of the shared
+/// log. Before a replica handles a frame for a group it reads whatever
+/// other replicas appended since it last did, and before it answers it
+/// appends the messages the frame brought, against the version it read.
+/// If another replica appended in between, the append is refused; the
+/// replica reads what was written, drops the messages it already holds and
+/// appends the rest. A batch two replicas receive at once is so written
+/// once, and every replica's trie ends up equal to one built from the log.
+///
+/// A log offering `SharedLog::changes`, as Postgres does with LISTEN and
+/// NOTIFY, spares replicas reading it for groups nobody else wrote. If the
+/// log fails, the group's cached engine is dropped, to be rebuilt from the
+/// log on its next request, and the client gets 503 and retries.
+pub fn replicated_router<L, S, F>(log: L, open: F) -> Router
+where
+    L: SharedLog,
+    S: MessageStore + Send + 'static,
+    F: Fn(&str) -> Engine<S> + Send + Sync + 'static,
+{
+    let changes = log.changes();
+    let replicas = Arc::new(Replicas {
+        log,
+        open,
+        clock: Mutex::new(SmoothedClock::new(NoSmear)),
+        state: Mutex::new(ReplicaState {
+            groups: HashMap::new(),
+            listening: changes.is_some(),
+            changes,
+            announced: HashMap::new(),
+            epoch: 0,
+        }),
+    });
+    Router::new()
+        .route("/sync", post(handle_replicated::<L, S, F>))
+        .layer(middleware::map_response(stamp_time))
+        .with_state(replicas)
+}
+
+struct Replicas<L, S, F> {
+    log: L,
+    open: F,
+    clock: Mutex<SmoothedClock<NoSmear>>,
+    state: Mutex<ReplicaState<S>>,
+}
+
+struct ReplicaState<S> {
+    groups: HashMap<String, Arc<tokio::sync::Mutex<Replica<S>>>>,
+    changes: Option<tokio::sync::broadcast::Receiver<Change>>,
+    listening: bool,
+    // The latest version announced for each group
+    announced: HashMap<String, u64>,
+    // Bumped whenever announcements were missed, so every group is reread
+    epoch: u64,
+}
+
+impl<S> ReplicaState<S> {
+    fn drain_changes(&mut self) {
+        use tokio::sync::broadcast::error::TryRecvError;
+
+        let Some(changes) = &mut self.changes else {
+            return;
+        };
+        loop {
+            match changes.try_recv() {
+                Ok(change) => {
+                    let version = self.announced.entry(change.group).or_default();
+                    *version = (*version).max(change.version);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Lagged(missed)) => {
+                    log_warn!(
+                        "missed {} shared log changes, rereading every group",
+                        missed
+                    );
+                    self.announced.clear();
+                    self.epoch += 1;
+                }
+                Err(TryRecvError::Closed) => {
+                    log_warn!("shared log stopped announcing changes, rereading on every request");
+                    self.changes = None;
+                    self.listening = false;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+struct Replica<S> {
+    engine: Engine<S>,
+    // How far into the shared log the engine has read
+    version: u64,
+    // The epoch it last read in, None before it first has
+    epoch: Option<u64>,
+}
+
+async fn handle_replicated<L, S, F>(
+    State(replicas): State<Arc<Replicas<L, S, F>>>,
+    Query(query): Query<SyncQuery>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response
+where
+    L: SharedLog,
+    S: MessageStore + Send + 'static,
+    F: Fn(&str) -> Engine<S> + Send + Sync + 'static,
+{
+    let phys = replicas
+        .clock
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .now();
+    let group = query.group;
+    let (replica, announced, epoch, listening) = {
+        let mut state = replicas.state.lock().unwrap_or_else(|err| err.into_inner());
+        state.drain_changes();
+        let replica = state
+            .groups
+            .entry(group.clone())
+            .or_insert_with(|| {
+                Arc::new(tokio::sync::Mutex::new(Replica {
+                    engine: (replicas.open)(&group),
+                    version: 0,
+                    epoch: None,
+                }))
+            })
+            .clone();
+        let announced = state.announced.get(&group).copied();
+        (replica, announced, state.epoch, state.listening)
+    };
+    // Requests for one group take turns; other groups carry on
+    let mut replica = replica.lock().await;
+
+    let stale = !listening
+        || replica.epoch != Some(epoch)
+        || announced.is_some_and(|version| version > replica.version);
+    if stale {
+        if let Err(err) = catch_up(&replicas.log, &group, &mut replica, phys).await {
+            return unavailable(&replicas, &group, err);
+        }
+        replica.epoch = Some(epoch);
+    }
+
+    let (response, mut pending) = answer(&mut replica.engine, &headers, &body, phys);
+    while !pending.is_empty() {
+        match replicas.log.append(&group, replica.version, &pending).await {
+            Ok(Some(version)) => {
+                replica.version = version;
+                counter!("markle_replica_appends_total", 1, "outcome" => "written");
+                break;
+            }
+            Ok(None) => {
+                debug!(group = %group, "shared log moved on, rereading before append");
+                counter!("markle_replica_appends_total", 1, "outcome" => "conflict");
+                match catch_up(&replicas.log, &group, &mut replica, phys).await {
+                    Ok(written) => pending.retain(|message| !written.contains(&message.timestamp)),
+                    Err(err) => return unavailable(&replicas, &group, err),
+                }
+            }
+            Err(err) => return unavailable(&replicas, &group, err.to_string()),
+        }
+    }
+    response
+}
+
+// Apply what the shared log holds past the replica's version, returning
+// the timestamps read
+async fn catch_up<L: SharedLog, S: MessageStore>(
+    log: &L,
+    group: &str,
+    replica: &mut Replica<S>,
+    phys: i64,
+) -> Result<BTreeSet<Timestamp>, String> {
+    let (messages, version) = log
+        .read(group, replica.version)
+        .await
+        .map_err(|err| err.to_string())?;
+    let written = messages
+        .iter()
+        .map(|message| message.timestamp.clone())
+        .collect();
+    // An empty remote trie, so no minute is skipped as already known
+    replica
+        .engine
+        .apply_batch(messages, &Trie::new(), phys)
+        .map_err(|err| err.to_string())?;
+    replica.version = version;
+    Ok(written)
+}
+
+// Drop the group's cached engine, which may now be behind the log in ways
+// its version doesn't show, and tell the client to retry
+fn unavailable<L, S, F>(replicas: &Replicas<L, S, F>, group: &str, err: String) -> Response {
+    log_error!("shared log failed for group {}: {}", group, err);
+    let mut state = replicas.state.lock().unwrap_or_else(|err| err.into_inner());
+    state.groups.remove(group);
+    (StatusCode::SERVICE_UNAVAILABLE, err).into_response()
+}
+
+// Every response carries our wall time, rejections included, so clients
+// can tell their own skew from ours
+async fn stamp_time(mut response: Response) -> Response {
+    let now = HeaderValue::from(Utc::now().timestamp_millis());
+    response.headers_mut().insert(TIME_HEADER, now);
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use axum::body::Body;
+    use axum::http::Request;
+    use tower::ServiceExt;
+
+    use crate::replica::MemoryLog;
+    use crate::store::MemoryStore;
+    use crate::timestamp::make_client_id;
+    use std::convert::Infallible;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::sync::broadcast;
+
+    fn engine() -> Engine<MemoryStore> {
+        Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap()
+    }
+
+    async fn post(app: &Router, uri: &str, frame: Vec<u8>) -> (StatusCode, Vec<u8>) {
+        let request = Request::post(uri).body(Body::from(frame)).unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let time = response.headers()[TIME_HEADER].to_str().unwrap();
+        assert!(time.parse::<i64>().is_ok());
+        if let Some(clock) = response.headers().get(CLOCK_HEADER) {
+            assert!(Timestamp::parse(clock.to_str().unwrap()).is_some());
+        }
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, body.to_vec())
+    }
+
+    #[tokio::test]
+    async fn test_sync_round_trip() {
+        let now = Utc::now().timestamp_millis();
+        let app = router(move |group| {
+            let mut engine = engine();
+            engine
+                .write("todos", group, "title", "seeded", now)
+                .unwrap();
+            engine
+        });
+
+        let mut client = engine();
+        client
+            .write("todos", "2", "title", "buy eggs", now)
+            .unwrap();
+        let mut session = SyncSession::new(&mut client);
+        let mut frame = session.start().unwrap();
+        loop {
+            let (status, reply) = post(&app, "/sync?group=family", frame).await;
+            if status == StatusCode::NO_CONTENT {
+                break;
+            }
+            assert_eq!(status, StatusCode::OK);
+            match session.handle(&reply).unwrap() {
+                Some(next) => frame = next,
+                None => break,
+            }
+        }
+        assert!(session.is_converged());
+        assert_eq!(session.applied().len(), 1);
+        assert_eq!(session.applied()[0].row, "family");
+
+        // A second pass finds nothing to do
+        let frame = SyncSession::new(&mut client).start().unwrap();
+        let (status, reply) = post(&app, "/sync?group=family", frame).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(SyncSession::new(&mut client).handle(&reply).unwrap(), None);
+
+        let (status, _) = post(&app, "/sync?group=family", vec![9]).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = post(&app, "/sync", Vec::new()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    // Sync `client` with `app` to the end, returning how many messages it
+    // took from the server
+    async fn sync(app: &Router, client: &mut Engine<MemoryStore>) -> usize {
+        let mut session = SyncSession::new(client);
+        let mut frame = session.start().unwrap();
+        loop {
+            let (status, reply) = post(app, "/sync?group=family", frame).await;
+            if status == StatusCode::NO_CONTENT {
+                break;
+            }
+            assert_eq!(status, StatusCode::OK);
+            match session.handle(&reply).unwrap() {
+                Some(next) => frame = next,
+                None => break,
+            }
+        }
+        assert!(session.is_converged());
+        session.applied().len()
+    }
+
+    // A shared log that yields before every read and append, so replicas
+    // serving requests at once interleave at each step
+    #[derive(Clone, Default)]
+    struct Interleaved {
+        log: MemoryLog,
+        listen: bool,
+        conflicts: Arc<AtomicUsize>,
+    }
+
+    impl SharedLog for Interleaved {
+        type Error = Infallible;
+
+        async fn read(&self, group: &str, after: u64) -> Result<(Vec<Message>, u64), Infallible> {
+            tokio::task::yield_now().await;
+            self.log.read(group, after).await
+        }
+
+        async fn append(
+            &self,
+            group: &str,
+            expected: u64,
+            messages: &[Message],
+        ) -> Result<Option<u64>, Infallible> {
+            tokio::task::yield_now().await;
+            let version = self.log.append(group, expected, messages).await;
+            if version == Ok(None) {
+                self.conflicts.fetch_add(1, Ordering::SeqCst);
+            }
+            version
+        }
+
+        fn changes(&self) -> Option<broadcast::Receiver<Change>> {
+            self.listen.then(|| self.log.changes()).flatten()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_replicas() {
+        let now = Utc::now().timestamp_millis();
+        for listen in [false, true] {
+            let log = Interleaved {
+                listen,
+                ..Interleaved::default()
+            };
+            let a = replicated_router(log.clone(), |_group| engine());
+            let b = replicated_router(log.clone(), |_group| engine());
+
+            let mut client = engine();
+            for row in ["1", "2", "3"] {
+                client
+                    .write("todos", row, "title", "buy milk", now)
+                    .unwrap();
+            }
+            // The frame pushing the client's messages to an empty server
+            let mut session = SyncSession::new(&mut client);
+            let (_, reply) = post(
+                &router(|_group| engine()),
+                "/sync?group=family",
+                session.start().unwrap(),
+            )
+            .await;
+            let push = session.handle(&reply).unwrap().unwrap();
+            drop(session);
+
+            // Both replicas get the same batch at once; one appends it, the
+            // other finds it written and appends nothing
+            let ((status_a, _), (status_b, _)) = tokio::join!(
+                post(&a, "/sync?group=family", push.clone()),
+                post(&b, "/sync?group=family", push),
+            );
+            assert_eq!(status_a, StatusCode::OK);
+            assert_eq!(status_b, StatusCode::OK);
+            assert_eq!(log.conflicts.load(Ordering::SeqCst), 1);
+            let written = log.log.messages("family");
+            assert_eq!(written.len(), 3);
+            let timestamps: BTreeSet<_> = written.iter().map(|m| &m.timestamp).collect();
+            assert_eq!(timestamps.len(), 3);
+
+            // Every replica, and one started after, serves the client's trie
+            let c = replicated_router(log.clone(), |_group| engine());
+            for app in [&a, &b, &c] {
+                assert_eq!(sync(app, &mut client).await, 0);
+                let mut fresh = engine();
+                assert_eq!(sync(app, &mut fresh).await, 3);
+                assert_eq!(fresh.trie().unwrap().diff(client.trie().unwrap()), None);
+            }
+
+            // A write through one replica is served by the others
+            let mut other = engine();
+            other.write("todos", "4", "title", "buy eggs", now).unwrap();
+            sync(&c, &mut other).await;
+            assert_eq!(sync(&a, &mut client).await, 1);
+            assert_eq!(log.log.messages("family").len(), 4);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_header() {
+        let app = router(|_group| engine());
+        let frame = SyncSession::new(&mut engine()).start().unwrap();
+        let request = Request::post("/sync?group=family")
+            .header(CAPABILITIES_HEADER, "compression, teleport")
+            .body(Body::from(frame))
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        // Only what this build supports is agreed to
+        let agreed = response.headers()[CAPABILITIES_HEADER].to_str().unwrap();
+        let want = Capabilities::SUPPORTED & Capabilities::COMPRESSION;
+        assert_eq!(Capabilities::from_header(agreed), want);
+    }
+}
@@ -0,0 +1,318 @@
+// A network of in-memory replicas for convergence tests. Nodes write at
+// random, push their tries to random peers, and answer with what the peer
+// lacks, the same exchange a sync session runs, but over a simulated
+// network that delays, reorders and partitions. Everything is driven by one
+// seed, so a failing run replays exactly.
+
+use std::ops::Range;
+
+use crate::engine::Engine;
+use crate::message::Message;
+use crate::store::MemoryStore;
+use crate::timestamp::{SeededClientIds, Timestamp};
+use crate::trie::Trie;
+
+/// Where simulated time starts, late 2023
+pub const START: i64 = 1_700_000_000_000;
+
+// A trie and the messages its sender thinks the receiver lacks
+#[derive(Debug)]
+struct Packet {
+    from: usize,
+    to: usize,
+    deliver_at: i64,
+    trie: Trie,
+    messages: Vec<Message>,
+}
+
+// Draws from the same splitmix64 stream node ids come from
+#[derive(Debug)]
+struct Rng(SeededClientIds);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    // Uniform enough for tests over small ranges
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    fn range(&mut self, range: &Range<i64>) -> i64 {
+        range.start + self.below((range.end - range.start).max(1) as u64) as i64
+    }
+}
+
+/// Simulated replicas syncing over an unreliable network
+///
+/// ```
+/// use markle::sim::Simulation;
+///
+/// let mut sim = Simulation::new(4, 7).delay(10..500);
+/// sim.write_random(50);
+/// sim.partition(&[0, 1]);
+/// sim.run_rounds(5);
+/// sim.write_random(50);
+/// sim.heal();
+/// assert!(sim.run_until_converged(100).is_some());
+/// ```
+///
+/// Time moves in rounds of `round` milliseconds: each round every node
+/// pushes to one random peer, then the network delivers whatever has
+/// arrived by the round's end, earliest first. Nodes' clocks are off from
+/// simulated time by up to `skew` either way. A rejected apply panics, as
+/// that is the protocol failing rather than the network.
+#[derive(Debug)]
+pub struct Simulation {
+    now: i64,
+    nodes: Vec<Engine<MemoryStore>>,
+    // Each node's clock minus simulated time
+    offsets: Vec<i64>,
+    // Which side of the partition each node is on
+    sides: Vec<bool>,
+    in_flight: Vec<Packet>,
+    delay: Range<i64>,
+    round: i64,
+    rng: Rng,
+    delivered: usize,
+    dropped: usize,
+}
+
+impl Simulation {
+    /// `nodes` empty replicas with clocks skewed by up to a second
+    ///
+    /// # Panics
+    ///
+    /// If `nodes` is less than two.
+    pub fn new(nodes: usize, seed: u64) -> Self {
+        assert!(nodes >= 2, "a simulation needs at least two nodes");
+        let mut rng = Rng(SeededClientIds::new(seed));
+        let engines = (0..nodes)
+            .map(|_| {
+                Engine::new(Timestamp::new(0, 0, rng.0.next_id()), MemoryStore::new())
+                    .expect("an empty store indexes")
+            })
+            .collect();
+        let sim = Simulation {
+            now: START,
+            nodes: engines,
+            offsets: vec![0; nodes],
+            sides: vec![false; nodes],
+            in_flight: Vec::new(),
+            delay: 1..100,
+            round: 1000,
+            rng,
+            delivered: 0,
+            dropped: 0,
+        };
+        sim.skew(1000)
+    }
+
+    /// Redraw every node's clock offset from `-max..=max` milliseconds;
+    /// past the drift limit, applies start failing
+    pub fn skew(mut self, max: i64) -> Self {
+        for offset in &mut self.offsets {
+            *offset = self.rng.range(&(-max..max + 1));
+        }
+        self
+    }
+
+    /// How long each packet takes, in milliseconds, drawn per packet
+    pub fn delay(mut self, delay: Range<i64>) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Milliseconds of simulated time per round
+    pub fn round(mut self, round: i64) -> Self {
+        self.round = round;
+        self
+    }
+
+    pub fn now(&self) -> i64 {
+        self.now
+    }
+
+    pub fn node(&self, index: usize) -> &Engine<MemoryStore> {
+        &self.nodes[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Packets that arrived, and packets lost to a partition
+    pub fn stats(&self) -> (usize, usize) {
+        (self.delivered, self.dropped)
+    }
+
+    // What `index`'s clock reads now
+    fn phys(&self, index: usize) -> i64 {
+        self.now + self.offsets[index]
+    }
+
+    /// Cut `group` off from every other node, dropping packets between the
+    /// two sides, including those already in flight when they arrive
+    pub fn partition(&mut self, group: &[usize]) {
+        for (index, side) in self.sides.iter_mut().enumerate() {
+            *side = group.contains(&index);
+        }
+    }
+
+    pub fn heal(&mut self) {
+        self.sides.fill(false);
+    }
+
+    fn connected(&self, a: usize, b: usize) -> bool {
+        self.sides[a] == self.sides[b]
+    }
+
+    /// Have random nodes write `count` random cells, over a few datasets
+    /// and rows so that writes contend
+    pub fn write_random(&mut self, count: usize) {
+        for _ in 0..count {
+            let node = self.rng.below(self.nodes.len() as u64) as usize;
+            let dataset = ["todos", "notes"][self.rng.below(2) as usize];
+            let row = self.rng.below(8).to_string();
+            let column = ["title", "done"][self.rng.below(2) as usize];
+            let value = self.rng.next_u64().to_string();
+            let phys = self.phys(node);
+            self.nodes[node]
+                .write(dataset, &row, column, &value, phys)
+                .expect("simulated write rejected");
+        }
+    }
+
+    fn send(&mut self, from: usize, to: usize, messages: Vec<Message>) {
+        let deliver_at = self.now + self.rng.range(&self.delay);
+        self.in_flight.push(Packet {
+            from,
+            to,
+            deliver_at,
+            trie: self.nodes[from]
+                .trie()
+                .expect("simulated trie built")
+                .clone(),
+            messages,
+        });
+    }
+
+    // Apply what arrived and send back whatever the sender lacks
+    fn receive(&mut self, packet: Packet) {
+        let Packet {
+            from,
+            to,
+            trie,
+            messages,
+            ..
+        } = packet;
+        if !self.connected(from, to) {
+            self.dropped += 1;
+            return;
+        }
+        self.delivered += 1;
+        let phys = self.phys(to);
+        let node = &mut self.nodes[to];
+        node.apply_batch(messages, &trie, phys)
+            .expect("simulated apply rejected");
+        if let Some(since) = node.trie().expect("simulated trie built").diff(&trie) {
+            let reply = node.messages_since(since.timestamp_millis());
+            self.send(to, from, reply);
+        }
+    }
+
+    /// Run one round: every node pushes its trie to a random peer, then
+    /// time moves on and arrivals are handled in arrival order
+    pub fn step(&mut self) {
+        for from in 0..self.nodes.len() {
+            let offset = 1 + self.rng.below(self.nodes.len() as u64 - 1) as usize;
+            let to = (from + offset) % self.nodes.len();
+            self.send(from, to, Vec::new());
+        }
+
+        self.now += self.round;
+        // Replies sent during delivery may land within the round too
+        loop {
+            let due = self
+                .in_flight
+                .iter()
+                .enumerate()
+                .filter(|(_, packet)| packet.deliver_at <= self.now)
+                .min_by_key(|(_, packet)| packet.deliver_at)
+                .map(|(index, _)| index);
+            let Some(index) = due else {
+                break;
+            };
+            let packet = self.in_flight.swap_remove(index);
+            self.receive(packet);
+        }
+    }
+
+    pub fn run_rounds(&mut self, rounds: usize) {
+        for _ in 0..rounds {
+            self.step();
+        }
+    }
+
+    /// Step until every node holds the same messages, returning how many
+    /// rounds it took, or None if `max_rounds` weren't enough
+    pub fn run_until_converged(&mut self, max_rounds: usize) -> Option<usize> {
+        for rounds in 0..=max_rounds {
+            if self.converged() {
+                return Some(rounds);
+            }
+            if rounds < max_rounds {
+                self.step();
+            }
+        }
+        None
+    }
+
+    /// Whether every node has the same root hash and the same messages
+    pub fn converged(&self) -> bool {
+        let first = &self.nodes[0];
+        let log = first.messages_since(i64::MIN);
+        self.nodes[1..].iter().all(|node| {
+            node.trie().ok().map(Trie::root_hash) == first.trie().ok().map(Trie::root_hash)
+                && node.messages_since(i64::MIN) == log
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_converges() {
+        for seed in 0..8 {
+            let mut sim = Simulation::new(5, seed).delay(1..3000).skew(20_000);
+            for _ in 0..5 {
+                sim.write_random(20);
+                sim.step();
+            }
+            sim.partition(&[0, 1]);
+            sim.write_random(40);
+            sim.run_rounds(10);
+            assert!(!sim.converged());
+            sim.heal();
+            assert!(sim.run_until_converged(200).is_some(), "seed {}", seed);
+
+            let (delivered, dropped) = sim.stats();
+            assert!(delivered > 0 && dropped > 0);
+            // Contended cells settle on the same winner everywhere
+            for row in 0..8 {
+                let row = row.to_string();
+                let cell = sim.node(0).get("todos", &row, "title");
+                for index in 1..sim.len() {
+                    assert_eq!(sim.node(index).get("todos", &row, "title"), cell);
+                }
+            }
+        }
+    }
+}
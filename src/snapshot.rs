@@ -0,0 +1,176 @@
+// Bootstrapping a replica from a peer's state instead of its history.
+//
+// A snapshot holds the message that won each cell, which is all a fresh
+// replica needs to read, plus the peer's trie and the timestamps of every
+// message those winners superseded. The trie lets the replica go straight
+// on to incremental diff sync; the timestamps let it recognise replays of
+// history it never received, so they aren't counted into the trie twice.
+
+use alloc::collections::BTreeSet;
+use alloc::vec::Vec;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::message::Message;
+use crate::table::Table;
+use crate::timestamp::Timestamp;
+use crate::trie::{HashScheme, Trie};
+
+/// A message log's state as of a timestamp
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateSnapshot {
+    /// Nothing newer is covered
+    pub as_of: Timestamp,
+    /// Indexes `messages` and `superseded` alike
+    pub trie: Trie,
+    /// The winning write of every cell, tombstones included
+    pub messages: Vec<Message>,
+    /// Every other message the trie indexes, in timestamp order
+    pub superseded: Vec<Timestamp>,
+}
+
+impl StateSnapshot {
+    /// Snapshot `messages` at or before `as_of`, indexed by timestamp
+    /// alone
+    pub fn build<'a>(
+        messages: impl IntoIterator<Item = &'a Message>,
+        as_of: &Timestamp,
+        scheme: HashScheme,
+    ) -> Self {
+        let mut trie = Trie::with_scheme(scheme);
+        let mut table = Table::new();
+        let mut all = BTreeSet::new();
+        for message in messages {
+            if message.timestamp > *as_of {
+                continue;
+            }
+            trie.insert(message.timestamp.clone());
+            table.apply(message);
+            all.insert(message.timestamp.clone());
+        }
+        StateSnapshot::from_parts(as_of.clone(), trie, &table, all)
+    }
+
+    /// A snapshot of a materialized `table` whose log held `all`, indexed
+    /// by `trie`
+    pub(crate) fn from_parts(
+        as_of: Timestamp,
+        trie: Trie,
+        table: &Table,
+        mut all: BTreeSet<Timestamp>,
+    ) -> Self {
+        let messages: Vec<Message> = table
+            .cells()
+            .map(|(dataset, row, column, cell)| Message {
+                timestamp: cell.timestamp.clone(),
+                dataset: dataset.into(),
+                row: row.into(),
+                column: column.into(),
+                value: cell.value.clone(),
+            })
+            .collect();
+        for message in &messages {
+            all.remove(&message.timestamp);
+        }
+        StateSnapshot {
+            as_of,
+            trie,
+            messages,
+            superseded: all.into_iter().collect(),
+        }
+    }
+
+    /// The table the snapshot's messages materialize
+    pub fn table(&self) -> Table {
+        let mut table = Table::new();
+        for message in &self.messages {
+            table.apply(message);
+        }
+        table
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        self.encode_into(&mut w);
+        w.into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let snapshot = StateSnapshot::decode_from(&mut r)?;
+        r.finish()?;
+        Ok(snapshot)
+    }
+
+    // The trie goes in its saved form, so its depth survives
+    pub(crate) fn encode_into(&self, w: &mut Writer) {
+        self.as_of.encode(w);
+        w.put_bytes(&self.trie.save());
+        w.put_u32(self.messages.len() as u32);
+        for message in &self.messages {
+            message.encode(w);
+        }
+        w.put_u32(self.superseded.len() as u32);
+        for timestamp in &self.superseded {
+            timestamp.encode(w);
+        }
+    }
+
+    pub(crate) fn decode_from(r: &mut Reader) -> Result<Self, DecodeError> {
+        let as_of = Timestamp::decode(r)?;
+        let trie = Trie::load(r.get_bytes()?)?;
+        let mut messages = Vec::new();
+        for _ in 0..r.get_u32()? {
+            messages.push(Message::decode(r)?);
+        }
+        let mut superseded = Vec::new();
+        for _ in 0..r.get_u32()? {
+            superseded.push(Timestamp::decode(r)?);
+        }
+        Ok(StateSnapshot {
+            as_of,
+            trie,
+            messages,
+            superseded,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    fn message(millis: i64, row: &str, value: &str) -> Message {
+        Message {
+            timestamp: Timestamp::new(millis, 0, "aaaaaaaaaaaaaaaa".to_string()),
+            dataset: "todos".to_string(),
+            row: row.to_string(),
+            column: "title".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_snapshot_build() {
+        let log = [
+            message(1000, "1", "buy milk"),
+            message(2000, "1", "buy oat milk"),
+            message(3000, "2", "buy eggs"),
+            message(9000, "2", "too late"),
+        ];
+        let as_of = Timestamp::new(5000, 0, "aaaaaaaaaaaaaaaa".to_string());
+        let snapshot = StateSnapshot::build(&log, &as_of, HashScheme::default());
+
+        let values: Vec<&str> = snapshot.messages.iter().map(|m| m.value.as_str()).collect();
+        assert_eq!(values, ["buy oat milk", "buy eggs"]);
+        assert_eq!(snapshot.superseded, [log[0].timestamp.clone()]);
+        let indexed = log[..3].iter().map(|m| m.timestamp.clone()).collect();
+        assert!(snapshot.trie.deep_eq(&Trie::build(indexed)));
+        assert_eq!(
+            snapshot.table().get("todos", "2", "title").unwrap().value,
+            "buy eggs"
+        );
+
+        assert_eq!(StateSnapshot::decode(&snapshot.encode()), Ok(snapshot));
+    }
+}
@@ -0,0 +1,373 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Bound::{Excluded, Included, Unbounded};
+
+use crate::message::Message;
+use crate::snapshot::StateSnapshot;
+use crate::timestamp::Timestamp;
+use crate::trie::{HashScheme, KeyError, Trie};
+
+/// Storage for the message log
+///
+/// Messages are identified by their timestamp; inserting a message whose
+/// timestamp is already present is a no-op.
+pub trait MessageStore {
+    /// Insert a message, returning false if it was already present
+    fn insert(&mut self, message: Message) -> bool;
+
+    /// Insert many messages at once, returning how many were new
+    ///
+    /// Stores with transactions should override this to write the batch in
+    /// one; the default inserts one at a time.
+    fn insert_batch(&mut self, messages: Vec<Message>) -> usize {
+        messages
+            .into_iter()
+            .map(|message| self.insert(message))
+            .filter(|inserted| *inserted)
+            .count()
+    }
+
+    fn contains(&self, timestamp: &Timestamp) -> bool;
+
+    fn get(&self, timestamp: &Timestamp) -> Option<Message>;
+
+    /// Physically remove a message, returning false if it wasn't present
+    fn remove(&mut self, timestamp: &Timestamp) -> bool;
+
+    /// All messages at or after `millis`, in timestamp order
+    fn messages_since(&self, millis: i64) -> Vec<Message>;
+
+    /// Up to `limit` messages at or after `millis`, in timestamp order,
+    /// starting after `cursor` if given
+    ///
+    /// Pass the last timestamp of one page as the cursor for the next. The
+    /// default reads the whole range and trims it, so stores that can seek
+    /// should override this to keep pages bounded in memory.
+    fn messages_since_page(
+        &self,
+        millis: i64,
+        limit: usize,
+        cursor: Option<&Timestamp>,
+    ) -> Vec<Message> {
+        self.messages_since(millis)
+            .into_iter()
+            .filter(|message| cursor.is_none_or(|cursor| message.timestamp > *cursor))
+            .take(limit)
+            .collect()
+    }
+
+    /// Bytes used by stored messages, as counted by `Message::size`
+    ///
+    /// The default walks the whole log; stores should keep a running total.
+    fn size_bytes(&self) -> usize {
+        self.messages_since(i64::MIN)
+            .iter()
+            .map(Message::size)
+            .sum()
+    }
+
+    /// Give space freed by removed messages back to the system, returning
+    /// how many bytes were reclaimed
+    ///
+    /// Disk-backed stores typically only mark removed rows free; this is
+    /// where they rewrite their files. The default has nothing to do.
+    fn vacuum(&mut self) -> usize {
+        0
+    }
+}
+
+mod sealed {
+    pub trait Sealed {}
+
+    impl<S: super::MessageStore + ?Sized> Sealed for S {}
+}
+
+/// Conveniences over every `MessageStore`
+///
+/// Sealed and blanket-implemented, so methods can be added here in minor
+/// releases without breaking downstream stores.
+pub trait MessageStoreExt: MessageStore + sealed::Sealed {
+    /// The whole log, in timestamp order
+    fn all_messages(&self) -> Vec<Message> {
+        self.messages_since(i64::MIN)
+    }
+
+    /// The log's state as of `as_of`, for bootstrapping a replica; see
+    /// `StateSnapshot`
+    ///
+    /// Replays the whole log. The trie indexes timestamps alone, so
+    /// replicas hashing content should use `Engine::state_snapshot`.
+    fn snapshot(&self, as_of: &Timestamp, scheme: HashScheme) -> StateSnapshot {
+        StateSnapshot::build(&self.all_messages(), as_of, scheme)
+    }
+
+    /// Store `message` and index it in `trie`, unless it is already stored,
+    /// returning whether it was new
+    ///
+    /// Under XOR schemes indexing a timestamp twice cancels it out of the
+    /// trie, so a message delivered twice must only be counted once; this
+    /// checks the store first. Nothing is stored if `trie` can't index the
+    /// timestamp.
+    fn apply(&mut self, trie: &mut Trie, message: Message) -> Result<bool, KeyError> {
+        if self.contains(&message.timestamp) {
+            return Ok(false);
+        }
+        trie.try_insert(message.timestamp.clone())?;
+        Ok(self.insert(message))
+    }
+}
+
+impl<S: MessageStore + ?Sized> MessageStoreExt for S {}
+
+/// Escalating size thresholds for a store, in bytes
+///
+/// Past `warn` the engine logs; past `compact` it compacts deleted rows and
+/// vacuums the store; still past `limit` after that, it reports an error
+/// so the application can tell the user before the device fills up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeBudget {
+    pub warn: usize,
+    pub compact: usize,
+    pub limit: usize,
+}
+
+/// What `Engine::enforce_budget` did
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BudgetStatus {
+    /// Below the warning threshold
+    Within(usize),
+    /// Past the warning threshold, nothing done
+    Warned(usize),
+    /// Compacted and vacuumed, ending at this size
+    Compacted(usize),
+}
+
+// Errors related to store budgets
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum BudgetError {
+    // Size, limit
+    Exceeded(usize, usize),
+}
+
+impl fmt::Display for BudgetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BudgetError::Exceeded(size, limit) => write!(
+                f,
+                "store holds {} bytes after compaction, over its {} byte limit",
+                size, limit
+            ),
+        }
+    }
+}
+
+impl core::error::Error for BudgetError {}
+
+// Errors related to indexing a store's messages
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum StoreError {
+    // A stored timestamp the trie can't index, so the store was written
+    // by something other than an engine, or is corrupt
+    Integrity(KeyError),
+    // The thread building the trie panicked
+    Poisoned,
+}
+
+impl From<KeyError> for StoreError {
+    fn from(err: KeyError) -> Self {
+        StoreError::Integrity(err)
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Integrity(ref err) => {
+                write!(f, "store failed its integrity check: {}", err)
+            }
+            StoreError::Poisoned => write!(f, "trie build panicked"),
+        }
+    }
+}
+
+impl core::error::Error for StoreError {}
+
+#[derive(Clone, Default, Debug)]
+pub struct MemoryStore {
+    messages: BTreeMap<Timestamp, Message>,
+    bytes: usize,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            messages: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+impl MessageStore for MemoryStore {
+    fn insert(&mut self, message: Message) -> bool {
+        if self.messages.contains_key(&message.timestamp) {
+            return false;
+        }
+        self.bytes += message.size();
+        self.messages.insert(message.timestamp.clone(), message);
+        true
+    }
+
+    fn contains(&self, timestamp: &Timestamp) -> bool {
+        self.messages.contains_key(timestamp)
+    }
+
+    fn get(&self, timestamp: &Timestamp) -> Option<Message> {
+        self.messages.get(timestamp).cloned()
+    }
+
+    fn remove(&mut self, timestamp: &Timestamp) -> bool {
+        match self.messages.remove(timestamp) {
+            Some(message) => {
+                self.bytes -= message.size();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn messages_since(&self, millis: i64) -> Vec<Message> {
+        // The empty node id sorts before every other node at the same millis
+        let start = Timestamp::new(millis, 0, String::new());
+        self.messages
+            .range(start..)
+            .map(|(_, m)| m.clone())
+            .collect()
+    }
+
+    fn messages_since_page(
+        &self,
+        millis: i64,
+        limit: usize,
+        cursor: Option<&Timestamp>,
+    ) -> Vec<Message> {
+        let start = Timestamp::new(millis, 0, String::new());
+        let range = match cursor {
+            Some(cursor) if *cursor >= start => self.messages.range((Excluded(cursor), Unbounded)),
+            _ => self.messages.range((Included(&start), Unbounded)),
+        };
+        range.take(limit).map(|(_, m)| m.clone()).collect()
+    }
+
+    fn size_bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(millis: i64, node: &str) -> Message {
+        Message {
+            timestamp: Timestamp::new(millis, 0, node.to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: "buy milk".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_size_bytes() {
+        let mut store = MemoryStore::new();
+        store.insert(message(1, "a"));
+        store.insert(message(1, "a"));
+        store.insert(message(2, "bb"));
+        let walked: usize = store
+            .messages_since(i64::MIN)
+            .iter()
+            .map(Message::size)
+            .sum();
+        assert_eq!(store.size_bytes(), walked);
+
+        store.remove(&Timestamp::new(2, 0, "bb".to_string()));
+        assert_eq!(store.size_bytes(), message(1, "a").size());
+    }
+
+    #[test]
+    fn test_insert_duplicate() {
+        let mut store = MemoryStore::new();
+        assert!(store.insert(message(1, "a")));
+        assert!(!store.insert(message(1, "a")));
+        assert!(store.insert(message(1, "b")));
+        assert_eq!(store.len(), 2);
+    }
+
+    #[test]
+    fn test_apply_duplicate() {
+        let messages = [message(60_000, "a"), message(120_000, "b")];
+        let mut store = MemoryStore::new();
+        let mut trie = Trie::new();
+        for message in messages.iter().chain(&messages) {
+            store.apply(&mut trie, message.clone()).unwrap();
+        }
+        assert_eq!(store.len(), 2);
+        let want = Trie::build(messages.iter().map(|m| m.timestamp.clone()).collect());
+        assert_eq!(trie.root_hash(), want.root_hash());
+
+        // Inserting the redelivery directly cancels the first copy out
+        let mut naive = want.clone();
+        naive.insert(messages[0].timestamp.clone());
+        assert_ne!(naive.root_hash(), want.root_hash());
+
+        let late = message(i64::MAX / 2, "a");
+        assert!(store.apply(&mut trie, late.clone()).is_err());
+        assert!(!store.contains(&late.timestamp));
+    }
+
+    #[test]
+    fn test_messages_since() {
+        let mut store = MemoryStore::new();
+        store.insert(message(3, "a"));
+        store.insert(message(1, "a"));
+        store.insert(message(2, "b"));
+
+        let got: Vec<i64> = store
+            .messages_since(2)
+            .iter()
+            .map(|m| m.timestamp.millis())
+            .collect();
+        assert_eq!(got, vec![2, 3]);
+
+        store.insert(message(2, "c"));
+        let millis = |page: Vec<Message>| -> Vec<(i64, String)> {
+            page.iter()
+                .map(|m| (m.timestamp.millis(), m.timestamp.node().to_string()))
+                .collect()
+        };
+        let first = store.messages_since_page(2, 2, None);
+        assert_eq!(
+            millis(first.clone()),
+            vec![(2, "b".to_string()), (2, "c".to_string())]
+        );
+        let cursor = &first[1].timestamp;
+        let rest = store.messages_since_page(2, 2, Some(cursor));
+        assert_eq!(millis(rest), vec![(3, "a".to_string())]);
+        // A cursor before `millis` doesn't reach back past it
+        let early = Timestamp::new(0, 0, "a".to_string());
+        assert_eq!(store.messages_since_page(2, 10, Some(&early)).len(), 3);
+    }
+}
@@ -0,0 +1,109 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::ops::Range;
+
+use crate::message::Message;
+use crate::timestamp::Epoch;
+use crate::trie::Trie;
+
+/// Which bucket changes a subscription hears about
+///
+/// The default filter matches every change.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BucketFilter {
+    /// Minutes to watch, or all of them
+    pub minutes: Option<Range<Epoch>>,
+    /// Dataset to watch, or all of them
+    pub dataset: Option<String>,
+}
+
+impl BucketFilter {
+    pub fn matches(&self, change: &BucketChange) -> bool {
+        let in_range = match &self.minutes {
+            Some(minutes) => minutes.contains(&change.epoch),
+            None => true,
+        };
+        let in_dataset = match &self.dataset {
+            Some(dataset) => change.datasets.contains(dataset),
+            None => true,
+        };
+        in_range && in_dataset
+    }
+}
+
+/// A minute bucket whose hash changed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BucketChange {
+    pub epoch: Epoch,
+    /// The bucket's new hash, or None if it was emptied
+    pub hash: Option<u64>,
+    /// Datasets of the messages that changed it, in order
+    pub datasets: Vec<String>,
+}
+
+/// Handle for removing a subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SubscriptionId(u64);
+
+type Callback = Box<dyn FnMut(&BucketChange) + Send>;
+
+// The engine's subscriptions
+#[derive(Default)]
+pub(crate) struct Subscriptions {
+    next: u64,
+    entries: Vec<(SubscriptionId, BucketFilter, Callback)>,
+}
+
+impl fmt::Debug for Subscriptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Subscriptions")
+            .field("count", &self.entries.len())
+            .finish()
+    }
+}
+
+impl Subscriptions {
+    pub(crate) fn add(&mut self, filter: BucketFilter, callback: Callback) -> SubscriptionId {
+        let id = SubscriptionId(self.next);
+        self.next += 1;
+        self.entries.push((id, filter, callback));
+        id
+    }
+
+    pub(crate) fn remove(&mut self, id: SubscriptionId) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|(entry, _, _)| *entry != id);
+        self.entries.len() != before
+    }
+
+    /// Tell subscribers about the buckets `messages` changed, once per
+    /// bucket, reading the new hashes from `trie`
+    pub(crate) fn notify<'a>(
+        &mut self,
+        trie: &Trie,
+        messages: impl IntoIterator<Item = &'a Message>,
+    ) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let mut buckets: BTreeMap<Epoch, BTreeSet<&str>> = BTreeMap::new();
+        for message in messages {
+            buckets
+                .entry(message.timestamp.epoch())
+                .or_default()
+                .insert(&message.dataset);
+        }
+        for (epoch, datasets) in buckets {
+            let change = BucketChange {
+                epoch,
+                hash: trie.minute_hash(epoch),
+                datasets: datasets.into_iter().map(str::to_string).collect(),
+            };
+            for (_, filter, callback) in &mut self.entries {
+                if filter.matches(&change) {
+                    callback(&change);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1384 @@
+use std::fmt;
+use std::ops::{BitAnd, BitOr};
+use std::time::Instant;
+
+use chrono::Utc;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::compress::decompress;
+#[cfg(feature = "compression")]
+use crate::compress::{compress, Compression};
+use crate::engine::Engine;
+pub use crate::error::SyncError;
+use crate::message::Message;
+use crate::snapshot::StateSnapshot;
+use crate::store::{MessageStore, StoreError};
+use crate::timestamp::{ClockError, Timestamp, TimestampError};
+use crate::trie::{Trie, TrieError};
+
+/// Response header carrying the server's wall time in milliseconds
+pub const TIME_HEADER: &str = "markle-time";
+/// Response header carrying the server's hybrid clock in string form
+pub const CLOCK_HEADER: &str = "markle-clock";
+/// Request and response header listing optional protocol features: what
+/// the client offers, and what the server agreed to
+pub const CAPABILITIES_HEADER: &str = "markle-capabilities";
+
+const TAG_SYNC: u8 = 1;
+const TAG_DONE: u8 = 2;
+const TAG_BOOTSTRAP: u8 = 3;
+const TAG_SNAPSHOT: u8 = 4;
+// Wraps another frame, compressed; only sent once both sides agreed
+const TAG_COMPRESSED: u8 = 5;
+const TAG_PAGE: u8 = 6;
+const TAG_CONTINUE: u8 = 7;
+const TAG_ROOT: u8 = 8;
+
+/// Messages per frame once both sides agreed to paging, unless a session
+/// is given another size
+pub const DEFAULT_PAGE_SIZE: usize = 10_000;
+
+// Frames smaller than this gain too little from compression to bother
+#[cfg(feature = "compression")]
+const COMPRESS_MIN_LEN: usize = 512;
+
+/// A frame exchanged between two sync sessions
+#[derive(Debug)]
+enum Frame {
+    /// The sender's trie plus any messages the receiver is missing
+    Sync { trie: Trie, messages: Vec<Message> },
+    /// The sender's trie matched the receiver's
+    Done,
+    /// The sender wants the receiver's state rather than its history
+    Bootstrap,
+    /// The receiver's state, in answer to `Bootstrap`
+    Snapshot(Box<StateSnapshot>),
+    /// The first messages of a `Sync` too long for one frame; the receiver
+    /// answers with `Continue` to get the next
+    Page {
+        trie: Trie,
+        messages: Vec<Message>,
+        next: Continuation,
+    },
+    /// Asks for the page after the one received
+    Continue(Continuation),
+    /// The sender's root hash alone; the receiver answers `Done` if it has
+    /// the same, or opens a full sync if not
+    Root(u64),
+}
+
+// Where the next page starts: the millis the sender's diff found, and the
+// last timestamp already sent. It travels with each page and comes back in
+// `Continue`, so the sender keeps nothing between pages and a stateless
+// server can page too.
+#[derive(Debug, Clone, PartialEq)]
+struct Continuation {
+    since: i64,
+    after: Timestamp,
+}
+
+impl Continuation {
+    fn encode(&self, w: &mut Writer) {
+        w.put_i64(self.since);
+        self.after.encode(w);
+    }
+
+    fn decode(r: &mut Reader) -> Result<Continuation, DecodeError> {
+        Ok(Continuation {
+            since: r.get_i64()?,
+            after: Timestamp::decode(r)?,
+        })
+    }
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            Frame::Sync { trie, messages } => {
+                w.put_u8(TAG_SYNC);
+                trie.encode_into(&mut w);
+                encode_messages(&mut w, messages);
+            }
+            Frame::Done => w.put_u8(TAG_DONE),
+            Frame::Bootstrap => w.put_u8(TAG_BOOTSTRAP),
+            Frame::Snapshot(snapshot) => {
+                w.put_u8(TAG_SNAPSHOT);
+                snapshot.encode_into(&mut w);
+            }
+            Frame::Page {
+                trie,
+                messages,
+                next,
+            } => {
+                w.put_u8(TAG_PAGE);
+                trie.encode_into(&mut w);
+                encode_messages(&mut w, messages);
+                next.encode(&mut w);
+            }
+            Frame::Continue(next) => {
+                w.put_u8(TAG_CONTINUE);
+                next.encode(&mut w);
+            }
+            Frame::Root(hash) => {
+                w.put_u8(TAG_ROOT);
+                w.put_u64(*hash);
+            }
+        }
+        w.into_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Frame, DecodeError> {
+        match bytes.split_first() {
+            Some((&TAG_COMPRESSED, body)) => {
+                let inner = decompress(body)?;
+                if inner.first() == Some(&TAG_COMPRESSED) {
+                    return Err(DecodeError::InvalidTag(TAG_COMPRESSED));
+                }
+                Frame::decode_bare(&inner)
+            }
+            _ => Frame::decode_bare(bytes),
+        }
+    }
+
+    fn decode_bare(bytes: &[u8]) -> Result<Frame, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let frame = match r.get_u8()? {
+            TAG_SYNC => {
+                let trie = Trie::decode_from(&mut r)?;
+                let messages = decode_messages(&mut r)?;
+                Frame::Sync { trie, messages }
+            }
+            TAG_PAGE => Frame::Page {
+                trie: Trie::decode_from(&mut r)?,
+                messages: decode_messages(&mut r)?,
+                next: Continuation::decode(&mut r)?,
+            },
+            TAG_CONTINUE => Frame::Continue(Continuation::decode(&mut r)?),
+            TAG_ROOT => Frame::Root(r.get_u64()?),
+            TAG_DONE => Frame::Done,
+            TAG_BOOTSTRAP => Frame::Bootstrap,
+            TAG_SNAPSHOT => Frame::Snapshot(Box::new(StateSnapshot::decode_from(&mut r)?)),
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        };
+        r.finish()?;
+        Ok(frame)
+    }
+}
+
+fn encode_messages(w: &mut Writer, messages: &[Message]) {
+    w.put_u32(messages.len() as u32);
+    for message in messages {
+        message.encode(w);
+    }
+}
+
+fn decode_messages(r: &mut Reader) -> Result<Vec<Message>, DecodeError> {
+    let count = r.get_u32()?;
+    let mut messages = Vec::new();
+    for _ in 0..count {
+        messages.push(Message::decode(r)?);
+    }
+    Ok(messages)
+}
+
+/// Optional protocol features a peer supports
+///
+/// Each side advertises its own in the handshake and only uses what both
+/// have. Peers that advertise nothing, as every client and server from
+/// before capabilities existed, get the base protocol, and names a peer
+/// doesn't know are ignored, so adding one never breaks older peers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Compressed frame bodies
+    pub const COMPRESSION: Capabilities = Capabilities(1);
+    /// Large values sent out of band, as references. Reserved: not
+    /// implemented, so never offered or agreed
+    pub const BLOBS: Capabilities = Capabilities(1 << 1);
+    /// Tries sent as changes since the last one rather than whole.
+    /// Reserved, like `BLOBS`
+    pub const DELTA_TRIES: Capabilities = Capabilities(1 << 2);
+    /// Divergent minute buckets settled by exchanging their timestamps.
+    /// Reserved, like `BLOBS`
+    pub const LEAF_RECONCILIATION: Capabilities = Capabilities(1 << 3);
+    /// Long runs of messages sent a page at a time
+    pub const PAGING: Capabilities = Capabilities(1 << 4);
+    /// Rounds opened with a bare root hash; see `SyncSession::start_gossip`
+    pub const GOSSIP: Capabilities = Capabilities(1 << 5);
+
+    /// What this build implements, and offers by default
+    #[cfg(feature = "compression")]
+    pub const SUPPORTED: Capabilities =
+        Capabilities(Capabilities::COMPRESSION.0 | Capabilities::PAGING.0 | Capabilities::GOSSIP.0);
+    /// What this build implements, and offers by default
+    #[cfg(not(feature = "compression"))]
+    pub const SUPPORTED: Capabilities =
+        Capabilities(Capabilities::PAGING.0 | Capabilities::GOSSIP.0);
+
+    const NAMES: [(Capabilities, &'static str); 6] = [
+        (Capabilities::COMPRESSION, "compression"),
+        (Capabilities::BLOBS, "blobs"),
+        (Capabilities::DELTA_TRIES, "delta-tries"),
+        (Capabilities::LEAF_RECONCILIATION, "leaf-reconciliation"),
+        (Capabilities::PAGING, "paging"),
+        (Capabilities::GOSSIP, "gossip"),
+    ];
+
+    /// The base protocol alone
+    pub fn empty() -> Self {
+        Capabilities(0)
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Parse a `CAPABILITIES_HEADER` value, skipping unknown names
+    pub fn from_header(value: &str) -> Self {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter_map(|name| Self::NAMES.iter().find(|(_, known)| *known == name))
+            .fold(Capabilities::empty(), |acc, (flag, _)| acc | *flag)
+    }
+
+    /// The `CAPABILITIES_HEADER` value: names separated by commas
+    pub fn to_header(self) -> String {
+        Self::NAMES
+            .iter()
+            .filter(|(flag, _)| self.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+
+    fn bitor(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+
+    fn bitand(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+/// Transport-agnostic sync state machine
+///
+/// Both peers run a session over their own engine. One side calls `start`
+/// and sends the bytes; from then on each side feeds whatever it receives
+/// into `handle` and sends back the reply, if any, until the session
+/// reports convergence.
+///
+/// Each reply carries the sender's trie and every message since the point
+/// where the two tries diverge, so a round trip moves data in both
+/// directions.
+pub struct SyncSession<'a, S> {
+    engine: &'a mut Engine<S>,
+    converged: bool,
+    applied: Vec<Message>,
+    capabilities: Capabilities,
+    negotiated: Capabilities,
+    page_size: usize,
+    peer: String,
+    rounds: u32,
+    #[cfg(feature = "metrics")]
+    started: Instant,
+}
+
+/// Where a deadline-bounded sync stopped: the next frame this side would
+/// have sent
+///
+/// Persist the bytes and hand the cursor back to the next
+/// `sync_once_with_deadline` to pick up where the last one left off,
+/// including any messages that were about to be pushed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyncCursor {
+    frame: Vec<u8>,
+}
+
+impl SyncCursor {
+    pub fn from_bytes(frame: Vec<u8>) -> Self {
+        SyncCursor { frame }
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.frame
+    }
+}
+
+/// How a deadline-bounded sync ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SyncOutcome {
+    Converged,
+    /// Ran out of time; resume from the cursor
+    Suspended(SyncCursor),
+}
+
+/// A request/response byte transport to a peer running its own session
+pub trait Transport {
+    type Error;
+
+    /// Send a frame and wait for the peer's reply, if it has one
+    fn exchange(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, Self::Error>;
+}
+
+impl<'a, S: MessageStore> SyncSession<'a, S> {
+    pub fn new(engine: &'a mut Engine<S>) -> Self {
+        SyncSession {
+            engine,
+            converged: false,
+            applied: Vec::new(),
+            capabilities: Capabilities::SUPPORTED,
+            negotiated: Capabilities::empty(),
+            page_size: DEFAULT_PAGE_SIZE,
+            peer: String::new(),
+            rounds: 0,
+            #[cfg(feature = "metrics")]
+            started: Instant::now(),
+        }
+    }
+
+    /// Name the peer in this session's metrics, so convergence time and
+    /// bandwidth can be told apart per peer
+    pub fn with_peer(mut self, peer: impl Into<String>) -> Self {
+        self.peer = peer.into();
+        self
+    }
+
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Send at most `page_size` messages per frame once paging is agreed
+    ///
+    /// # Panics
+    ///
+    /// If `page_size` is zero.
+    pub fn with_page_size(mut self, page_size: usize) -> Self {
+        assert!(page_size > 0, "page size must be at least one");
+        self.page_size = page_size;
+        self
+    }
+
+    /// Offer `capabilities` rather than everything this build supports;
+    /// any it doesn't support are dropped
+    pub fn with_capabilities(mut self, capabilities: Capabilities) -> Self {
+        self.capabilities = capabilities & Capabilities::SUPPORTED;
+        self
+    }
+
+    /// What this side offers; send it in the transport's handshake
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Settle on the features both sides have, given the peer's offer or
+    /// answer, and return them; send them back when answering a handshake
+    pub fn negotiate(&mut self, peer: Capabilities) -> Capabilities {
+        self.negotiated = self.capabilities & peer;
+        self.negotiated
+    }
+
+    /// The features both sides agreed on, empty until `negotiate`
+    pub fn negotiated(&self) -> Capabilities {
+        self.negotiated
+    }
+
+    /// The opening frame: our trie, no messages
+    pub fn start(&mut self) -> Result<Vec<u8>, SyncError> {
+        Ok(self.encode(Frame::Sync {
+            trie: self.engine.trie()?.clone(),
+            messages: Vec::new(),
+        }))
+    }
+
+    /// An opening frame carrying only our root hash, for peers that
+    /// check in often and usually agree
+    ///
+    /// A peer with the same root ends the session at once; otherwise it
+    /// answers with its trie and the session carries on as if opened with
+    /// `start`. Only send it to peers that offer `Capabilities::GOSSIP`;
+    /// older ones reject the frame.
+    pub fn start_gossip(&mut self) -> Result<Vec<u8>, SyncError> {
+        Ok(self.encode(Frame::Root(self.engine.trie()?.root_hash())))
+    }
+
+    // Frames are compressed once both sides agree to it. Any frame decodes
+    // either way, so the side that learns of the agreement first can start.
+    fn encode(&self, frame: Frame) -> Vec<u8> {
+        let bytes = self.compress(frame.encode());
+        counter!("markle_sync_frames_sent_total", 1, "peer" => self.peer.clone());
+        counter!("markle_sync_bytes_sent_total", bytes.len(), "peer" => self.peer.clone());
+        bytes
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Vec<u8> {
+        #[cfg(feature = "compression")]
+        if self.negotiated.contains(Capabilities::COMPRESSION) && bytes.len() >= COMPRESS_MIN_LEN {
+            let mut compressed = vec![TAG_COMPRESSED];
+            compressed.extend(compress(&bytes, Compression::Deflate));
+            return compressed;
+        }
+        bytes
+    }
+
+    /// An opening frame asking the peer for its state rather than its
+    /// history, for a new replica; see `Engine::apply_snapshot`
+    ///
+    /// Once the snapshot is applied the session carries on by diff as
+    /// usual, uploading anything written here first. Peers from before
+    /// snapshots existed reject the frame.
+    pub fn start_bootstrap(&mut self) -> Vec<u8> {
+        // Uncompressed, as nothing has been agreed yet
+        let bytes = Frame::Bootstrap.encode();
+        counter!("markle_sync_frames_sent_total", 1, "peer" => self.peer.clone());
+        counter!("markle_sync_bytes_sent_total", bytes.len(), "peer" => self.peer.clone());
+        bytes
+    }
+
+    pub fn handle(&mut self, peer_message: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+        self.handle_at(peer_message, Utc::now().timestamp_millis())
+    }
+
+    /// Handle a frame from the peer, returning the reply to send back
+    pub fn handle_at(
+        &mut self,
+        peer_message: &[u8],
+        phys: i64,
+    ) -> Result<Option<Vec<u8>>, SyncError> {
+        self.rounds += 1;
+        let _span = debug_span!(
+            "sync_round",
+            peer = %self.peer,
+            round = self.rounds,
+            bytes = peer_message.len(),
+        );
+        counter!("markle_sync_frames_received_total", 1, "peer" => self.peer.clone());
+        counter!("markle_sync_bytes_received_total", peer_message.len(), "peer" => self.peer.clone());
+        let reply = self.reply(peer_message, phys);
+        if let Err(ref err) = reply {
+            counter!("markle_sync_failures_total", 1, "peer" => self.peer.clone());
+            log_error!("sync failed: {}", err);
+        }
+        reply
+    }
+
+    // Mark the session converged, recording how long it took the first time
+    fn converge(&mut self) {
+        if !self.converged {
+            histogram!("markle_sync_rounds", self.rounds, "peer" => self.peer.clone());
+            histogram!(
+                "markle_sync_convergence_seconds",
+                self.started.elapsed().as_secs_f64(),
+                "peer" => self.peer.clone(),
+            );
+        }
+        self.converged = true;
+    }
+
+    fn reply(&mut self, peer_message: &[u8], phys: i64) -> Result<Option<Vec<u8>>, SyncError> {
+        let (trie, messages) = match Frame::decode(peer_message)? {
+            Frame::Done => {
+                self.converge();
+                return Ok(None);
+            }
+            Frame::Sync { trie, messages } => (trie, messages),
+            Frame::Bootstrap => {
+                self.converged = false;
+                let snapshot = self.engine.state_snapshot()?;
+                debug!(messages = snapshot.messages.len(), "sending snapshot");
+                return Ok(Some(self.encode(Frame::Snapshot(Box::new(snapshot)))));
+            }
+            Frame::Snapshot(snapshot) => return self.bootstrap(*snapshot, phys),
+            Frame::Page {
+                trie,
+                messages,
+                next,
+            } => {
+                self.engine.trie()?.check_layout(&trie)?;
+                self.apply(messages, &trie, phys)?;
+                self.converged = false;
+                return Ok(Some(self.encode(Frame::Continue(next))));
+            }
+            Frame::Root(hash) => {
+                if hash == self.engine.trie()?.root_hash() {
+                    self.converge();
+                    return Ok(Some(self.encode(Frame::Done)));
+                }
+                self.converged = false;
+                debug!("root hashes differ");
+                return self.start().map(Some);
+            }
+            // The peer only asks if it was sent a page, so pages were agreed
+            Frame::Continue(next) => {
+                self.converged = false;
+                let reply = self.messages_frame(next.since, Some(&next.after), true)?;
+                return Ok(Some(self.encode(reply)));
+            }
+        };
+
+        self.engine.trie()?.check_layout(&trie)?;
+        self.apply(messages, &trie, phys)?;
+
+        match self.engine.trie()?.diff(&trie) {
+            None => {
+                self.converge();
+                log_info!("sync converged, {} messages applied", self.applied.len());
+                Ok(Some(self.encode(Frame::Done)))
+            }
+            Some(since) => {
+                self.converged = false;
+                let paged = self.negotiated.contains(Capabilities::PAGING);
+                let reply = self.messages_frame(since.timestamp_millis(), None, paged)?;
+                Ok(Some(self.encode(reply)))
+            }
+        }
+    }
+
+    fn apply(&mut self, messages: Vec<Message>, trie: &Trie, phys: i64) -> Result<(), SyncError> {
+        let span = debug_span!(
+            "sync_apply",
+            received = messages.len(),
+            applied = tracing::field::Empty,
+        );
+        debug!(received = messages.len(), "sync frame");
+        counter!("markle_sync_messages_received_total", messages.len(), "peer" => self.peer.clone());
+        let applied = self.engine.apply_batch(messages, trie, phys)?;
+        record!(span, applied = applied.len());
+        counter!("markle_sync_messages_applied_total", applied.len(), "peer" => self.peer.clone());
+        for message in applied {
+            trace!(timestamp = %message.timestamp, "applied message");
+            self.applied.push(message);
+        }
+        Ok(())
+    }
+
+    // Our trie and the messages from `since` on, after `after`: all of them,
+    // or if `paged` a page, continued if there may be more
+    fn messages_frame(
+        &self,
+        since: i64,
+        after: Option<&Timestamp>,
+        paged: bool,
+    ) -> Result<Frame, SyncError> {
+        let span = debug_span!("sync_send", since, paged, sent = tracing::field::Empty,);
+        let trie = self.engine.trie()?.clone();
+        let messages = if paged {
+            self.engine
+                .messages_since_page(since, self.page_size, after)
+        } else {
+            self.engine.messages_since(since)
+        };
+        record!(span, sent = messages.len());
+        counter!("markle_sync_messages_sent_total", messages.len(), "peer" => self.peer.clone());
+        if !paged {
+            return Ok(Frame::Sync { trie, messages });
+        }
+        let frame = match messages.last() {
+            Some(last) if messages.len() == self.page_size => {
+                let next = Continuation {
+                    since,
+                    after: last.timestamp.clone(),
+                };
+                debug!(sent = messages.len(), "sending page");
+                Frame::Page {
+                    trie,
+                    messages,
+                    next,
+                }
+            }
+            _ => Frame::Sync { trie, messages },
+        };
+        Ok(frame)
+    }
+
+    // Take on the peer's state, then send it what it lacks as a diff
+    // against the trie it sent
+    fn bootstrap(
+        &mut self,
+        snapshot: StateSnapshot,
+        phys: i64,
+    ) -> Result<Option<Vec<u8>>, SyncError> {
+        self.engine.trie()?.check_layout(&snapshot.trie)?;
+        let theirs = snapshot.trie.clone();
+        let applied = self.engine.apply_snapshot(snapshot, phys)?;
+        log_info!("bootstrapped from snapshot of {} messages", applied.len());
+        self.applied.extend(applied);
+
+        let reply = match self.engine.trie()?.diff(&theirs) {
+            Some(since) => {
+                let paged = self.negotiated.contains(Capabilities::PAGING);
+                self.messages_frame(since.timestamp_millis(), None, paged)?
+            }
+            None => Frame::Sync {
+                trie: self.engine.trie()?.clone(),
+                messages: Vec::new(),
+            },
+        };
+        Ok(Some(self.encode(reply)))
+    }
+
+    pub fn is_converged(&self) -> bool {
+        self.converged
+    }
+
+    /// How many frames from the peer this session has handled
+    pub fn rounds(&self) -> u32 {
+        self.rounds
+    }
+
+    /// Messages from the peer that were new to this engine, in arrival order
+    pub fn applied(&self) -> &[Message] {
+        &self.applied
+    }
+
+    /// Drive the session from this side until the peer stops replying
+    pub fn run<T>(&mut self, transport: &mut T) -> Result<(), T::Error>
+    where
+        T: Transport,
+        T::Error: From<SyncError>,
+    {
+        let start = self.start()?;
+        self.run_from(start, transport)
+    }
+
+    /// `run`, opening with `start_gossip`
+    pub fn run_gossip<T>(&mut self, transport: &mut T) -> Result<(), T::Error>
+    where
+        T: Transport,
+        T::Error: From<SyncError>,
+    {
+        let start = self.start_gossip()?;
+        self.run_from(start, transport)
+    }
+
+    fn run_from<T>(&mut self, start: Vec<u8>, transport: &mut T) -> Result<(), T::Error>
+    where
+        T: Transport,
+        T::Error: From<SyncError>,
+    {
+        let mut frame = start;
+        while let Some(reply) = transport.exchange(&frame)? {
+            match self.handle(&reply)? {
+                Some(next) => frame = next,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Drive the session until it converges or `deadline` passes
+    ///
+    /// Meant for OS background task windows: every round's messages are
+    /// applied to the store as they arrive, so work done before the
+    /// deadline is kept, and the returned cursor carries the rest. The
+    /// deadline is checked between round trips, so the transport's own
+    /// timeout should fit inside the window.
+    pub fn sync_once_with_deadline<T>(
+        &mut self,
+        transport: &mut T,
+        resume: Option<SyncCursor>,
+        deadline: Instant,
+    ) -> Result<SyncOutcome, T::Error>
+    where
+        T: Transport,
+        T::Error: From<SyncError>,
+    {
+        let mut frame = match resume {
+            Some(cursor) => cursor.frame,
+            None => self.start()?,
+        };
+        loop {
+            if Instant::now() >= deadline {
+                debug!("sync deadline reached");
+                return Ok(SyncOutcome::Suspended(SyncCursor { frame }));
+            }
+            let Some(reply) = transport.exchange(&frame)? else {
+                return Ok(SyncOutcome::Converged);
+            };
+            match self.handle(&reply)? {
+                Some(next) => frame = next,
+                None => return Ok(SyncOutcome::Converged),
+            }
+        }
+    }
+}
+
+impl SyncError {
+    /// What went wrong, in terms an application can act on
+    pub fn failure(&self) -> SyncFailure {
+        match *self {
+            SyncError::Decode(ref err) => SyncFailure::from_decode(err),
+            SyncError::Timestamp(ref err) => SyncFailure::from_timestamp(err),
+            SyncError::Trie(TrieError::Key(_)) => SyncFailure::OutOfRange {
+                detail: self.to_string(),
+            },
+            SyncError::Trie(TrieError::SchemeMismatch(..))
+            | SyncError::Trie(TrieError::ResolutionMismatch(..)) => SyncFailure::ProtocolMismatch {
+                detail: self.to_string(),
+            },
+            SyncError::DuplicateNode(ref node) => SyncFailure::DuplicateNode { node: node.clone() },
+            SyncError::ReusedTimestamp(_) | SyncError::Store(StoreError::Integrity(_)) => {
+                SyncFailure::Corruption {
+                    detail: self.to_string(),
+                }
+            }
+            SyncError::Store(StoreError::Poisoned) => SyncFailure::Internal {
+                detail: self.to_string(),
+            },
+        }
+    }
+}
+
+/// Why a sync failed, grouped by what the application should do about it
+///
+/// Every sync error converts to one, via `SyncError::failure` and the
+/// transports' equivalents; `remedy` suggests what to show the user.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SyncFailure {
+    /// The peer couldn't be reached, or the connection dropped
+    Network { detail: String },
+    /// The server refused our credentials
+    Auth { status: u16 },
+    /// The peer speaks another protocol version or hash scheme
+    ProtocolMismatch { detail: String },
+    /// Our clock disagrees with the peer's by more than the drift limit;
+    /// `offset` is how far the offending time was ahead of ours, in
+    /// milliseconds
+    Drift { offset: i64 },
+    /// Data on one side is damaged, or a client reused timestamps
+    Corruption { detail: String },
+    /// A timestamp, ours or the peer's, beyond the range the trie can
+    /// index
+    OutOfRange { detail: String },
+    /// The peer already holds messages under our node id, so this replica
+    /// was cloned from another or restored from its backup
+    DuplicateNode { node: String },
+    /// This replica failed on its own, whatever the peer sent
+    Internal { detail: String },
+    /// The server turned the sync down by policy: quota, size or the like
+    PolicyRejected { status: u16, detail: String },
+}
+
+/// What an application should do about a `SyncFailure`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Remedy {
+    /// Try again later without bothering the user
+    RetrySilently,
+    /// Ask the user to sign in again
+    SignIn,
+    /// Ask the user to correct their device's clock
+    FixClock,
+    /// Ask the user to update the app
+    Upgrade,
+    /// Give this replica a fresh node id, as from `make_client_id`, and
+    /// sync again
+    NewNodeId,
+    /// Nothing the user can fix; report it
+    ContactSupport,
+}
+
+impl SyncFailure {
+    pub fn remedy(&self) -> Remedy {
+        match self {
+            SyncFailure::Network { .. } => Remedy::RetrySilently,
+            SyncFailure::Auth { .. } => Remedy::SignIn,
+            SyncFailure::ProtocolMismatch { .. } => Remedy::Upgrade,
+            SyncFailure::Drift { .. } => Remedy::FixClock,
+            SyncFailure::DuplicateNode { .. } => Remedy::NewNodeId,
+            SyncFailure::Corruption { .. }
+            | SyncFailure::OutOfRange { .. }
+            | SyncFailure::Internal { .. }
+            | SyncFailure::PolicyRejected { .. } => Remedy::ContactSupport,
+        }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        self.remedy() == Remedy::RetrySilently
+    }
+
+    /// Classify an HTTP error status from a sync server
+    pub fn from_status(status: u16, detail: String) -> SyncFailure {
+        match status {
+            401 | 403 => SyncFailure::Auth { status },
+            // What the bundled server answers a frame it can't handle
+            400 => SyncFailure::ProtocolMismatch { detail },
+            408 | 429 | 500..=599 => SyncFailure::Network { detail },
+            _ => SyncFailure::PolicyRejected { status, detail },
+        }
+    }
+
+    pub(crate) fn from_decode(err: &DecodeError) -> SyncFailure {
+        let detail = err.to_string();
+        match err {
+            DecodeError::InvalidTag(_)
+            | DecodeError::UnsupportedVersion(_)
+            | DecodeError::UnknownHashScheme(_)
+            | DecodeError::UnknownResolution(_)
+            | DecodeError::HeaderMismatch(..) => SyncFailure::ProtocolMismatch { detail },
+            _ => SyncFailure::Corruption { detail },
+        }
+    }
+
+    fn from_timestamp(err: &TimestampError) -> SyncFailure {
+        if let Some(offset) = err.clock().and_then(ClockError::drift_offset) {
+            return SyncFailure::Drift { offset };
+        }
+        let detail = err.to_string();
+        match *err {
+            TimestampError::OutOfRangeError(_) | TimestampError::UnrepresentableError(_) => {
+                SyncFailure::OutOfRange { detail }
+            }
+            TimestampError::Clock(_) | TimestampError::InvalidNodeError(_) => {
+                SyncFailure::Corruption { detail }
+            }
+        }
+    }
+}
+
+impl fmt::Display for SyncFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncFailure::Network { detail } => write!(f, "network error: {}", detail),
+            SyncFailure::Auth { status } => write!(f, "not authorized ({})", status),
+            SyncFailure::ProtocolMismatch { detail } => write!(f, "protocol mismatch: {}", detail),
+            SyncFailure::Drift { offset } => {
+                write!(f, "clock is off by {} ms from the peer's", offset)
+            }
+            SyncFailure::Corruption { detail } => write!(f, "corrupt data: {}", detail),
+            SyncFailure::OutOfRange { detail } => write!(f, "timestamp out of range: {}", detail),
+            SyncFailure::DuplicateNode { node } => {
+                write!(f, "node id {} is already in use", node)
+            }
+            SyncFailure::Internal { detail } => write!(f, "internal error: {}", detail),
+            SyncFailure::PolicyRejected { status, detail } => {
+                write!(f, "rejected by server policy ({}): {}", status, detail)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::MemoryStore;
+    use crate::timestamp::{make_client_id, Timestamp};
+    use crate::trie::{HashScheme, Resolution};
+
+    const MINUTE: i64 = 1000 * 60;
+
+    fn engine() -> Engine<MemoryStore> {
+        Engine::new(Timestamp::new(0, 0, make_client_id()), MemoryStore::new()).unwrap()
+    }
+
+    // Shuttle frames between two sessions until neither has anything to say
+    fn run(a: &mut SyncSession<MemoryStore>, b: &mut SyncSession<MemoryStore>, phys: i64) -> usize {
+        let start = a.start().unwrap();
+        run_from(start, a, b, phys)
+    }
+
+    // `run`, with `a` opening with `start`
+    fn run_from(
+        start: Vec<u8>,
+        a: &mut SyncSession<MemoryStore>,
+        b: &mut SyncSession<MemoryStore>,
+        phys: i64,
+    ) -> usize {
+        let mut frame = Some(start);
+        let mut rounds = 0;
+        while let Some(bytes) = frame {
+            frame = if rounds % 2 == 0 {
+                b.handle_at(&bytes, phys).unwrap()
+            } else {
+                a.handle_at(&bytes, phys).unwrap()
+            };
+            rounds += 1;
+            assert!(rounds < 10, "sync did not converge");
+        }
+        rounds
+    }
+
+    #[test]
+    fn test_sync_converges() {
+        let mut a = engine();
+        let mut b = engine();
+        a.write("todos", "1", "title", "buy milk", MINUTE).unwrap();
+        a.write("todos", "2", "title", "buy eggs", 5 * MINUTE)
+            .unwrap();
+        b.write("todos", "1", "done", "true", 3 * MINUTE).unwrap();
+
+        let mut sa = SyncSession::new(&mut a);
+        let mut sb = SyncSession::new(&mut b);
+        run(&mut sa, &mut sb, 10 * MINUTE);
+        assert!(sa.is_converged());
+        assert!(sb.is_converged());
+
+        assert_eq!(a.trie().unwrap().diff(b.trie().unwrap()), None);
+        assert_eq!(a.store().len(), 3);
+        assert_eq!(b.store().len(), 3);
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let mut server = engine();
+        for minute in 1..=5 {
+            let value = format!("draft {}", minute);
+            server
+                .write("todos", "1", "title", &value, minute * MINUTE)
+                .unwrap();
+        }
+        server
+            .write("todos", "2", "title", "buy eggs", 6 * MINUTE)
+            .unwrap();
+        let mut client = engine();
+        client
+            .write("todos", "3", "title", "offline", 7 * MINUTE)
+            .unwrap();
+
+        let mut sc = SyncSession::new(&mut client);
+        let mut ss = SyncSession::new(&mut server);
+        let start = sc.start_bootstrap();
+        run_from(start, &mut sc, &mut ss, 10 * MINUTE);
+        assert!(sc.is_converged());
+        assert_eq!(sc.applied().len(), 2);
+
+        // Only the winners were sent, but the tries agree
+        assert_eq!(client.trie().unwrap().diff(server.trie().unwrap()), None);
+        assert_eq!(client.store().len(), 3);
+        assert_eq!(server.store().len(), 7);
+        assert_eq!(client.get("todos", "1", "title").unwrap().value, "draft 5");
+        assert!(server.get("todos", "3", "title").is_some());
+
+        // Replayed history is known, and later writes sync by diff
+        let old = server.messages_since(i64::MIN).remove(0);
+        assert!(!client.apply(old, 10 * MINUTE).unwrap());
+        server
+            .write("todos", "1", "title", "final", 10 * MINUTE)
+            .unwrap();
+        run(
+            &mut SyncSession::new(&mut client),
+            &mut SyncSession::new(&mut server),
+            11 * MINUTE,
+        );
+        assert_eq!(client.trie().unwrap().diff(server.trie().unwrap()), None);
+        assert_eq!(client.get("todos", "1", "title").unwrap().value, "final");
+    }
+
+    #[test]
+    fn test_sync_already_equal() {
+        let mut a = engine();
+        let mut b = engine();
+
+        let mut sa = SyncSession::new(&mut a);
+        let mut sb = SyncSession::new(&mut b);
+        let rounds = run(&mut sa, &mut sb, MINUTE);
+
+        // start -> Done -> (nothing)
+        assert_eq!(rounds, 2);
+        assert!(sa.is_converged());
+        assert!(sb.is_converged());
+    }
+
+    #[test]
+    fn test_gossip() {
+        let mut a = engine();
+        let mut b = engine();
+        a.write("todos", "1", "title", "buy milk", MINUTE).unwrap();
+        b.apply(a.messages_since(i64::MIN).remove(0), MINUTE)
+            .unwrap();
+
+        // Same root: Root -> Done -> (nothing)
+        let mut sa = SyncSession::new(&mut a);
+        let mut sb = SyncSession::new(&mut b);
+        let start = sa.start_gossip().unwrap();
+        assert_eq!(start.len(), 9);
+        assert_eq!(run_from(start, &mut sa, &mut sb, MINUTE), 2);
+        assert!(sa.is_converged());
+
+        // Different roots escalate to a diff in the same session
+        b.write("todos", "2", "title", "buy eggs", 2 * MINUTE)
+            .unwrap();
+        let mut sa = SyncSession::new(&mut a);
+        let mut sb = SyncSession::new(&mut b);
+        let start = sa.start_gossip().unwrap();
+        assert!(run_from(start, &mut sa, &mut sb, 3 * MINUTE) > 2);
+        assert!(sa.is_converged());
+        assert_eq!(sa.applied().len(), 1);
+        assert_eq!(a.trie().unwrap().diff(b.trie().unwrap()), None);
+    }
+
+    struct Loopback<'a> {
+        peer: SyncSession<'a, MemoryStore>,
+    }
+
+    impl Transport for Loopback<'_> {
+        type Error = SyncError;
+
+        fn exchange(&mut self, frame: &[u8]) -> Result<Option<Vec<u8>>, SyncError> {
+            self.peer.handle(frame)
+        }
+    }
+
+    #[test]
+    fn test_run_over_transport() {
+        let now = Utc::now().timestamp_millis();
+        let mut a = engine();
+        let mut b = engine();
+        b.write("todos", "1", "title", "buy milk", now).unwrap();
+        b.write("todos", "2", "title", "buy eggs", now).unwrap();
+
+        let mut sa = SyncSession::new(&mut a);
+        let mut transport = Loopback {
+            peer: SyncSession::new(&mut b),
+        };
+        sa.run(&mut transport).unwrap();
+
+        assert!(sa.is_converged());
+        assert!(transport.peer.is_converged());
+        let rows: Vec<&str> = sa.applied().iter().map(|m| m.row.as_str()).collect();
+        assert_eq!(rows, vec!["1", "2"]);
+    }
+
+    #[test]
+    fn test_sync_with_deadline() {
+        let now = Utc::now().timestamp_millis();
+        let mut a = engine();
+        let mut b = engine();
+        b.write("todos", "1", "title", "buy milk", now).unwrap();
+
+        // An expired deadline suspends before the first round trip
+        let mut sa = SyncSession::new(&mut a);
+        let mut transport = Loopback {
+            peer: SyncSession::new(&mut b),
+        };
+        let got = sa
+            .sync_once_with_deadline(&mut transport, None, Instant::now())
+            .unwrap();
+        let SyncOutcome::Suspended(cursor) = got else {
+            panic!("expected to suspend, got {:?}", got);
+        };
+        assert!(sa.applied().is_empty());
+
+        let cursor = SyncCursor::from_bytes(cursor.as_bytes().to_vec());
+        let deadline = Instant::now() + std::time::Duration::from_secs(30);
+        let got = sa
+            .sync_once_with_deadline(&mut transport, Some(cursor), deadline)
+            .unwrap();
+        assert_eq!(got, SyncOutcome::Converged);
+        assert_eq!(sa.applied().len(), 1);
+    }
+
+    #[test]
+    fn test_scheme_mismatch() {
+        let mut a = engine();
+        let mut b = Engine::with_scheme(
+            Timestamp::new(0, 0, make_client_id()),
+            MemoryStore::new(),
+            HashScheme::Xxh64Add,
+        )
+        .unwrap();
+
+        let start = SyncSession::new(&mut a).start().unwrap();
+        let got = SyncSession::new(&mut b).handle_at(&start, 0).err();
+        let want = TrieError::SchemeMismatch(HashScheme::Xxh64Add, HashScheme::Murmur32Xor);
+        assert_eq!(got.as_ref().unwrap().failure().remedy(), Remedy::Upgrade);
+        assert_eq!(got, Some(SyncError::Trie(want)));
+    }
+
+    #[test]
+    fn test_resolution_mismatch() {
+        let mut a = engine();
+        let hourly = Frame::Sync {
+            trie: Trie::with_resolution(HashScheme::default(), Resolution::Hour),
+            messages: vec![],
+        };
+        let got = SyncSession::new(&mut a)
+            .handle_at(&hourly.encode(), 0)
+            .err();
+        let want = SyncError::Trie(TrieError::ResolutionMismatch(
+            Resolution::Minute,
+            Resolution::Hour,
+        ));
+        assert_eq!(got.as_ref().unwrap().failure().remedy(), Remedy::Upgrade);
+        assert_eq!(got, Some(want));
+    }
+
+    #[test]
+    fn test_handle_garbage() {
+        let mut a = engine();
+        let mut sa = SyncSession::new(&mut a);
+
+        let got = sa.handle_at(&[9], 0).err();
+        assert_eq!(got, Some(SyncError::Decode(DecodeError::InvalidTag(9))));
+
+        let got = sa.handle_at(&[1, 1, 0, 0], 0).unwrap_err().failure();
+        assert_eq!(got.remedy(), Remedy::ContactSupport);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let both = Capabilities::COMPRESSION | Capabilities::DELTA_TRIES;
+        assert_eq!(both.to_header(), "compression,delta-tries");
+        // Names from newer peers are skipped
+        let got = Capabilities::from_header("delta-tries, teleport,compression");
+        assert_eq!(got, both);
+        assert!(Capabilities::from_header("").is_empty());
+
+        let mut a = engine();
+        let session = SyncSession::new(&mut a);
+        assert_eq!(session.capabilities(), Capabilities::SUPPORTED);
+        // Reserved names parse but are never supported, offered or agreed
+        for reserved in [
+            Capabilities::BLOBS,
+            Capabilities::DELTA_TRIES,
+            Capabilities::LEAF_RECONCILIATION,
+        ] {
+            assert!(!Capabilities::SUPPORTED.contains(reserved));
+        }
+        let mut session = session.with_capabilities(both | Capabilities::PAGING);
+        assert_eq!(
+            session.capabilities(),
+            (Capabilities::COMPRESSION | Capabilities::PAGING) & Capabilities::SUPPORTED
+        );
+        assert!(session.negotiated().is_empty());
+        let peer = Capabilities::PAGING | Capabilities::DELTA_TRIES;
+        assert_eq!(session.negotiate(peer), Capabilities::PAGING);
+        // A peer from before capabilities advertises nothing
+        assert!(session.negotiate(Capabilities::empty()).is_empty());
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn test_compressed_frames() {
+        let mut a = engine();
+        let mut b = engine();
+        for i in 0..200 {
+            let row = i.to_string();
+            a.write("todos", &row, "title", "buy milk", MINUTE + i)
+                .unwrap();
+        }
+
+        let mut sa = SyncSession::new(&mut a);
+        let mut sb = SyncSession::new(&mut b);
+        sa.negotiate(Capabilities::COMPRESSION);
+        // A trie over one minute is too small to bother compressing
+        let start = sa.start().unwrap();
+        assert_eq!(start[0], TAG_SYNC);
+
+        // `b` hasn't agreed yet, but reads `a`'s compressed push anyway
+        let reply = sb.handle_at(&start, 10 * MINUTE).unwrap().unwrap();
+        let push = sa.handle_at(&reply, 10 * MINUTE).unwrap().unwrap();
+        assert_eq!(push[0], TAG_COMPRESSED);
+        let bare = Frame::decode(&push).unwrap().encode();
+        assert_eq!(bare[0], TAG_SYNC);
+        assert!(push.len() * 4 < bare.len());
+        sb.negotiate(Capabilities::COMPRESSION);
+        let done = sb.handle_at(&push, 10 * MINUTE).unwrap().unwrap();
+        // Too small to compress
+        assert_eq!(done, vec![TAG_DONE]);
+        assert_eq!(sa.handle_at(&done, 10 * MINUTE).unwrap(), None);
+        drop((sa, sb));
+        assert_eq!(b.store().len(), 200);
+
+        let nested = [vec![TAG_COMPRESSED], compress(&push, Compression::None)].concat();
+        assert_eq!(
+            Frame::decode(&nested).unwrap_err(),
+            DecodeError::InvalidTag(TAG_COMPRESSED)
+        );
+    }
+
+    #[test]
+    fn test_paging() {
+        let mut a = engine();
+        let mut b = engine();
+        // Many messages in one minute, so only the cursor can split them
+        for i in 0..25 {
+            a.write("todos", &i.to_string(), "title", "buy milk", MINUTE + i)
+                .unwrap();
+        }
+        b.write("todos", "b", "title", "buy eggs", 2 * MINUTE)
+            .unwrap();
+
+        let mut sa = SyncSession::new(&mut a).with_page_size(10);
+        let mut sb = SyncSession::new(&mut b).with_page_size(10);
+        sa.negotiate(Capabilities::PAGING);
+        sb.negotiate(Capabilities::PAGING);
+
+        let reply = sb
+            .handle_at(&sa.start().unwrap(), 10 * MINUTE)
+            .unwrap()
+            .unwrap();
+        let mut frame = sa.handle_at(&reply, 10 * MINUTE).unwrap();
+        let mut pages = 0;
+        while let Some(bytes) = frame {
+            if bytes[0] == TAG_PAGE {
+                pages += 1;
+                let Frame::Page { messages, .. } = Frame::decode(&bytes).unwrap() else {
+                    unreachable!()
+                };
+                assert_eq!(messages.len(), 10);
+            }
+            frame = sb.handle_at(&bytes, 10 * MINUTE).unwrap();
+            if let Some(bytes) = frame {
+                frame = sa.handle_at(&bytes, 10 * MINUTE).unwrap();
+            }
+        }
+        assert_eq!(pages, 2);
+        assert!(sa.is_converged() && sb.is_converged());
+        drop((sa, sb));
+        assert_eq!(a.trie().unwrap().diff(b.trie().unwrap()), None);
+        assert_eq!(b.store().len(), 26);
+
+        // Without agreement the whole run goes in one frame
+        let mut c = engine();
+        let mut sa = SyncSession::new(&mut a).with_page_size(10);
+        let mut sc = SyncSession::new(&mut c).with_page_size(10);
+        assert_eq!(run(&mut sa, &mut sc, 10 * MINUTE), 4);
+        drop((sa, sc));
+        assert_eq!(c.store().len(), 26);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use metrics::{
+            Counter, CounterFn, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+            SharedString, Unit,
+        };
+        use std::collections::BTreeMap;
+        use std::sync::{Arc, Mutex};
+
+        // Totals by name and labels, histograms counted by samples
+        #[derive(Default, Clone)]
+        struct Totals(Arc<Mutex<BTreeMap<String, f64>>>);
+
+        struct Handle(Totals, String, bool);
+
+        impl CounterFn for Handle {
+            fn increment(&self, value: u64) {
+                *self.0 .0.lock().unwrap().entry(self.1.clone()).or_default() += value as f64;
+            }
+
+            fn absolute(&self, _: u64) {}
+        }
+
+        impl HistogramFn for Handle {
+            fn record(&self, value: f64) {
+                let value = if self.2 { 1.0 } else { value };
+                *self.0 .0.lock().unwrap().entry(self.1.clone()).or_default() += value;
+            }
+        }
+
+        impl Totals {
+            fn handle(&self, key: &Key, count: bool) -> Arc<Handle> {
+                let labels: Vec<String> = key
+                    .labels()
+                    .map(|label| format!("{}={}", label.key(), label.value()))
+                    .collect();
+                let name = format!("{}{{{}}}", key.name(), labels.join(","));
+                Arc::new(Handle(self.clone(), name, count))
+            }
+
+            fn get(&self, name: &str) -> f64 {
+                self.0.lock().unwrap().get(name).copied().unwrap_or(0.0)
+            }
+        }
+
+        impl Recorder for Totals {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+                Counter::from_arc(self.handle(key, false))
+            }
+
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                // Record the rounds themselves, count the timings
+                let count = key.name() != "markle_sync_rounds";
+                Histogram::from_arc(self.handle(key, count))
+            }
+        }
+
+        let mut a = engine();
+        let mut b = engine();
+        for i in 0..3 {
+            a.write("todos", &i.to_string(), "title", "from a", MINUTE)
+                .unwrap();
+        }
+        b.write("todos", "9", "title", "from b", MINUTE).unwrap();
+
+        let totals = Totals::default();
+        let rounds = metrics::with_local_recorder(&totals, || {
+            let mut sa = SyncSession::new(&mut a).with_peer("b");
+            let mut sb = SyncSession::new(&mut b).with_peer("a");
+            run(&mut sa, &mut sb, MINUTE)
+        });
+
+        // Labelled by the peer at the other end, so `b`'s session is `a`
+        let get = |name: &str, peer: &str| totals.get(&format!("{}{{peer={}}}", name, peer));
+        assert_eq!(get("markle_sync_messages_applied_total", "a"), 3.0);
+        assert_eq!(get("markle_sync_messages_applied_total", "b"), 1.0);
+        assert!(get("markle_sync_messages_sent_total", "b") >= 3.0);
+        // Every frame one side sends, the other receives
+        for (from, to) in [("a", "b"), ("b", "a")] {
+            assert_eq!(
+                get("markle_sync_bytes_sent_total", to),
+                get("markle_sync_bytes_received_total", from)
+            );
+        }
+        let frames = get("markle_sync_frames_received_total", "a")
+            + get("markle_sync_frames_received_total", "b");
+        assert_eq!(frames, rounds as f64);
+        // Both sides converge once each
+        assert_eq!(get("markle_sync_convergence_seconds", "a"), 1.0);
+        assert_eq!(get("markle_sync_convergence_seconds", "b"), 1.0);
+        assert!(totals.get("markle_trie_diffs_total{diverged=false}") >= 1.0);
+    }
+
+    #[test]
+    fn test_failure() {
+        let drift = ClockError::Drift {
+            logical: 3 * MINUTE,
+            physical: MINUTE,
+            max_drift: MINUTE,
+        };
+        let got = SyncError::from(drift).failure();
+        assert_eq!(got, SyncFailure::Drift { offset: 2 * MINUTE });
+        assert_eq!(got.remedy(), Remedy::FixClock);
+
+        let got = |status| SyncFailure::from_status(status, String::new()).remedy();
+        assert_eq!(got(401), Remedy::SignIn);
+        assert_eq!(got(503), Remedy::RetrySilently);
+        assert_eq!(got(413), Remedy::ContactSupport);
+        assert!(SyncFailure::from_status(429, String::new()).is_retryable());
+
+        let got = SyncError::from(TimestampError::OutOfRangeError(i64::MAX)).failure();
+        assert!(matches!(got, SyncFailure::OutOfRange { .. }));
+        let got = SyncError::DuplicateNode("n".into()).failure();
+        assert_eq!(got, SyncFailure::DuplicateNode { node: "n".into() });
+        assert_eq!(got.remedy(), Remedy::NewNodeId);
+        let got = SyncError::Store(StoreError::Poisoned).failure();
+        assert!(matches!(got, SyncFailure::Internal { .. }));
+    }
+}
@@ -0,0 +1,363 @@
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::message::Message;
+use crate::timestamp::Timestamp;
+
+/// The current value of one cell and the timestamp of the write that set it
+#[derive(Debug, PartialEq, Clone)]
+pub struct Cell {
+    pub value: String,
+    pub timestamp: Timestamp,
+}
+
+type Row = BTreeMap<String, Cell>;
+
+/// Reserved column marking a row deleted while its value is `"1"`
+///
+/// Deletion is an ordinary write, so it syncs and resolves like any other;
+/// writing `"0"` restores the row.
+pub const TOMBSTONE: &str = "tombstone";
+
+/// Last-writer-wins materialization of the message log
+///
+/// Each `dataset`/`row`/`column` holds the value of the message with the
+/// greatest timestamp, so replicas that have seen the same messages agree
+/// regardless of the order they arrived in.
+#[derive(Clone, Default, Debug)]
+pub struct Table {
+    datasets: BTreeMap<String, BTreeMap<String, Row>>,
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Table {
+            datasets: BTreeMap::new(),
+        }
+    }
+
+    /// Merge a message, returning true if it is now the cell's winning write
+    pub fn apply(&mut self, message: &Message) -> bool {
+        apply_message(self, message, None)
+    }
+
+    /// `apply`, letting `resolver` merge the message with the cell's
+    /// current write, returning true if the cell changed
+    ///
+    /// The table keeps one write per cell, so it can't tell a message it
+    /// merged before from a new one; apply each once, as `Engine` does.
+    pub fn apply_with(&mut self, message: &Message, resolver: &mut dyn ConflictResolver) -> bool {
+        apply_message(self, message, Some(resolver))
+    }
+
+    /// The current value of a cell; cells of deleted rows are hidden
+    pub fn get(&self, dataset: &str, row: &str, column: &str) -> Option<&Cell> {
+        self.row(dataset, row)?.get(column)
+    }
+
+    /// Every cell of a row, keyed by column, unless the row is deleted
+    pub fn row(&self, dataset: &str, row: &str) -> Option<&BTreeMap<String, Cell>> {
+        let cells = self.datasets.get(dataset)?.get(row)?;
+        match cells.get(TOMBSTONE) {
+            Some(cell) if cell.value == "1" => None,
+            _ => Some(cells),
+        }
+    }
+
+    pub fn is_deleted(&self, dataset: &str, row: &str) -> bool {
+        self.tombstone(dataset, row).is_some()
+    }
+
+    /// The write that deleted a row, if it is deleted
+    pub fn tombstone(&self, dataset: &str, row: &str) -> Option<&Cell> {
+        let cell = self.datasets.get(dataset)?.get(row)?.get(TOMBSTONE)?;
+        (cell.value == "1").then_some(cell)
+    }
+
+    /// Every deleted row as (dataset, row, tombstone)
+    pub fn deleted_rows(&self) -> Vec<(&str, &str, &Cell)> {
+        let mut deleted = Vec::new();
+        for (dataset, rows) in &self.datasets {
+            for (row, cells) in rows {
+                match cells.get(TOMBSTONE) {
+                    Some(cell) if cell.value == "1" => {
+                        deleted.push((dataset.as_str(), row.as_str(), cell))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        deleted
+    }
+
+    /// Every stored cell as (dataset, row, column, cell), deleted rows
+    /// included
+    pub fn cells(&self) -> impl Iterator<Item = (&str, &str, &str, &Cell)> {
+        self.datasets.iter().flat_map(|(dataset, rows)| {
+            rows.iter().flat_map(move |(row, cells)| {
+                cells.iter().map(move |(column, cell)| {
+                    (dataset.as_str(), row.as_str(), column.as_str(), cell)
+                })
+            })
+        })
+    }
+
+    /// Forget every cell of a row but its tombstone
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn compact_row(&mut self, dataset: &str, row: &str) {
+        if let Some(cells) = self.datasets.get_mut(dataset).and_then(|d| d.get_mut(row)) {
+            cells.retain(|column, _| column == TOMBSTONE);
+        }
+    }
+}
+
+/// Somewhere cells are materialized: the in-memory `Table`, or an
+/// application's own database
+///
+/// Implementations only store and fetch; `apply` decides which write wins.
+pub trait TableStore {
+    /// The stored cell, whether or not its row is deleted
+    fn cell(&self, dataset: &str, row: &str, column: &str) -> Option<Cell>;
+
+    fn put_cell(&mut self, dataset: &str, row: &str, column: &str, cell: Cell);
+}
+
+impl TableStore for Table {
+    fn cell(&self, dataset: &str, row: &str, column: &str) -> Option<Cell> {
+        self.datasets.get(dataset)?.get(row)?.get(column).cloned()
+    }
+
+    fn put_cell(&mut self, dataset: &str, row: &str, column: &str, cell: Cell) {
+        self.datasets
+            .entry(dataset.to_string())
+            .or_default()
+            .entry(row.to_string())
+            .or_default()
+            .insert(column.to_string(), cell);
+    }
+}
+
+/// Two writes to the same cell, for a `ConflictResolver` to merge
+#[derive(Debug, Clone, Copy)]
+pub struct Conflict<'a> {
+    pub dataset: &'a str,
+    pub row: &'a str,
+    pub column: &'a str,
+    /// What the cell holds now, from whichever replica wrote it
+    pub current: &'a Cell,
+    /// The write being applied, earlier or later than `current`
+    pub incoming: &'a Cell,
+}
+
+/// Merges writes to a cell for data last-writer-wins would lose, such as
+/// counters kept as deltas
+///
+/// Replicas only converge if they all resolve alike whatever order writes
+/// reach them in, so `resolve` must be deterministic, and merging must be
+/// commutative and associative. Merged values aren't written to the log;
+/// every replica derives them again, so all of them need the resolver.
+pub trait ConflictResolver {
+    /// The value the cell should hold, or None to keep the later of the
+    /// two writes. The cell keeps the later timestamp either way. Deleting
+    /// a row writes its `TOMBSTONE` column, which is resolved here too.
+    fn resolve(&mut self, conflict: &Conflict<'_>) -> Option<String>;
+}
+
+/// Merge messages into `store`, the latest timestamp winning each cell,
+/// returning how many won
+///
+/// Any two stores that were given the same messages agree, whatever order
+/// they came in, which is what lets crdt.js-style apps sync a log of
+/// `(dataset, row, column, value)` writes rather than the tables
+/// themselves.
+pub fn apply<T: TableStore + ?Sized>(store: &mut T, messages: &[Message]) -> usize {
+    messages
+        .iter()
+        .filter(|message| apply_message(store, message, None))
+        .count()
+}
+
+/// `apply`, merging with `resolver` where a cell already holds a write,
+/// returning how many cells changed
+///
+/// Each message must reach the store once; see `Table::apply_with`.
+pub fn apply_with<T: TableStore + ?Sized>(
+    store: &mut T,
+    messages: &[Message],
+    resolver: &mut dyn ConflictResolver,
+) -> usize {
+    messages
+        .iter()
+        .filter(|message| apply_message(store, message, Some(&mut *resolver)))
+        .count()
+}
+
+fn apply_message<T: TableStore + ?Sized>(
+    store: &mut T,
+    message: &Message,
+    resolver: Option<&mut dyn ConflictResolver>,
+) -> bool {
+    let incoming = Cell {
+        value: message.value.clone(),
+        timestamp: message.timestamp.clone(),
+    };
+    let cell = match store.cell(&message.dataset, &message.row, &message.column) {
+        None => incoming,
+        // The same write again
+        Some(current) if current.timestamp == incoming.timestamp => return false,
+        Some(current) => {
+            let merged = resolver.and_then(|resolver| {
+                resolver.resolve(&Conflict {
+                    dataset: &message.dataset,
+                    row: &message.row,
+                    column: &message.column,
+                    current: &current,
+                    incoming: &incoming,
+                })
+            });
+            let later = if incoming.timestamp > current.timestamp {
+                incoming
+            } else {
+                current.clone()
+            };
+            let cell = match merged {
+                Some(value) => Cell {
+                    value,
+                    timestamp: later.timestamp,
+                },
+                None => later,
+            };
+            if cell == current {
+                return false;
+            }
+            cell
+        }
+    };
+    store.put_cell(&message.dataset, &message.row, &message.column, cell);
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn message(millis: i64, node: &str, value: &str) -> Message {
+        Message {
+            timestamp: Timestamp::new(millis, 0, node.to_string()),
+            dataset: "todos".to_string(),
+            row: "1".to_string(),
+            column: "title".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_latest_wins() {
+        let mut table = Table::new();
+        assert!(table.apply(&message(2, "a", "new")));
+        assert!(!table.apply(&message(1, "a", "old")));
+        assert_eq!(table.get("todos", "1", "title").unwrap().value, "new");
+
+        // Same millis and counter: the node id breaks the tie
+        assert!(table.apply(&message(2, "b", "tie")));
+        assert_eq!(table.get("todos", "1", "title").unwrap().value, "tie");
+        assert_eq!(table.get("todos", "1", "done"), None);
+    }
+
+    // Values are deltas, and the cell holds their sum
+    struct Sum;
+
+    impl ConflictResolver for Sum {
+        fn resolve(&mut self, conflict: &Conflict<'_>) -> Option<String> {
+            let current: i64 = conflict.current.value.parse().ok()?;
+            let incoming: i64 = conflict.incoming.value.parse().ok()?;
+            Some((current + incoming).to_string())
+        }
+    }
+
+    #[test]
+    fn test_resolver() {
+        let messages = [
+            message(1, "a", "5"),
+            message(3, "b", "-2"),
+            message(2, "c", "10"),
+        ];
+        let mut table = Table::new();
+        assert_eq!(apply_with(&mut table, &messages, &mut Sum), 3);
+        let cell = table.get("todos", "1", "title").unwrap().clone();
+        assert_eq!(cell.value, "13");
+        assert_eq!(cell.timestamp, messages[1].timestamp);
+
+        let mut reversed = Table::new();
+        for message in messages.iter().rev() {
+            reversed.apply_with(message, &mut Sum);
+        }
+        assert_eq!(reversed.get("todos", "1", "title"), Some(&cell));
+
+        // Declining falls back to the later write
+        assert!(table.apply_with(&message(4, "a", "milk"), &mut Sum));
+        assert_eq!(table.get("todos", "1", "title").unwrap().value, "milk");
+    }
+
+    #[test]
+    fn test_tombstone() {
+        let mut table = Table::new();
+        table.apply(&message(1, "a", "buy milk"));
+
+        let mut delete = message(2, "a", "1");
+        delete.column = TOMBSTONE.to_string();
+        table.apply(&delete);
+        assert!(table.is_deleted("todos", "1"));
+        assert_eq!(table.get("todos", "1", "title"), None);
+        assert_eq!(table.row("todos", "1"), None);
+        assert_eq!(table.deleted_rows().len(), 1);
+
+        let mut restore = message(3, "a", "0");
+        restore.column = TOMBSTONE.to_string();
+        table.apply(&restore);
+        assert!(!table.is_deleted("todos", "1"));
+        assert_eq!(table.get("todos", "1", "title").unwrap().value, "buy milk");
+    }
+
+    // A flat store, the shape an application's own database might take
+    #[derive(Default)]
+    struct Flat(Vec<(String, Cell)>);
+
+    impl TableStore for Flat {
+        fn cell(&self, dataset: &str, row: &str, column: &str) -> Option<Cell> {
+            let key = format!("{}/{}/{}", dataset, row, column);
+            self.0
+                .iter()
+                .find(|(k, _)| *k == key)
+                .map(|(_, c)| c.clone())
+        }
+
+        fn put_cell(&mut self, dataset: &str, row: &str, column: &str, cell: Cell) {
+            let key = format!("{}/{}/{}", dataset, row, column);
+            self.0.retain(|(k, _)| *k != key);
+            self.0.push((key, cell));
+        }
+    }
+
+    #[test]
+    fn test_apply_to_store() {
+        let messages = [
+            message(1, "a", "first"),
+            message(3, "a", "last"),
+            message(2, "b", "middle"),
+        ];
+        let mut flat = Flat::default();
+        assert_eq!(apply(&mut flat, &messages), 2);
+
+        let mut reversed = messages.clone();
+        reversed.reverse();
+        let mut table = Table::new();
+        assert_eq!(apply(&mut table, &reversed), 2);
+        assert_eq!(flat.cell("todos", "1", "title").unwrap().value, "last");
+        assert_eq!(
+            table.cell("todos", "1", "title"),
+            flat.cell("todos", "1", "title")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+// Fixtures for tests of code built on this crate: readable node ids,
+// timestamps by the minute, engines with a known history and a clock that
+// only moves when told, so tests needn't reach for `make_client_id`, raw
+// millisecond constants or sleeps.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use crate::clock::TimeSource;
+use crate::engine::{Engine, Identity};
+use crate::message::Message;
+use crate::store::MemoryStore;
+use crate::timestamp::{Epoch, Timestamp};
+use crate::trie::HashScheme;
+
+/// The node `ts` stamps with, `fixed_node("a")`
+pub const DEFAULT_NODE: &str = "aaaaaaaaaaaaaaaa";
+
+/// A 16 character node id spelled out by repeating `name`, so
+/// `fixed_node("a")` is `"aaaaaaaaaaaaaaaa"`. Node ids break timestamp
+/// ties in string order, so `fixed_node("a")` loses to `fixed_node("b")`.
+///
+/// # Panics
+///
+/// If `name` is empty.
+pub fn fixed_node(name: &str) -> String {
+    assert!(!name.is_empty(), "node name must not be empty");
+    name.chars().cycle().take(16).collect()
+}
+
+/// Milliseconds at the start of `minute` since the epoch
+pub fn minute(minute: i64) -> i64 {
+    Epoch(minute).millis()
+}
+
+/// A timestamp `minute` minutes after the epoch on `DEFAULT_NODE`
+pub fn ts(minute: i64, counter: u16) -> Timestamp {
+    ts_on("a", minute, counter)
+}
+
+/// A timestamp `minute` minutes after the epoch on `fixed_node(name)`
+pub fn ts_on(name: &str, minute: i64, counter: u16) -> Timestamp {
+    Timestamp::new(self::minute(minute), counter, fixed_node(name))
+}
+
+/// Builds an engine over a `MemoryStore` with a fixed node id and history
+///
+/// ```
+/// use markle::testing::EngineBuilder;
+///
+/// let engine = EngineBuilder::new("a")
+///     .write("todos", "1", "title", "buy milk", 0)
+///     .write("todos", "1", "done", "true", 3)
+///     .build();
+/// assert_eq!(engine.get("todos", "1", "done").unwrap().value, "true");
+/// ```
+#[derive(Debug, Clone)]
+pub struct EngineBuilder {
+    node: String,
+    scheme: HashScheme,
+    identity: Identity,
+    // dataset, row, column, value, minute
+    writes: Vec<(String, String, String, String, i64)>,
+}
+
+impl EngineBuilder {
+    /// An engine on `fixed_node(name)` whose clock starts at the epoch
+    pub fn new(name: &str) -> Self {
+        EngineBuilder {
+            node: fixed_node(name),
+            scheme: HashScheme::default(),
+            identity: Identity::default(),
+            writes: Vec::new(),
+        }
+    }
+
+    pub fn scheme(mut self, scheme: HashScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+
+    pub fn identity(mut self, identity: Identity) -> Self {
+        self.identity = identity;
+        self
+    }
+
+    /// Write a cell at the start of `minute`; writes are made in order
+    pub fn write(
+        mut self,
+        dataset: &str,
+        row: &str,
+        column: &str,
+        value: &str,
+        minute: i64,
+    ) -> Self {
+        self.writes.push((
+            dataset.to_string(),
+            row.to_string(),
+            column.to_string(),
+            value.to_string(),
+            minute,
+        ));
+        self
+    }
+
+    /// # Panics
+    ///
+    /// If a write is rejected, e.g. for going back in time far enough to
+    /// trip the drift check.
+    pub fn build(self) -> Engine<MemoryStore> {
+        let clock = Timestamp::new(0, 0, self.node);
+        let mut engine = Engine::with_scheme(clock, MemoryStore::new(), self.scheme)
+            .expect("an empty store indexes");
+        engine
+            .set_identity(self.identity)
+            .expect("a verified trie is built");
+        for (dataset, row, column, value, minute) in &self.writes {
+            engine
+                .write(dataset, row, column, value, self::minute(*minute))
+                .expect("fixture write rejected");
+        }
+        engine
+    }
+
+    /// The engine and the messages it wrote, in order
+    pub fn build_with_messages(self) -> (Engine<MemoryStore>, Vec<Message>) {
+        let engine = self.build();
+        let messages = engine.messages_since(i64::MIN);
+        (engine, messages)
+    }
+}
+
+/// A wall clock that only moves when told to
+///
+/// Clones share the same time, so a test can hand one to the code under
+/// test and move time from outside. Readings can also be scripted: each
+/// `now` then takes the next one, for clocks that jump or run backwards
+/// between two calls.
+///
+/// ```
+/// use std::time::Duration;
+/// use markle::clock::TimeSource;
+/// use markle::testing::{minute, EngineBuilder, MockClock};
+///
+/// let clock = MockClock::new(minute(1));
+/// let mut engine = EngineBuilder::new("a").build();
+/// engine.write("todos", "1", "title", "buy milk", clock.now()).unwrap();
+/// clock.advance(Duration::from_secs(90));
+/// let done = engine.write("todos", "1", "done", "true", clock.now()).unwrap();
+/// assert_eq!(done.timestamp.millis(), minute(1) + 90_000);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct MockClock {
+    state: Arc<Mutex<MockState>>,
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    now: i64,
+    script: VecDeque<i64>,
+}
+
+impl MockClock {
+    /// A clock reading `millis` until moved
+    pub fn new(millis: i64) -> Self {
+        let clock = MockClock::default();
+        clock.set_millis(millis);
+        clock
+    }
+
+    /// Move forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.state.lock().unwrap().now += by.as_millis() as i64;
+    }
+
+    /// Jump to `time`, backwards or forwards
+    pub fn set(&self, time: DateTime<Utc>) {
+        self.set_millis(time.timestamp_millis());
+    }
+
+    pub fn set_millis(&self, millis: i64) {
+        self.state.lock().unwrap().now = millis;
+    }
+
+    /// Have the next calls to `now` return `readings` in order, then keep
+    /// reading the last of them; replaces any readings still queued
+    pub fn script(&self, readings: impl IntoIterator<Item = i64>) {
+        self.state.lock().unwrap().script = readings.into_iter().collect();
+    }
+}
+
+impl TimeSource for MockClock {
+    fn now(&self) -> i64 {
+        let mut state = self.state.lock().unwrap();
+        if let Some(next) = state.script.pop_front() {
+            state.now = next;
+        }
+        state.now
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fixtures() {
+        assert_eq!(fixed_node("a"), DEFAULT_NODE);
+        assert_eq!(fixed_node("bob"), "bobbobbobbobbobb");
+        assert!(ts_on("a", 1, 0) < ts_on("b", 1, 0));
+        assert_eq!(
+            ts(2, 3).to_string(),
+            "1970-01-01T00:02:00.000Z-0003-aaaaaaaaaaaaaaaa"
+        );
+    }
+
+    #[test]
+    fn test_engine_builder() {
+        let build = || {
+            EngineBuilder::new("a")
+                .scheme(HashScheme::Xxh64Add)
+                .write("todos", "1", "title", "buy milk", 0)
+                .write("todos", "2", "title", "buy eggs", 0)
+                .build_with_messages()
+        };
+        let (engine, messages) = build();
+        // The clock starts at the epoch, so writes there count up from 1
+        assert_eq!(messages[0].timestamp, ts(0, 1));
+        assert_eq!(messages[1].timestamp, ts(0, 2));
+        assert_eq!(engine.trie().unwrap().scheme(), HashScheme::Xxh64Add);
+
+        // Same fixture, same trie
+        assert!(build().0.trie().unwrap().deep_eq(engine.trie().unwrap()));
+    }
+
+    #[test]
+    fn test_mock_clock() {
+        let clock = MockClock::new(minute(5));
+        let shared = clock.clone();
+        shared.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now(), minute(5) + 1500);
+
+        clock.set(DateTime::from_timestamp_millis(minute(2)).unwrap());
+        assert_eq!(shared.now(), minute(2));
+
+        // A step back mid-script, then the last reading holds
+        clock.script([minute(3), minute(1), minute(4)]);
+        let readings: Vec<i64> = (0..4).map(|_| clock.now()).collect();
+        assert_eq!(readings, [minute(3), minute(1), minute(4), minute(4)]);
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(clock.now(), minute(5));
+    }
+}
@@ -1,23 +1,138 @@
-use std::fmt;
-use std::io::Cursor;
+use alloc::format;
+use alloc::string::{String, ToString};
+#[cfg(feature = "std")]
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::str::FromStr;
 
-use chrono::{DateTime, NaiveDateTime, Utc};
-use murmur3::murmur3_32;
-use uuid::Uuid;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::error::SyncError;
 
 // Configuration for maximum clock drift allowed
 static MAX_DRIFT: i64 = 60_000; // milliseconds
 
-#[derive(Debug, PartialEq, Clone)]
+// Characters of the node in the string form
+const NODE_LEN: usize = 16;
+
+// The string form hashed from a stack buffer: 30 bytes of time and counter,
+// then the node
+const KEY_LEN: usize = 30 + NODE_LEN;
+
+// Field order matters: the derived ordering compares millis, then counter, then node
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Timestamp {
     millis: i64,
     counter: u16,
     node: String,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+/// What a clock does when 65,536 events land in the same millisecond
+///
+/// The counter stays 16 bits either way, so the string and binary forms,
+/// and the trie hashes taken over them, are unchanged.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+pub enum CounterOverflow {
+    /// Fail with `ClockError::Overflow`
+    #[default]
+    Error,
+    /// Move on to the next millisecond with the counter at zero, running
+    /// ahead of the physical clock until it catches up. Runaway imports
+    /// still stop at the drift limit, a minute of spilling.
+    Spill,
+}
+
+impl CounterOverflow {
+    // The logical time and counter after `counter`, which overflowed if None
+    fn next(self, millis: i64, counter: Option<u16>) -> Result<(i64, u16), ClockError> {
+        match (counter, self) {
+            (Some(counter), _) => Ok((millis, counter)),
+            (None, CounterOverflow::Error) => {
+                counter!("markle_clock_counter_overflows_total", 1, "policy" => "error");
+                Err(ClockError::Overflow)
+            }
+            (None, CounterOverflow::Spill) => {
+                counter!("markle_clock_counter_overflows_total", 1, "policy" => "spill");
+                millis
+                    .checked_add(1)
+                    .map(|millis| (millis, 0))
+                    .ok_or(ClockError::Overflow)
+            }
+        }
+    }
+}
+
+/// A minute since the Unix epoch, the granularity of trie buckets
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub struct Epoch(pub i64);
 
+impl Epoch {
+    pub const MILLIS_PER_MINUTE: i64 = 60_000;
+
+    /// The minute containing `millis`, rounding down before the epoch too
+    pub fn from_millis(millis: i64) -> Self {
+        Epoch(millis.div_euclid(Self::MILLIS_PER_MINUTE))
+    }
+
+    pub fn minutes(self) -> i64 {
+        self.0
+    }
+
+    /// The first millisecond of the minute
+    pub fn millis(self) -> i64 {
+        self.0 * Self::MILLIS_PER_MINUTE
+    }
+
+    /// None past the range chrono can represent
+    pub fn to_datetime(self) -> Option<DateTime<Utc>> {
+        DateTime::from_timestamp_millis(self.0.checked_mul(Self::MILLIS_PER_MINUTE)?)
+    }
+}
+
+impl From<DateTime<Utc>> for Epoch {
+    fn from(time: DateTime<Utc>) -> Self {
+        Epoch::from_millis(time.timestamp_millis())
+    }
+}
+
+impl From<&Timestamp> for Epoch {
+    fn from(timestamp: &Timestamp) -> Self {
+        Epoch::from_millis(timestamp.millis)
+    }
+}
+
+/// Adds whole minutes
+impl Add<i64> for Epoch {
+    type Output = Epoch;
+
+    fn add(self, minutes: i64) -> Epoch {
+        Epoch(self.0 + minutes)
+    }
+}
+
+/// Subtracts whole minutes
+impl Sub<i64> for Epoch {
+    type Output = Epoch;
+
+    fn sub(self, minutes: i64) -> Epoch {
+        Epoch(self.0 - minutes)
+    }
+}
+
+/// Minutes between two epochs
+impl Sub for Epoch {
+    type Output = i64;
+
+    fn sub(self, other: Epoch) -> i64 {
+        self.0 - other.0
+    }
+}
+
 impl Timestamp {
     pub fn new(millis: i64, counter: u16, node: String) -> Self {
         Timestamp {
@@ -27,26 +142,46 @@ impl Timestamp {
         }
     }
 
-    pub fn ts_minutes(&self) -> i64 {
-        self.millis / 1000 / 60
+    /// `new`, refusing what can't round trip through the string form
+    ///
+    /// The time must fall in years 0 to 9999, which RFC 3339 can write,
+    /// and the node must be printable ASCII without dashes or spaces, so
+    /// parsing doesn't split or trim it differently. Everything `send` and
+    /// `recv` return from a valid clock is valid too.
+    pub fn try_new(millis: i64, counter: u16, node: String) -> Result<Self, TimestampError> {
+        let timestamp = Timestamp::new(millis, counter, node);
+        timestamp.validate()?;
+        Ok(timestamp)
     }
 
-    fn to_string(&self) -> String {
-        let time = chrono::DateTime::from_timestamp_millis(self.millis).unwrap();
-        let time = time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    /// Whether `try_new` would accept this timestamp's parts
+    pub fn validate(&self) -> Result<(), TimestampError> {
+        let year = DateTime::from_timestamp_millis(self.millis).map(|time| time.year());
+        if !matches!(year, Some(0..=9999)) {
+            return Err(TimestampError::UnrepresentableError(self.millis));
+        }
+        let valid = |byte: &u8| byte.is_ascii_graphic() && *byte != b'-';
+        if self.node.is_empty() || !self.node.bytes().all(|byte| valid(&byte)) {
+            return Err(TimestampError::InvalidNodeError(self.node.clone()));
+        }
+        Ok(())
+    }
 
-        format!("{}-{:04X}-{:016}", time, self.counter, self.node)
+    /// The minute bucket this timestamp falls in
+    pub fn epoch(&self) -> Epoch {
+        Epoch::from(self)
     }
 
     pub fn millis(&self) -> i64 {
         self.millis
     }
 
-    fn counter(&self) -> u16 {
+    /// Orders timestamps issued within the same millisecond
+    pub fn counter(&self) -> u16 {
         self.counter
     }
 
-    fn node(&self) -> &str {
+    pub fn node(&self) -> &str {
         &self.node
     }
 
@@ -58,34 +193,107 @@ impl Timestamp {
         self.counter = counter;
     }
 
-    fn set_node(&mut self, node: String) {
-        self.node = node;
+    /// Murmur3 of the string form
+    ///
+    /// Millis outside chrono's range of dates hash their `Display`
+    /// fallback; `try_hash` refuses them instead.
+    pub fn hash(&self) -> u32 {
+        self.with_key(|key| murmur3_32(key, 0))
     }
 
-    pub fn hash(&self) -> u32 {
-        let timestamp_str = self.to_string();
-        let mut buffer = Cursor::new(timestamp_str.as_bytes());
+    /// `hash`, or an error if the time has no date to format
+    pub fn try_hash(&self) -> Result<u32, TimestampError> {
+        self.check_representable()?;
+        Ok(self.hash())
+    }
 
-        // Use the murmur3_32 function with a chosen seed; 0 is used here for simplicity.
-        // Adjust the seed as necessary for your application.
-        murmur3_32(&mut buffer, 0).unwrap_or(0)
+    /// The string form, or an error if the time has no date to format
+    ///
+    /// `Display` can't fail, so for such times it writes the raw millis
+    /// instead, which `parse` doesn't read back.
+    pub fn try_to_string(&self) -> Result<String, TimestampError> {
+        self.check_representable()?;
+        Ok(self.to_string())
     }
 
-    pub fn send(&mut self, phys: i64) -> Result<Self, TimestampError> {
-        //let phys = Utc::now().timestamp_millis();
+    fn check_representable(&self) -> Result<(), TimestampError> {
+        match DateTime::from_timestamp_millis(self.millis) {
+            Some(_) => Ok(()),
+            None => Err(TimestampError::UnrepresentableError(self.millis)),
+        }
+    }
 
+    /// Call `f` with the string form, the bytes trie hashes are taken over
+    ///
+    /// Tries hash every timestamp they insert, so the common case is
+    /// written into a stack buffer rather than going through `Display`.
+    pub(crate) fn with_key<R>(&self, f: impl FnOnce(&[u8]) -> R) -> R {
+        let mut buf = [0; KEY_LEN];
+        match self.write_key(&mut buf) {
+            Some(len) => f(&buf[..len]),
+            None => f(self.to_string().as_bytes()),
+        }
+    }
+
+    // What `Display` writes, or None for years outside 0..=9999, which
+    // chrono signs and widens, and for nodes that aren't ASCII
+    fn write_key(&self, buf: &mut [u8; KEY_LEN]) -> Option<usize> {
+        if !self.node.is_ascii() {
+            return None;
+        }
+        let node = self.node.as_bytes();
+        let node = &node[node.len().saturating_sub(NODE_LEN)..];
+        let time = DateTime::from_timestamp_millis(self.millis)?;
+        let year = u32::try_from(time.year())
+            .ok()
+            .filter(|year| *year <= 9999)?;
+
+        put_digits(&mut buf[0..4], year);
+        buf[4] = b'-';
+        put_digits(&mut buf[5..7], time.month());
+        buf[7] = b'-';
+        put_digits(&mut buf[8..10], time.day());
+        buf[10] = b'T';
+        put_digits(&mut buf[11..13], time.hour());
+        buf[13] = b':';
+        put_digits(&mut buf[14..16], time.minute());
+        buf[16] = b':';
+        put_digits(&mut buf[17..19], time.second());
+        buf[19] = b'.';
+        put_digits(&mut buf[20..23], time.timestamp_subsec_millis());
+        buf[23] = b'Z';
+        buf[24] = b'-';
+        for (i, byte) in buf[25..29].iter_mut().enumerate() {
+            *byte = b"0123456789ABCDEF"[(self.counter >> (12 - 4 * i)) as usize & 0xf];
+        }
+        buf[29] = b'-';
+        buf[30..KEY_LEN - node.len()].fill(b'0');
+        buf[KEY_LEN - node.len()..].copy_from_slice(node);
+        Some(KEY_LEN)
+    }
+
+    pub fn send(&mut self, phys: i64) -> Result<Self, ClockError> {
+        self.send_with(phys, CounterOverflow::Error)
+    }
+
+    /// `send`, handling a full counter as `overflow` says
+    pub fn send_with(&mut self, phys: i64, overflow: CounterOverflow) -> Result<Self, ClockError> {
         let l_old = self.millis;
         let c_old = self.counter;
 
-        let l_new = std::cmp::max(l_old, phys);
-        let c_new = if l_old == l_new {
-            c_old.checked_add(1).ok_or(TimestampError::OverflowError)?
+        let l_new = core::cmp::max(l_old, phys);
+        let (l_new, c_new) = if l_old == l_new {
+            overflow.next(l_new, c_old.checked_add(1))?
         } else {
-            0
+            (l_new, 0)
         };
 
         if l_new - phys > MAX_DRIFT {
-            return Err(TimestampError::ClockDriftError(l_new, phys, MAX_DRIFT));
+            return Err(ClockError::Drift {
+                logical: l_new,
+                physical: phys,
+                max_drift: MAX_DRIFT,
+            });
         }
 
         self.set_millis(l_new);
@@ -94,18 +302,55 @@ impl Timestamp {
         Ok(Timestamp::new(self.millis, self.counter, self.node.clone()))
     }
 
-    pub fn recv(&mut self, msg: &Timestamp, phys: i64) -> Result<Timestamp, TimestampError> {
+    /// Resume from a timestamp this clock issued before a restart
+    ///
+    /// The clock is raised to `last` so it never issues anything earlier.
+    /// If the physical clock is behind `last` by more than the allowed
+    /// drift, the OS clock was rolled back; that is reported rather than
+    /// absorbed, since every later `send` would fail or reuse history.
+    pub fn observe_persisted(&mut self, last: &Timestamp, phys: i64) -> Result<(), ClockError> {
+        if last.millis - phys > MAX_DRIFT {
+            return Err(ClockError::Regression {
+                persisted: last.millis,
+                physical: phys,
+            });
+        }
+        if (last.millis, last.counter) > (self.millis, self.counter) {
+            self.set_millis(last.millis);
+            self.set_counter(last.counter);
+        }
+        Ok(())
+    }
+
+    /// Take in a timestamp from a peer, failing with
+    /// `SyncError::DuplicateNode` if it carries our own node id
+    pub fn recv(&mut self, msg: &Timestamp, phys: i64) -> Result<Timestamp, SyncError> {
+        self.recv_with(msg, phys, CounterOverflow::Error)
+    }
+
+    /// `recv`, handling a full counter as `overflow` says
+    pub fn recv_with(
+        &mut self,
+        msg: &Timestamp,
+        phys: i64,
+        overflow: CounterOverflow,
+    ) -> Result<Timestamp, SyncError> {
         // Unpack the message wall time/counter
         let l_msg = msg.millis;
         let c_msg = msg.counter;
 
         // Assert the node id and remote clock drift
         if msg.node == self.node {
-            return Err(TimestampError::DuplicateNodeError(self.node.clone()));
+            return Err(SyncError::DuplicateNode(self.node.clone()));
         }
 
         if l_msg > phys && l_msg - phys > MAX_DRIFT {
-            return Err(TimestampError::ClockDriftError(l_msg, phys, MAX_DRIFT));
+            return Err(ClockError::Drift {
+                logical: l_msg,
+                physical: phys,
+                max_drift: MAX_DRIFT,
+            }
+            .into());
         }
 
         // Unpack the clock.timestamp logical time and counter
@@ -113,22 +358,25 @@ impl Timestamp {
         let c_old = self.counter;
 
         // Calculate the next logical time and counter
-        let l_new = std::cmp::max(std::cmp::max(l_old, phys), l_msg);
-        let c_new = if l_new == l_old && l_new == l_msg {
-            std::cmp::max(c_old, c_msg)
-                .checked_add(1)
-                .ok_or(TimestampError::OverflowError)?
+        let l_new = core::cmp::max(core::cmp::max(l_old, phys), l_msg);
+        let (l_new, c_new) = if l_new == l_old && l_new == l_msg {
+            overflow.next(l_new, core::cmp::max(c_old, c_msg).checked_add(1))?
         } else if l_new == l_old {
-            c_old.checked_add(1).ok_or(TimestampError::OverflowError)?
+            overflow.next(l_new, c_old.checked_add(1))?
         } else if l_new == l_msg {
-            c_msg.checked_add(1).ok_or(TimestampError::OverflowError)?
+            overflow.next(l_new, c_msg.checked_add(1))?
         } else {
-            0
+            (l_new, 0)
         };
 
         // Check the result for drift and counter overflow
         if l_new > phys && l_new - phys > MAX_DRIFT {
-            return Err(TimestampError::ClockDriftError(l_new, phys, MAX_DRIFT));
+            return Err(ClockError::Drift {
+                logical: l_new,
+                physical: phys,
+                max_drift: MAX_DRIFT,
+            }
+            .into());
         }
 
         // Repack the logical time/counter
@@ -142,100 +390,305 @@ impl Timestamp {
         })
     }
 
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn encode(&self, w: &mut Writer) {
+        w.put_i64(self.millis);
+        w.put_u16(self.counter());
+        w.put_str(self.node());
+    }
+
+    #[cfg_attr(not(feature = "std"), allow(dead_code))]
+    pub(crate) fn decode(r: &mut Reader) -> Result<Self, DecodeError> {
+        let millis = r.get_i64()?;
+        let counter = r.get_u16()?;
+        let node = r.get_str()?.to_string();
+        Ok(Timestamp::new(millis, counter, node))
+    }
+
+    /// The timestamp `s` is the string form of, or None; `str::parse`
+    /// returns a `ParseError` saying what was wrong with it
     pub fn parse(s: &str) -> Option<Self> {
-        // let parts: Vec<&str> = s.split('-').collect();
-        // if parts.len() !== 3 {
-        //     return None;
-        // }
+        s.parse().ok()
+    }
+}
 
-        // let time = parts[0];
-        // let counter = parts[1];
-        // let node = parts[2];
+impl FromStr for Timestamp {
+    type Err = ParseError;
 
-        // let time = chrono::DateTime::parse_from_rfc3339(time)
-        //     .map_err(|_| "invalid timestamp format".to_string())?;
-        // let millis = time.timestamp_millis();
+    fn from_str(s: &str) -> Result<Self, ParseError> {
+        // The time part contains dashes itself, so split from the right
+        let mut parts = s.rsplitn(3, '-');
+        let (Some(node), Some(counter), Some(time)) = (parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ParseError::Malformed);
+        };
 
-        // let counter =
-        //     u16::from_str_radix(counter, 16).map_err(|_| "invalid counter format".to_string())?;
+        if node.is_empty() {
+            return Err(ParseError::EmptyNode);
+        }
+        let invalid_counter = || ParseError::InvalidCounter(counter.to_string());
+        if counter.len() != 4 {
+            return Err(invalid_counter());
+        }
 
-        // Ok(Timestamp {
-        //     millis,
-        //     counter,
-        //     node: node.to_string(),
-        // })
+        let millis = chrono::DateTime::parse_from_rfc3339(time)
+            .map_err(|_| ParseError::InvalidTime(time.to_string()))?
+            .timestamp_millis();
+        let counter = u16::from_str_radix(counter, 16).map_err(|_| invalid_counter())?;
 
-        //TODO
-        None
+        Ok(Timestamp {
+            millis,
+            counter,
+            node: node.to_string(),
+        })
     }
 }
 
 // Implement Display for Timestamp to enable easy printing
 impl fmt::Display for Timestamp {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.to_string())
+        // Corrupt or hostile millis mustn't panic whoever logs them
+        let Some(time) = chrono::DateTime::from_timestamp_millis(self.millis) else {
+            return write!(
+                f,
+                "{}ms-{:04X}-{}",
+                self.millis,
+                self.counter,
+                NodeKey(&self.node)
+            );
+        };
+        let time = time.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+
+        write!(f, "{}-{:04X}-{}", time, self.counter, NodeKey(&self.node))
     }
 }
 
+// The node as crdt.js writes it, `('0000000000000000' + node).slice(-16)`:
+// zeros on the left of a short one, the last 16 characters of a long one
+struct NodeKey<'a>(&'a str);
+
+impl fmt::Display for NodeKey<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let chars = self.0.chars().count();
+        for _ in chars..NODE_LEN {
+            f.write_str("0")?;
+        }
+        let start = self
+            .0
+            .char_indices()
+            .nth(chars.saturating_sub(NODE_LEN))
+            .map_or(0, |(i, _)| i);
+        f.write_str(&self.0[start..])
+    }
+}
+
+/// # Panics
+///
+/// If the millis are outside chrono's range; see `try_to_string`.
 impl From<Timestamp> for DateTime<Utc> {
     fn from(ts: Timestamp) -> Self {
         DateTime::<Utc>::from_timestamp_millis(ts.millis).unwrap()
     }
 }
 
+// Errors related to the hybrid logical clock
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ClockError {
+    /// A logical time further ahead of the physical clock than allowed:
+    /// a remote peer's clock runs fast, or this device's runs slow
+    #[error("maximum clock drift exceeded: {logical} - {physical} > {max_drift}")]
+    Drift {
+        logical: i64,
+        physical: i64,
+        max_drift: i64,
+    },
+    // The counter is full; see `CounterOverflow`
+    #[error("timestamp counter overflow")]
+    Overflow,
+    /// The physical clock is behind a timestamp persisted before a
+    /// restart, so the OS clock was rolled back
+    #[error("physical clock {physical} is behind persisted timestamp {persisted}")]
+    Regression { persisted: i64, physical: i64 },
+}
+
 // Errors related to timestamp processing
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, thiserror::Error)]
+#[allow(clippy::enum_variant_names)]
+#[non_exhaustive]
 pub enum TimestampError {
-    ClockDriftError(i64, i64, i64),
-    OverflowError,
-    DuplicateNodeError(String),
+    #[error(transparent)]
+    Clock(#[from] ClockError),
+    #[error("timestamp {0} is outside the indexable range")]
+    OutOfRangeError(i64),
+    // Millis with no date chrono can represent
+    #[error("timestamp {0} is outside the range of dates")]
+    UnrepresentableError(i64),
+    // A node id the string form can't carry; see `Timestamp::try_new`
+    #[error("invalid node identifier {0:?}")]
+    InvalidNodeError(String),
+}
+
+// Errors related to reading a timestamp's string form
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[non_exhaustive]
+pub enum ParseError {
+    // Fewer than three dash-separated parts
+    #[error("timestamp is not time-counter-node")]
+    Malformed,
+    #[error("invalid timestamp time {0}")]
+    InvalidTime(String),
+    #[error("invalid timestamp counter {0}")]
+    InvalidCounter(String),
+    #[error("timestamp has an empty node")]
+    EmptyNode,
+}
+
+/// A random 16 hex digit node id, 64 bits of entropy
+#[cfg(feature = "std")]
+pub fn make_client_id() -> String {
+    make_client_id_with_len(16)
 }
 
-fn millis_to_base3(mut millis: i64) -> String {
-    if millis == 0 {
-        return "0".to_string();
+/// A random node id of `len` lowercase hex digits
+///
+/// # Panics
+///
+/// If the operating system's random source is unavailable.
+#[cfg(feature = "std")]
+pub fn make_client_id_with_len(len: usize) -> String {
+    let mut bytes = vec![0; len.div_ceil(2)];
+    getrandom::getrandom(&mut bytes).expect("os random source");
+    to_hex(&bytes, len)
+}
+
+/// Deterministic node ids for tests and simulations
+///
+/// Ids look like `make_client_id`'s but are drawn from splitmix64 seeded
+/// with a fixed value, so a run can be replayed exactly.
+#[derive(Debug, Clone)]
+pub struct SeededClientIds {
+    state: u64,
+}
+
+impl SeededClientIds {
+    pub fn new(seed: u64) -> Self {
+        SeededClientIds { state: seed }
+    }
+
+    pub fn next_id(&mut self) -> String {
+        self.next_id_with_len(16)
     }
 
-    let mut base3 = Vec::new();
-    while millis > 0 {
-        base3.push((millis % 3).to_string());
-        millis /= 3;
+    pub fn next_id_with_len(&mut self, len: usize) -> String {
+        let mut bytes = Vec::with_capacity(len.div_ceil(2) + 8);
+        while bytes.len() < len.div_ceil(2) {
+            bytes.extend_from_slice(&self.next_u64().to_be_bytes());
+        }
+        to_hex(&bytes, len)
+    }
+
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
     }
-    base3.reverse();
-    base3.join("")
 }
 
-pub fn make_client_id() -> String {
-    // Generate a new v4 UUID
-    let uuid = Uuid::new_v4().to_string();
-    // Remove dashes and take the last 16 characters
-    uuid.replace("-", "")
-        .chars()
-        .rev()
-        .take(16)
-        .collect::<String>()
-        .chars()
-        .rev()
-        .collect()
+// MurmurHash3's x86 32-bit variant, as the JavaScript murmurhash package
+// computes it
+fn murmur3_32(data: &[u8], seed: u32) -> u32 {
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let mut blocks = data.chunks_exact(4);
+    for block in &mut blocks {
+        let mut k = u32::from_le_bytes([block[0], block[1], block[2], block[3]]);
+        k = k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h ^= k;
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+
+    let tail = blocks.remainder();
+    if !tail.is_empty() {
+        let mut k = 0u32;
+        for (i, byte) in tail.iter().enumerate() {
+            k |= (*byte as u32) << (8 * i);
+        }
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ (h >> 16)
 }
 
-impl fmt::Display for TimestampError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+// `value` as zero-padded decimal filling `buf`
+fn put_digits(buf: &mut [u8], mut value: u32) {
+    for byte in buf.iter_mut().rev() {
+        *byte = b'0' + (value % 10) as u8;
+        value /= 10;
+    }
+}
+
+// The first `len` hex digits of `bytes`
+fn to_hex(bytes: &[u8], len: usize) -> String {
+    let mut hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    hex.truncate(len);
+    hex
+}
+
+impl ClockError {
+    /// For errors caused by a wrong physical clock, how far the time it
+    /// was checked against was ahead of it, in milliseconds
+    pub fn drift_offset(&self) -> Option<i64> {
+        Some(self.logical_time()? - self.physical_time()?)
+    }
+
+    /// For errors caused by a wrong physical clock, the time it was
+    /// checked against: the logical time that drifted, or the persisted one
+    pub fn logical_time(&self) -> Option<i64> {
         match *self {
-            TimestampError::ClockDriftError(l_new, phys, max_drift) => write!(
-                f,
-                "maximum clock drift exceeded: {} - {} > {}",
-                l_new, phys, max_drift
-            ),
-            TimestampError::OverflowError => write!(f, "timestamp counter overflow"),
-            TimestampError::DuplicateNodeError(ref node) => {
-                write!(f, "duplicate node identifier {}", node)
+            ClockError::Drift { logical, .. } => Some(logical),
+            ClockError::Regression { persisted, .. } => Some(persisted),
+            ClockError::Overflow => None,
+        }
+    }
+
+    /// For errors caused by a wrong physical clock, what it read
+    pub fn physical_time(&self) -> Option<i64> {
+        match *self {
+            ClockError::Drift { physical, .. } | ClockError::Regression { physical, .. } => {
+                Some(physical)
             }
+            ClockError::Overflow => None,
+        }
+    }
+
+    /// For drift errors, the most the logical time may lead by
+    pub fn max_drift(&self) -> Option<i64> {
+        match *self {
+            ClockError::Drift { max_drift, .. } => Some(max_drift),
+            _ => None,
         }
     }
 }
 
-impl std::error::Error for TimestampError {}
+impl TimestampError {
+    /// The clock's error, if the clock refused
+    pub fn clock(&self) -> Option<&ClockError> {
+        match self {
+            TimestampError::Clock(err) => Some(err),
+            _ => None,
+        }
+    }
+}
 
 #[cfg(test)]
 mod test {
@@ -254,6 +707,109 @@ mod test {
             ts.to_string(),
             "2024-03-23T22:10:55.000Z-FFFE-1234123412341234"
         );
+        assert_eq!(ts.try_to_string(), Ok(ts.to_string()));
+        assert_eq!(ts.try_hash(), Ok(ts.hash()));
+
+        // Far past chrono's dates: formatted without panicking, but refused
+        // by the fallible forms
+        let ts = Timestamp::new(i64::MAX, 1, "1234123412341234".to_string());
+        assert_eq!(
+            ts.to_string(),
+            "9223372036854775807ms-0001-1234123412341234"
+        );
+        let want = Err(TimestampError::UnrepresentableError(i64::MAX));
+        assert_eq!(ts.try_to_string(), want);
+        assert_eq!(
+            ts.try_hash(),
+            Err(TimestampError::UnrepresentableError(i64::MAX))
+        );
+        assert_eq!(Timestamp::parse(&ts.to_string()), None);
+    }
+
+    #[test]
+    fn test_hash_key() {
+        // Year 0, before the epoch, year 9999, and a leap day
+        let millis = [-62167219200000, -1, 0, 253402300799999, 951782400123];
+        let nodes = ["1234123412341234", "ab", "é", &"f".repeat(40)];
+        let mut seed = 0x2545f4914f6cdd1d_u64;
+        let random = (0..100).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % 253402300800000) as i64
+        });
+        for millis in millis.into_iter().chain(random) {
+            for node in nodes {
+                let ts = Timestamp::new(millis, (millis & 0xffff) as u16, node.to_string());
+                let key = ts.with_key(|key| String::from_utf8(key.to_vec()).unwrap());
+                assert_eq!(key, ts.to_string());
+                assert_eq!(ts.hash(), murmur3_32(key.as_bytes(), 0));
+            }
+        }
+        // Nodes are cut or zero-padded to 16 characters, as crdt.js does
+        let ts = |node: &str| Timestamp::new(0, 0, node.to_string()).to_string();
+        assert_eq!(ts("ab"), "1970-01-01T00:00:00.000Z-0000-00000000000000ab");
+        assert_eq!(
+            ts(&format!("{}{}", "f".repeat(24), "1234123412341234")),
+            "1970-01-01T00:00:00.000Z-0000-1234123412341234"
+        );
+        assert_eq!(ts("né"), "1970-01-01T00:00:00.000Z-0000-00000000000000né");
+        // chrono writes years past 9999 with a sign
+        let ts = Timestamp::new(253402300800000, 0, "1234123412341234".to_string());
+        assert_eq!(ts.with_key(|key| key[0]), b'+');
+    }
+
+    #[test]
+    fn test_parse() {
+        let s = "2024-03-23T22:10:55.000Z-FFFE-1234123412341234";
+        let got = Timestamp::parse(s);
+        let want = Timestamp::new(1711231855000, 0xFFFE, "1234123412341234".to_string());
+        assert_eq!(got, Some(want));
+
+        let ts = Timestamp::new(1, 0x1234, make_client_id());
+        assert_eq!(Timestamp::parse(&ts.to_string()), Some(ts));
+
+        assert_eq!(Timestamp::parse(""), None);
+        assert_eq!(Timestamp::parse("2024-03-23T22:10:55.000Z-FFFE"), None);
+        assert_eq!(Timestamp::parse("2024-03-23T22:10:55.000Z-XYZW-1234"), None);
+        assert_eq!(Timestamp::parse("yesterday-0000-1234123412341234"), None);
+
+        let parse = |s: &str| s.parse::<Timestamp>().err();
+        assert_eq!(parse("1234"), Some(ParseError::Malformed));
+        assert_eq!(
+            parse("2024-03-23T22:10:55.000Z-XYZW-1234"),
+            Some(ParseError::InvalidCounter("XYZW".to_string()))
+        );
+        assert_eq!(
+            parse("yesterday-0000-1234123412341234"),
+            Some(ParseError::InvalidTime("yesterday".to_string()))
+        );
+        assert_eq!(
+            parse("2024-03-23T22:10:55.000Z-0000-"),
+            Some(ParseError::EmptyNode)
+        );
+    }
+
+    #[test]
+    fn test_try_new() {
+        let node = "1234123412341234".to_string();
+        let ts = Timestamp::try_new(1711231855000, 7, node.clone()).unwrap();
+        assert_eq!(ts, Timestamp::new(1711231855000, 7, node.clone()));
+        assert_eq!(ts.to_string().parse(), Ok(ts));
+        assert!(Timestamp::try_new(-62_000_000_000_000, 0, node.clone()).is_ok());
+
+        for millis in [-62_200_000_000_000, 253_402_300_800_000, i64::MAX] {
+            assert_eq!(
+                Timestamp::try_new(millis, 0, node.clone()),
+                Err(TimestampError::UnrepresentableError(millis))
+            );
+        }
+        for node in ["", "ab-cd", "ab cd", "n\u{f6}de", "ab\n"] {
+            assert_eq!(
+                Timestamp::try_new(0, 0, node.to_string()),
+                Err(TimestampError::InvalidNodeError(node.to_string()))
+            );
+        }
     }
 
     #[test]
@@ -261,19 +817,56 @@ mod test {
         let mut ts = Timestamp::new(1, 0xFFFF, "1234123412341234".to_string());
 
         let got = ts.send(1).err().unwrap();
-        let want = TimestampError::OverflowError;
+        let want = ClockError::Overflow;
 
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn test_spill_overflow() {
+        let node = "1234123412341234".to_string();
+        let mut ts = Timestamp::new(1, 0xFFFF, node.clone());
+        let got = ts.send_with(1, CounterOverflow::Spill).unwrap();
+        assert_eq!(got, Timestamp::new(2, 0, node.clone()));
+        assert_eq!(ts, got);
+
+        let remote = Timestamp::new(5, 0xFFFF, "abcdabcdabcdabcd".to_string());
+        assert_eq!(
+            ts.recv(&remote, 1),
+            Err(SyncError::Timestamp(ClockError::Overflow.into()))
+        );
+        let got = ts.recv_with(&remote, 1, CounterOverflow::Spill).unwrap();
+        assert_eq!(got, Timestamp::new(6, 0, node.clone()));
+
+        // Spilling never gets past the drift limit
+        let mut ts = Timestamp::new(MAX_DRIFT, 0xFFFF, node);
+        assert_eq!(
+            ts.send_with(0, CounterOverflow::Spill),
+            Err(ClockError::Drift {
+                logical: MAX_DRIFT + 1,
+                physical: 0,
+                max_drift: MAX_DRIFT,
+            })
+        );
+    }
+
     #[test]
     fn test_send_drift() {
         let mut ts = Timestamp::new(MAX_DRIFT + 1, 0x0, "1234123412341234".to_string());
 
         let got = ts.send(0).err().unwrap();
-        let want = TimestampError::ClockDriftError(MAX_DRIFT + 1, 0, MAX_DRIFT);
+        let want = ClockError::Drift {
+            logical: MAX_DRIFT + 1,
+            physical: 0,
+            max_drift: MAX_DRIFT,
+        };
 
         assert_eq!(got, want);
+        assert_eq!(got.logical_time(), Some(MAX_DRIFT + 1));
+        assert_eq!(got.physical_time(), Some(0));
+        assert_eq!(got.max_drift(), Some(MAX_DRIFT));
+        assert_eq!(got.drift_offset(), Some(MAX_DRIFT + 1));
+        assert_eq!(ClockError::Overflow.physical_time(), None);
     }
 
     #[test]
@@ -296,6 +889,26 @@ mod test {
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn test_observe_persisted() {
+        let node = "1234123412341234".to_string();
+        let mut ts = Timestamp::new(0, 0x0, node.clone());
+        let last = Timestamp::new(10, 0x5, node.clone());
+
+        ts.observe_persisted(&last, 10).unwrap();
+        let got = ts.send(1).unwrap();
+        let want = Timestamp::new(10, 0x6, node.clone());
+        assert_eq!(got, want);
+
+        let last = Timestamp::new(MAX_DRIFT + 11, 0x0, node);
+        let got = ts.observe_persisted(&last, 10).err().unwrap();
+        let want = ClockError::Regression {
+            persisted: MAX_DRIFT + 11,
+            physical: 10,
+        };
+        assert_eq!(got, want);
+    }
+
     #[test]
     fn test_recv_duplicate_node() {
         let node = "1234123412341234".to_string();
@@ -303,7 +916,7 @@ mod test {
         let msg = Timestamp::new(1, 0x0, node.clone());
 
         let got = ts.recv(&msg, 1).err().unwrap();
-        let want = TimestampError::DuplicateNodeError(node);
+        let want = SyncError::DuplicateNode(node);
 
         assert_eq!(got, want);
     }
@@ -314,9 +927,48 @@ mod test {
         let msg = Timestamp::new(MAX_DRIFT + 1, 0x0, make_client_id());
 
         let got = ts.recv(&msg, 0).err().unwrap();
-        let want = TimestampError::ClockDriftError(MAX_DRIFT + 1, 0, MAX_DRIFT);
+        let want = ClockError::Drift {
+            logical: MAX_DRIFT + 1,
+            physical: 0,
+            max_drift: MAX_DRIFT,
+        };
 
-        assert_eq!(got, want);
+        assert_eq!(got.clock(), Some(&want));
+    }
+
+    #[test]
+    fn test_epoch() {
+        let ts = Timestamp::new(1711231855000, 0, make_client_id());
+        let epoch = ts.epoch();
+        assert_eq!(epoch, Epoch(28520530));
+        assert_eq!(epoch.millis(), 1711231800000);
+        assert_eq!(Epoch::from(epoch.to_datetime().unwrap()), epoch);
+        assert_eq!((epoch + 2) - epoch, 2);
+        assert_eq!(epoch - 1, Epoch::from_millis(1711231799999));
+
+        // Rounds down before the epoch rather than towards zero
+        assert_eq!(Epoch::from_millis(-1), Epoch(-1));
+    }
+
+    #[test]
+    fn test_make_client_id() {
+        let id = make_client_id();
+        assert_eq!(id.len(), 16);
+        assert!(id.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(id, make_client_id());
+        assert_eq!(make_client_id_with_len(7).len(), 7);
+    }
+
+    #[test]
+    fn test_seeded_client_ids() {
+        let mut a = SeededClientIds::new(42);
+        let mut b = SeededClientIds::new(42);
+        let first = a.next_id();
+        assert_eq!(first, b.next_id());
+        assert_ne!(first, a.next_id());
+        assert_eq!(first.len(), 16);
+        assert_eq!(SeededClientIds::new(42).next_id_with_len(5), first[..5]);
+        assert_eq!(a.next_id_with_len(32).len(), 32);
     }
 
     #[test]
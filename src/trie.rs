@@ -1,191 +1,1773 @@
-use std::collections::{BTreeSet, HashMap};
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
 
-use crate::timestamp::{make_client_id, Epoch, Timestamp};
+use crate::arena::TrieArena;
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::compress::{compress, decompress, Compression};
+use crate::header::Header;
+#[cfg(feature = "proto")]
+use crate::proto;
+use crate::timestamp::{Epoch, Timestamp};
 use chrono::{DateTime, Utc};
+#[cfg(feature = "js")]
+use serde_json::{Map, Value};
+use xxhash_rust::xxh3::xxh3_64;
 
-#[derive(Clone, Default, Debug)]
+// Deepest nesting accepted when decoding a peer's trie
+const MAX_DECODE_DEPTH: usize = 64;
+
+/// Key digits used unless a trie is built with another depth. Sixteen
+/// base-3 digits cover minutes from 1970 until late 2051.
+pub const DEFAULT_DEPTH: usize = 16;
+
+/// The deepest trie whose keys still map back to a representable date
+pub const MAX_DEPTH: usize = 23;
+
+/// What a trie does with timestamps past the end of its range
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Overflow {
+    /// Refuse them with `KeyError::OutOfRange`
+    #[default]
+    Reject,
+    /// Index them all in the trie's last minute bucket. Diffs that land
+    /// there report that minute, which is still no later than any of the
+    /// timestamps in it, so sync stays correct; it just can't narrow down
+    /// which of them differ. Timestamps before the epoch are still refused.
+    LastBucket,
+}
+
+/// How long a trie's buckets are
+///
+/// Coarser buckets make shallower tries with fewer nodes, at the cost of
+/// resending more history after a diff, since a divergence can only be
+/// placed to the start of a bucket. Peers must use the same resolution;
+/// it is encoded with the trie, and old peers only know minutes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Resolution {
+    Second,
+    #[default]
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    /// Milliseconds per bucket
+    pub fn millis(self) -> i64 {
+        match self {
+            Resolution::Second => 1000,
+            Resolution::Minute => 60_000,
+            Resolution::Hour => 60 * 60_000,
+            Resolution::Day => 24 * 60 * 60_000,
+        }
+    }
+
+    /// Key digits reaching from 1970 past 2050, as `DEFAULT_DEPTH` does
+    /// for minutes
+    pub fn default_depth(self) -> usize {
+        match self {
+            Resolution::Second => 20,
+            Resolution::Minute => DEFAULT_DEPTH,
+            Resolution::Hour => 13,
+            Resolution::Day => 10,
+        }
+    }
+
+    /// The deepest trie whose keys still map back to a representable date
+    pub fn max_depth(self) -> usize {
+        match self {
+            Resolution::Second | Resolution::Minute => MAX_DEPTH,
+            Resolution::Hour => 19,
+            Resolution::Day => 16,
+        }
+    }
+
+    // Minutes are 0 so that minute tries encode as they always have
+    fn id(self) -> u8 {
+        match self {
+            Resolution::Minute => 0,
+            Resolution::Second => 1,
+            Resolution::Hour => 2,
+            Resolution::Day => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(Resolution::Minute),
+            1 => Some(Resolution::Second),
+            2 => Some(Resolution::Hour),
+            3 => Some(Resolution::Day),
+            _ => None,
+        }
+    }
+}
+
+/// The byte leading an encoded trie: the scheme id, with the resolution
+/// in the high nibble
+pub(crate) fn layout_id(scheme: HashScheme, resolution: Resolution) -> u8 {
+    resolution.id() << 4 | scheme.id()
+}
+
+pub(crate) fn from_layout_id(id: u8) -> Result<(HashScheme, Resolution), DecodeError> {
+    let scheme = HashScheme::from_id(id & 0x0f).ok_or(DecodeError::UnknownHashScheme(id & 0x0f))?;
+    let resolution = Resolution::from_id(id >> 4).ok_or(DecodeError::UnknownResolution(id >> 4))?;
+    Ok((scheme, resolution))
+}
+
+/// How timestamp hashes are computed and folded into each trie node
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HashScheme {
+    /// murmur3_32 of the timestamp string, combined with XOR. This is what
+    /// deployed peers speak, but a timestamp inserted twice cancels out and
+    /// distinct sets collide easily.
+    #[default]
+    Murmur32Xor,
+    /// xxh3_64 of the timestamp string, combined with wrapping addition so
+    /// repeated inserts accumulate instead of cancelling
+    Xxh64Add,
+}
+
+impl HashScheme {
+    pub fn id(self) -> u8 {
+        match self {
+            HashScheme::Murmur32Xor => 1,
+            HashScheme::Xxh64Add => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            1 => Some(HashScheme::Murmur32Xor),
+            2 => Some(HashScheme::Xxh64Add),
+            _ => None,
+        }
+    }
+
+    pub fn hash(self, timestamp: &Timestamp) -> u64 {
+        match self {
+            HashScheme::Murmur32Xor => Murmur3.hash(timestamp),
+            HashScheme::Xxh64Add => Xxh3.hash(timestamp),
+        }
+    }
+
+    // Clamp a hasher's output to the width the scheme puts on the wire
+    pub(crate) fn fit(self, hash: u64) -> u64 {
+        match self {
+            HashScheme::Murmur32Xor => hash as u32 as u64,
+            HashScheme::Xxh64Add => hash,
+        }
+    }
+
+    pub(crate) fn combine(self, acc: u64, hash: u64) -> u64 {
+        match self {
+            HashScheme::Murmur32Xor => acc ^ hash,
+            HashScheme::Xxh64Add => acc.wrapping_add(hash),
+        }
+    }
+
+    // Inverse of `combine`
+    fn uncombine(self, acc: u64, hash: u64) -> u64 {
+        match self {
+            HashScheme::Murmur32Xor => acc ^ hash,
+            HashScheme::Xxh64Add => acc.wrapping_sub(hash),
+        }
+    }
+}
+
+/// Computes the hash a timestamp contributes to a trie
+///
+/// The scheme still decides how hashes are combined and how wide they are
+/// on the wire; a 32-bit scheme keeps the low 32 bits of each hash. Peers
+/// must agree on the hasher out of band, since it isn't encoded.
+pub trait TimestampHasher: fmt::Debug + Send + Sync {
+    fn hash(&self, timestamp: &Timestamp) -> u64;
+}
+
+/// murmur3_32 with seed 0 over the timestamp string, the v1 hash
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Murmur3;
+
+impl TimestampHasher for Murmur3 {
+    fn hash(&self, timestamp: &Timestamp) -> u64 {
+        timestamp.hash() as u64
+    }
+}
+
+/// xxh3_64 over the timestamp string
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Xxh3;
+
+impl TimestampHasher for Xxh3 {
+    fn hash(&self, timestamp: &Timestamp) -> u64 {
+        timestamp.with_key(xxh3_64)
+    }
+}
+
+/// Merkle trie over minute buckets
+///
+/// A timestamp's key is its minute since the epoch written as `depth`
+/// base-3 digits, most significant first, so a trie of depth `d` indexes
+/// timestamps from the epoch up to, but excluding, minute `3^d`. Tries
+/// built `at_resolution` count seconds, hours or days instead.
+#[derive(Clone, Debug)]
 pub struct Trie {
-    hash: u32,
-    children: HashMap<String, Trie>,
+    scheme: HashScheme,
+    depth: usize,
+    resolution: Resolution,
+    overflow: Overflow,
+    // Only set on tries built with a custom hasher
+    hasher: Option<Arc<dyn TimestampHasher>>,
+    // Only set on tries recording deltas
+    deltas: Option<Vec<TrieDelta>>,
+    root: Node,
+}
+
+// One node of the tree. Settings shared by the whole trie live on `Trie`,
+// so the millions of these in a large one hold only what differs.
+#[derive(Clone, Debug, Default)]
+struct Node {
+    hash: u64,
+    // Minute buckets under this node, kept so equality needn't walk the tree
+    buckets: usize,
+    children: Children,
 }
 
-impl Trie {
-    pub fn new() -> Trie {
-        Trie {
-            hash: 0,
-            children: HashMap::new(),
+impl Default for Trie {
+    fn default() -> Self {
+        Trie::new()
+    }
+}
+
+impl Trie {
+    pub fn new() -> Trie {
+        Trie::with_scheme(HashScheme::default())
+    }
+
+    pub fn with_scheme(scheme: HashScheme) -> Trie {
+        Trie::with_depth(scheme, DEFAULT_DEPTH)
+    }
+
+    /// # Panics
+    ///
+    /// If `depth` is zero or greater than `MAX_DEPTH`.
+    pub fn with_depth(scheme: HashScheme, depth: usize) -> Trie {
+        assert!(
+            (1..=MAX_DEPTH).contains(&depth),
+            "trie depth must be between 1 and {}",
+            MAX_DEPTH
+        );
+        Trie {
+            scheme,
+            depth,
+            resolution: Resolution::default(),
+            overflow: Overflow::default(),
+            hasher: None,
+            deltas: None,
+            root: Node::default(),
+        }
+    }
+
+    /// A trie of `resolution` buckets, deep enough to reach past 2050
+    pub fn with_resolution(scheme: HashScheme, resolution: Resolution) -> Trie {
+        Trie::with_depth(scheme, resolution.default_depth()).at_resolution(resolution)
+    }
+
+    /// Bucket by `resolution` rather than by minute, keeping the depth
+    ///
+    /// # Panics
+    ///
+    /// If anything was inserted already, or the depth is greater than the
+    /// resolution's `max_depth`.
+    pub fn at_resolution(mut self, resolution: Resolution) -> Trie {
+        assert!(
+            self.root.hash == 0 && self.root.children.is_empty(),
+            "trie is not empty"
+        );
+        assert!(
+            self.depth <= resolution.max_depth(),
+            "{:?} tries can be at most {} deep",
+            resolution,
+            resolution.max_depth()
+        );
+        self.resolution = resolution;
+        self
+    }
+
+    /// A trie hashing timestamps with `hasher` rather than the scheme's own
+    pub fn with_hasher(scheme: HashScheme, hasher: impl TimestampHasher + 'static) -> Trie {
+        let mut trie = Trie::with_scheme(scheme);
+        trie.hasher = Some(Arc::new(hasher));
+        trie
+    }
+
+    pub fn scheme(&self) -> HashScheme {
+        self.scheme
+    }
+
+    pub(crate) fn entry_hash(&self, timestamp: &Timestamp, content: u64) -> u64 {
+        self.scheme.fit(self.hash_timestamp(timestamp) ^ content)
+    }
+
+    fn hash_timestamp(&self, timestamp: &Timestamp) -> u64 {
+        match &self.hasher {
+            Some(hasher) => self.scheme.fit(hasher.hash(timestamp)),
+            None => self.scheme.hash(timestamp),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// The first minute past the range this trie can index, rounded down
+    /// for tries of seconds
+    pub fn end(&self) -> Epoch {
+        Epoch::from_millis(self.end_millis())
+    }
+
+    /// The first millisecond past the range this trie can index
+    pub fn end_millis(&self) -> i64 {
+        self.buckets_end() * self.resolution.millis()
+    }
+
+    // One past the last bucket
+    fn buckets_end(&self) -> i64 {
+        3i64.pow(self.depth as u32)
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.overflow
+    }
+
+    /// Choose what happens to timestamps past the end of the range. This is
+    /// a local policy and isn't encoded, so a decoded trie rejects them.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Error unless this trie can index `timestamp`
+    pub fn check(&self, timestamp: &Timestamp) -> Result<(), KeyError> {
+        self.bucket(timestamp.millis()).map(|_| ())
+    }
+
+    // The bucket holding `millis`
+    fn bucket(&self, millis: i64) -> Result<i64, KeyError> {
+        let bucket = millis.div_euclid(self.resolution.millis());
+        let end = self.buckets_end();
+        match self.overflow {
+            _ if (0..end).contains(&bucket) => Ok(bucket),
+            Overflow::LastBucket if bucket >= end => Ok(end - 1),
+            _ => Err(KeyError::OutOfRange(millis, self.depth)),
+        }
+    }
+
+    // The start of the bucket, or subtree, a key names
+    fn key_time(&self, key: &Key) -> DateTime<Utc> {
+        key.time(self.depth, self.resolution)
+    }
+
+    /// Every inserted timestamp's hash, folded together by the trie's scheme
+    pub fn root_hash(&self) -> u64 {
+        self.root.hash
+    }
+
+    /// # Panics
+    ///
+    /// If the timestamp is outside the trie's range; see `try_insert`.
+    pub fn insert(&mut self, timestamp: Timestamp) {
+        self.insert_with(timestamp, 0)
+    }
+
+    pub fn try_insert(&mut self, timestamp: Timestamp) -> Result<(), KeyError> {
+        self.try_insert_with(timestamp, 0)
+    }
+
+    /// # Panics
+    ///
+    /// If the timestamp is outside the trie's range; see `try_insert_with`.
+    pub fn insert_with(&mut self, timestamp: Timestamp, content: u64) {
+        if let Err(err) = self.try_insert_with(timestamp, content) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Insert a timestamp with its hash mixed with `content`, so the same
+    /// timestamp carrying a different payload leaves a different trie. A
+    /// `content` of 0 is a plain insert.
+    pub fn try_insert_with(&mut self, timestamp: Timestamp, content: u64) -> Result<(), KeyError> {
+        let bucket = self.bucket(timestamp.millis())?;
+        // Want to be specific to the TS
+        let hash = self.entry_hash(&timestamp, content);
+
+        trace!(bucket, hash, "trie insert");
+        self.insert_hash(bucket, hash);
+        Ok(())
+    }
+
+    // Every single insert ends here, so it is where deltas are recorded
+    fn insert_hash(&mut self, bucket: i64, hash: u64) {
+        let scheme = self.scheme;
+        self.root.hash = scheme.combine(self.root.hash, hash);
+        let key = Key::of(bucket, self.depth);
+        self.root.insert_key(scheme, key.digits(), hash);
+        self.record(TrieDelta::Insert { bucket, hash });
+    }
+
+    /// Insert many timestamps at once
+    ///
+    /// Entries are grouped by key so each node's hash is updated once per
+    /// batch rather than once per timestamp. Input already in timestamp
+    /// order skips the sort.
+    ///
+    /// # Panics
+    ///
+    /// If any timestamp is outside the trie's range; see
+    /// `try_insert_batch`.
+    pub fn insert_batch(&mut self, timestamps: &[Timestamp]) {
+        if let Err(err) = self.try_insert_batch(timestamps) {
+            panic!("{}", err);
+        }
+    }
+
+    /// Insert many timestamps at once, or none if any is out of range
+    pub fn try_insert_batch(&mut self, timestamps: &[Timestamp]) -> Result<(), KeyError> {
+        let mut entries = timestamps
+            .iter()
+            .map(|ts| Ok((self.bucket(ts.millis())?, self.hash_timestamp(ts))))
+            .collect::<Result<Vec<_>, KeyError>>()?;
+        if !entries.is_sorted_by_key(|(bucket, _)| *bucket) {
+            entries.sort_by_key(|(bucket, _)| *bucket);
+        }
+
+        self.root
+            .insert_entries(self.scheme, self.depth, &entries, 0);
+        if self.deltas.is_some() {
+            for &(bucket, hash) in &entries {
+                self.record(TrieDelta::Insert { bucket, hash });
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a previously inserted timestamp
+    ///
+    /// Its hash is taken back out of every node along its key path, and
+    /// nodes left with no children and a zero hash are dropped. Removing a
+    /// timestamp that was never inserted corrupts the trie; one outside the
+    /// trie's range can't have been, and is ignored.
+    pub fn remove(&mut self, timestamp: &Timestamp) {
+        self.remove_with(timestamp, 0)
+    }
+
+    /// Remove a timestamp inserted with `try_insert_with`
+    pub fn remove_with(&mut self, timestamp: &Timestamp, content: u64) {
+        let Ok(bucket) = self.bucket(timestamp.millis()) else {
+            return;
+        };
+        let hash = self.entry_hash(timestamp, content);
+        trace!(bucket, hash, "trie remove");
+        self.remove_hash(bucket, hash);
+    }
+
+    fn remove_hash(&mut self, bucket: i64, hash: u64) {
+        let scheme = self.scheme;
+        self.root.hash = scheme.uncombine(self.root.hash, hash);
+        let key = Key::of(bucket, self.depth);
+        self.root.remove_key(scheme, key.digits(), hash);
+        self.record(TrieDelta::Remove { bucket, hash });
+    }
+
+    // For callers that key buckets themselves, checked against the range
+    pub(crate) fn insert_bucket(&mut self, bucket: i64, hash: u64) {
+        self.insert_hash(bucket, hash);
+    }
+
+    pub(crate) fn remove_bucket(&mut self, bucket: i64, hash: u64) {
+        self.remove_hash(bucket, hash);
+    }
+
+    /// Start or stop keeping a log of every change, to persist in place of
+    /// the whole trie; see `take_deltas`
+    ///
+    /// Stopping drops anything not yet taken.
+    pub fn record_deltas(&mut self, record: bool) {
+        self.deltas = record.then(Vec::new);
+    }
+
+    pub fn is_recording_deltas(&self) -> bool {
+        self.deltas.is_some()
+    }
+
+    /// The changes since recording started or the last call, oldest first
+    ///
+    /// Append them to a log, and restore at boot by loading the last saved
+    /// trie and passing the log written since to `apply_deltas`.
+    pub fn take_deltas(&mut self) -> Vec<TrieDelta> {
+        self.deltas
+            .as_mut()
+            .map(core::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn record(&mut self, delta: TrieDelta) {
+        if let Some(deltas) = &mut self.deltas {
+            deltas.push(delta);
+        }
+    }
+
+    /// Replay recorded changes, in the order they were taken
+    ///
+    /// The deltas must have come from a trie with the same scheme, depth
+    /// and resolution, which held what this one does when they started.
+    /// Stops at the first delta outside this trie's range, leaving those
+    /// before it applied. Applied deltas are recorded again if this trie
+    /// is recording.
+    pub fn apply_deltas<'a>(
+        &mut self,
+        deltas: impl IntoIterator<Item = &'a TrieDelta>,
+    ) -> Result<(), KeyError> {
+        for delta in deltas {
+            let (TrieDelta::Insert { bucket, hash } | TrieDelta::Remove { bucket, hash }) = *delta;
+            if !(0..self.buckets_end()).contains(&bucket) {
+                return Err(KeyError::OutOfRange(
+                    bucket.saturating_mul(self.resolution.millis()),
+                    self.depth,
+                ));
+            }
+            match delta {
+                TrieDelta::Insert { .. } => self.insert_hash(bucket, hash),
+                TrieDelta::Remove { .. } => self.remove_hash(bucket, hash),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any timestamp in the minute containing `time` has been
+    /// indexed
+    ///
+    /// Nodes only keep hashes, so this can't answer for a single timestamp;
+    /// a receiver can still use it to skip fetching buckets it already has.
+    pub fn contains_minute(&self, time: DateTime<Utc>) -> bool {
+        self.minute_hash(Epoch::from(time)).is_some()
+    }
+
+    /// The hash of a minute bucket, or None if nothing was indexed in it
+    ///
+    /// Two tries with the same hash for a minute hold the same timestamps
+    /// in it. At other resolutions this is the bucket holding the minute's
+    /// start.
+    pub fn minute_hash(&self, epoch: Epoch) -> Option<u64> {
+        let bucket = self.bucket(epoch.millis()).ok()?;
+        let mut node = &self.root;
+        for &digit in Key::of(bucket, self.depth).digits() {
+            node = node.children.get(digit as usize)?;
+        }
+        Some(node.hash)
+    }
+
+    /// How many minute buckets hold anything
+    pub fn len(&self) -> usize {
+        self.root.buckets
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.buckets == 0
+    }
+
+    /// Size and shape, for watching growth and deciding when to prune
+    ///
+    /// Walks every node, so costs as much as a clone.
+    pub fn stats(&self) -> TrieStats {
+        let mut stats = TrieStats {
+            nodes: 0,
+            leaves: 0,
+            max_depth: 0,
+            memory_bytes: 0,
+            oldest: None,
+            newest: None,
+        };
+        self.root.gather_stats(0, &mut stats);
+        // Non-root nodes are boxed; the root lives wherever its owner does
+        stats.memory_bytes = stats.nodes * core::mem::size_of::<Node>();
+        stats.oldest = self.edge_key(false).map(|key| self.key_time(&key));
+        stats.newest = self.edge_key(true).map(|key| self.key_time(&key));
+        stats
+    }
+
+    // The key of the lowest or highest bucket, or None if the trie is empty
+    fn edge_key(&self, highest: bool) -> Option<Key> {
+        let mut key = Key::default();
+        let mut node = &self.root;
+        loop {
+            let mut children = node.children.iter();
+            let next = if highest {
+                children.next_back()
+            } else {
+                children.next()
+            };
+            let Some((digit, child)) = next else {
+                break;
+            };
+            key = key.child(digit);
+            node = child;
+        }
+        (!key.digits().is_empty()).then_some(key)
+    }
+
+    /// Every indexed minute bucket and its hash, oldest first
+    ///
+    /// Hashes are as wide as the scheme's, the same values `minute_hash`
+    /// returns.
+    pub fn iter_minutes(&self) -> Minutes<'_> {
+        Minutes {
+            stack: vec![(Key::default(), &self.root)],
+            depth: self.depth,
+            resolution: self.resolution,
+        }
+    }
+
+    pub fn build(timestamps: Vec<Timestamp>) -> Self {
+        Trie::build_with_scheme(HashScheme::default(), timestamps)
+    }
+
+    /// Build a trie under a given scheme; this is also the migration path,
+    /// since hashes can't be converted and must be recomputed from the log
+    pub fn build_with_scheme(scheme: HashScheme, timestamps: Vec<Timestamp>) -> Self {
+        let mut trie = Trie::with_scheme(scheme);
+        trie.insert_batch(&timestamps);
+        trie
+    }
+
+    /// Error unless `theirs` hashes and buckets timestamps as this trie
+    /// does, without which the two can't be diffed
+    pub fn check_layout(&self, theirs: &Trie) -> Result<(), TrieError> {
+        if theirs.scheme() != self.scheme() {
+            return Err(TrieError::SchemeMismatch(self.scheme(), theirs.scheme()));
+        }
+        if theirs.resolution() != self.resolution() {
+            return Err(TrieError::ResolutionMismatch(
+                self.resolution(),
+                theirs.resolution(),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn diff(&self, other: &Trie) -> Option<DateTime<Utc>> {
+        let span = debug_span!("trie_diff", divergence = tracing::field::Empty);
+        let divergence = self
+            .root
+            .diff_recursive(&other.root, Key::default(), &mut 0)
+            .map(|path| self.key_time(&path));
+        debug!(?divergence, "trie diff");
+        record!(span, divergence = tracing::field::debug(&divergence));
+        counter!(
+            "markle_trie_diffs_total",
+            1,
+            "diverged" => if divergence.is_some() { "true" } else { "false" },
+        );
+        divergence
+    }
+
+    // `diff` as a bucket number rather than a time
+    pub(crate) fn diff_bucket(&self, other: &Trie) -> Option<i64> {
+        self.root
+            .diff_recursive(&other.root, Key::default(), &mut 0)
+            .map(|path| path.bucket(self.depth))
+    }
+
+    /// `diff`, but never earlier than `floor`
+    ///
+    /// A corrupt or ancient peer trie can diverge back at the epoch, and
+    /// syncing from there re-sends the whole log. When the divergence lies
+    /// before `floor` this returns `floor` with `floored` set, and the
+    /// caller can transfer a snapshot instead.
+    pub fn diff_since(&self, other: &Trie, floor: DateTime<Utc>) -> Option<FlooredDiff> {
+        let time = self.diff(other)?;
+        Some(if time < floor {
+            FlooredDiff {
+                time: floor,
+                floored: true,
+            }
+        } else {
+            FlooredDiff {
+                time,
+                floored: false,
+            }
+        })
+    }
+
+    /// `diff`, plus which side holds the divergent bucket
+    pub fn diff_detailed(&self, other: &Trie) -> Option<DiffDetail> {
+        let path = self
+            .root
+            .diff_recursive(&other.root, Key::default(), &mut 0)?;
+        let (mut ours, mut theirs) = (Some(&self.root), Some(&other.root));
+        for &digit in path.digits() {
+            ours = ours.and_then(|node| node.children.get(digit as usize));
+            theirs = theirs.and_then(|node| node.children.get(digit as usize));
+        }
+        let divergence = match (ours, theirs) {
+            (Some(_), None) => Divergence::LocalOnly,
+            (None, Some(_)) => Divergence::RemoteOnly,
+            _ => Divergence::Both,
+        };
+        Some(DiffDetail {
+            time: self.key_time(&path),
+            divergence,
+        })
+    }
+
+    /// How many node pairs `diff` compares to find the divergence
+    ///
+    /// Diff follows a single path down, so this is bounded by the key depth
+    /// no matter how many timestamps either trie holds; tests and the bench
+    /// use it to keep that guarantee from regressing.
+    pub fn diff_visits(&self, other: &Trie) -> usize {
+        let mut visits = 0;
+        self.root
+            .diff_recursive(&other.root, Key::default(), &mut visits);
+        visits
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        self.encode_into(&mut w);
+        w.into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Trie, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let trie = Trie::decode_from(&mut r)?;
+        r.finish()?;
+        Ok(trie)
+    }
+
+    /// `encode`, compressed behind a byte naming the compression; a large
+    /// trie's hashes are noise, but its key structure packs well
+    pub fn encode_compressed(&self, compression: Compression) -> Vec<u8> {
+        compress(&self.encode(), compression)
+    }
+
+    /// Decode what `encode_compressed` wrote, with any compression
+    pub fn decode_compressed(bytes: &[u8]) -> Result<Trie, DecodeError> {
+        Trie::decode(&decompress(bytes)?)
+    }
+
+    /// Encode for persisting, behind a header recording the scheme and
+    /// depth
+    pub fn save(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        Header::of(self).encode(&mut w);
+        self.encode_nodes(&mut w);
+        w.into_bytes()
+    }
+
+    /// Load a trie written by `save`
+    pub fn load(bytes: &[u8]) -> Result<Trie, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let header = Header::decode(&mut r)?;
+        let mut root = Node::decode(&mut r, header.scheme, 0)?;
+        r.finish()?;
+        if root.height() > header.depth {
+            return Err(DecodeError::TooDeep(header.depth));
+        }
+        root.count_buckets(0, header.depth);
+        let mut trie = Trie::with_depth(header.scheme, header.depth);
+        trie.resolution = header.resolution;
+        trie.root = root;
+        Ok(trie)
+    }
+
+    /// Load a trie written by `save`, failing unless it was written with
+    /// the same scheme and depth as `expected`
+    pub fn load_expecting(bytes: &[u8], expected: &Header) -> Result<Trie, DecodeError> {
+        let trie = Trie::load(bytes)?;
+        Header::of(&trie).expect(expected)?;
+        Ok(trie)
+    }
+
+    pub(crate) fn encode_into(&self, w: &mut Writer) {
+        w.put_u8(layout_id(self.scheme, self.resolution));
+        self.encode_nodes(w);
+    }
+
+    pub(crate) fn decode_from(r: &mut Reader) -> Result<Trie, DecodeError> {
+        let (scheme, resolution) = from_layout_id(r.get_u8()?)?;
+        Trie::decode_nodes(r, scheme, resolution)
+    }
+
+    /// The node layout, without the leading scheme id. 32-bit schemes write
+    /// 4-byte hashes so that v1 tries keep their original bytes.
+    pub(crate) fn encode_nodes(&self, w: &mut Writer) {
+        self.root.encode(w, self.scheme);
+    }
+
+    /// Decode the node layout. The depth isn't on the wire, so it is taken
+    /// from the longest key path, or the default for an empty trie.
+    pub(crate) fn decode_nodes(
+        r: &mut Reader,
+        scheme: HashScheme,
+        resolution: Resolution,
+    ) -> Result<Trie, DecodeError> {
+        Trie::from_root(scheme, resolution, Node::decode(r, scheme, 0)?)
+    }
+
+    /// Encode as nested objects, `{"0": {...}, "1": {...}, "hash": n}`
+    ///
+    /// This is the layout crdt.js-style servers keep their merkle blobs in.
+    /// A 32-bit scheme writes hashes as signed integers, as JavaScript's XOR
+    /// leaves them; a 64-bit scheme writes them unsigned. An empty trie is
+    /// `{}`, like a fresh JavaScript one.
+    #[cfg(feature = "js")]
+    pub fn to_json(&self) -> String {
+        if self.root.hash == 0 && self.root.children.is_empty() {
+            return "{}".to_string();
+        }
+        self.root.to_json(self.scheme).to_string()
+    }
+
+    /// Decode the layout written by `to_json`. The scheme isn't recorded in
+    /// it, so the caller names the one the blob was written with.
+    #[cfg(feature = "js")]
+    pub fn from_json(json: &str, scheme: HashScheme) -> Result<Trie, DecodeError> {
+        let value: Value =
+            serde_json::from_str(json).map_err(|err| DecodeError::InvalidJson(err.to_string()))?;
+        let root = Node::from_json(&value, scheme, 0)?;
+        Trie::from_root(scheme, Resolution::Minute, root)
+    }
+
+    #[cfg(feature = "proto")]
+    pub(crate) fn to_proto(&self) -> proto::Trie {
+        proto::Trie {
+            scheme: self.scheme.id() as i32,
+            resolution: self.resolution.id() as i32,
+            root: Some(self.root.to_proto()),
+        }
+    }
+
+    #[cfg(feature = "proto")]
+    pub(crate) fn from_proto(trie: &proto::Trie) -> Result<Trie, DecodeError> {
+        let scheme = u8::try_from(trie.scheme).unwrap_or(u8::MAX);
+        let scheme = HashScheme::from_id(scheme).ok_or(DecodeError::UnknownHashScheme(scheme))?;
+        let resolution = u8::try_from(trie.resolution).unwrap_or(u8::MAX);
+        let resolution =
+            Resolution::from_id(resolution).ok_or(DecodeError::UnknownResolution(resolution))?;
+        let root = match &trie.root {
+            Some(node) => Node::from_proto(node, scheme, 0)?,
+            None => Node::default(),
+        };
+        Trie::from_root(scheme, resolution, root)
+    }
+
+    /// Wrap a decoded root, taking the depth from its longest key path,
+    /// or the default for an empty trie
+    fn from_root(
+        scheme: HashScheme,
+        resolution: Resolution,
+        mut root: Node,
+    ) -> Result<Trie, DecodeError> {
+        let height = root.height();
+        let max = resolution.max_depth();
+        if height > max {
+            return Err(DecodeError::TooDeep(max));
+        }
+        let depth = if height == 0 {
+            resolution.default_depth()
+        } else {
+            height
+        };
+        root.count_buckets(0, depth);
+        let mut trie = Trie::with_depth(scheme, depth);
+        trie.resolution = resolution;
+        trie.root = root;
+        Ok(trie)
+    }
+
+    /// Compare two tries node by node
+    ///
+    /// `==` only looks at the root, which is what sync checks want; this
+    /// walks both trees, so keep it to tests and debugging.
+    pub fn deep_eq(&self, other: &Trie) -> bool {
+        self.scheme == other.scheme
+            && self.depth == other.depth
+            && self.resolution == other.resolution
+            && self.root.deep_eq(&other.root)
+    }
+
+    /// The tree drawn line by line: every node's key prefix and hash, and
+    /// each minute bucket's start time
+    ///
+    /// ```text
+    /// root 0x3a0e5c11 (2 buckets)
+    /// └── 0 0x3a0e5c11
+    ///     ├── 00 ...
+    /// ```
+    ///
+    /// Meant for finding where two peers' tries part ways; diff the output
+    /// of both.
+    pub fn render_ascii(&self) -> String {
+        let mut out = format!(
+            "root {} ({} buckets)\n",
+            self.format_hash(self.root.hash),
+            self.root.buckets
+        );
+        self.render_children(&self.root, &mut out, Key::default(), "");
+        out
+    }
+
+    fn render_children(&self, node: &Node, out: &mut String, key: Key, indent: &str) {
+        let mut children = node.children.iter().peekable();
+        while let Some((digit, child)) = children.next() {
+            let last = children.peek().is_none();
+            let key = key.child(digit);
+            let (branch, rest) = if last {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            out.push_str(&format!(
+                "{}{}{} {}",
+                indent,
+                branch,
+                key,
+                self.format_hash(child.hash)
+            ));
+            if key.digits().len() == self.depth {
+                out.push_str(&format!(" {}", self.key_time(&key)));
+            }
+            out.push('\n');
+            self.render_children(child, out, key, &format!("{}{}", indent, rest));
+        }
+    }
+
+    /// The tree as a Graphviz digraph, nodes named by key prefix and
+    /// labelled with their hashes; render it with `dot -Tsvg`
+    pub fn to_dot(&self) -> String {
+        let mut out = String::from("digraph trie {\n    node [shape=box, fontname=monospace];\n");
+        out.push_str(&format!(
+            "    \"root\" [label=\"root\\n{}\"];\n",
+            self.format_hash(self.root.hash)
+        ));
+        self.dot_children(&self.root, &mut out, Key::default());
+        out.push_str("}\n");
+        out
+    }
+
+    fn dot_children(&self, node: &Node, out: &mut String, key: Key) {
+        let parent = if key.digits().is_empty() {
+            String::from("root")
+        } else {
+            key.to_string()
+        };
+        for (digit, child) in node.children.iter() {
+            let key = key.child(digit);
+            let mut label = format!("{}\\n{}", key, self.format_hash(child.hash));
+            if key.digits().len() == self.depth {
+                label.push_str(&format!("\\n{}", self.key_time(&key)));
+            }
+            out.push_str(&format!("    \"{}\" [label=\"{}\"];\n", key, label));
+            out.push_str(&format!("    \"{}\" -> \"{}\";\n", parent, key));
+            self.dot_children(child, out, key);
+        }
+    }
+
+    // Hex, padded to the scheme's width
+    fn format_hash(&self, hash: u64) -> String {
+        match self.scheme {
+            HashScheme::Murmur32Xor => format!("{:#010x}", hash),
+            HashScheme::Xxh64Add => format!("{:#018x}", hash),
+        }
+    }
+}
+
+impl Node {
+    // Returns whether the insert opened a new minute bucket
+    fn insert_key(&mut self, scheme: HashScheme, key: &[u8], hash: u64) -> bool {
+        let Some((&digit, rest)) = key.split_first() else {
+            let opened = self.buckets == 0;
+            self.buckets = 1;
+            return opened;
+        };
+
+        let child = self.children.get_or_insert(digit as usize);
+        child.hash = scheme.combine(child.hash, hash);
+
+        let opened = child.insert_key(scheme, rest, hash);
+        if opened {
+            self.buckets += 1;
+        }
+        opened
+    }
+
+    // Every entry's bucket shares the first `level` of a trie's `depth` key
+    // digits. Returns how many minute buckets the batch opened.
+    fn insert_entries(
+        &mut self,
+        scheme: HashScheme,
+        depth: usize,
+        entries: &[(i64, u64)],
+        level: usize,
+    ) -> usize {
+        self.hash = entries
+            .iter()
+            .fold(self.hash, |acc, (_, hash)| scheme.combine(acc, *hash));
+        if level == depth {
+            let opened = usize::from(self.buckets == 0);
+            self.buckets = 1;
+            return opened;
+        }
+
+        let mut opened = 0;
+        let mut rest = entries;
+        while let Some(&(bucket, _)) = rest.first() {
+            let first = digit(bucket, level, depth);
+            let run = rest
+                .iter()
+                .take_while(|(bucket, _)| digit(*bucket, level, depth) == first)
+                .count();
+            opened += self.children.get_or_insert(first).insert_entries(
+                scheme,
+                depth,
+                &rest[..run],
+                level + 1,
+            );
+            rest = &rest[run..];
+        }
+        self.buckets += opened;
+        opened
+    }
+
+    // Returns how many minute buckets were dropped
+    fn remove_key(&mut self, scheme: HashScheme, key: &[u8], hash: u64) -> usize {
+        let Some((&digit, rest)) = key.split_first() else {
+            return 0;
+        };
+
+        let digit = digit as usize;
+        let Some(child) = self.children.get_mut(digit) else {
+            return 0;
+        };
+        child.hash = scheme.uncombine(child.hash, hash);
+        let mut dropped = child.remove_key(scheme, rest, hash);
+
+        if child.hash == 0 && child.children.is_empty() {
+            dropped += child.buckets;
+            self.children.remove(digit);
+        }
+        self.buckets -= dropped;
+        dropped
+    }
+
+    fn gather_stats(&self, level: usize, stats: &mut TrieStats) {
+        stats.nodes += 1;
+        stats.max_depth = stats.max_depth.max(level);
+        if level > 0 && self.children.is_empty() {
+            stats.leaves += 1;
+        }
+        for child in self.children.values() {
+            child.gather_stats(level + 1, stats);
+        }
+    }
+
+    // find last time the two trees were equal, their divergent point
+    fn diff_recursive(&self, other: &Node, path: Key, visits: &mut usize) -> Option<Key> {
+        *visits += 1;
+
+        // There is no divergent path
+        if self.hash == other.hash {
+            return None;
+        }
+
+        let diff_key =
+            (0..3).find(
+                |&digit| match (self.children.get(digit), other.children.get(digit)) {
+                    (Some(c), Some(oc)) => c.hash != oc.hash,
+                    (None, None) => false,
+                    _ => true,
+                },
+            );
+
+        if let Some(dk) = diff_key {
+            let path = path.child(dk);
+            let empty = Node::default();
+            match (self.children.get(dk), other.children.get(dk)) {
+                (Some(c), Some(oc)) => c.diff_recursive(oc, path, visits),
+                (Some(c), None) => c.diff_recursive(&empty, path, visits),
+                (None, Some(oc)) => oc.diff_recursive(&empty, path, visits),
+                (None, None) => empty.diff_recursive(&empty, path, visits),
+            }
+        } else {
+            Some(path)
+        }
+    }
+
+    fn deep_eq(&self, other: &Node) -> bool {
+        self.hash == other.hash
+            && self.children.len() == other.children.len()
+            && self.children.iter().all(|(digit, child)| {
+                other
+                    .children
+                    .get(digit)
+                    .is_some_and(|theirs| child.deep_eq(theirs))
+            })
+    }
+
+    // Children go in key order, so equal tries always encode alike
+    fn encode(&self, w: &mut Writer, scheme: HashScheme) {
+        match scheme {
+            HashScheme::Murmur32Xor => w.put_u32(self.hash as u32),
+            HashScheme::Xxh64Add => w.put_u64(self.hash),
+        }
+        w.put_u8(self.children.len() as u8);
+        for (digit, child) in self.children.iter() {
+            w.put_u8(b'0' + digit as u8);
+            child.encode(w, scheme);
+        }
+    }
+
+    fn decode(r: &mut Reader, scheme: HashScheme, depth: usize) -> Result<Node, DecodeError> {
+        if depth > MAX_DECODE_DEPTH {
+            return Err(DecodeError::TooDeep(MAX_DECODE_DEPTH));
+        }
+
+        let hash = match scheme {
+            HashScheme::Murmur32Xor => r.get_u32()? as u64,
+            HashScheme::Xxh64Add => r.get_u64()?,
+        };
+        let count = r.get_u8()?;
+        let mut children = Children::default();
+        for _ in 0..count {
+            let key = r.get_u8()?;
+            if !matches!(key, b'0'..=b'2') {
+                return Err(DecodeError::InvalidKey(key));
+            }
+            let child = Node::decode(r, scheme, depth + 1)?;
+            children.insert((key - b'0') as usize, child);
+        }
+        Ok(Node::from_parts(hash, children))
+    }
+
+    // serde_json sorts keys, which puts the digits before "hash" just like
+    // JavaScript's integer-keys-first property order
+    #[cfg(feature = "js")]
+    fn to_json(&self, scheme: HashScheme) -> Value {
+        let mut map = Map::new();
+        for (digit, child) in self.children.iter() {
+            map.insert(digit.to_string(), child.to_json(scheme));
+        }
+        let hash = match scheme {
+            HashScheme::Murmur32Xor => Value::from(self.hash as u32 as i32),
+            HashScheme::Xxh64Add => Value::from(self.hash),
+        };
+        map.insert("hash".to_string(), hash);
+        Value::Object(map)
+    }
+
+    #[cfg(feature = "js")]
+    fn from_json(value: &Value, scheme: HashScheme, depth: usize) -> Result<Node, DecodeError> {
+        if depth > MAX_DECODE_DEPTH {
+            return Err(DecodeError::TooDeep(MAX_DECODE_DEPTH));
+        }
+        let map = value
+            .as_object()
+            .ok_or_else(|| DecodeError::InvalidJson("expected an object".to_string()))?;
+
+        let mut hash = 0;
+        let mut children = Children::default();
+        for (key, value) in map {
+            match key.as_str() {
+                "hash" => {
+                    let bad = || DecodeError::InvalidJson(format!("bad hash {}", value));
+                    hash = match scheme {
+                        // Signed from JavaScript, but accept the unsigned form too
+                        HashScheme::Murmur32Xor => value
+                            .as_i64()
+                            .filter(|n| (i32::MIN as i64..=u32::MAX as i64).contains(n))
+                            .ok_or_else(bad)?
+                            as u32 as u64,
+                        HashScheme::Xxh64Add => value.as_u64().ok_or_else(bad)?,
+                    };
+                }
+                "0" | "1" | "2" => {
+                    let child = Node::from_json(value, scheme, depth + 1)?;
+                    children.insert((key.as_bytes()[0] - b'0') as usize, child);
+                }
+                _ => {
+                    return Err(DecodeError::InvalidJson(format!(
+                        "unexpected key {:?}",
+                        key
+                    )))
+                }
+            }
         }
+        Ok(Node::from_parts(hash, children))
     }
 
-    fn get_keys(&self) -> Vec<String> {
-        self.children.keys().cloned().collect()
+    #[cfg(feature = "proto")]
+    fn to_proto(&self) -> proto::TrieNode {
+        proto::TrieNode {
+            hash: self.hash,
+            children: self
+                .children
+                .iter()
+                .map(|(digit, child)| proto::TrieChild {
+                    digit: digit as u32,
+                    node: Some(child.to_proto()),
+                })
+                .collect(),
+        }
     }
 
-    pub fn insert(&mut self, timestamp: Timestamp) {
-        // Want to be specific to the TS
-        let hash = timestamp.hash();
+    #[cfg(feature = "proto")]
+    fn from_proto(
+        node: &proto::TrieNode,
+        scheme: HashScheme,
+        depth: usize,
+    ) -> Result<Node, DecodeError> {
+        if depth > MAX_DECODE_DEPTH {
+            return Err(DecodeError::TooDeep(MAX_DECODE_DEPTH));
+        }
+        if scheme.fit(node.hash) != node.hash {
+            return Err(DecodeError::InvalidProto(format!("bad hash {}", node.hash)));
+        }
+        let mut children = Children::default();
+        for child in &node.children {
+            let digit = usize::try_from(child.digit)
+                .ok()
+                .filter(|digit| *digit < 3)
+                .ok_or_else(|| DecodeError::InvalidProto(format!("bad digit {}", child.digit)))?;
+            if children.get(digit).is_some() {
+                return Err(DecodeError::InvalidProto(format!(
+                    "repeated digit {}",
+                    child.digit
+                )));
+            }
+            let grandchild = child
+                .node
+                .as_ref()
+                .ok_or_else(|| DecodeError::InvalidProto("child without a node".to_string()))?;
+            children.insert(digit, Node::from_proto(grandchild, scheme, depth + 1)?);
+        }
+        Ok(Node::from_parts(node.hash, children))
+    }
+
+    /// Assemble a decoded node; pass the finished root to `Trie::from_root`
+    fn from_parts(hash: u64, children: Children) -> Node {
+        Node {
+            hash,
+            buckets: 0,
+            children,
+        }
+    }
 
-        let key = timestamp_to_key(timestamp);
-        self.hash = self.hash ^ hash;
+    fn height(&self) -> usize {
+        self.children
+            .values()
+            .map(|child| child.height() + 1)
+            .max()
+            .unwrap_or(0)
+    }
 
-        self.insert_key(&key, hash)
+    // Recount the minute buckets of a decoded node at `level`, once the
+    // trie's depth is known
+    fn count_buckets(&mut self, level: usize, depth: usize) -> usize {
+        self.buckets = if level == depth {
+            1
+        } else {
+            self.children
+                .values_mut()
+                .map(|child| child.count_buckets(level + 1, depth))
+                .sum()
+        };
+        self.buckets
     }
 
-    fn insert_key(&mut self, key: &str, hash: u32) {
-        if key.is_empty() {
+    // Take on the arena's subtree at `node`, `level` of `depth` digits down
+    fn copy_arena(&mut self, arena: &TrieArena, node: usize, level: usize, depth: usize) {
+        let (hash, children) = arena.node(node);
+        self.hash = hash;
+        if level == depth {
+            self.buckets = 1;
             return;
         }
+        for (digit, child) in children.into_iter().enumerate() {
+            let Some(child) = child else { continue };
+            let mut sub = Node::default();
+            sub.copy_arena(arena, child, level + 1, depth);
+            self.buckets += sub.buckets;
+            self.children.insert(digit, sub);
+        }
+    }
+}
 
-        let child_key = &key[0..1];
-        let child = self
-            .children
-            .entry(child_key.to_string())
-            .or_insert_with(Trie::new);
-        child.hash = child.hash ^ hash;
+// A key prefix: the digits, 0 to 2, from the root down to some node, most
+// significant first. A whole key names a bucket, a shorter one the subtree
+// of buckets starting with it. Kept on the stack, since every insert,
+// removal and diff walks one.
+#[derive(Clone, Copy, Debug, Default)]
+struct Key {
+    digits: [u8; MAX_DEPTH],
+    len: usize,
+}
 
-        child.insert_key(&key[1..], hash)
+impl Key {
+    // The whole key of a bucket already checked to fit in `depth` digits
+    fn of(bucket: i64, depth: usize) -> Key {
+        let all = to_base3::<MAX_DEPTH>(bucket);
+        let mut digits = [0; MAX_DEPTH];
+        digits[..depth].copy_from_slice(&all[MAX_DEPTH - depth..]);
+        Key { digits, len: depth }
     }
 
-    pub fn build(timestamps: Vec<Timestamp>) -> Self {
-        let mut trie = Trie::new();
-        for timestamp in timestamps {
-            trie.insert(timestamp);
-        }
-        trie
+    // One digit further down
+    fn child(mut self, digit: usize) -> Key {
+        self.digits[self.len] = digit as u8;
+        self.len += 1;
+        self
     }
 
-    fn prune(&mut self, timestamp: u32) {
-        unimplemented!()
+    fn digits(&self) -> &[u8] {
+        &self.digits[..self.len]
     }
 
-    fn prune_key(&mut self, key: &str, hash: u32) {
-        unimplemented!()
+    // The first bucket under the node the key leads to
+    fn bucket(&self, depth: usize) -> i64 {
+        let prefix = self
+            .digits()
+            .iter()
+            .fold(0, |acc, &digit| acc * 3 + digit as i64);
+        prefix * 3i64.pow((depth - self.len) as u32)
     }
 
-    pub fn diff<'a>(&self, other: &'a Trie) -> Option<DateTime<Utc>> {
-        let mut path = Vec::new();
-        if let Some(divergence_path) = self.diff_recursive(other, &mut path) {
-            Some(key_to_timestamp(&divergence_path.join("")))
-        } else {
-            None
-        }
+    // The start of that bucket
+    fn time(&self, depth: usize, resolution: Resolution) -> DateTime<Utc> {
+        DateTime::from_timestamp_millis(self.bucket(depth) * resolution.millis()).unwrap()
     }
+}
 
-    // find last time the two trees were equal, their divergent point
-    fn diff_recursive<'a>(
-        &self,
-        other: &'a Trie,
-        path: &'a mut Vec<String>,
-    ) -> Option<Vec<String>> {
-        // There is no divergent path
-        if self.hash == other.hash {
-            return None;
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for &digit in self.digits() {
+            write!(f, "{}", digit)?;
         }
+        Ok(())
+    }
+}
 
-        let mut keys: BTreeSet<String> = BTreeSet::from_iter(self.get_keys());
-        keys.extend(other.get_keys());
+// A node's children, one slot per key digit. Fixed slots rather than a map
+// keyed by digit strings, since large tries hold millions of nodes.
+#[derive(Clone, Debug, Default)]
+struct Children([Option<Box<Node>>; 3]);
 
-        let mut diff_key = None;
+impl Children {
+    fn get(&self, digit: usize) -> Option<&Node> {
+        self.0[digit].as_deref()
+    }
 
-        for key in keys.iter() {
-            let child = self.children.get(key);
-            let other_child = other.children.get(key);
+    fn get_mut(&mut self, digit: usize) -> Option<&mut Node> {
+        self.0[digit].as_deref_mut()
+    }
 
-            match (child, other_child) {
-                (Some(c), Some(oc)) => {
-                    if c.hash != oc.hash {
-                        diff_key = Some(key.clone());
-                        break;
-                    }
-                }
-                (Some(_), None) => {
-                    diff_key = Some(key.clone());
-                    break;
-                }
-                (None, Some(_)) => {
-                    diff_key = Some(key.clone());
-                    break;
-                }
-                _ => {}
-            }
+    fn get_or_insert(&mut self, digit: usize) -> &mut Node {
+        self.0[digit].get_or_insert_with(Box::default)
+    }
+
+    // Callers check the digit, as decoding reports bad ones
+    fn insert(&mut self, digit: usize, child: Node) {
+        self.0[digit] = Some(Box::new(child));
+    }
+
+    fn remove(&mut self, digit: usize) {
+        self.0[digit] = None;
+    }
+
+    fn len(&self) -> usize {
+        self.0.iter().flatten().count()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.iter().all(Option::is_none)
+    }
+
+    // In key order
+    fn iter(&self) -> impl DoubleEndedIterator<Item = (usize, &Node)> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(digit, child)| Some((digit, child.as_deref()?)))
+    }
+
+    fn values(&self) -> impl Iterator<Item = &Node> {
+        self.0.iter().flatten().map(|child| &**child)
+    }
+
+    fn values_mut(&mut self) -> impl Iterator<Item = &mut Node> {
+        self.0.iter_mut().flatten().map(|child| &mut **child)
+    }
+}
+
+/// Where two tries part ways; see `Trie::diff_detailed`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffDetail {
+    /// The same time `diff` returns
+    pub time: DateTime<Utc>,
+    pub divergence: Divergence,
+}
+
+/// One change to a trie: an entry's hash added to or taken from a bucket;
+/// see `Trie::record_deltas`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrieDelta {
+    Insert { bucket: i64, hash: u64 },
+    Remove { bucket: i64, hash: u64 },
+}
+
+impl TrieDelta {
+    /// Bytes in an encoded delta; they're fixed-size so a log can be
+    /// truncated to its last whole record after a crash
+    pub const LEN: usize = 17;
+
+    /// The tag, the bucket, counted in the trie's resolution from the Unix
+    /// epoch, then the hash
+    pub fn encode(&self) -> [u8; TrieDelta::LEN] {
+        let (tag, bucket, hash) = match *self {
+            TrieDelta::Insert { bucket, hash } => (1, bucket, hash),
+            TrieDelta::Remove { bucket, hash } => (2, bucket, hash),
+        };
+        let mut bytes = [0; TrieDelta::LEN];
+        bytes[0] = tag;
+        bytes[1..9].copy_from_slice(&bucket.to_be_bytes());
+        bytes[9..].copy_from_slice(&hash.to_be_bytes());
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<TrieDelta, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let tag = r.get_u8()?;
+        let bucket = r.get_i64()?;
+        let hash = r.get_u64()?;
+        r.finish()?;
+        match tag {
+            1 => Ok(TrieDelta::Insert { bucket, hash }),
+            2 => Ok(TrieDelta::Remove { bucket, hash }),
+            tag => Err(DecodeError::InvalidTag(tag)),
         }
+    }
+}
 
-        if let Some(dk) = diff_key {
-            path.push(dk.clone());
-            match (self.children.get(&dk), other.children.get(&dk)) {
-                (Some(c), Some(oc)) => c.diff_recursive(oc, path),
-                (Some(c), None) => c.diff_recursive(&Trie::new(), path),
-                (None, Some(oc)) => oc.diff_recursive(&Trie::new(), path),
-                (None, None) => Trie::new().diff_recursive(&Trie::new(), path),
+/// A trie's size and shape; see `Trie::stats`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrieStats {
+    /// Nodes in the tree, the root included
+    pub nodes: usize,
+    /// Nodes without children, the bucket count for a trie built locally
+    pub leaves: usize,
+    /// The longest path from the root, at most `Trie::depth`
+    pub max_depth: usize,
+    /// Roughly what the nodes occupy in memory, in bytes
+    pub memory_bytes: usize,
+    /// The start of the oldest bucket holding anything
+    pub oldest: Option<DateTime<Utc>>,
+    /// The start of the newest bucket holding anything
+    pub newest: Option<DateTime<Utc>>,
+}
+
+/// A divergence clamped to a floor; see `Trie::diff_since`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlooredDiff {
+    pub time: DateTime<Utc>,
+    /// The tries diverge before `time`, which is the floor
+    pub floored: bool,
+}
+
+/// Which side holds the first divergent bucket
+///
+/// Only that bucket is described; later ones may differ either way.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Divergence {
+    /// Only this trie has it, so there is nothing to download for it
+    LocalOnly,
+    /// Only the other trie has it, so there is nothing to upload for it
+    RemoteOnly,
+    /// Both have it with different hashes
+    Both,
+}
+
+/// Iterator over a trie's minute buckets; see `Trie::iter_minutes`
+#[derive(Debug, Clone)]
+pub struct Minutes<'a> {
+    // Nodes still to visit and their keys, next on top
+    stack: Vec<(Key, &'a Node)>,
+    depth: usize,
+    resolution: Resolution,
+}
+
+impl Iterator for Minutes<'_> {
+    type Item = (DateTime<Utc>, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((key, node)) = self.stack.pop() {
+            if key.digits().len() == self.depth {
+                return Some((key.time(self.depth, self.resolution), node.hash));
+            }
+            // Reversed, so the lowest key comes off the stack first
+            for (digit, child) in node.children.iter().rev() {
+                self.stack.push((key.child(digit), child));
             }
-        } else {
-            Some(path.clone())
         }
+        None
+    }
+}
+
+/// Copies the arena's nodes, boxing each one again
+impl From<&TrieArena> for Trie {
+    fn from(arena: &TrieArena) -> Trie {
+        let mut trie = Trie::with_depth(arena.scheme(), arena.depth());
+        trie.root.copy_arena(arena, 0, 0, arena.depth());
+        trie
     }
 }
 
-/// To Base3
-fn to_base3(mut input: i64) -> String {
-    if input == 0 {
-        return "0".to_string();
+/// Tries are equal when their schemes, root hashes and minute bucket counts
+/// are, which is all two peers need to exchange to know whether a full diff
+/// is necessary. This never walks the tree; see `deep_eq` for that.
+impl PartialEq for Trie {
+    fn eq(&self, other: &Self) -> bool {
+        self.scheme == other.scheme
+            && self.resolution == other.resolution
+            && self.root_hash() == other.root_hash()
+            && self.root.buckets == other.root.buckets
     }
+}
+
+impl Eq for Trie {}
+
+// The key digit of `bucket` at `level` in a trie `depth` digits deep, most
+// significant first
+fn digit(bucket: i64, level: usize, depth: usize) -> usize {
+    (bucket / 3i64.pow((depth - 1 - level) as u32) % 3) as usize
+}
 
-    let mut base3 = Vec::new();
-    while input > 0 {
-        base3.push((input % 3).to_string());
+// The base-3 digits of `input`, below 3^N, zero-padded to N and most
+// significant first
+fn to_base3<const N: usize>(mut input: i64) -> [u8; N] {
+    let mut digits = [0; N];
+    for digit in digits.iter_mut().rev() {
+        *digit = (input % 3) as u8;
         input /= 3;
     }
-    base3.reverse();
-    base3.join("")
+    digits
 }
 
-/// Key to timestamp
-///
-/// Key is a base 3 representation of the minutes since epoch
-fn key_to_timestamp(key: &str) -> DateTime<Utc> {
-    let full_key = format!("{:0<16}", key);
-    let minutes = i64::from_str_radix(&full_key, 3).unwrap_or(0);
-    let ms = minutes * 1000 * 60;
-    DateTime::from_timestamp_millis(ms).unwrap()
+// Errors related to trie keys
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum KeyError {
+    #[error("timestamp {0} is outside the range of a depth {1} trie")]
+    OutOfRange(i64, usize),
 }
 
-/// Timestamp to key
-fn timestamp_to_key(ts: Timestamp) -> String {
-    let millis = ts.millis();
-    let minutes = millis / (1000 * 60);
-    let b3 = to_base3(minutes);
-    format!("{:0>16}", b3)
+// Errors related to tries: indexing timestamps, and comparing two tries
+#[derive(Clone, Debug, PartialEq, thiserror::Error)]
+#[non_exhaustive]
+pub enum TrieError {
+    #[error(transparent)]
+    Key(#[from] KeyError),
+    // ours, theirs
+    #[error("trie uses hash scheme {1:?}, expected {0:?}")]
+    SchemeMismatch(HashScheme, HashScheme),
+    // ours, theirs
+    #[error("trie buckets by {1:?}, expected {0:?}")]
+    ResolutionMismatch(Resolution, Resolution),
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use maplit::hashmap;
+    use crate::timestamp::make_client_id;
+
+    // A key written out, e.g. "012"
+    fn key(digits: &str) -> Key {
+        digits.bytes().fold(Key::default(), |key, digit| {
+            key.child((digit - b'0') as usize)
+        })
+    }
+
+    #[test]
+    fn test_key() {
+        assert_eq!(Key::of(0, 3).to_string(), "000");
+        assert_eq!(Key::of(5, 3).to_string(), "012");
+        let last = 3i64.pow(MAX_DEPTH as u32) - 1;
+        assert_eq!(Key::of(last, MAX_DEPTH).to_string(), "2".repeat(MAX_DEPTH));
+        assert_eq!(Key::of(1234567, 16).bucket(16), 1234567);
+        // A prefix names the first bucket of its subtree
+        assert_eq!(key("1").bucket(3), 9);
+    }
 
     #[test]
     fn test_key_to_timestamp() {
-        let got = key_to_timestamp("0");
+        let got = key("0").time(DEFAULT_DEPTH, Resolution::Minute);
         let want = DateTime::from_timestamp_millis(0).unwrap();
         assert_eq!(got, want);
 
-        let got = key_to_timestamp("1222022111000201");
+        let got = key("1222022111000201").time(DEFAULT_DEPTH, Resolution::Minute);
         let want = DateTime::from_timestamp_millis(1699999980000).unwrap();
         assert_eq!(got, want);
     }
 
     #[test]
     fn test_ts_to_key() {
-        let key = "1222022111000201";
-        let ts = Timestamp::new(1699999980000, 0, make_client_id());
-        let got = timestamp_to_key(ts);
-        let want = key;
-        assert_eq!(got, want);
+        let trie = Trie::new();
+        let ts_key = |millis| {
+            let ts = Timestamp::new(millis, 0, make_client_id());
+            Key::of(trie.bucket(ts.millis()).unwrap(), trie.depth()).to_string()
+        };
+        assert_eq!(ts_key(1699999980000), "1222022111000201");
+        assert_eq!(ts_key(2582803200000), "2222222222222222");
+    }
 
-        let key = "2222222222222222";
-        let ts = Timestamp::new(2582803200000, 0, make_client_id());
-        let got = timestamp_to_key(ts);
-        let want = key;
-        assert_eq!(got, want);
+    #[test]
+    fn test_key_range() {
+        let node = make_client_id();
+        let mut trie = Trie::new();
+        let last = Timestamp::new(trie.end_millis() - 1, 0, node.clone());
+        let past_end = Timestamp::new(trie.end_millis(), 0, node.clone());
+        let before_epoch = Timestamp::new(-1, 0, node.clone());
+
+        assert_eq!(trie.try_insert(last.clone()), Ok(()));
+        let got = trie.try_insert(past_end.clone());
+        assert_eq!(got, Err(KeyError::OutOfRange(2582803260000, 16)));
+        let got = trie.try_insert_batch(&[Timestamp::new(0, 0, node), before_epoch]);
+        assert_eq!(got, Err(KeyError::OutOfRange(-1, 16)));
+        assert_eq!(trie.root_hash(), Trie::build(vec![last]).root_hash());
+
+        // A deeper trie takes the far future
+        let mut deep = Trie::with_depth(HashScheme::default(), 20);
+        assert_eq!(deep.try_insert(past_end.clone()), Ok(()));
+        let got = deep.diff(&Trie::with_depth(HashScheme::default(), 20));
+        assert_eq!(got.unwrap().timestamp_millis(), past_end.millis());
+
+        let decoded = Trie::decode(&deep.encode()).unwrap();
+        assert_eq!(decoded.depth(), 20);
+        assert_eq!(decoded.diff(&deep), None);
+    }
+
+    #[test]
+    fn test_overflow_bucket() {
+        let node = make_client_id();
+        let mut trie = Trie::new();
+        trie.set_overflow(Overflow::LastBucket);
+        let end = trie.end_millis();
+        let far = Timestamp::new(end * 2, 0, node.clone());
+
+        assert_eq!(trie.try_insert(far.clone()), Ok(()));
+        let got = trie.try_insert(Timestamp::new(-1, 0, node));
+        assert_eq!(got, Err(KeyError::OutOfRange(-1, 16)));
+
+        let last_minute = DateTime::from_timestamp_millis(end - 60_000).unwrap();
+        assert!(trie.contains_minute(last_minute));
+        assert_eq!(trie.diff(&Trie::new()), Some(last_minute));
+
+        trie.remove(&far);
+        assert_eq!(trie.root_hash(), 0);
+        assert!(!trie.contains_minute(last_minute));
+    }
+
+    // Hashes only the millis, like a deployment keyed on wall time
+    #[derive(Debug)]
+    struct MillisHasher;
+
+    impl TimestampHasher for MillisHasher {
+        fn hash(&self, timestamp: &Timestamp) -> u64 {
+            xxh3_64(&timestamp.millis().to_be_bytes())
+        }
+    }
+
+    #[test]
+    fn test_with_hasher() {
+        let ts1 = Timestamp::new(60_000, 0, make_client_id());
+        let ts2 = Timestamp::new(120_000, 0, make_client_id());
+
+        let mut builtin = Trie::with_hasher(HashScheme::Murmur32Xor, Murmur3);
+        builtin.insert_batch(&[ts1.clone(), ts2.clone()]);
+        assert_eq!(builtin, Trie::build(vec![ts1.clone(), ts2.clone()]));
+
+        let mut custom = Trie::with_hasher(HashScheme::Murmur32Xor, MillisHasher);
+        custom.insert(ts1.clone());
+        custom.insert(ts2.clone());
+        let want = (MillisHasher.hash(&ts1) ^ MillisHasher.hash(&ts2)) as u32 as u64;
+        assert_eq!(custom.root_hash(), want);
+
+        // Peers only see hashes, so a decoded copy still diffs clean
+        let decoded = Trie::decode(&custom.encode()).unwrap();
+        assert_eq!(decoded.diff(&custom), None);
+
+        custom.remove(&ts2);
+        let mut want = Trie::with_hasher(HashScheme::Murmur32Xor, MillisHasher);
+        want.insert(ts1);
+        assert_eq!(custom, want);
+    }
+
+    #[test]
+    fn test_save_load() {
+        let ts = Timestamp::new(60_000, 0, make_client_id());
+        let mut trie = Trie::with_depth(HashScheme::Xxh64Add, 18);
+        trie.insert(ts);
+
+        let bytes = trie.save();
+        let loaded = Trie::load(&bytes).unwrap();
+        assert_eq!(loaded.depth(), 18);
+        assert_eq!(loaded, trie);
+        assert_eq!(loaded.diff(&trie), None);
+
+        let ours = Header::new(HashScheme::Xxh64Add, DEFAULT_DEPTH);
+        let got = Trie::load_expecting(&bytes, &ours).err();
+        assert_eq!(
+            got,
+            Some(DecodeError::HeaderMismatch(Header::of(&trie), ours))
+        );
+
+        // The unheadered encoding is refused
+        assert_eq!(
+            Trie::load(&trie.encode()).err(),
+            Some(DecodeError::BadMagic)
+        );
+    }
+
+    #[cfg(feature = "js")]
+    #[test]
+    fn test_json_roundtrip() {
+        for scheme in [HashScheme::Murmur32Xor, HashScheme::Xxh64Add] {
+            let ts1 = Timestamp::new(1711231855000, 0, make_client_id());
+            let ts2 = Timestamp::new(1711231915000, 0, make_client_id());
+            let trie = Trie::build_with_scheme(scheme, vec![ts1, ts2]);
+
+            let json = trie.to_json();
+            let decoded = Trie::from_json(&json, scheme).unwrap();
+            assert_eq!(decoded, trie);
+            assert_eq!(decoded.diff(&trie), None);
+            assert_eq!(decoded.to_json(), json);
+        }
+
+        let empty = Trie::from_json("{}", HashScheme::Xxh64Add).unwrap();
+        assert_eq!(empty.to_json(), "{}");
+        let got = Trie::from_json(r#"{"hash":-1}"#, HashScheme::Xxh64Add).err();
+        let want = DecodeError::InvalidJson("bad hash -1".to_string());
+        assert_eq!(got, Some(want));
     }
 
     #[test]
@@ -219,4 +1801,412 @@ mod test {
         let want = Some(ts2.into());
         assert_eq!(got, want);
     }
+
+    #[test]
+    fn test_root_hash_eq() {
+        let minute = 1000 * 60;
+        let ts1 = Timestamp::new(minute, 0, make_client_id());
+        let ts2 = Timestamp::new(7 * minute, 3, make_client_id());
+
+        assert_eq!(Trie::new().root_hash(), 0);
+        assert_eq!(
+            Trie::build(vec![ts1.clone()]).root_hash(),
+            ts1.hash() as u64
+        );
+
+        let trie1 = Trie::build(vec![ts1.clone(), ts2.clone()]);
+        let trie2 = Trie::build(vec![ts2.clone(), ts1.clone()]);
+        assert_eq!(trie1.root_hash(), (ts1.hash() ^ ts2.hash()) as u64);
+        assert_eq!(trie1, trie2);
+        assert_ne!(trie1, Trie::build(vec![ts1]));
+    }
+
+    #[test]
+    fn test_iter_minutes() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let timestamps = vec![make_ts(900), make_ts(1), make_ts(2), make_ts(2)];
+
+        for scheme in [HashScheme::Murmur32Xor, HashScheme::Xxh64Add] {
+            let trie = Trie::build_with_scheme(scheme, timestamps.clone());
+            assert_eq!(trie.len(), 3);
+            let minutes: Vec<_> = trie.iter_minutes().collect();
+            let times: Vec<i64> = minutes.iter().map(|(t, _)| t.timestamp_millis()).collect();
+            assert_eq!(times, vec![minute, 2 * minute, 900 * minute]);
+            for (time, hash) in minutes {
+                assert_eq!(trie.minute_hash(Epoch::from(time)), Some(hash));
+            }
+
+            let decoded = Trie::load(&trie.save()).unwrap();
+            assert_eq!(decoded.len(), 3);
+            assert_eq!(decoded.iter_minutes().count(), 3);
+        }
+        assert!(Trie::new().is_empty());
+        assert_eq!(Trie::new().iter_minutes().next(), None);
+    }
+
+    #[test]
+    fn test_deltas() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let old: Vec<Timestamp> = (0..20).map(|m| make_ts(m * 7)).collect();
+        let new: Vec<Timestamp> = (0..20).map(|m| make_ts(1000 + m)).collect();
+
+        for scheme in [HashScheme::Murmur32Xor, HashScheme::Xxh64Add] {
+            let mut trie = Trie::build_with_scheme(scheme, old.clone());
+            let saved = trie.save();
+            assert!(trie.take_deltas().is_empty());
+
+            trie.record_deltas(true);
+            trie.insert_batch(&new[..10]);
+            for ts in &new[10..] {
+                trie.insert_with(ts.clone(), 99);
+            }
+            trie.remove(&old[3]);
+            let log: Vec<u8> = trie.take_deltas().iter().flat_map(|d| d.encode()).collect();
+            assert_eq!(log.len(), 21 * TrieDelta::LEN);
+            assert!(trie.take_deltas().is_empty());
+
+            let deltas: Vec<TrieDelta> = log
+                .chunks(TrieDelta::LEN)
+                .map(|chunk| TrieDelta::decode(chunk).unwrap())
+                .collect();
+            let mut restored = Trie::load(&saved).unwrap();
+            restored.apply_deltas(&deltas).unwrap();
+            assert!(restored.deep_eq(&trie));
+            assert_eq!(restored.len(), trie.len());
+        }
+
+        let far = TrieDelta::Insert {
+            bucket: 3i64.pow(DEFAULT_DEPTH as u32),
+            hash: 1,
+        };
+        assert!(Trie::new().apply_deltas(&[far]).is_err());
+        assert_eq!(
+            TrieDelta::decode(&[9; TrieDelta::LEN]),
+            Err(DecodeError::InvalidTag(9))
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let trie = Trie::build(vec![make_ts(900), make_ts(1), make_ts(2), make_ts(2)]);
+        let stats = trie.stats();
+        assert_eq!(stats.leaves, trie.len());
+        assert_eq!(stats.max_depth, DEFAULT_DEPTH);
+        assert!(stats.nodes > DEFAULT_DEPTH && stats.nodes < 3 * DEFAULT_DEPTH);
+        assert_eq!(
+            stats.memory_bytes,
+            stats.nodes * core::mem::size_of::<Node>()
+        );
+        // A node is its hash, bucket count and three child pointers; the
+        // trie's settings live on the root alone
+        assert_eq!(
+            core::mem::size_of::<Node>(),
+            core::mem::size_of::<u64>() + 4 * core::mem::size_of::<usize>()
+        );
+        assert_eq!(stats.oldest.unwrap().timestamp_millis(), minute);
+        assert_eq!(stats.newest.unwrap().timestamp_millis(), 900 * minute);
+
+        let empty = Trie::new().stats();
+        assert_eq!((empty.nodes, empty.leaves, empty.max_depth), (1, 0, 0));
+        assert_eq!((empty.oldest, empty.newest), (None, None));
+    }
+
+    #[test]
+    fn test_resolution() {
+        let hour = 60 * 60 * 1000;
+        let make_ts = |millis: i64| Timestamp::new(millis, 0, "1234123412341234".to_string());
+        let timestamps = vec![
+            make_ts(5 * hour),
+            make_ts(5 * hour + 59_000),
+            make_ts(900 * hour),
+        ];
+
+        let trie = Trie::with_resolution(HashScheme::Xxh64Add, Resolution::Hour);
+        assert_eq!(trie.depth(), Resolution::Hour.default_depth());
+        assert_eq!(trie.end_millis(), 3i64.pow(13) * hour);
+        let mut trie = trie;
+        trie.insert_batch(&timestamps);
+        assert_eq!(trie.len(), 2);
+        let times: Vec<i64> = trie
+            .iter_minutes()
+            .map(|(t, _)| t.timestamp_millis())
+            .collect();
+        assert_eq!(times, vec![5 * hour, 900 * hour]);
+        assert!(
+            trie.contains_minute(DateTime::from_timestamp_millis(5 * hour + 30 * 60_000).unwrap())
+        );
+
+        // A divergence lands on the start of the hour
+        let mut other = trie.clone();
+        other.insert(make_ts(5 * hour + 7 * 60_000));
+        assert_eq!(
+            trie.diff(&other).map(|t| t.timestamp_millis()),
+            Some(5 * hour)
+        );
+
+        let decoded = Trie::decode(&trie.encode()).unwrap();
+        assert_eq!(decoded.resolution(), Resolution::Hour);
+        assert!(decoded.deep_eq(&trie));
+        let loaded = Trie::load(&trie.save()).unwrap();
+        assert!(loaded.deep_eq(&trie));
+
+        // Minute tries keep the bytes they always had
+        let minutes = Trie::build_with_scheme(HashScheme::Xxh64Add, timestamps);
+        assert_eq!(minutes.encode()[0], HashScheme::Xxh64Add.id());
+        assert_ne!(minutes, trie);
+        assert_eq!(
+            Trie::decode(&[0x41, 0, 0, 0, 0, 0, 0, 0, 0, 0]).err(),
+            Some(DecodeError::UnknownResolution(4))
+        );
+    }
+
+    #[test]
+    fn test_diff_detailed() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let (a, b, c) = (make_ts(1), make_ts(5), make_ts(500));
+
+        let base = Trie::build(vec![a.clone()]);
+        let local = Trie::build(vec![a.clone(), b.clone()]);
+        let remote = Trie::build(vec![a.clone(), c]);
+        assert_eq!(base.diff_detailed(&base), None);
+
+        let detail = local.diff_detailed(&base).unwrap();
+        assert_eq!(detail.divergence, Divergence::LocalOnly);
+        assert_eq!(Some(detail.time), local.diff(&base));
+        assert_eq!(
+            base.diff_detailed(&local).unwrap().divergence,
+            Divergence::RemoteOnly
+        );
+
+        // Both hold minute 5, one of them with more in it
+        let more = Trie::build(vec![a, b, make_ts(5)]);
+        let detail = local.diff_detailed(&more).unwrap();
+        assert_eq!(detail.divergence, Divergence::Both);
+        assert_eq!(detail.time.timestamp_millis(), 5 * minute);
+
+        // The earlier bucket decides
+        assert_eq!(
+            local.diff_detailed(&remote).unwrap().divergence,
+            Divergence::LocalOnly
+        );
+    }
+
+    #[test]
+    fn test_diff_since() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let at = |m: i64| DateTime::from_timestamp_millis(m * minute).unwrap();
+        let (old, recent) = (make_ts(10), make_ts(500));
+
+        let ours = Trie::build(vec![old.clone(), recent.clone()]);
+        let theirs = Trie::build(vec![old]);
+        assert_eq!(ours.diff_since(&ours, at(100)), None);
+        assert_eq!(
+            ours.diff_since(&theirs, at(100)),
+            Some(FlooredDiff {
+                time: at(500),
+                floored: false
+            })
+        );
+
+        // A peer that lost everything diverges at minute 10
+        let empty = Trie::new();
+        assert_eq!(
+            ours.diff_since(&empty, at(100)),
+            Some(FlooredDiff {
+                time: at(100),
+                floored: true
+            })
+        );
+        assert_eq!(ours.diff_since(&empty, at(0)).unwrap().time, at(10));
+    }
+
+    #[test]
+    fn test_render() {
+        let minute = 1000 * 60;
+        let timestamps = vec![
+            Timestamp::new(minute, 0, make_client_id()),
+            Timestamp::new(2 * minute, 0, make_client_id()),
+        ];
+        let mut trie = Trie::with_depth(HashScheme::Murmur32Xor, 2);
+        trie.insert_batch(&timestamps);
+        let ascii = trie.render_ascii();
+        let lines: Vec<&str> = ascii.lines().collect();
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("root 0x") && lines[0].ends_with("(2 buckets)"));
+        assert!(lines[1].starts_with("└── 0 0x"));
+        assert!(lines[2].starts_with("    ├── 01 0x"));
+        assert!(lines[2].ends_with("1970-01-01 00:01:00 UTC"));
+        assert!(lines[3].starts_with("    └── 02 0x"));
+
+        let dot = trie.to_dot();
+        assert!(dot.starts_with("digraph trie {"));
+        assert!(dot.contains("\"0\" -> \"01\";"));
+        assert!(dot.contains("\"root\" -> \"0\";"));
+        assert!(dot.trim_end().ends_with('}'));
+    }
+
+    #[test]
+    fn test_eq_counts_buckets() {
+        let ts = Timestamp::new(60_000, 0, make_client_id());
+
+        // The XOR scheme cancels a duplicate out of the root hash, but the
+        // bucket it opened still tells the tries apart
+        let doubled = Trie::build(vec![ts.clone(), ts.clone()]);
+        assert_eq!(doubled.root_hash(), Trie::new().root_hash());
+        assert_ne!(doubled, Trie::new());
+
+        let trie = Trie::build(vec![ts, Timestamp::new(7 * 60_000, 0, make_client_id())]);
+        let decoded = Trie::load(&trie.save()).unwrap();
+        assert_eq!(decoded, trie);
+        assert!(decoded.deep_eq(&trie));
+        assert!(!doubled.deep_eq(&Trie::new()));
+    }
+
+    #[test]
+    fn test_encode_roundtrip() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let trie = Trie::build(vec![make_ts(1), make_ts(2), make_ts(30)]);
+
+        let decoded = Trie::decode(&trie.encode()).unwrap();
+        assert_eq!(decoded.diff(&trie), None);
+        assert_eq!(decoded.encode(), trie.encode());
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let got = Trie::decode(&[1, 0, 0, 0, 0, 1, b'7', 0, 0, 0, 0, 0]).err();
+        assert_eq!(got, Some(DecodeError::InvalidKey(b'7')));
+
+        let got = Trie::decode(&[1, 0, 0, 0, 0, 1]).err();
+        assert_eq!(got, Some(DecodeError::UnexpectedEof));
+
+        let got = Trie::decode(&[9, 0, 0, 0, 0, 0]).err();
+        assert_eq!(got, Some(DecodeError::UnknownHashScheme(9)));
+    }
+
+    #[test]
+    fn test_xxh64_does_not_cancel() {
+        let ts = Timestamp::new(60_000, 0, make_client_id());
+
+        let v1 = Trie::build(vec![ts.clone(), ts.clone()]);
+        assert_eq!(v1.root_hash(), 0);
+
+        let strong = Trie::build_with_scheme(HashScheme::Xxh64Add, vec![ts.clone(), ts.clone()]);
+        assert_ne!(strong.root_hash(), 0);
+        assert_ne!(strong, Trie::with_scheme(HashScheme::Xxh64Add));
+    }
+
+    #[test]
+    fn test_xxh64_order_independent() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let (ts1, ts2, ts3) = (make_ts(1), make_ts(2), make_ts(900));
+
+        let scheme = HashScheme::Xxh64Add;
+        let trie1 = Trie::build_with_scheme(scheme, vec![ts1.clone(), ts2.clone(), ts3.clone()]);
+        let trie2 = Trie::build_with_scheme(scheme, vec![ts3.clone(), ts1.clone(), ts2.clone()]);
+        assert_eq!(trie1, trie2);
+        assert_eq!(trie1.diff(&trie2), None);
+
+        let trie3 = Trie::build_with_scheme(scheme, vec![ts1, ts3]);
+        assert_eq!(trie1.diff(&trie3), Some(ts2.into()));
+
+        let decoded = Trie::decode(&trie1.encode()).unwrap();
+        assert_eq!(decoded.scheme(), scheme);
+        assert_eq!(decoded, trie1);
+    }
+
+    #[test]
+    fn test_remove() {
+        let minute = 1000 * 60;
+        let make_ts = |m: i64| Timestamp::new(m * minute, 0, make_client_id());
+        let (ts1, ts2, ts3) = (make_ts(1), make_ts(2), make_ts(900));
+
+        for scheme in [HashScheme::Murmur32Xor, HashScheme::Xxh64Add] {
+            let mut trie =
+                Trie::build_with_scheme(scheme, vec![ts1.clone(), ts2.clone(), ts3.clone()]);
+            trie.remove(&ts2);
+            trie.remove(&ts3);
+
+            // Byte-equal encodings mean the emptied branches were dropped too
+            let want = Trie::build_with_scheme(scheme, vec![ts1.clone()]);
+            assert_eq!(trie.encode(), want.encode());
+
+            assert_eq!(trie, want);
+
+            trie.remove(&ts1);
+            assert_eq!(trie.encode(), Trie::with_scheme(scheme).encode());
+            assert_eq!(trie, Trie::with_scheme(scheme));
+        }
+    }
+
+    #[test]
+    fn test_insert_batch() {
+        let minute = 1000 * 60;
+        let timestamps: Vec<Timestamp> = [5, 1, 900, 2, 2, 40_000]
+            .iter()
+            .enumerate()
+            .map(|(i, m)| Timestamp::new(m * minute, i as u16, make_client_id()))
+            .collect();
+
+        for scheme in [HashScheme::Murmur32Xor, HashScheme::Xxh64Add] {
+            let mut one_by_one = Trie::with_scheme(scheme);
+            for ts in &timestamps {
+                one_by_one.insert(ts.clone());
+            }
+
+            let mut batched = Trie::with_scheme(scheme);
+            batched.insert_batch(&timestamps[..3]);
+            batched.insert_batch(&timestamps[3..]);
+            assert_eq!(batched.encode(), one_by_one.encode());
+            assert!(batched.deep_eq(&one_by_one));
+        }
+    }
+
+    #[test]
+    fn test_contains_minute() {
+        let ts = Timestamp::new(1711231855000, 0, make_client_id());
+        let mut trie = Trie::build(vec![ts.clone()]);
+
+        let minute = DateTime::from_timestamp_millis(1711231800000).unwrap();
+        let next = DateTime::from_timestamp_millis(1711231860000).unwrap();
+        assert!(trie.contains_minute(minute));
+        let end = DateTime::from_timestamp_millis(1711231859999).unwrap();
+        assert!(trie.contains_minute(end));
+        assert!(!trie.contains_minute(next));
+
+        trie.remove(&ts);
+        assert!(!trie.contains_minute(minute));
+    }
+
+    #[test]
+    fn test_diff_visits_bounded_by_depth() {
+        let node = make_client_id();
+        // Ten timestamps a second, so the big trie spans about half an hour
+        let log = |n: i64| -> Vec<Timestamp> {
+            (0..n)
+                .map(|i| Timestamp::new(1_700_000_000_000 + i * 100, 0, node.clone()))
+                .collect()
+        };
+
+        let mut visits = Vec::new();
+        for n in [10, 1_000, 20_000] {
+            let full = log(n);
+            let a = Trie::build(full.clone());
+            let b = Trie::build(full[..full.len() - 1].to_vec());
+            assert!(a.diff(&b).is_some());
+            visits.push(a.diff_visits(&b));
+        }
+
+        // One node per key digit plus the root
+        assert!(visits.iter().all(|v| *v <= 17), "{:?}", visits);
+        assert_eq!(Trie::new().diff_visits(&Trie::new()), 1);
+    }
 }
@@ -0,0 +1,159 @@
+// The newest timestamp seen from each node. A hybrid clock only ever moves
+// forward, so a node's newest timestamp sums up everything received from
+// it in one entry, much like a vector clock.
+
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::timestamp::Timestamp;
+
+/// The highest timestamp seen per node id
+///
+/// Answers "anything new from this node?" and flags likely redeliveries
+/// without touching the store or the trie. Entries are a summary, not a
+/// log: a timestamp at or below its node's newest may still be missing if
+/// it was skipped, so `covers` only rules messages in for a closer look.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NodeVersionMap {
+    newest: BTreeMap<String, Timestamp>,
+}
+
+impl NodeVersionMap {
+    pub fn new() -> Self {
+        NodeVersionMap::default()
+    }
+
+    /// Record `timestamp`, returning whether it is its node's new newest
+    pub fn observe(&mut self, timestamp: &Timestamp) -> bool {
+        match self.newest.get_mut(timestamp.node()) {
+            Some(newest) if *newest >= *timestamp => false,
+            Some(newest) => {
+                *newest = timestamp.clone();
+                true
+            }
+            None => {
+                self.newest
+                    .insert(timestamp.node().to_string(), timestamp.clone());
+                true
+            }
+        }
+    }
+
+    /// The newest timestamp seen from `node`
+    pub fn get(&self, node: &str) -> Option<&Timestamp> {
+        self.newest.get(node)
+    }
+
+    /// Whether anything from `node` newer than `since` has been seen
+    pub fn has_newer(&self, node: &str, since: &Timestamp) -> bool {
+        self.get(node).is_some_and(|newest| newest > since)
+    }
+
+    /// Whether `timestamp` is no newer than its node's newest, so the
+    /// message carrying it may be a redelivery
+    pub fn covers(&self, timestamp: &Timestamp) -> bool {
+        self.get(timestamp.node())
+            .is_some_and(|newest| newest >= timestamp)
+    }
+
+    /// Nodes `other` has seen newer timestamps from, each with our newest
+    /// from it, the point to catch up from; None means from the start
+    pub fn behind<'a>(
+        &'a self,
+        other: &'a NodeVersionMap,
+    ) -> Vec<(&'a str, Option<&'a Timestamp>)> {
+        other
+            .newest
+            .iter()
+            .filter(|(_, theirs)| !self.covers(theirs))
+            .map(|(node, _)| (node.as_str(), self.get(node)))
+            .collect()
+    }
+
+    /// Take the newer entry for every node
+    pub fn merge(&mut self, other: &NodeVersionMap) {
+        for timestamp in other.newest.values() {
+            self.observe(timestamp);
+        }
+    }
+
+    /// Every node's newest timestamp, by node id
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Timestamp)> {
+        self.newest
+            .iter()
+            .map(|(node, newest)| (node.as_str(), newest))
+    }
+
+    /// How many nodes have been seen
+    pub fn len(&self) -> usize {
+        self.newest.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_empty()
+    }
+
+    /// A count, then each newest timestamp in node order
+    pub fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        w.put_u32(self.newest.len() as u32);
+        for timestamp in self.newest.values() {
+            timestamp.encode(&mut w);
+        }
+        w.into_bytes()
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<NodeVersionMap, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let mut map = NodeVersionMap::new();
+        for _ in 0..r.get_u32()? {
+            map.observe(&Timestamp::decode(&mut r)?);
+        }
+        r.finish()?;
+        Ok(map)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ts(millis: i64, node: &str) -> Timestamp {
+        Timestamp::new(millis, 0, node.to_string())
+    }
+
+    #[test]
+    fn test_node_versions() {
+        let mut ours = NodeVersionMap::new();
+        assert!(ours.observe(&ts(10, "a")));
+        assert!(ours.observe(&ts(20, "a")));
+        assert!(!ours.observe(&ts(15, "a")));
+        assert!(ours.observe(&ts(5, "b")));
+        assert_eq!(ours.get("a"), Some(&ts(20, "a")));
+        assert_eq!(ours.len(), 2);
+
+        assert!(ours.has_newer("a", &ts(10, "a")));
+        assert!(!ours.has_newer("a", &ts(20, "a")));
+        assert!(!ours.has_newer("c", &ts(0, "c")));
+        assert!(ours.covers(&ts(15, "a")));
+        assert!(!ours.covers(&ts(6, "b")));
+
+        let mut theirs = NodeVersionMap::new();
+        theirs.observe(&ts(10, "a"));
+        theirs.observe(&ts(8, "b"));
+        theirs.observe(&ts(1, "c"));
+        assert_eq!(
+            ours.behind(&theirs),
+            vec![("b", Some(&ts(5, "b"))), ("c", None)]
+        );
+        assert_eq!(theirs.behind(&ours), vec![("a", Some(&ts(10, "a")))]);
+
+        let decoded = NodeVersionMap::decode(&theirs.encode()).unwrap();
+        assert_eq!(decoded, theirs);
+        ours.merge(&theirs);
+        assert!(ours.behind(&theirs).is_empty());
+        assert_eq!(ours.get("b"), Some(&ts(8, "b")));
+    }
+}
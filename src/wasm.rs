@@ -0,0 +1,218 @@
+// JavaScript bindings. Browser clients get the same clock, trie and diff
+// as the server, so the two can't drift apart the way parallel
+// implementations do. Millisecond times cross the boundary as JavaScript
+// numbers, hashes as `BigInt`s.
+
+use wasm_bindgen::prelude::*;
+
+use crate::clock::ClockState;
+use crate::timestamp::{make_client_id, Timestamp};
+use crate::trie::{HashScheme, Trie};
+
+// The host's wall clock, `Date.now()`
+fn now() -> i64 {
+    js_sys::Date::now() as i64
+}
+
+#[wasm_bindgen(js_name = Timestamp)]
+#[derive(Debug, Clone)]
+pub struct WasmTimestamp {
+    inner: Timestamp,
+}
+
+#[wasm_bindgen(js_class = Timestamp)]
+impl WasmTimestamp {
+    #[wasm_bindgen(constructor)]
+    pub fn new(millis: f64, counter: u16, node: String) -> Self {
+        WasmTimestamp {
+            inner: Timestamp::new(millis as i64, counter, node),
+        }
+    }
+
+    /// Parse the string form, or undefined if it is malformed
+    pub fn parse(s: &str) -> Option<WasmTimestamp> {
+        Timestamp::parse(s).map(|inner| WasmTimestamp { inner })
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn millis(&self) -> f64 {
+        self.inner.millis() as f64
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn counter(&self) -> u16 {
+        self.inner.counter()
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn node(&self) -> String {
+        self.inner.node().to_string()
+    }
+
+    #[wasm_bindgen(js_name = toString)]
+    pub fn to_js_string(&self) -> String {
+        self.inner.to_string()
+    }
+}
+
+/// A node's hybrid logical clock, reading time from `Date.now()`
+#[wasm_bindgen]
+#[derive(Debug, Clone)]
+pub struct HlcClock {
+    clock: Timestamp,
+}
+
+#[wasm_bindgen]
+impl HlcClock {
+    /// A clock for `node`, or for a fresh random node id
+    #[wasm_bindgen(constructor)]
+    pub fn new(node: Option<String>) -> Self {
+        HlcClock {
+            clock: Timestamp::new(0, 0, node.unwrap_or_else(make_client_id)),
+        }
+    }
+
+    /// Resume from bytes written by `toState`
+    #[wasm_bindgen(js_name = fromState)]
+    pub fn from_state(bytes: &[u8]) -> Result<HlcClock, JsError> {
+        let state = ClockState::from_bytes(bytes)?;
+        let mut clock = HlcClock::new(Some(state.last.node().to_string()));
+        clock.clock.observe_persisted(&state.last, now())?;
+        Ok(clock)
+    }
+
+    #[wasm_bindgen(js_name = toState)]
+    pub fn to_state(&self) -> Vec<u8> {
+        self.clock.to_state().to_bytes()
+    }
+
+    /// The last timestamp issued or received
+    #[wasm_bindgen(getter)]
+    pub fn timestamp(&self) -> WasmTimestamp {
+        WasmTimestamp {
+            inner: self.clock.clone(),
+        }
+    }
+
+    /// Mint a timestamp for a local event
+    pub fn send(&mut self) -> Result<WasmTimestamp, JsError> {
+        self.send_at(now() as f64)
+    }
+
+    #[wasm_bindgen(js_name = sendAt)]
+    pub fn send_at(&mut self, phys: f64) -> Result<WasmTimestamp, JsError> {
+        let inner = self.clock.send(phys as i64)?;
+        Ok(WasmTimestamp { inner })
+    }
+
+    /// Merge a timestamp received from another node
+    pub fn recv(&mut self, timestamp: &WasmTimestamp) -> Result<WasmTimestamp, JsError> {
+        self.recv_at(timestamp, now() as f64)
+    }
+
+    #[wasm_bindgen(js_name = recvAt)]
+    pub fn recv_at(
+        &mut self,
+        timestamp: &WasmTimestamp,
+        phys: f64,
+    ) -> Result<WasmTimestamp, JsError> {
+        let inner = self.clock.recv(&timestamp.inner, phys as i64)?;
+        Ok(WasmTimestamp { inner })
+    }
+}
+
+#[wasm_bindgen(js_name = Trie)]
+#[derive(Debug, Clone, Default)]
+pub struct WasmTrie {
+    inner: Trie,
+}
+
+#[wasm_bindgen(js_class = Trie)]
+impl WasmTrie {
+    /// An empty trie under the v1 hash scheme, the one JavaScript peers use
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Self {
+        WasmTrie::default()
+    }
+
+    /// Parse a trie in the crdt example's nested-object layout
+    #[wasm_bindgen(js_name = fromJSON)]
+    pub fn from_json(json: &str) -> Result<WasmTrie, JsError> {
+        let inner = Trie::from_json(json, HashScheme::Murmur32Xor)?;
+        Ok(WasmTrie { inner })
+    }
+
+    #[wasm_bindgen(js_name = toJSON)]
+    pub fn to_json(&self) -> String {
+        self.inner.to_json()
+    }
+
+    /// Decode bytes written by `save`
+    pub fn load(bytes: &[u8]) -> Result<WasmTrie, JsError> {
+        let inner = Trie::load(bytes)?;
+        Ok(WasmTrie { inner })
+    }
+
+    pub fn save(&self) -> Vec<u8> {
+        self.inner.save()
+    }
+
+    #[wasm_bindgen(js_name = rootHash)]
+    pub fn root_hash(&self) -> u64 {
+        self.inner.root_hash()
+    }
+
+    pub fn insert(&mut self, timestamp: &WasmTimestamp) -> Result<(), JsError> {
+        self.inner.try_insert(timestamp.inner.clone())?;
+        Ok(())
+    }
+
+    /// The millisecond time the tries diverge from, or undefined if they
+    /// match
+    pub fn diff(&self, other: &WasmTrie) -> Option<f64> {
+        self.inner
+            .diff(&other.inner)
+            .map(|time| time.timestamp_millis() as f64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // JsError has no Debug, and building one panics off wasm anyway
+    fn ok<T>(result: Result<T, JsError>) -> T {
+        result.unwrap_or_else(|_| panic!("unexpected error"))
+    }
+
+    #[test]
+    fn test_trie() {
+        let mut a = WasmTrie::new();
+        let b = ok(WasmTrie::from_json(&a.to_json()));
+        assert_eq!(a.diff(&b), None);
+
+        let ts = WasmTimestamp::new(1711231855000.0, 0, "1234123412341234".to_string());
+        ok(a.insert(&ts));
+        assert_eq!(a.diff(&b), Some(1711231800000.0));
+
+        let b = ok(WasmTrie::load(&a.save()));
+        assert_eq!(b.root_hash(), a.root_hash());
+        assert_eq!(ok(WasmTrie::from_json(&a.to_json())).diff(&a), None);
+    }
+
+    #[test]
+    fn test_clock() {
+        let mut clock = HlcClock::new(Some("1234123412341234".to_string()));
+        let ts = ok(clock.send_at(1000.0));
+        assert_eq!(
+            ts.to_js_string(),
+            "1970-01-01T00:00:01.000Z-0000-1234123412341234"
+        );
+
+        let remote =
+            WasmTimestamp::parse("1970-01-01T00:00:02.000Z-0005-5678567856785678").unwrap();
+        let ts = ok(clock.recv_at(&remote, 1000.0));
+        assert_eq!(ts.millis(), 2000.0);
+        assert_eq!(clock.timestamp().to_js_string(), ts.to_js_string());
+    }
+}
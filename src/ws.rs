@@ -0,0 +1,724 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use chrono::Utc;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc};
+use tokio_tungstenite::tungstenite::{self, Message as WsMessage};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::sync::CancellationToken;
+
+use crate::codec::{DecodeError, Reader, Writer};
+use crate::engine::Engine;
+use crate::message::Message;
+use crate::store::MessageStore;
+use crate::sync::{SyncError, SyncFailure, SyncSession};
+
+const TAG_SYNC: u8 = 1;
+const TAG_QUIET: u8 = 2;
+const TAG_PUSH: u8 = 3;
+const TAG_RESYNC: u8 = 4;
+
+// Most messages sent in one push frame
+const MAX_PUSH: usize = 256;
+
+/// A frame on a sync socket, carried in a binary WebSocket message
+#[derive(Debug, PartialEq)]
+enum Frame {
+    /// A `SyncSession` frame, for catching up by trie diff
+    Sync(Vec<u8>),
+    /// The session had no reply to the last sync frame
+    Quiet,
+    /// Messages written since the last push
+    Push(Vec<Message>),
+    /// The sender dropped pushes meant for us; catch up by diff
+    Resync,
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        let mut w = Writer::new();
+        match self {
+            Frame::Sync(bytes) => {
+                w.put_u8(TAG_SYNC);
+                w.put_bytes(bytes);
+            }
+            Frame::Quiet => w.put_u8(TAG_QUIET),
+            Frame::Push(messages) => {
+                w.put_u8(TAG_PUSH);
+                w.put_u32(messages.len() as u32);
+                for message in messages {
+                    message.encode(&mut w);
+                }
+            }
+            Frame::Resync => w.put_u8(TAG_RESYNC),
+        }
+        w.into_bytes()
+    }
+
+    fn decode(bytes: &[u8]) -> Result<Frame, DecodeError> {
+        let mut r = Reader::new(bytes);
+        let frame = match r.get_u8()? {
+            TAG_SYNC => Frame::Sync(r.get_bytes()?.to_vec()),
+            TAG_QUIET => Frame::Quiet,
+            TAG_PUSH => {
+                let count = r.get_u32()?;
+                let mut messages = Vec::new();
+                for _ in 0..count {
+                    messages.push(Message::decode(&mut r)?);
+                }
+                Frame::Push(messages)
+            }
+            TAG_RESYNC => Frame::Resync,
+            tag => return Err(DecodeError::InvalidTag(tag)),
+        };
+        r.finish()?;
+        Ok(frame)
+    }
+}
+
+// The engine is shared with the application, and only ever locked between
+// awaits
+fn lock<S>(engine: &Mutex<Engine<S>>) -> MutexGuard<'_, Engine<S>> {
+    engine.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+// Apply pushed messages, returning the ones that were new
+fn apply_pushed<S: MessageStore>(
+    engine: &Mutex<Engine<S>>,
+    messages: Vec<Message>,
+) -> Result<Vec<Message>, SyncError> {
+    let phys = Utc::now().timestamp_millis();
+    let mut engine = lock(engine);
+    let mut applied = Vec::new();
+    for message in messages {
+        if engine.apply(message.clone(), phys)? {
+            applied.push(message);
+        }
+    }
+    Ok(applied)
+}
+
+/// One open sync socket, from either end
+pub struct WsConnection<T> {
+    socket: WebSocketStream<T>,
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> WsConnection<T> {
+    pub fn new(socket: WebSocketStream<T>) -> Self {
+        WsConnection { socket }
+    }
+
+    async fn send(&mut self, frame: &Frame) -> Result<(), WsSyncError> {
+        self.socket
+            .send(WsMessage::binary(frame.encode()))
+            .await
+            .map_err(WsSyncError::from)
+    }
+
+    // The next frame, or None once the peer closes; pings are answered by
+    // tungstenite and skipped here
+    async fn recv(&mut self) -> Result<Option<Frame>, WsSyncError> {
+        while let Some(message) = self.socket.next().await {
+            match message? {
+                WsMessage::Binary(bytes) => return Ok(Some(Frame::decode(&bytes)?)),
+                WsMessage::Close(_) => return Ok(None),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Catch up with the peer by trie diff, returning the messages that
+    /// were new to `engine`
+    ///
+    /// Pushes that arrive mid-session are applied as they come.
+    pub async fn catch_up<S: MessageStore>(
+        &mut self,
+        engine: &Mutex<Engine<S>>,
+    ) -> Result<Vec<Message>, WsSyncError> {
+        let mut frame = SyncSession::new(&mut lock(engine))
+            .start()
+            .map_err(WsSyncError::Sync)?;
+        let mut applied = Vec::new();
+        loop {
+            self.send(&Frame::Sync(frame)).await?;
+            let reply = loop {
+                match self.recv().await?.ok_or(WsSyncError::Closed)? {
+                    Frame::Sync(reply) => break Some(reply),
+                    Frame::Quiet => break None,
+                    Frame::Push(messages) => applied.extend(apply_pushed(engine, messages)?),
+                    Frame::Resync => {}
+                }
+            };
+            let Some(reply) = reply else {
+                return Ok(applied);
+            };
+
+            let mut engine = lock(engine);
+            let mut session = SyncSession::new(&mut engine);
+            let next = session.handle(&reply)?;
+            applied.extend_from_slice(session.applied());
+            match next {
+                Some(next) => frame = next,
+                None => return Ok(applied),
+            }
+        }
+    }
+
+    /// Send messages to the peer straight away
+    pub async fn push(&mut self, messages: Vec<Message>) -> Result<(), WsSyncError> {
+        self.send(&Frame::Push(messages)).await
+    }
+
+    async fn close(mut self) -> Result<(), WsSyncError> {
+        self.socket.close(None).await.map_err(WsSyncError::from)
+    }
+}
+
+/// The application's end of the queue of local writes to push
+///
+/// Sending never waits. When the queue is full the message is dropped and
+/// the client catches up by trie diff instead, which sends everything the
+/// peer lacks anyway; a slow link costs latency, never writes.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    queue: mpsc::Sender<Message>,
+    overflowed: Arc<AtomicBool>,
+}
+
+/// The sync client's end of an `Outbox`
+#[derive(Debug)]
+pub struct Pending {
+    queue: mpsc::Receiver<Message>,
+    overflowed: Arc<AtomicBool>,
+}
+
+/// A queue holding up to `capacity` unsent writes
+pub fn outbox(capacity: usize) -> (Outbox, Pending) {
+    let (queue, receiver) = mpsc::channel(capacity);
+    let overflowed = Arc::new(AtomicBool::new(false));
+    let outbox = Outbox {
+        queue,
+        overflowed: overflowed.clone(),
+    };
+    let pending = Pending {
+        queue: receiver,
+        overflowed,
+    };
+    (outbox, pending)
+}
+
+impl Outbox {
+    /// Queue a local write for pushing
+    pub fn send(&self, message: Message) {
+        if let Err(mpsc::error::TrySendError::Full(_)) = self.queue.try_send(message) {
+            self.overflowed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Pending {
+    // The next batch of writes, or None once every Outbox is dropped
+    async fn next_batch(&mut self) -> Option<Vec<Message>> {
+        let first = self.queue.recv().await?;
+        let mut batch = vec![first];
+        while batch.len() < MAX_PUSH {
+            match self.queue.try_recv() {
+                Ok(message) => batch.push(message),
+                Err(_) => break,
+            }
+        }
+        Some(batch)
+    }
+
+    fn take_overflow(&self) -> bool {
+        self.overflowed.swap(false, Ordering::Relaxed)
+    }
+
+    // Whatever is queued already went out with a diff
+    fn clear(&mut self) {
+        while self.queue.try_recv().is_ok() {}
+        self.take_overflow();
+    }
+}
+
+/// Exponential backoff between reconnect attempts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay after `delay`
+    pub fn next(&self, delay: Duration) -> Duration {
+        (delay * 2).min(self.max)
+    }
+}
+
+/// Continuous sync with a server over a WebSocket
+///
+/// Each connection starts with a trie diff, so nothing written while
+/// disconnected is lost; after that local writes are pushed as they're
+/// queued and the server's pushes applied as they arrive. Dropped
+/// connections are retried with backoff.
+#[derive(Debug, Clone)]
+pub struct WsSyncClient {
+    url: String,
+    backoff: Backoff,
+}
+
+impl WsSyncClient {
+    /// A client for the socket at `url`, e.g. `ws://host/sync?group=...`
+    pub fn new(url: &str) -> Self {
+        WsSyncClient {
+            url: url.to_string(),
+            backoff: Backoff::default(),
+        }
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Sync until every `Outbox` for `pending` is dropped, calling
+    /// `on_applied` with each batch of messages new to `engine`
+    ///
+    /// Only protocol errors (a malformed frame, a rejected message, a
+    /// scheme mismatch) end the loop early; network errors reconnect.
+    ///
+    /// Cancellation safe: `engine` is only locked between awaits, so
+    /// dropping the future leaves it consistent. Writes taken from
+    /// `pending` but not yet sent go out in the next run's diff.
+    pub async fn run<S: MessageStore>(
+        &self,
+        engine: &Mutex<Engine<S>>,
+        pending: &mut Pending,
+        on_applied: impl FnMut(&[Message]),
+    ) -> Result<(), WsSyncError> {
+        self.run_cancellable(engine, pending, on_applied, &CancellationToken::new())
+            .await
+    }
+
+    /// `run`, dropping the connection and returning
+    /// `WsSyncError::Cancelled` once `cancel` fires
+    ///
+    /// Messages applied before then stay applied, though if a catch-up was
+    /// cut short `on_applied` won't have heard about them; subscribers to
+    /// the engine have.
+    pub async fn run_cancellable<S: MessageStore>(
+        &self,
+        engine: &Mutex<Engine<S>>,
+        pending: &mut Pending,
+        on_applied: impl FnMut(&[Message]),
+        cancel: &CancellationToken,
+    ) -> Result<(), WsSyncError> {
+        tokio::select! {
+            biased;
+            () = cancel.cancelled() => Err(WsSyncError::Cancelled),
+            result = self.reconnect(engine, pending, on_applied) => result,
+        }
+    }
+
+    async fn reconnect<S: MessageStore>(
+        &self,
+        engine: &Mutex<Engine<S>>,
+        pending: &mut Pending,
+        mut on_applied: impl FnMut(&[Message]),
+    ) -> Result<(), WsSyncError> {
+        let mut delay = self.backoff.initial;
+        loop {
+            match self
+                .connection(engine, pending, &mut on_applied, &mut delay)
+                .await
+            {
+                Ok(()) => return Ok(()),
+                Err(WsSyncError::Sync(err)) => return Err(WsSyncError::Sync(err)),
+                Err(err) => {
+                    log_warn!("sync socket failed, retrying in {:?}: {}", delay, err);
+                    tokio::time::sleep(delay).await;
+                    delay = self.backoff.next(delay);
+                }
+            }
+        }
+    }
+
+    async fn connection<S: MessageStore>(
+        &self,
+        engine: &Mutex<Engine<S>>,
+        pending: &mut Pending,
+        on_applied: &mut impl FnMut(&[Message]),
+        delay: &mut Duration,
+    ) -> Result<(), WsSyncError> {
+        let (socket, _) = tokio_tungstenite::connect_async(self.url.as_str()).await?;
+        let mut conn = WsConnection::new(socket);
+        pending.clear();
+        let applied = conn.catch_up(engine).await?;
+        on_applied(&applied);
+        *delay = self.backoff.initial;
+
+        loop {
+            tokio::select! {
+                batch = pending.next_batch() => {
+                    let Some(batch) = batch else {
+                        return conn.close().await;
+                    };
+                    conn.push(batch).await?;
+                    if pending.take_overflow() {
+                        pending.clear();
+                        on_applied(&conn.catch_up(engine).await?);
+                    }
+                }
+                frame = conn.recv() => match frame?.ok_or(WsSyncError::Closed)? {
+                    Frame::Push(messages) => on_applied(&apply_pushed(engine, messages)?),
+                    Frame::Resync => on_applied(&conn.catch_up(engine).await?),
+                    // Only ever replies to our own sync frames
+                    Frame::Sync(_) | Frame::Quiet => {}
+                },
+            }
+        }
+    }
+}
+
+/// Fans pushes out between the connections of one group
+#[derive(Debug, Clone)]
+pub struct WsHub {
+    pushes: broadcast::Sender<(u64, Arc<Vec<Message>>)>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl WsHub {
+    /// A hub buffering up to `capacity` pushes for slow connections, which
+    /// are told to catch up by diff once they fall further behind
+    pub fn new(capacity: usize) -> Self {
+        let (pushes, _) = broadcast::channel(capacity);
+        WsHub {
+            pushes,
+            next_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn publish(&self, from: u64, messages: Vec<Message>) {
+        if !messages.is_empty() {
+            // No receivers just means no one else is connected
+            let _ = self.pushes.send((from, Arc::new(messages)));
+        }
+    }
+}
+
+/// Serve one client socket until it closes
+///
+/// Sync frames are answered from `engine`, and whatever a client sends
+/// that is new, by push or by diff, is pushed on to the hub's other
+/// connections. Cancellation safe, as `WsSyncClient::run` is.
+pub async fn serve<S, T>(
+    socket: WebSocketStream<T>,
+    engine: &Mutex<Engine<S>>,
+    hub: &WsHub,
+) -> Result<(), WsSyncError>
+where
+    S: MessageStore,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    serve_cancellable(socket, engine, hub, &CancellationToken::new()).await
+}
+
+/// `serve`, dropping the socket and returning `WsSyncError::Cancelled`
+/// once `cancel` fires, e.g. on server shutdown
+pub async fn serve_cancellable<S, T>(
+    socket: WebSocketStream<T>,
+    engine: &Mutex<Engine<S>>,
+    hub: &WsHub,
+    cancel: &CancellationToken,
+) -> Result<(), WsSyncError>
+where
+    S: MessageStore,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::select! {
+        biased;
+        () = cancel.cancelled() => Err(WsSyncError::Cancelled),
+        result = serve_socket(socket, engine, hub) => result,
+    }
+}
+
+async fn serve_socket<S, T>(
+    socket: WebSocketStream<T>,
+    engine: &Mutex<Engine<S>>,
+    hub: &WsHub,
+) -> Result<(), WsSyncError>
+where
+    S: MessageStore,
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let id = hub.next_id.fetch_add(1, Ordering::Relaxed);
+    let mut pushes = hub.pushes.subscribe();
+    let mut conn = WsConnection::new(socket);
+    loop {
+        tokio::select! {
+            frame = conn.recv() => match frame? {
+                None => return Ok(()),
+                Some(Frame::Sync(bytes)) => {
+                    let (reply, applied) = {
+                        let mut engine = lock(engine);
+                        let mut session = SyncSession::new(&mut engine);
+                        let reply = session.handle(&bytes)?;
+                        (reply, session.applied().to_vec())
+                    };
+                    hub.publish(id, applied);
+                    match reply {
+                        Some(reply) => conn.send(&Frame::Sync(reply)).await?,
+                        None => conn.send(&Frame::Quiet).await?,
+                    }
+                }
+                Some(Frame::Push(messages)) => hub.publish(id, apply_pushed(engine, messages)?),
+                Some(Frame::Quiet | Frame::Resync) => {}
+            },
+            push = pushes.recv() => match push {
+                Ok((from, _)) if from == id => {}
+                Ok((_, messages)) => conn.push(messages.to_vec()).await?,
+                Err(broadcast::error::RecvError::Lagged(_)) => conn.send(&Frame::Resync).await?,
+                Err(broadcast::error::RecvError::Closed) => return Ok(()),
+            },
+        }
+    }
+}
+
+// Errors related to syncing over a WebSocket
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum WsSyncError {
+    WebSocket(Box<tungstenite::Error>),
+    Decode(DecodeError),
+    Sync(SyncError),
+    // The peer hung up mid-session
+    Closed,
+    Cancelled,
+}
+
+impl From<tungstenite::Error> for WsSyncError {
+    fn from(err: tungstenite::Error) -> Self {
+        WsSyncError::WebSocket(Box::new(err))
+    }
+}
+
+impl From<DecodeError> for WsSyncError {
+    fn from(err: DecodeError) -> Self {
+        WsSyncError::Decode(err)
+    }
+}
+
+impl From<SyncError> for WsSyncError {
+    fn from(err: SyncError) -> Self {
+        WsSyncError::Sync(err)
+    }
+}
+
+impl fmt::Display for WsSyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            WsSyncError::WebSocket(ref err) => write!(f, "sync socket failed: {}", err),
+            WsSyncError::Decode(ref err) => write!(f, "malformed sync frame: {}", err),
+            WsSyncError::Sync(ref err) => err.fmt(f),
+            WsSyncError::Closed => write!(f, "peer closed the sync socket"),
+            WsSyncError::Cancelled => write!(f, "sync cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for WsSyncError {}
+
+impl WsSyncError {
+    /// What went wrong, in terms an application can act on
+    pub fn failure(&self) -> SyncFailure {
+        match *self {
+            WsSyncError::WebSocket(ref err) => match **err {
+                tungstenite::Error::Http(ref response) => {
+                    SyncFailure::from_status(response.status().as_u16(), err.to_string())
+                }
+                _ => SyncFailure::Network {
+                    detail: err.to_string(),
+                },
+            },
+            WsSyncError::Decode(ref err) => SyncFailure::from_decode(err),
+            WsSyncError::Sync(ref err) => err.failure(),
+            WsSyncError::Closed | WsSyncError::Cancelled => SyncFailure::Network {
+                detail: self.to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    use crate::store::{MemoryStore, MessageStoreExt};
+    use crate::timestamp::{make_client_id, Timestamp};
+    use crate::trie::Trie;
+
+    fn engine() -> Arc<Mutex<Engine<MemoryStore>>> {
+        let clock = Timestamp::new(0, 0, make_client_id());
+        Arc::new(Mutex::new(Engine::new(clock, MemoryStore::new()).unwrap()))
+    }
+
+    fn write(engine: &Mutex<Engine<MemoryStore>>, row: &str) -> Message {
+        let now = Utc::now().timestamp_millis();
+        lock(engine).write("todos", row, "title", row, now).unwrap()
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let message = write(&engine(), "1");
+        for frame in [
+            Frame::Sync(vec![1, 2, 3]),
+            Frame::Quiet,
+            Frame::Push(vec![message]),
+            Frame::Resync,
+        ] {
+            assert_eq!(Frame::decode(&frame.encode()), Ok(frame));
+        }
+        assert_eq!(Frame::decode(&[9]), Err(DecodeError::InvalidTag(9)));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(3),
+        };
+        assert_eq!(backoff.next(backoff.initial), Duration::from_secs(2));
+        assert_eq!(backoff.next(Duration::from_secs(2)), Duration::from_secs(3));
+    }
+
+    #[tokio::test]
+    async fn test_live_sync() {
+        let server = engine();
+        write(&server, "server");
+        let hub = WsHub::new(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/sync", listener.local_addr().unwrap());
+        {
+            let server = server.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let (server, hub) = (server.clone(), hub.clone());
+                    tokio::spawn(async move { serve(socket, &server, &hub).await });
+                }
+            });
+        }
+
+        // Each client reports what it applies
+        let start = |engine: Arc<Mutex<Engine<MemoryStore>>>| {
+            let (outbox, mut pending) = outbox(16);
+            let (tx, rx) = mpsc::unbounded_channel();
+            let client = WsSyncClient::new(&url);
+            let handle = tokio::spawn(async move {
+                client
+                    .run(&engine, &mut pending, |applied| {
+                        let rows = applied.iter().map(|m| m.row.clone());
+                        tx.send(rows.collect::<Vec<_>>()).unwrap();
+                    })
+                    .await
+            });
+            (outbox, rx, handle)
+        };
+
+        let a = engine();
+        write(&a, "offline");
+        let (a_outbox, mut a_applied, a_handle) = start(a.clone());
+        assert_eq!(a_applied.recv().await.unwrap(), vec!["server"]);
+
+        let b = engine();
+        let (b_outbox, mut b_applied, b_handle) = start(b.clone());
+        let mut caught_up = b_applied.recv().await.unwrap();
+        caught_up.sort();
+        assert_eq!(caught_up, vec!["offline", "server"]);
+
+        // A live write reaches the other client without another diff
+        a_outbox.send(write(&a, "live"));
+        assert_eq!(b_applied.recv().await.unwrap(), vec!["live"]);
+        assert!(lock(&server).get("todos", "live", "title").is_some());
+
+        drop(a_outbox);
+        drop(b_outbox);
+        a_handle.await.unwrap().unwrap();
+        b_handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_run_cancelled() {
+        let server = engine();
+        for row in 0..20 {
+            write(&server, &row.to_string());
+        }
+        let hub = WsHub::new(16);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/sync", listener.local_addr().unwrap());
+        {
+            let server = server.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+                    let (server, hub) = (server.clone(), hub.clone());
+                    tokio::spawn(async move { serve(socket, &server, &hub).await });
+                }
+            });
+        }
+
+        // Cancel at a different point each round: connecting, catching
+        // up, pushing, or idle
+        let mut seed = 0x9e37_79b9_u32;
+        for round in 0..20 {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            let delay = Duration::from_micros(u64::from(seed % 10_000));
+
+            let client = engine();
+            write(&client, &format!("client {}", round));
+            let (outbox, mut pending) = outbox(16);
+            outbox.send(write(&client, &format!("pushed {}", round)));
+            let cancel = CancellationToken::new();
+            let timer = {
+                let cancel = cancel.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    cancel.cancel();
+                })
+            };
+            let result = WsSyncClient::new(&url)
+                .run_cancellable(&client, &mut pending, |_| {}, &cancel)
+                .await;
+            assert!(matches!(result, Err(WsSyncError::Cancelled)));
+            timer.await.unwrap();
+
+            // Whatever was applied is whole: the trie indexes the store
+            let engine = lock(&client);
+            let stored = engine.store().all_messages();
+            let timestamps = stored.iter().map(|m| m.timestamp.clone()).collect();
+            assert!(engine.trie().unwrap().deep_eq(&Trie::build(timestamps)));
+            for message in &stored {
+                assert!(engine.get("todos", &message.row, "title").is_some());
+            }
+        }
+    }
+}
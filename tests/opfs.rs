@@ -0,0 +1,64 @@
+// The OPFS store against a real origin private file system. Browsers only
+// hand out synchronous access handles in dedicated workers, so run with
+//
+//   wasm-pack test --headless --chrome --features opfs
+#![cfg(all(target_arch = "wasm32", feature = "opfs"))]
+
+use markle::message::Message;
+use markle::opfs::{LogFile, OpfsStore};
+use markle::store::MessageStore;
+use markle::timestamp::Timestamp;
+use wasm_bindgen_test::*;
+
+wasm_bindgen_test_configure!(run_in_dedicated_worker);
+
+fn message(millis: i64, value: &str) -> Message {
+    Message {
+        timestamp: Timestamp::new(millis, 0, "aaaaaaaaaaaaaaaa".to_string()),
+        dataset: "todos".to_string(),
+        row: "1".to_string(),
+        column: "title".to_string(),
+        value: value.to_string(),
+    }
+}
+
+#[wasm_bindgen_test]
+async fn test_opfs_reopen() {
+    let name = "markle-test-reopen.log";
+    {
+        let mut store = OpfsStore::open_opfs(name).await.unwrap();
+        for existing in store.messages_since(i64::MIN) {
+            store.remove(&existing.timestamp);
+        }
+        assert_eq!(
+            store.insert_batch(vec![message(1, "a"), message(2, "b")]),
+            2
+        );
+        assert!(store.remove(&message(1, "a").timestamp));
+    }
+
+    // Dropping the store closed the handle, as a closed tab would
+    let store = OpfsStore::open_opfs(name).await.unwrap();
+    assert_eq!(store.messages_since(i64::MIN), vec![message(2, "b")]);
+}
+
+#[wasm_bindgen_test]
+async fn test_opfs_torn_record() {
+    let name = "markle-test-torn.log";
+    let mut store = OpfsStore::open_opfs(name).await.unwrap();
+    for existing in store.messages_since(i64::MIN) {
+        store.remove(&existing.timestamp);
+    }
+    store.insert(message(1, "a"));
+    let intact = store.file_size();
+
+    // Half a record, as a tab killed mid-write leaves it
+    let mut file = store.into_file();
+    file.write_at(&[0, 0, 0, 64, 1, 2, 3], intact).unwrap();
+    file.flush().unwrap();
+    drop(file);
+
+    let store = OpfsStore::open_opfs(name).await.unwrap();
+    assert_eq!(store.messages_since(i64::MIN), vec![message(1, "a")]);
+    assert_eq!(store.file_size(), intact);
+}
@@ -0,0 +1,78 @@
+//! Property tests for trie and diff invariants
+#![cfg(not(target_arch = "wasm32"))]
+
+use std::collections::BTreeSet;
+
+use proptest::prelude::*;
+
+use markle::timestamp::Timestamp;
+use markle::trie::{HashScheme, Trie};
+
+// Keys are 16 base-3 digits, which covers minutes 0 .. 3^16
+const MAX_MILLIS: i64 = 3i64.pow(16) * 60_000;
+
+fn timestamp() -> impl Strategy<Value = Timestamp> {
+    // Bias towards the epoch so all-zero and leading-zero keys come up
+    let millis = prop_oneof![0..120_000i64, 0..MAX_MILLIS];
+    (millis, any::<u16>(), "[0-9A-F]{16}")
+        .prop_map(|(millis, counter, node)| Timestamp::new(millis, counter, node))
+}
+
+fn timestamps() -> impl Strategy<Value = Vec<Timestamp>> {
+    prop::collection::btree_set(timestamp(), 0..40).prop_map(|set| set.into_iter().collect())
+}
+
+fn scheme() -> impl Strategy<Value = HashScheme> {
+    prop_oneof![Just(HashScheme::Murmur32Xor), Just(HashScheme::Xxh64Add)]
+}
+
+fn insert_all(scheme: HashScheme, timestamps: &[Timestamp]) -> Trie {
+    let mut trie = Trie::with_scheme(scheme);
+    for ts in timestamps {
+        trie.insert(ts.clone());
+    }
+    trie
+}
+
+proptest! {
+    #[test]
+    fn test_insert_order_independent(
+        scheme in scheme(),
+        (timestamps, shuffled) in timestamps().prop_flat_map(|v| (Just(v.clone()), Just(v).prop_shuffle())),
+    ) {
+        let a = insert_all(scheme, &timestamps);
+        let b = insert_all(scheme, &shuffled);
+        prop_assert_eq!(a.root_hash(), b.root_hash());
+        prop_assert_eq!(Trie::build_with_scheme(scheme, shuffled).root_hash(), a.root_hash());
+    }
+
+    #[test]
+    fn test_diff_identical(scheme in scheme(), timestamps in timestamps()) {
+        let a = insert_all(scheme, &timestamps);
+        let b = Trie::build_with_scheme(scheme, timestamps);
+        prop_assert_eq!(a.diff(&b), None);
+        prop_assert_eq!(b.diff(&a), None);
+    }
+
+    #[test]
+    fn test_diff_one_missing(scheme in scheme(), timestamps in timestamps(), extra in timestamp()) {
+        let shared: BTreeSet<Timestamp> = timestamps.into_iter().filter(|ts| *ts != extra).collect();
+        let a = Trie::build_with_scheme(scheme, shared.iter().cloned().collect());
+        let mut b = a.clone();
+        b.insert(extra.clone());
+
+        let minute = extra.epoch().millis();
+        for got in [a.diff(&b), b.diff(&a)] {
+            let got = got.expect("tries differing by a timestamp must diverge");
+            prop_assert!(got.timestamp_millis() <= minute);
+        }
+    }
+
+    #[test]
+    fn test_encode_roundtrip(scheme in scheme(), timestamps in timestamps()) {
+        let trie = Trie::build_with_scheme(scheme, timestamps);
+        let decoded = Trie::decode(&trie.encode()).unwrap();
+        prop_assert_eq!(decoded.diff(&trie), None);
+        prop_assert_eq!(decoded, trie);
+    }
+}
@@ -0,0 +1,242 @@
+//! Cross-checks against the reference JavaScript implementation
+//!
+//! `test_vectors` always runs, against the driver's output saved in
+//! tests/fixtures. The rest are skipped unless `MARKLE_REFERENCE_JS` names
+//! a directory containing the reference `timestamp.js` and `merkle.js`, and
+//! `node` is on the path. Set `MARKLE_REFERENCE_SEED` to replay a failing
+//! run.
+//!
+//! To regenerate the vectors after changing tests/fixtures/cases.txt:
+//!
+//! ```text
+//! MARKLE_REFERENCE_JS=<dir> node tests/reference/driver.js vectors \
+//!     < tests/fixtures/cases.txt > tests/fixtures/vectors.txt
+//! ```
+
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+#[cfg(feature = "js")]
+use markle::compat::CrdtJson;
+use markle::timestamp::Timestamp;
+use markle::trie::Trie;
+
+const CASES: usize = 200;
+
+// One line per case: its timestamps' strings, their hashes, and the JSON of
+// the reference trie holding them all
+const VECTORS: &str = include_str!("fixtures/vectors.txt");
+
+// Minute 2004-01-01 .. 2040-01-01, the range where keys are 16 digits wide
+const MIN_MILLIS: i64 = 1_072_915_200_000;
+const MAX_MILLIS: i64 = 2_208_988_800_000;
+
+// xorshift64, so failures can be replayed from the printed seed
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + (self.next() % (hi - lo) as u64) as i64
+    }
+
+    fn case(&mut self) -> Case {
+        Case {
+            millis: self.range(MIN_MILLIS, MAX_MILLIS),
+            counter: self.range(0, 0x10000) as u16,
+            node: self.node(),
+        }
+    }
+
+    // Hex of 1 to 24 digits, so the reference pads some nodes and cuts
+    // others
+    fn node(&mut self) -> String {
+        let len = self.range(1, 25);
+        (0..len)
+            .map(|_| char::from(b"0123456789ABCDEF"[(self.next() % 16) as usize]))
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+struct Case {
+    millis: i64,
+    counter: u16,
+    node: String,
+}
+
+impl Case {
+    fn timestamp(&self) -> Timestamp {
+        Timestamp::new(self.millis, self.counter, self.node.clone())
+    }
+}
+
+fn reference() -> Option<String> {
+    let dir = env::var("MARKLE_REFERENCE_JS").ok()?;
+    Command::new("node").arg("--version").output().ok()?;
+    Some(dir)
+}
+
+fn rng() -> Rng {
+    let seed = env::var("MARKLE_REFERENCE_SEED")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0x5EED_1234_ABCD_0001);
+    println!("seed {}", seed);
+    Rng(seed)
+}
+
+fn run(dir: &str, command: &str, input: &str) -> String {
+    let driver = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/reference/driver.js");
+    let mut child = Command::new("node")
+        .arg(driver)
+        .arg(command)
+        .env("MARKLE_REFERENCE_JS", dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "reference driver failed");
+    String::from_utf8(output.stdout).unwrap()
+}
+
+fn encode_set(cases: &[Case]) -> String {
+    cases
+        .iter()
+        .map(|c| format!("{}:{}:{}", c.millis, c.counter, c.node))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn build(cases: &[Case]) -> Trie {
+    Trie::build(cases.iter().map(Case::timestamp).collect())
+}
+
+#[test]
+fn test_vectors() {
+    let mut count = 0;
+    for line in VECTORS.lines() {
+        let mut parts = line.splitn(3, ' ');
+        let (Some(strings), Some(hashes), Some(json)) = (parts.next(), parts.next(), parts.next())
+        else {
+            panic!("malformed vector {}", line);
+        };
+
+        let mut trie = Trie::new();
+        let mut root = 0;
+        for (string, hash) in strings.split(',').zip(hashes.split(',')) {
+            let ts: Timestamp = string.parse().unwrap();
+            let hash: u32 = hash.parse().unwrap();
+            assert_eq!(ts.to_string(), string);
+            assert_eq!(ts.hash(), hash, "{}", string);
+            trie.insert(ts);
+            root ^= hash;
+            count += 1;
+        }
+        assert_eq!(trie.root_hash(), u64::from(root), "{}", strings);
+        // The reference stores hashes as signed 32 bit XORs
+        assert!(json.ends_with(&format!("\"hash\":{}}}", root as i32)));
+
+        #[cfg(feature = "js")]
+        {
+            assert_eq!(CrdtJson::encode(&trie).unwrap(), json, "{}", strings);
+            assert_eq!(CrdtJson::decode(json).unwrap().diff(&trie), None);
+        }
+    }
+    assert!(count > 100);
+}
+
+#[test]
+fn test_timestamps_match_reference() {
+    let Some(dir) = reference() else {
+        println!("MARKLE_REFERENCE_JS not set, skipping");
+        return;
+    };
+    let mut rng = rng();
+
+    let cases: Vec<Case> = (0..CASES).map(|_| rng.case()).collect();
+    let input: String = cases
+        .iter()
+        .map(|c| format!("{} {} {}\n", c.millis, c.counter, c.node))
+        .collect();
+
+    let output = run(&dir, "timestamps", &input);
+    assert_eq!(output.lines().count(), cases.len());
+    for (case, line) in cases.iter().zip(output.lines()) {
+        let (string, hash) = line.split_once(' ').unwrap();
+        let ts = case.timestamp();
+        assert_eq!(
+            ts.to_string().as_bytes(),
+            string.as_bytes(),
+            "{}",
+            case.node
+        );
+        assert_eq!(ts.hash().to_string(), hash, "{}", case.node);
+    }
+}
+
+#[cfg(feature = "js")]
+#[test]
+fn test_json_matches_reference() {
+    let Some(dir) = reference() else {
+        println!("MARKLE_REFERENCE_JS not set, skipping");
+        return;
+    };
+    let mut rng = rng();
+
+    for _ in 0..CASES / 10 {
+        let cases: Vec<Case> = (0..rng.range(1, 20)).map(|_| rng.case()).collect();
+        let want = run(&dir, "merkle", &format!("{}\n", encode_set(&cases)));
+        let got = CrdtJson::encode(&build(&cases)).unwrap();
+        assert_eq!(got, want.trim(), "cases={}", encode_set(&cases));
+
+        let decoded = CrdtJson::decode(want.trim()).unwrap();
+        assert_eq!(decoded.diff(&build(&cases)), None);
+    }
+}
+
+#[test]
+fn test_diff_matches_reference() {
+    let Some(dir) = reference() else {
+        println!("MARKLE_REFERENCE_JS not set, skipping");
+        return;
+    };
+    let mut rng = rng();
+
+    for _ in 0..CASES / 10 {
+        let shared: Vec<Case> = (0..rng.range(0, 20)).map(|_| rng.case()).collect();
+        let mut a = shared.clone();
+        let mut b = shared;
+        a.extend((0..rng.range(0, 5)).map(|_| rng.case()));
+        b.extend((0..rng.range(0, 5)).map(|_| rng.case()));
+
+        let got = build(&a)
+            .diff(&build(&b))
+            .map(|dt| dt.timestamp_millis().to_string())
+            .unwrap_or_else(|| "null".to_string());
+
+        let input = format!("{}\n{}\n", encode_set(&a), encode_set(&b));
+        let want = run(&dir, "diff", &input);
+        assert_eq!(
+            got,
+            want.trim(),
+            "a={} b={}",
+            encode_set(&a),
+            encode_set(&b)
+        );
+    }
+}
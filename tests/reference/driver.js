@@ -0,0 +1,61 @@
+// Cross-check driver for tests/reference.rs
+//
+// Loads the reference crdt implementation (timestamp.js and merkle.js from
+// the crdt example app) from the directory named by MARKLE_REFERENCE_JS and
+// answers one command per invocation, reading cases from stdin:
+//
+//   timestamps  "millis counter node" per line -> "string hash" per line
+//   diff        two lines of "millis:counter:node,..." -> diff millis or "null"
+//   merkle      one line of "millis:counter:node,..." -> JSON.stringify(trie)
+//   vectors     "millis:counter:node,..." per line -> "strings hashes trie" per
+//               line, for tests/fixtures/vectors.txt
+const fs = require('fs');
+const path = require('path');
+
+const dir = process.env.MARKLE_REFERENCE_JS;
+const { Timestamp } = require(path.join(dir, 'timestamp.js'));
+const merkle = require(path.join(dir, 'merkle.js'));
+
+const lines = fs.readFileSync(0, 'utf8').split('\n');
+
+function parseTimestamp(s) {
+  const [millis, counter, node] = s.split(':');
+  return new Timestamp(Number(millis), Number(counter), node);
+}
+
+function buildTrie(line) {
+  let trie = {};
+  for (const s of line.split(',').filter(Boolean)) {
+    trie = merkle.insert(trie, parseTimestamp(s));
+  }
+  return trie;
+}
+
+switch (process.argv[2]) {
+  case 'timestamps':
+    for (const line of lines.filter(Boolean)) {
+      const [millis, counter, node] = line.split(' ');
+      const ts = new Timestamp(Number(millis), Number(counter), node);
+      // hashes are compared as unsigned 32-bit integers
+      console.log(`${ts.toString()} ${ts.hash() >>> 0}`);
+    }
+    break;
+  case 'diff': {
+    const diff = merkle.diff(buildTrie(lines[0]), buildTrie(lines[1]));
+    console.log(diff === null ? 'null' : String(diff));
+    break;
+  }
+  case 'merkle':
+    console.log(JSON.stringify(buildTrie(lines[0])));
+    break;
+  case 'vectors':
+    for (const line of lines.filter(Boolean)) {
+      const timestamps = line.split(',').map(parseTimestamp);
+      const strings = timestamps.map(ts => ts.toString()).join(',');
+      const hashes = timestamps.map(ts => ts.hash() >>> 0).join(',');
+      console.log(`${strings} ${hashes} ${JSON.stringify(buildTrie(line))}`);
+    }
+    break;
+  default:
+    throw new Error(`unknown command ${process.argv[2]}`);
+}